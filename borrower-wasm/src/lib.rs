@@ -1,5 +1,5 @@
 use wasm_bindgen::prelude::*;
-use bitcoin::{Address, Sequence};
+use bitcoin::Address;
 use firefish_core::contract::{self, participant};
 use secp256k1::{Keypair, SECP256K1};
 
@@ -15,39 +15,54 @@ pub struct Offer(firefish_core::contract::offer::Offer);
 
 #[wasm_bindgen]
 impl Offer {
-    /// Parses the offer from base64-encoded string.
-    pub fn parse(offer_base64: &str) -> Result<Offer, JsValue> {
-        let bytes = base64::decode(offer_base64).map_err(into_string)?;
-        let offer = contract::offer::Offer::deserialize(&mut &*bytes).map_err(into_debug_string)?;
-        Ok(Offer(offer))
+    /// Parses the offer from its `ffoffer1` string form.
+    ///
+    /// Legacy base64-encoded offers without the prefix are accepted as well.
+    pub fn parse(offer: &str) -> Result<Offer, JsValue> {
+        match offer.parse::<contract::offer::Offer>() {
+            Ok(offer) => Ok(Offer(offer)),
+            Err(contract::offer::OfferParseError::NotAnOffer) => {
+                let bytes = contract::encoding::decode_message(offer).map_err(into_string)?;
+                let offer = contract::offer::Offer::deserialize(&mut &*bytes).map_err(into_debug_string)?;
+                Ok(Offer(offer))
+            },
+            Err(error) => Err(into_string(error).into()),
+        }
     }
 
     /// Creates borrower state using the offer and return address.
     ///
-    /// If this method returns an error it means the return address is invalid.
+    /// If this method returns an error it means the return address is invalid, the offer reuses
+    /// keys or it uses an internal key other than the default one.
     pub fn accept(&self, return_address: &str) -> Result<Borrower, JsValue> {
         let return_address = return_address.parse::<Address<_>>()
             .map_err(into_string)?
             .require_network(self.0.escrow.network)
             .map_err(into_string)?;
+        self.0.validate_key_distinctness().map_err(into_debug_string)?;
         let key_pair = Keypair::new(SECP256K1, &mut secp256k1::rand::thread_rng());
 
         let params = participant::borrower::MandatoryPrefundParams {
             key_pair,
-            lock_time: Sequence::from_height(144 * 7), // 7 days
+            lock_time: participant::borrower::PrefundDelay::from_height(144 * 7), // 7 days
             return_script: return_address.script_pubkey(),
         };
 
-        let borrower = participant::borrower::init_prefund(self.0.clone(), params.into_params());
+        let borrower = participant::borrower::init_prefund(self.0.clone(), params.into_params())
+            .map_err(into_debug_string)?;
 
         let mut message = Vec::new();
         borrower.borrower_info().serialize(&mut message);
-        let message = base64::encode(&message);
+        let message = contract::encoding::encode_message(&message);
 
         Ok(Borrower {
             state: Some(participant::borrower::State::WaitingForFunding(borrower)),
             message: Some(message),
             cancel_tx: None,
+            last_error_context: None,
+            deterministic_escrow_key: false,
+            cancel_fee_surcharge_sat_per_vb: DEFAULT_CANCEL_FEE_SURCHARGE_SAT_PER_VB,
+            current_height: None,
         })
     }
 }
@@ -59,9 +74,21 @@ pub struct Borrower {
     // None means message_received panicked
     state: Option<participant::borrower::State>,
     message: Option<String>,
-    cancel_tx: Option<bitcoin::Transaction>
+    cancel_tx: Option<bitcoin::Transaction>,
+    last_error_context: Option<String>,
+    deterministic_escrow_key: bool,
+    cancel_fee_surcharge_sat_per_vb: u64,
+    current_height: Option<bitcoin::absolute::Height>,
 }
 
+/// Added to the escrow fee rate so that the pre-computed cancel transaction confirms quickly.
+const DEFAULT_CANCEL_FEE_SURCHARGE_SAT_PER_VB: u64 = 50;
+
+/// How many blocks old the escrow hints may be before they're rejected.
+///
+/// Old hints may be missing funding transactions or have an outdated fee rate.
+const MAX_ESCROW_HINTS_AGE_BLOCKS: u32 = 6;
+
 struct TakenStateInner<'a, S, F> {
     state: S,
     map: F,
@@ -125,71 +152,27 @@ impl Borrower {
 	/// After this method returns messageToSend() MUST be called to potentially obtain a response.
     ///
     /// If this function returns an error (exception) the message was invalid and the error should
-    /// be logged.
+    /// be logged together with lastErrorContext().
     pub fn message_received(&mut self, message: &str) -> Result<(), JsValue> {
-        use contract::escrow::TedSignatures;
-
-        let bytes = base64::decode(message).map_err(into_string)?;
-
-        match self.state.take().expect("use of invalidated Borrower") {
-            participant::borrower::State::WaitingForFunding(state) => {
-                let state = TakenState::new(state, &mut self.state, participant::borrower::State::WaitingForFunding);
-                let hints = contract::offer::EscrowHints::deserialize(&mut &*bytes)
-                    .map_err(into_debug_string)?;
-                let cancel_fee_rate = bitcoin::FeeRate::from_sat_per_vb(50 + hints.fee_rate.to_sat_per_vb_ceil()).unwrap();
-                let funding = participant::borrower::Funding::from_hints(hints);
-                let mut response = Vec::new();
-                let txs = funding.mandatory.transactions.clone();
-                let height = bitcoin::absolute::Height::from_consensus(0).unwrap();
-                let delay = participant::borrower::RelativeDelay::Height(144 * 7);
-                let cancel_tx = state.state().funding_cancel(txs, cancel_fee_rate, height, delay)
-                    .map_err(into_debug_string)?;
-                self.cancel_tx = Some(cancel_tx);
-                state.try_map(|state| {
-                    state.funding_received(funding, &mut response)
-                        .map(|state| participant::borrower::State::ReceivingEscrowSignature { state, received: None })
-                })
-                    .map_err(into_debug_string)?;
-                self.message = Some(base64::encode(&response));
-                Ok(())
+        use bitcoin::hex::DisplayHex;
+
+        let result = self.message_received_inner(message);
+        self.last_error_context = match &result {
+            Ok(()) => None,
+            Err(_) => match contract::encoding::decode_message(message) {
+                Ok(bytes) => Some(format!("rejected message (hex): {}", bytes.to_lower_hex_string())),
+                Err(_) => Some(format!("rejected message (invalid base64): {}", message)),
             },
-            participant::borrower::State::ReceivingEscrowSignature { state, received } => {
-                let mut state = TakenState::new((state, received), &mut self.state, |(state, received)| participant::borrower::State::ReceivingEscrowSignature { state, received });
-                let message = TedSignatures::deserialize(&mut &*bytes)
-                    .map_err(into_debug_string)?
-                    .ok_or("empty message")?;
-                let received = &mut state.state_mut().1;
-                match (received.take(), message) {
-                    (None, message) => {
-                        *received = Some(message);
-                        self.message = None;
-                        Ok(())
-                    },
-                    (Some(TedSignatures::TedO(ted_o)), TedSignatures::TedP(ted_p)) |
-                     (Some(TedSignatures::TedP(ted_p)), TedSignatures::TedO(ted_o)) => {
-                         state.try_map(|state| {
-                             state.0.verify_signatures(ted_o, ted_p)
-                                 .map(participant::borrower::State::SignaturesVerified)
-                                 .map_err(|(old, err)| ((old, None), err))
-                         })
-                         .map_err(into_debug_string)?;
-                         Ok(())
-                     },
-                    (Some(old @ TedSignatures::TedO(_)), TedSignatures::TedO(_)) | (Some(old @ TedSignatures::TedP(_)), TedSignatures::TedP(_)) => {
-                        *received = Some(old);
-                        Err("message already received".into())
-                    },
-                }
-            },
-            state @ participant::borrower::State::SignaturesVerified(_) => {
-                self.state = Some(state);
-                Err("No message was expected in this state".into())
-            },
-            state @ participant::borrower::State::EscrowSigned(_) => {
-                self.state = Some(state);
-                Err("No message was expected in this state".into())
-            },
-        }
+        };
+        result
+    }
+
+    /// Returns the information needed to reproduce the last failure of messageReceived().
+    ///
+    /// This contains the raw bytes of the rejected message, so it can be logged and inspected
+    /// without the state. Returns null if the last message was accepted.
+    pub fn last_error_context(&self) -> Option<String> {
+        self.last_error_context.clone()
     }
 
 	/// Call this when the user confirmed he backed up the recover transaction.
@@ -205,6 +188,8 @@ impl Borrower {
         match self.state.take().expect("use of invalid state") {
             participant::borrower::State::SignaturesVerified(state) => {
                 let state = TakenState::new(state, &mut self.state, participant::borrower::State::SignaturesVerified);
+                state.state().participant_data().validate_return_script(state.state().network())
+                    .map_err(into_debug_string)?;
                 let mut message = Vec::new();
                 state.try_map(|state| {
                     let new_state = state.assemble_escrow()?;
@@ -212,7 +197,7 @@ impl Borrower {
 
                     Ok(participant::borrower::State::EscrowSigned(new_state))
                 }).map_err(into_debug_string)?;
-                self.message = Some(base64::encode(&message));
+                self.message = Some(contract::encoding::encode_message(&message));
                 Ok(())
             },
             state => {
@@ -240,6 +225,17 @@ impl Borrower {
         self.message.clone()
     }
 
+    /// Returns the Electrum-style script hash of the funding address.
+    ///
+    /// This can be used to subscribe to the funding address using Electrum or Esplora APIs.
+    /// Throws if the contract is not waiting for funding.
+    pub fn funding_script_hash(&self) -> Result<String, JsValue> {
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::WaitingForFunding(state) => Ok(state.funding_script_hash().to_string()),
+            _ => Err("funding_script_hash called in invalid state".into()),
+        }
+    }
+
 	/// Returns the invoice for the user to pay.
 	///
 	/// This method may only be called in PrefundReady state!
@@ -265,17 +261,21 @@ impl Borrower {
     pub fn serialize_state(&self) -> String {
         let mut buf = Vec::new();
         self.state.as_ref().expect("attempt to use invalid state").serialize(&mut buf);
-        base64::encode(&buf)
+        contract::encoding::encode_message(&buf)
     }
 
     /// Deserializes the whole borrower state.
     pub fn deserialize_state(state: &str) -> Result<Borrower, JsValue> {
-        let bytes = base64::decode(state).map_err(into_string)?;
+        let bytes = contract::encoding::decode_message(state).map_err(into_string)?;
         let state = participant::borrower::State::deserialize(&mut &*bytes).map_err(into_debug_string)?;
         Ok(Borrower {
             state: Some(state),
             message: None,
             cancel_tx: None,
+            last_error_context: None,
+            deterministic_escrow_key: false,
+            cancel_fee_surcharge_sat_per_vb: DEFAULT_CANCEL_FEE_SURCHARGE_SAT_PER_VB,
+            current_height: None,
         })
     }
 
@@ -329,6 +329,134 @@ impl Borrower {
         }
     }
 
+    /// Returns the raw lock time of the recover transaction.
+    ///
+    /// Values below 500 000 000 are block heights, the others are unix timestamps.
+    /// The recover transaction can not be broadcast before this lock time passes.
+    pub fn recover_lock_time(&self) -> Result<u32, JsValue> {
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::SignaturesVerified(state) => {
+                Ok(state.recover_lock_time().to_consensus_u32())
+            },
+            participant::borrower::State::EscrowSigned(state) => {
+                Ok(state.recover_lock_time().to_consensus_u32())
+            },
+            _ => Err("recover_lock_time called in invalid state".into()),
+        }
+    }
+
+    /// Checks whether the recover transaction can be broadcast now.
+    ///
+    /// Parameters:
+    ///
+    /// * tip_height - the height of the current best block
+    /// * median_time_past - the median time of the last 11 blocks (as returned by bitcoind)
+    pub fn can_broadcast_recover(&self, tip_height: u32, median_time_past: u32) -> Result<bool, JsValue> {
+        let tip_height = bitcoin::absolute::Height::from_consensus(tip_height).map_err(into_string)?;
+        let median_time_past = bitcoin::absolute::Time::from_consensus(median_time_past).map_err(into_string)?;
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::SignaturesVerified(state) => {
+                Ok(state.can_broadcast_recover(tip_height, median_time_past))
+            },
+            participant::borrower::State::EscrowSigned(state) => {
+                Ok(state.can_broadcast_recover(tip_height, median_time_past))
+            },
+            _ => Err("can_broadcast_recover called in invalid state".into()),
+        }
+    }
+
+    /// Returns the fee rate paid by the signed escrow transaction in sat/vB.
+    ///
+    /// This can be compared with the requested fee rate to detect a significant prediction miss.
+    pub fn escrow_fee_rate(&self) -> Result<f64, JsValue> {
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::EscrowSigned(state) => {
+                let fee_rate = state.escrow_fee_rate().ok_or("the spent outputs are unknown")?;
+                Ok(fee_rate.to_sat_per_kwu() as f64 / 250.0)
+            },
+            _ => Err("escrow_fee_rate called in invalid state".into()),
+        }
+    }
+
+    /// Returns `true` if the prefund can still be cancelled.
+    ///
+    /// This is `false` once the escrow transaction is signed since it may be broadcast anytime.
+    pub fn is_cancellable(&self) -> bool {
+        self.state.as_ref().expect("use of invalid borrower").is_cancellable()
+    }
+
+    /// Returns the address receiving recovered and repaid funds.
+    pub fn return_address(&self) -> Result<String, JsValue> {
+        let address = self.state.as_ref().expect("use of invalid borrower")
+            .return_address()
+            .map_err(into_debug_string)?;
+        Ok(address.to_string())
+    }
+
+    /// Returns the position of the current state in the protocol.
+    ///
+    /// Higher values mean the contract is further along.
+    pub fn progress_rank(&self) -> u8 {
+        self.state.as_ref().expect("use of invalid borrower").progress_rank()
+    }
+
+    /// Returns `true` if `message_received` can process messages in the current state.
+    ///
+    /// Once this returns `false` the app can stop listening for Firefish messages.
+    pub fn expects_message(&self) -> bool {
+        self.state.as_ref().expect("use of invalid borrower").expects_message()
+    }
+
+    /// Makes the escrow key derived from the prefund key instead of generating a random one.
+    ///
+    /// This allows the whole contract to be reconstructed from the prefund key and the offer.
+    /// The setting is not a part of the state so it has to be set again after deserialization.
+    /// It only has effect if called before the funding is received.
+    pub fn use_deterministic_escrow_key(&mut self, enabled: bool) {
+        self.deterministic_escrow_key = enabled;
+    }
+
+    /// Sets how much the pre-computed cancel transaction pays on top of the escrow fee rate.
+    ///
+    /// The default is 50 sat/vB. The setting is not a part of the state so it has to be set again
+    /// after deserialization.
+    pub fn set_cancel_fee_surcharge(&mut self, sat_per_vb: u64) {
+        self.cancel_fee_surcharge_sat_per_vb = sat_per_vb;
+    }
+
+    /// Sets the height of the current best block.
+    ///
+    /// If set, funding transactions with a lock time far above this height are rejected and so are
+    /// escrow hints older than 6 blocks. The setting is not a part of the state so it has to be set
+    /// again after deserialization.
+    pub fn set_current_height(&mut self, height: u32) -> Result<(), JsValue> {
+        let height = bitcoin::absolute::Height::from_consensus(height).map_err(into_string)?;
+        self.current_height = Some(height);
+        Ok(())
+    }
+
+    /// Returns the number of blocks after which the borrower can recover the prefund.
+    ///
+    /// Returns null if the delay is measured in time, see prefundRecoveryDelaySeconds().
+    /// This method may only be called in PrefundReady state!
+    pub fn prefund_recovery_delay_blocks(&self) -> Result<Option<u16>, JsValue> {
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::WaitingForFunding(state) => Ok(state.recovery_delay().blocks()),
+            _ => Err("prefund_recovery_delay_blocks called in invalid state".into()),
+        }
+    }
+
+    /// Returns the number of seconds after which the borrower can recover the prefund.
+    ///
+    /// Returns null if the delay is measured in blocks, see prefundRecoveryDelayBlocks().
+    /// This method may only be called in PrefundReady state!
+    pub fn prefund_recovery_delay_seconds(&self) -> Result<Option<u32>, JsValue> {
+        match self.state.as_ref().expect("use of invalid borrower") {
+            participant::borrower::State::WaitingForFunding(state) => Ok(state.recovery_delay().seconds()),
+            _ => Err("prefund_recovery_delay_seconds called in invalid state".into()),
+        }
+    }
+
     /// Cancels the prefund.
     ///
     /// Parameters:
@@ -353,15 +481,105 @@ impl Borrower {
             .map_err(Into::into)
     }
 
+    /// Returns the offer this state was created from.
+    ///
+    /// The returned offer can be passed to `reset`. It's not available once the escrow
+    /// transaction is signed.
+    pub fn embedded_offer(&self) -> Option<Offer> {
+        self.state.as_ref().expect("use of invalid borrower").embedded_offer().map(Offer)
+    }
+
     /// Changes the state back to `PrefundReady` forgetting all steps since that state.
     ///
-    /// The offer has to be the original one used to create this state.
+    /// The offer has to be the original one used to create this state, see `embedded_offer`.
     /// The behavior is **UNSPECIFIED** if a different offer is passed.
     pub fn reset(&mut self, offer: Offer) {
         self.state.as_mut().unwrap().reset(offer.0);
     }
 }
 
+impl Borrower {
+    fn message_received_inner(&mut self, message: &str) -> Result<(), JsValue> {
+        use contract::escrow::TedSignatures;
+
+        let bytes = contract::encoding::decode_message(message).map_err(into_string)?;
+
+        match self.state.take().expect("use of invalidated Borrower") {
+            participant::borrower::State::WaitingForFunding(state) => {
+                let state = TakenState::new(state, &mut self.state, participant::borrower::State::WaitingForFunding);
+                let hints = contract::offer::EscrowHints::deserialize(&mut &*bytes)
+                    .map_err(into_debug_string)?;
+                hints.validate().map_err(into_debug_string)?;
+                if let Some(current_height) = self.current_height {
+                    if hints.is_stale(current_height, MAX_ESCROW_HINTS_AGE_BLOCKS) {
+                        return Err("the escrow hints are outdated, request new ones".into());
+                    }
+                }
+                let cancel_fee_rate = hints.fee_rate.to_sat_per_vb_ceil()
+                    .checked_add(self.cancel_fee_surcharge_sat_per_vb)
+                    .and_then(bitcoin::FeeRate::from_sat_per_vb)
+                    .ok_or("cancel fee rate overflow")?;
+                let mut funding = participant::borrower::Funding::from_hints(hints);
+                if self.deterministic_escrow_key {
+                    funding.escrow_key_pair = Some(state.state().deterministic_escrow_key_pair());
+                }
+                // Prefer the height set by the application, the one in hints may be older.
+                funding.current_height = self.current_height.or(funding.current_height);
+                let mut response = Vec::new();
+                let txs = funding.mandatory.transactions.clone();
+                let height = bitcoin::absolute::Height::from_consensus(0).unwrap();
+                let delay = participant::borrower::RelativeDelay::Height(144 * 7);
+                let cancel_tx = state.state().funding_cancel(txs, cancel_fee_rate, height, delay)
+                    .map_err(into_debug_string)?;
+                self.cancel_tx = Some(cancel_tx);
+                state.try_map(|state| {
+                    state.funding_received(funding, &mut response)
+                        .map(|state| participant::borrower::State::ReceivingEscrowSignature { state, received: None })
+                })
+                    .map_err(into_debug_string)?;
+                self.message = Some(contract::encoding::encode_message(&response));
+                Ok(())
+            },
+            participant::borrower::State::ReceivingEscrowSignature { state, received } => {
+                let mut state = TakenState::new((state, received), &mut self.state, |(state, received)| participant::borrower::State::ReceivingEscrowSignature { state, received });
+                let message = TedSignatures::deserialize(&mut &*bytes)
+                    .map_err(into_debug_string)?
+                    .ok_or("empty message")?;
+                let received = &mut state.state_mut().1;
+                match (received.take(), message) {
+                    (None, message) => {
+                        *received = Some(message);
+                        self.message = None;
+                        Ok(())
+                    },
+                    (Some(TedSignatures::TedO(ted_o)), TedSignatures::TedP(ted_p)) |
+                     (Some(TedSignatures::TedP(ted_p)), TedSignatures::TedO(ted_o)) => {
+                         state.try_map(|state| {
+                             state.0.verify_signatures(ted_o, ted_p)
+                                 .map(participant::borrower::State::SignaturesVerified)
+                                 .map_err(|(old, err)| ((old, None), err))
+                         })
+                         .map_err(into_debug_string)?;
+                         Ok(())
+                     },
+                    (Some(old @ TedSignatures::TedO(_)), TedSignatures::TedO(_)) | (Some(old @ TedSignatures::TedP(_)), TedSignatures::TedP(_)) => {
+                        *received = Some(old);
+                        Err("message already received".into())
+                    },
+                }
+            },
+            state @ participant::borrower::State::SignaturesVerified(_) => {
+                self.state = Some(state);
+                Err("No message was expected in this state".into())
+            },
+            state @ participant::borrower::State::EscrowSigned(_) => {
+                self.state = Some(state);
+                Err("No message was expected in this state".into())
+            },
+        }
+    }
+}
+
 /// The state of borrower contract
 #[wasm_bindgen]
 pub enum BorrowerState {
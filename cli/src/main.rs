@@ -1,115 +1,147 @@
 use std::io::{Read, Write};
+use core::fmt;
 use firefish_core::contract;
 use core::convert::TryInto;
 use contract::participant::{self, Ted};
-use contract::{Serialize, Deserialize, prefund, escrow};
+use contract::{Serialize, Deserialize, InitError, encoding, prefund, escrow};
 use bitcoin::key::Keypair;
 use bitcoin::TxOut;
 use secp256k1::SECP256K1;
 
-fn offer_create(mut args: std::env::ArgsOs) {
-    use contract::offer::AnyTedSigKeys::*;
+/// Error presented to the user.
+#[derive(Debug)]
+enum CliError {
+    /// The command line arguments are missing or malformed.
+    Usage(String),
+    /// An operation failed, `cause` describes why.
+    Failed { context: String, cause: String },
+    /// A check failed, the details were already printed.
+    CheckFailed,
+}
+
+impl CliError {
+    fn usage(message: impl Into<String>) -> Self {
+        CliError::Usage(message.into())
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Usage(_) => 2,
+            CliError::Failed { .. } | CliError::CheckFailed => 1,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => f.write_str(message),
+            CliError::Failed { context, cause } => write!(f, "{}: {}", context, cause),
+            CliError::CheckFailed => f.write_str("some checks failed"),
+        }
+    }
+}
+
+/// Converts failures into `CliError` with the given description.
+///
+/// `None` is assumed to be a missing argument so it's reported as a usage error.
+trait Context<T> {
+    fn context(self, context: &str) -> Result<T, CliError>;
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context(self, context: &str) -> Result<T, CliError> {
+        self.ok_or_else(|| CliError::usage(context))
+    }
+}
+
+impl<T, E: fmt::Display> Context<T> for Result<T, E> {
+    fn context(self, context: &str) -> Result<T, CliError> {
+        self.map_err(|error| CliError::Failed { context: context.to_owned(), cause: error.to_string() })
+    }
+}
 
-    let network = args
-        .next()
-        .expect("missing bitcoin network")
+/// Like [`Context`] but for errors that don't implement `Display`.
+///
+/// Many errors of the core library only implement `Debug` so this formats them with it.
+trait DebugContext<T> {
+    fn debug_context(self, context: &str) -> Result<T, CliError>;
+}
+
+impl<T, E: fmt::Debug> DebugContext<T> for Result<T, E> {
+    fn debug_context(self, context: &str) -> Result<T, CliError> {
+        self.map_err(|error| CliError::Failed { context: context.to_owned(), cause: format!("{:?}", error) })
+    }
+}
+
+fn next_string(args: &mut std::env::ArgsOs, missing: &str, what: &str) -> Result<String, CliError> {
+    args.next()
+        .context(missing)?
         .into_string()
-        .expect("bitcoin network is not UTF-8")
+        .map_err(|_| CliError::usage(format!("{} is not UTF-8", what)))
+}
+
+fn parse_lock_time(lock_time: &str, current_unix_time: u64) -> Result<bitcoin::absolute::LockTime, CliError> {
+    let lock_time = chrono::DateTime::parse_from_rfc3339(lock_time)
+        .context("failed to parse time lock - the format has to be RFC 3339")?
+        .timestamp();
+    let lock_time: u64 = lock_time.try_into().map_err(|_| CliError::usage("time lock is in the past"))?;
+    if lock_time < current_unix_time {
+        return Err(CliError::usage("time lock is in the past"));
+    }
+    let lock_time: u32 = lock_time.try_into().map_err(|_| CliError::usage("time lock is past the Bitcoin overflow bug"))?;
+    // The current unix time is above genesis block and genesis block is above lock time threshold
+    Ok(bitcoin::absolute::LockTime::from_time(lock_time).expect("if you can see this there's a bug in the program"))
+}
+
+fn offer_create(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let network = next_string(&mut args, "missing bitcoin network", "bitcoin network")?
         .parse::<bitcoin::Network>()
-        .expect("invalid bitcoin network");
-    let liquidator_amount = args.next()
-        .expect("missing liquidator amount")
-        .into_string()
-        .expect("liquidator amount is not UTF-8")
+        .context("invalid bitcoin network")?;
+    let liquidator_amount = next_string(&mut args, "missing liquidator amount", "liquidator amount")?
         .parse::<bitcoin::Amount>()
-        .expect("failed to parse liquidator amount");
-    let liquidator_address_default = args.next()
-        .expect("missing liquidator address for default")
-        .into_string()
-        .expect("liquidator address is not UTF-8")
+        .context("failed to parse liquidator amount")?;
+    let liquidator_address_default = next_string(&mut args, "missing liquidator address for default", "liquidator address")?
         .parse::<bitcoin::Address<_>>()
-        .expect("invalid bitcoin address");
-    let liquidator_address_liquidation = args.next()
-        .expect("missing liquidator address for liquidation")
-        .into_string()
-        .expect("liquidator address is not UTF-8")
+        .context("invalid bitcoin address")?;
+    let liquidator_address_liquidation = next_string(&mut args, "missing liquidator address for liquidation", "liquidator address")?
         .parse::<bitcoin::Address<_>>()
-        .expect("invalid bitcoin address");
+        .context("invalid bitcoin address")?;
 
 
     let liquidator_address_default = liquidator_address_default.require_network(network)
-        .expect("The liquidator address belongs to a different network");
+        .context("The liquidator address belongs to a different network")?;
     let liquidator_address_liquidation = liquidator_address_liquidation.require_network(network)
-        .expect("The liquidator address belongs to a different network");
+        .context("The liquidator address belongs to a different network")?;
 
-    let fee_bump_address = args.next()
-        .expect("missing fee bump address")
-        .into_string()
-        .expect("fee bump address is not UTF-8")
+    let fee_bump_address = next_string(&mut args, "missing fee bump address", "fee bump address")?
         .parse::<bitcoin::Address<_>>()
-        .expect("invalid bitcoin address")
+        .context("invalid bitcoin address")?
         .require_network(network)
-        .expect("The fee bump address belongs to a different network");
+        .context("The fee bump address belongs to a different network")?;
 
     let fee_bump_output = TxOut::minimal_non_dust(fee_bump_address.script_pubkey());
 
     let current_unix_time = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
-        .expect("misconfigured system time (before existence of Bitcoin)")
+        .context("misconfigured system time (before existence of Bitcoin)")?
         .as_secs();
-    let recover_lock_time = args.next()
-        .expect("missing time lock")
-        .into_string()
-        .expect("time lock is not UTF-8");
-    let recover_lock_time = chrono::DateTime::parse_from_rfc3339(&recover_lock_time)
-        .expect("failed to parse time lock - the format has to be RFC 3339")
-        .timestamp();
     // Sanity check
-    assert!(current_unix_time >= 1_231_006_505, "misconfigured system time (before Bitcoin genesis block)");
-    let recover_lock_time: u64 = recover_lock_time.try_into().expect("time lock is in the past");
-    assert!(recover_lock_time >= current_unix_time, "time lock is in the past");
-    let recover_lock_time: u32 = recover_lock_time.try_into().expect("time lock is past the Bitcoin overflow bug");
-    // The current unix time is above genesis block and genesis block is above lock time threshold
-    let recover_lock_time = bitcoin::absolute::LockTime::from_time(recover_lock_time).expect("if you can see this there's a bug in the program");
-    let default_lock_time = args.next()
-        .expect("missing time lock")
-        .into_string()
-        .expect("time lock is not UTF-8");
-    let default_lock_time = chrono::DateTime::parse_from_rfc3339(&default_lock_time)
-        .expect("failed to parse time lock - the format has to be RFC 3339")
-        .timestamp();
-    // Sanity check
-    assert!(current_unix_time >= 1_231_006_505, "misconfigured system time (before Bitcoin genesis block)");
-    let default_lock_time: u64 = default_lock_time.try_into().expect("time lock is in the past");
-    assert!(default_lock_time >= current_unix_time, "time lock is in the past");
-    let default_lock_time: u32 = default_lock_time.try_into().expect("time lock is past the Bitcoin overflow bug");
-    // The current unix time is above genesis block and genesis block is above lock time threshold
-    let default_lock_time = bitcoin::absolute::LockTime::from_time(default_lock_time).expect("if you can see this there's a bug in the program");
-    assert!(default_lock_time < recover_lock_time);
-
-    let mut ted_o = None;
-    let mut ted_p = None;
-
-    for keys in args.by_ref().take(2) {
-        let keys = keys
-            .into_string()
-            .expect("key is not an UTF-8 string")
-            .parse::<contract::offer::AnyTedSigKeys>()
-            .expect("failed to parse TedSig keys");
-        match (keys, &ted_o, &ted_p) {
-            (TedO(keys), None, _) => ted_o = Some(keys),
-            (TedO(_), Some(_), _) => panic!("TED-O keys entered twice"),
-            (TedP(keys), _, None) => ted_p = Some(keys),
-            (TedP(_), _, Some(_)) => panic!("TED-P keys entered twice"),
-        }
+    if current_unix_time < 1_231_006_505 {
+        return Err(CliError::usage("misconfigured system time (before Bitcoin genesis block)"));
+    }
+    let recover_lock_time = next_string(&mut args, "missing time lock", "time lock")?;
+    let recover_lock_time = parse_lock_time(&recover_lock_time, current_unix_time)?;
+    let default_lock_time = next_string(&mut args, "missing time lock", "time lock")?;
+    let default_lock_time = parse_lock_time(&default_lock_time, current_unix_time)?;
+    if default_lock_time.to_consensus_u32() >= recover_lock_time.to_consensus_u32() {
+        return Err(CliError::usage("the default time lock must be before the recover time lock"));
     }
 
-    let (ted_o_keys, ted_p_keys) = match (ted_o, ted_p) {
-        (Some(ted_o), Some(ted_p)) => (ted_o, ted_p),
-        (None, Some(_)) => panic!("missing TED-O public keys"),
-        (Some(_), None) => panic!("missing TED-P public keys"),
-        (None, None) => panic!("missing TedSig public keys"),
-    };
+    let first = next_string(&mut args, "missing TedSig public keys", "key")?;
+    let second = next_string(&mut args, "missing TedSig public keys of the other TED", "key")?;
+    let (ted_o_keys, ted_p_keys) = contract::offer::try_from_key_strings(&first, &second)
+        .debug_context("invalid TedSig keys")?;
 
     let mut optional_fields = contract::offer::OptionalOfferFields::default();
     optional_fields.extra_termination_outputs.push(fee_bump_output);
@@ -122,40 +154,74 @@ fn offer_create(mut args: std::env::ArgsOs) {
         default_lock_time,
         ted_o_keys,
         ted_p_keys,
-    }.into_offer_with_optional(optional_fields);
+    }.into_offer_with_optional(optional_fields)
+        .debug_context("liquidator addresses must be standard")?;
     let mut buf = Vec::new();
     offer.serialize(&mut buf);
 
     match args.next() {
-        Some(path) => write_non_existing(&path, &buf),
-        None => {
-            let encoded = base64::encode(buf);
-            println!("{}", encoded);
-        },
+        Some(path) => write_non_existing(&path, &buf)?,
+        None => println!("{}", offer),
     }
+    Ok(())
 }
 
-fn offer_decode(mut args: std::env::ArgsOs) {
-    let offer = load_offer(&mut args);
+fn offer_decode(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let offer = load_offer(&mut args)?;
     println!("{:#?}", offer);
+    Ok(())
 }
 
-fn offer_accept(mut args: std::env::ArgsOs) {
-    let state_path = args.next().expect("missing state file path");
-    let lock_time = args.next().expect("missing sequence number (relative lock time)");
-    let return_address = args.next().expect("missing return address");
-    let offer = load_offer(&mut args);
+fn offer_verify(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let offer = load_offer(&mut args)?;
 
-    let lock_time = lock_time.into_string()
-        .expect("lock time is not UTF-8")
-        .parse()
-        .expect("invalid sequence number");
-    let return_address = return_address.into_string()
-        .expect("lock time is not UTF-8")
+    let max_dust = offer.escrow.liquidator_script_default.minimal_non_dust()
+        .max(offer.escrow.liquidator_script_liquidation.minimal_non_dust());
+    let collateral = if offer.escrow.min_collateral < max_dust {
+        Err(format!("minimum collateral {} is below dust limit {}", offer.escrow.min_collateral, max_dust))
+    } else {
+        Ok(())
+    };
+    let checks = [
+        ("lock time ordering", offer.validate_lock_time_order().map_err(|error| format!("{:?}", error))),
+        ("key distinctness", offer.validate_key_distinctness().map_err(|error| format!("{:?}", error))),
+        ("liquidator script standardness", offer.validate_liquidator_scripts().map_err(|error| format!("{:?}", error))),
+        ("internal key", offer.validate_internal_key(Default::default()).map_err(|error| format!("unexpected key {}", error.found.to_x_only_public_key()))),
+        ("collateral", collateral),
+    ];
+
+    let mut failed = false;
+    for (name, result) in &checks {
+        match result {
+            Ok(()) => println!("{}: ok", name),
+            Err(error) => {
+                println!("{}: FAILED ({})", name, error);
+                failed = true;
+            },
+        }
+    }
+    if failed {
+        return Err(CliError::CheckFailed);
+    }
+    Ok(())
+}
+
+fn offer_accept(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let state_path = args.next().context("missing state file path")?;
+    let lock_time = next_string(&mut args, "missing sequence number (relative lock time)", "lock time")?;
+    let return_address = next_string(&mut args, "missing return address", "return address")?;
+    let offer = load_offer(&mut args)?;
+
+    let lock_time = lock_time
+        .parse::<bitcoin::Sequence>()
+        .context("invalid sequence number")?
+        .try_into()
+        .debug_context("the sequence number is not a valid relative lock time")?;
+    let return_address = return_address
         .parse::<bitcoin::Address<_>>()
-        .expect("invalid bitcoin address")
+        .context("invalid bitcoin address")?
         .require_network(offer.escrow.network)
-        .expect("The return address belongs to a different network");
+        .context("The return address belongs to a different network")?;
 
     let key_pair = Keypair::new(SECP256K1, &mut secp256k1::rand::thread_rng());
 
@@ -165,13 +231,14 @@ fn offer_accept(mut args: std::env::ArgsOs) {
         return_script: return_address.script_pubkey(),
     };
 
-    let borrower = participant::borrower::init_prefund(offer, params.into_params());
+    let borrower = participant::borrower::init_prefund(offer, params.into_params())
+        .debug_context("the offer uses an unexpected internal key")?;
     let mut state = Vec::new();
     borrower.serialize(&mut state);
     let mut message = Vec::new();
     borrower.borrower_info().serialize(&mut message);
-    let message = base64::encode(message);
-    write_non_existing(&state_path, &state);
+    let message = encoding::encode_message(&message);
+    write_non_existing(&state_path, &state)?;
 
     println!();
     println!("=========================================================");
@@ -183,269 +250,250 @@ fn offer_accept(mut args: std::env::ArgsOs) {
     println!("Funding address: {}", borrower.funding_address());
     println!();
     println!("Message for Firefish:\n{}", message);
+    Ok(())
 }
 
-fn offer_assign(mut args: std::env::ArgsOs) {
+fn offer_assign(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     use firefish_core::contract::context;
     use firefish_core::contract::pub_keys::ContractNumber;
 
     let key_file = args.next()
-        .expect("missing key file");
+        .context("missing key file")?;
     let state_file = args.next()
-        .expect("missing state file");
-    let key_bytes = std::fs::read(key_file).expect("failed to read offer");
+        .context("missing state file")?;
+    let key_bytes = std::fs::read(key_file).context("failed to read key file")?;
     let (prefund_key, escrow_key, network) = if key_bytes.len() != 64 {
         if key_bytes.starts_with(b"xprv") || key_bytes.starts_with(b"tprv") {
-            let derive_path = args.next()
-                .expect("missing derivation path");
-            let derive_path = derive_path.into_string()
-                .expect("derivation path is not UTF-8")
+            let derive_path = next_string(&mut args, "missing derivation path", "derivation path")?
                 .parse::<bitcoin::bip32::DerivationPath>()
-                .expect("invalid derivation path");
+                .context("invalid derivation path")?;
 
-            let key_str = std::str::from_utf8(&key_bytes).expect("xpriv is not UTF-8");
+            let key_str = std::str::from_utf8(&key_bytes).context("xpriv is not UTF-8")?;
             let xpriv = key_str.parse::<bitcoin::bip32::Xpriv>()
-                .expect("failed to parse xpriv");
+                .context("failed to parse xpriv")?;
             let prefund_deriv_path = derive_path.extend(&[context::Prefund::CHILD_NUMBER]);
             let escrow_deriv_path = derive_path.extend(&[context::Escrow::CHILD_NUMBER]);
             let prefund_key = xpriv.derive_priv(&SECP256K1, &prefund_deriv_path)
-                .expect("failed to derive key");
+                .context("failed to derive key")?;
             let escrow_key = xpriv.derive_priv(&SECP256K1, &escrow_deriv_path)
-                .expect("failed to derive key");
+                .context("failed to derive key")?;
 
             (prefund_key.to_keypair(&SECP256K1), escrow_key.to_keypair(&SECP256K1), Some(xpriv.network))
         } else {
-            panic!("invalid key file");
+            return Err(CliError::usage("invalid key file"));
         }
     } else {
         let prefund_key = Keypair::from_seckey_slice(SECP256K1, &key_bytes[..32])
-            .expect("invalid key file");
+            .context("invalid key file")?;
         let escrow_key = Keypair::from_seckey_slice(SECP256K1, &key_bytes[32..])
-            .expect("invalid key file");
+            .context("invalid key file")?;
         (prefund_key, escrow_key, None)
     };
-    let offer = load_offer(&mut args);
+    let offer = load_offer(&mut args)?;
     if let Some(network) = network {
         if network.is_mainnet() && offer.escrow.network != bitcoin::Network::Bitcoin || !network.is_mainnet() && offer.escrow.network == bitcoin::Network::Bitcoin {
-            panic!("networks don't match {:?} and {}", network, offer.escrow.network)
+            return Err(CliError::usage(format!("networks don't match {:?} and {}", network, offer.escrow.network)));
         }
     }
-    let state = Ted::init(prefund_key, escrow_key, offer)
-        .expect("The keys don't match any role in the offer");
+    let state = match Ted::init(prefund_key, escrow_key, offer) {
+        Ok(state) => state,
+        Err(InitError::EscrowKeyMismatch(role)) => return Err(CliError::usage(format!("The prefund key matches {:?} but the escrow key doesn't, check the derivation path", role))),
+        Err(InitError::PrefundKeyMismatch(role)) => return Err(CliError::usage(format!("The escrow key matches {:?} but the prefund key doesn't, check the derivation path", role))),
+        Err(error) => return Err(CliError::usage(format!("The keys don't match any role in the offer: {:?}", error))),
+    };
     let mut bytes = Vec::new();
     state.serialize(&mut bytes);
-    write_non_existing(&state_file, &bytes);
+    write_non_existing(&state_file, &bytes)
 }
 
-fn offer(mut args: std::env::ArgsOs) {
-    let command = args.next()
-        .expect("missing subcommand (create, decode, accept)")
-        .into_string()
-        .expect("unrecognized command");
+fn offer(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let command = next_string(&mut args, "missing subcommand (create, decode, verify, accept, assign)", "command")?;
 
     match &*command {
         "create" => offer_create(args),
         "decode" => offer_decode(args),
+        "verify" => offer_verify(args),
         "accept" => offer_accept(args),
         "assign" => offer_assign(args),
-        _ => panic!("unknown command \"{}\"", command),
+        _ => Err(CliError::usage(format!("unknown command \"{}\"", command))),
     }
 }
 
-fn escrow_init_from_prefund(mut args: std::env::ArgsOs) {
+fn transactions_from_stdin() -> Result<Vec<bitcoin::Transaction>, CliError> {
     use bitcoin::hashes::hex::FromHex;
     use bitcoin::consensus::Decodable;
-    use bitcoin::blockdata::FeeRate;
-
-    let state_file = args.next().expect("missing state file");
-    let escrow_fee_rate = args.next()
-        .expect("missing fee rate")
-        .into_string()
-        .expect("fee rate is not UTF-8")
-        .parse::<u64>()
-        .expect("invalid fee rate");
-    let finalization_fee_rate = args.next()
-        .expect("missing fee rate")
-        .into_string()
-        .expect("fee rate is not UTF-8")
-        .parse::<u64>()
-        .expect("invalid fee rate");
-    let fee_bump_address = args.next()
-        .expect("missing fee bump address")
-        .into_string()
-        .expect("fee bump address is not UTF-8")
-        .parse::<bitcoin::Address<_>>()
-        .expect("invalid fee bump address");
-    let state_bytes = std::fs::read(&state_file).expect("failed to read state file");
-    let state = participant::borrower::WaitingForFunding::deserialize(&mut &*state_bytes).expect("invalid state file");
 
-    let fee_bump_address = fee_bump_address
-        .require_network(state.network())
-        .expect("The fee bump address belongs to a different network");
     let mut transactions = String::new();
-    std::io::stdin().read_to_string(&mut transactions).expect("Failed to read stdin as UTF-8 string");
+    std::io::stdin().read_to_string(&mut transactions).context("Failed to read stdin as UTF-8 string")?;
     if transactions.ends_with('\n') {
         transactions.pop();
     }
     // using awful bitcoin hex API because there's nothing better today.
-    let transactions_bytes = Vec::from_hex(&transactions).expect("invalid hex");
+    let transactions_bytes = Vec::from_hex(&transactions).context("invalid hex")?;
     let mut transaction_bytes = &*transactions_bytes;
     let mut transactions = Vec::new();
     while !transaction_bytes.is_empty() {
         let transaction = bitcoin::Transaction::consensus_decode(&mut transaction_bytes)
-            .expect("invalid transaction");
+            .context("invalid transaction")?;
         transactions.push(transaction);
     }
+    Ok(transactions)
+}
+
+fn escrow_init_from_prefund(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    use bitcoin::blockdata::FeeRate;
+
+    let state_file = args.next().context("missing state file")?;
+    let escrow_fee_rate = next_string(&mut args, "missing fee rate", "fee rate")?
+        .parse::<u64>()
+        .context("invalid fee rate")?;
+    let finalization_fee_rate = next_string(&mut args, "missing fee rate", "fee rate")?
+        .parse::<u64>()
+        .context("invalid fee rate")?;
+    let fee_bump_address = next_string(&mut args, "missing fee bump address", "fee bump address")?
+        .parse::<bitcoin::Address<_>>()
+        .context("invalid fee bump address")?;
+    // Optional, enables rejecting funding transactions with implausible lock times.
+    let current_height = match args.next() {
+        Some(height) => {
+            let height = height.into_string()
+                .map_err(|_| CliError::usage("current height is not UTF-8"))?
+                .parse::<u32>()
+                .context("invalid current height")?;
+            Some(bitcoin::absolute::Height::from_consensus(height).context("invalid current height")?)
+        },
+        None => None,
+    };
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
+    let state = participant::borrower::WaitingForFunding::deserialize(&mut &*state_bytes).debug_context("invalid state file")?;
+
+    let fee_bump_address = fee_bump_address
+        .require_network(state.network())
+        .context("The fee bump address belongs to a different network")?;
+    let transactions = transactions_from_stdin()?;
 
     let params = participant::borrower::MandatoryFundingParams {
         transactions,
-        escrow_fee_rate: FeeRate::from_sat_per_vb(escrow_fee_rate).expect("fee rate too high"),
-        finalization_fee_rate: FeeRate::from_sat_per_vb(finalization_fee_rate).expect("fee rate too high"),
+        escrow_fee_rate: FeeRate::from_sat_per_vb(escrow_fee_rate).context("fee rate too high")?,
+        finalization_fee_rate: FeeRate::from_sat_per_vb(finalization_fee_rate).context("fee rate too high")?,
     };
     let mut funding = params.into_funding();
+    funding.current_height = current_height;
     let fee_bump_txout = TxOut::minimal_non_dust(fee_bump_address.script_pubkey());
     funding.repayment_extra_outputs.push(fee_bump_txout.clone());
     funding.recover_extra_outputs.push(fee_bump_txout);
     let mut message = Vec::new();
-    let state = match state.funding_received(funding, &mut message) {
-        Ok(state) => state,
-        Err((_, error)) => panic!("funding error: {:?}", error),
-    };
+    let state = state.funding_received(funding, &mut message)
+        .map_err(|(_, error)| error)
+        .debug_context("funding error")?;
     // Reuse allocation :)
     let mut state_bytes = state_bytes;
     state_bytes.clear();
     state.serialize_with_header(&mut state_bytes);
-    atomic_update(&state_file, &state_bytes);
-    let message = base64::encode(message);
+    atomic_update(&state_file, &state_bytes)?;
+    let message = encoding::encode_message(&message);
     println!("Message for Firefish (TedSig):\n{}", message);
+    Ok(())
 }
 
-fn write_non_existing(path: &std::ffi::OsStr, data: &[u8]) {
+fn write_non_existing(path: &std::ffi::OsStr, data: &[u8]) -> Result<(), CliError> {
     let mut file = std::fs::OpenOptions::new()
         .create_new(true)
         .write(true)
         .open(path)
-        .unwrap_or_else(|error| panic!("failed to open {:?}: {:?}", path, error));
-    file.write_all(data).expect("failed to write");
+        .context(&format!("failed to open {:?}", path))?;
+    file.write_all(data).context("failed to write")
 }
 
-fn atomic_update(path: &std::ffi::OsStr, data: &[u8]) {
+fn atomic_update(path: &std::ffi::OsStr, data: &[u8]) -> Result<(), CliError> {
     let mut tmp_state_file = path.to_owned();
     tmp_state_file.push(".tmp");
     // we want to call sync, so we create `File` manually
-    let mut file = std::fs::File::create(&tmp_state_file).expect("failed to open temporary state file");
-    file.write_all(&data).expect("failed to write new state");
-    file.sync_data().expect("failed to ensure the file is on disk");
+    let mut file = std::fs::File::create(&tmp_state_file).context("failed to open temporary state file")?;
+    file.write_all(&data).context("failed to write new state")?;
+    file.sync_data().context("failed to ensure the file is on disk")?;
     drop(file);
-    std::fs::rename(tmp_state_file, &path).expect("failed to commit the state file");
+    std::fs::rename(tmp_state_file, &path).context("failed to commit the state file")
 }
 
-fn prefund_decode(mut args: std::env::ArgsOs) {
-    let state_file = args.next().expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("failed to read state file");
-    let state = participant::borrower::WaitingForFunding::deserialize(&mut &*state_bytes).expect("invalid state file");
+fn prefund_decode(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let state_file = args.next().context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
+    let state = participant::borrower::WaitingForFunding::deserialize(&mut &*state_bytes).debug_context("invalid state file")?;
 
     println!("Funding address: {}", state.funding_address());
+    Ok(())
 }
 
-fn prefund_set_spend_info(mut args: std::env::ArgsOs) {
-    let state_file = args.next().expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("failed to read state file");
-    let state = Ted::<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorrowerInfo<participant::TedP>>::deserialize(&mut &*state_bytes).expect("invalid state file");
+fn prefund_set_spend_info(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let state_file = args.next().context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
+    let state = Ted::<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorrowerInfo<participant::TedP>>::deserialize(&mut &*state_bytes).debug_context("invalid state file")?;
 
-    let mut message = Vec::new();
-    std::io::stdin().read_to_end(&mut message).expect("Failed to read stdin borrower spend info");
-    if message.ends_with(b"\n") {
-        message.pop();
-    }
-    let message_bytes = base64::decode(&message).expect("failed to decode the message");
+    let message_bytes = base64_bytes_from_stdin()?;
     let borrower_info = prefund::BorrowerSpendInfo::deserialize(&mut &*message_bytes)
-        .expect("invalid borrower spend info");
-    let new_state = state.prefund_borrower_info(borrower_info).unwrap_or_else(|(_, error)| panic!("can't set borrower info: {:?}", error));
-    message.clear();
-    new_state.serialize(&mut message);
-    atomic_update(&state_file, &message);
+        .debug_context("invalid borrower spend info")?;
+    let new_state = state.prefund_borrower_info(borrower_info)
+        .map_err(|(_, error)| error)
+        .debug_context("can't set borrower info")?;
+    // Reuse allocation
+    let mut state_bytes = state_bytes;
+    state_bytes.clear();
+    new_state.serialize(&mut state_bytes);
+    atomic_update(&state_file, &state_bytes)
 }
 
-fn prefund_cancel(mut args: std::env::ArgsOs) {
-    use bitcoin::hashes::hex::FromHex;
-    use bitcoin::consensus::Decodable;
-
-    let state_file = args.next().expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("failed to read state file");
-    let state = participant::borrower::State::deserialize(&mut &*state_bytes).expect("invalid state file");
-    let fee_rate = args.next()
-        .expect("missing fee rate")
-        .into_string()
-        .expect("fee rate is not UTF-8")
+fn prefund_cancel(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let state_file = args.next().context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
+    let state = participant::borrower::State::deserialize(&mut &*state_bytes).debug_context("invalid state file")?;
+    let fee_rate = next_string(&mut args, "missing fee rate", "fee rate")?
         .parse()
-        .expect("invalid fee rate");
-    let fee_rate = bitcoin::blockdata::FeeRate::from_sat_per_vb(fee_rate).expect("fee rate too high");
+        .context("invalid fee rate")?;
+    let fee_rate = bitcoin::blockdata::FeeRate::from_sat_per_vb(fee_rate).context("fee rate too high")?;
 
-    let mut transactions = String::new();
-    std::io::stdin().read_to_string(&mut transactions).expect("Failed to read stdin as UTF-8 string");
-    if transactions.ends_with('\n') {
-        transactions.pop();
-    }
-    // using awful bitcoin hex API because there's nothing better today.
-    let transactions_bytes = Vec::from_hex(&transactions).expect("invalid hex");
-    let mut transaction_bytes = &*transactions_bytes;
-    let mut transactions = Vec::new();
-    while !transaction_bytes.is_empty() {
-        let transaction = bitcoin::Transaction::consensus_decode(&mut transaction_bytes)
-            .expect("invalid transaction");
-        transactions.push(transaction);
-    }
+    let transactions = transactions_from_stdin()?;
     let height = bitcoin::locktime::absolute::Height::ZERO;
     let delay = participant::borrower::RelativeDelay::Zero;
-    let tx = state.funding_cancel(transactions, fee_rate, height, delay).expect("failed to construct cancel transaction");
+    let tx = state.funding_cancel(transactions, fee_rate, height, delay).debug_context("failed to construct cancel transaction")?;
     let tx = bitcoin::consensus::encode::serialize_hex(&tx);
     println!("{}", tx);
+    Ok(())
 }
 
-fn prefund(mut args: std::env::ArgsOs) {
-    let command = args.next()
-        .expect("missing subcommand (decode)")
-        .into_string()
-        .expect("unrecognized command");
+fn prefund(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let command = next_string(&mut args, "missing subcommand (decode)", "command")?;
 
     match &*command {
         "decode" => prefund_decode(args),
         "set-spend-info" => prefund_set_spend_info(args),
         "cancel" => prefund_cancel(args),
-        _ => panic!("unknown command \"{}\"", command),
+        _ => Err(CliError::usage(format!("unknown command \"{}\"", command))),
     }
 }
 
-fn escrow_sign_from_prefund(mut args: std::env::ArgsOs) {
+fn escrow_sign_from_prefund(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     use std::io::BufRead;
 
-    let state_file = args.next().expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("failed to read state file");
+    let state_file = args.next().context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
     let state = escrow::ReceivingEscrowSignature::<participant::Borrower>::deserialize_with_header(&mut &*state_bytes)
-        .expect("invalid state");
+        .debug_context("invalid state")?;
 
-    let msg1 = args.next()
-        .expect("missing first signature")
-        .into_string()
-        .expect("could not convert message to a valid UTF8 string");
-    let mut msg1 = base64::decode(&msg1).expect("failed to decode message");
-    
-    let msg2 = args.next()
-        .expect("missing second signature")
-        .into_string()
-        .expect("could not convert second message to a valid UTF8 string");
-    let mut msg2 = base64::decode(&msg2).expect("failed to decode message");
+    let msg1 = next_string(&mut args, "missing first signature", "first message")?;
+    let mut msg1 = encoding::decode_message(&msg1).context("failed to decode message")?;
 
-    if msg1[0] == 7 {
+    let msg2 = next_string(&mut args, "missing second signature", "second message")?;
+    let mut msg2 = encoding::decode_message(&msg2).context("failed to decode message")?;
+
+    if msg1.first() == Some(&7) {
         std::mem::swap(&mut msg1, &mut msg2);
     }
-    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*msg1).expect("failed to deserialize TED-O signatures");
-    let ted_p_sigs = escrow::TedPSignatures::deserialize(&mut &*msg2).expect("failed to deserialize TED-P signatures");
-    let state = match state.verify_signatures(ted_o_sigs, ted_p_sigs) {
-        Ok(state) => state,
-        Err((_, error)) => panic!("invalid signatures: {:?}", error),
-    };
+    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*msg1).debug_context("failed to deserialize TED-O signatures")?;
+    let ted_p_sigs = escrow::TedPSignatures::deserialize(&mut &*msg2).debug_context("failed to deserialize TED-P signatures")?;
+    let state = state.verify_signatures(ted_o_sigs, ted_p_sigs)
+        .map_err(|(_, error)| error)
+        .debug_context("invalid signatures")?;
     println!();
     println!("===========================");
     println!();
@@ -459,7 +507,7 @@ fn escrow_sign_from_prefund(mut args: std::env::ArgsOs) {
     let stdin = std::io::stdin();
     let mut lines = stdin.lock().lines();
     loop {
-        let mut line = lines.next().expect("transaction not backed up, aborting").expect("IO error");
+        let mut line = lines.next().context("transaction not backed up, aborting")?.context("IO error")?;
         if line.ends_with('\n') {
             line.pop();
         }
@@ -468,127 +516,169 @@ fn escrow_sign_from_prefund(mut args: std::env::ArgsOs) {
         }
         println!("You didn't back it up yet?");
     }
-    let state = match state.assemble_escrow() {
-        Ok(state) => state,
-        Err((_, error)) => panic!("Recover signatures are OK but the escrow signatures are invalid, {:?}", error),
-    };
+    let state = state.assemble_escrow()
+        .map_err(|(_, error)| error)
+        .debug_context("Recover signatures are OK but the escrow signatures are invalid")?;
     println!();
     println!("===========================");
     println!("Done!");
     println!();
     println!("Broadcast this transaction:");
     println!("{}", bitcoin::consensus::encode::serialize_hex(state.tx_escrow()));
+    Ok(())
 }
 
-fn escrow_presign(mut args: std::env::ArgsOs) {
+fn escrow_verify_sigs(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let state_file = args.next().context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("failed to read state file")?;
+    let state = escrow::ReceivingEscrowSignature::<participant::Borrower>::deserialize_with_header(&mut &*state_bytes)
+        .debug_context("invalid state")?;
+
+    let signatures = escrow::TedSignatures::deserialize(&mut &*base64_bytes_from_stdin()?)
+        .debug_context("failed to deserialize signatures")?
+        .context("empty message")?;
+    let sender = match &signatures {
+        escrow::TedSignatures::TedO(_) => "TED-O",
+        escrow::TedSignatures::TedP(_) => "TED-P",
+    };
+    match state.check_ted_signatures(&signatures) {
+        Ok(()) => println!("OK: the signatures from {} are valid", sender),
+        Err(error) => {
+            println!("invalid signatures from {}: {:?}", sender, error);
+            return Err(CliError::CheckFailed);
+        },
+    }
+    Ok(())
+}
+
+fn escrow_presign(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     let state_file = args.next()
-        .expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("can't read state file");
-    let state = Ted::<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorrowerInfo<participant::TedP>>::deserialize(&mut &*state_bytes).expect("invalid state file");
+        .context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("can't read state file")?;
+    let state = Ted::<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorrowerInfo<participant::TedP>>::deserialize(&mut &*state_bytes).debug_context("invalid state file")?;
 
-    let mut buf = Vec::new();
-    std::io::stdin().read_to_end(&mut buf).expect("failed to read message from stdin");
-    if buf.last() == Some(&b'\n') {
-        buf.pop();
-    }
-    let bytes = base64::decode(buf).expect("invlid message encoding");
+    let bytes = base64_bytes_from_stdin()?;
     let message = contract::escrow::BorrowerInfoMessage::deserialize(&mut &*bytes)
-        .expect("invalid message from borrower");
+        .debug_context("invalid message from borrower")?;
     let escrow = match &state {
         Ted::O(state) => &state.params,
         Ted::P(state) => &state.params,
     };
-    let info = message.borrower_info.validate(escrow).expect("invalid borrower information");
+    let info = message.borrower_info.validate(escrow).debug_context("invalid borrower information")?;
     let transactions = state.borrower_info(info);
-    transactions.verify_borrower(&message.signatures).expect("transactions have invalid signature(s)");
+    match transactions.verify_borrower(&message.signatures) {
+        Ok(()) => (),
+        Err(escrow::BorrowerSignatureError::BorrowerKeyMismatch) => return Err(CliError::usage("the borrower signed the transactions with a key other than the declared ephemeral key")),
+        Err(error) => return Err(error).debug_context("transactions have invalid signature(s)"),
+    }
     println!("{}", transactions.explain());
     let mut serialized_signatures = Vec::new();
     let state = state.set_and_sign_transactions(transactions, message.signatures, &mut serialized_signatures);
     let mut state_bytes = Vec::new();
     state.serialize(&mut state_bytes);
-    atomic_update(&state_file, &state_bytes);
-    let encoded_signatures = base64::encode(serialized_signatures);
+    atomic_update(&state_file, &state_bytes)?;
+    let encoded_signatures = encoding::encode_message(&serialized_signatures);
     let txid = match state {
         Ted::O(state) => state.escrow_txid(),
         Ted::P(state) => state.escrow_txid(),
     };
     println!("Watch for this transaction to confirm: {}", txid);
     println!("Signatures:\n{}", encoded_signatures);
+    Ok(())
 }
 
-fn escrow_repayment(mut args: std::env::ArgsOs) {
+fn escrow_repayment(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     let state_file = args.next()
-        .expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("can't read state file");
-    let mut state = escrow::WaitingForEscrowConfirmation::<participant::TedP>::deserialize_with_header(&mut &*state_bytes).expect("invalid state");
-    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*base64_bytes_from_stdin())
-        .expect("invalid message from TED-O");
+        .context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("can't read state file")?;
+    let mut state = escrow::WaitingForEscrowConfirmation::<participant::TedP>::deserialize_with_header(&mut &*state_bytes).debug_context("invalid state")?;
+    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*base64_bytes_from_stdin()?)
+        .debug_context("invalid message from TED-O")?;
     let tx = bitcoin::consensus::encode::serialize_hex(&mut state.sign_repayment(&ted_o_sigs.repayment));
     println!("{}", tx);
+    Ok(())
 }
 
-fn escrow_default(mut args: std::env::ArgsOs) {
+fn escrow_default(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     let state_file = args.next()
-        .expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("can't read state file");
-    let mut state = escrow::WaitingForEscrowConfirmation::<participant::TedP>::deserialize_with_header(&mut &*state_bytes).expect("invalid state");
-    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*base64_bytes_from_stdin())
-        .expect("invalid message from TED-O");
+        .context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("can't read state file")?;
+    let mut state = escrow::WaitingForEscrowConfirmation::<participant::TedP>::deserialize_with_header(&mut &*state_bytes).debug_context("invalid state")?;
+    let ted_o_sigs = escrow::TedOSignatures::deserialize(&mut &*base64_bytes_from_stdin()?)
+        .debug_context("invalid message from TED-O")?;
     let tx = bitcoin::consensus::encode::serialize_hex(&mut state.sign_default(&ted_o_sigs.default));
     println!("{}", tx);
+    Ok(())
 }
 
-fn escrow_liquidation(mut args: std::env::ArgsOs) {
+fn escrow_liquidation(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     use escrow::WaitingForEscrowConfirmation;
 
     let state_file = args.next()
-        .expect("missing state file");
-    let state_bytes = std::fs::read(&state_file).expect("can't read state file");
-    let state = Ted::<WaitingForEscrowConfirmation<participant::TedO>, WaitingForEscrowConfirmation<participant::TedP>>::deserialize(&mut &*state_bytes).expect("invalid state");
+        .context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("can't read state file")?;
+    let state = Ted::<WaitingForEscrowConfirmation<participant::TedO>, WaitingForEscrowConfirmation<participant::TedP>>::deserialize(&mut &*state_bytes).debug_context("invalid state")?;
     match state {
         Ted::O(state) => {
             let sig = state.ted_o_sign_liquidation();
-            println!("Signature:\n{}", base64::encode(sig.as_ref()));
+            println!("Signature:\n{}", encoding::encode_message(sig.as_ref()));
         },
         Ted::P(mut state) => {
-            let ted_o_sig = secp256k1::schnorr::Signature::from_slice(&base64_bytes_from_stdin())
-                .expect("invalid message from TED-O");
+            let ted_o_sig = secp256k1::schnorr::Signature::from_slice(&base64_bytes_from_stdin()?)
+                .context("invalid message from TED-O")?;
+            state.verify_ted_o_liquidation(&ted_o_sig).debug_context("invalid liquidation signature from TED-O")?;
             let tx = bitcoin::consensus::encode::serialize_hex(&mut state.sign_liquidation(&ted_o_sig));
             println!("{}", tx);
         },
     }
+    Ok(())
 }
 
-fn escrow(mut args: std::env::ArgsOs) {
-    let command = args.next()
-        .expect("missing subcommand (init-from-prefund, presign, sign-from-prefund)")
-        .into_string()
-        .expect("unrecognized command");
+fn escrow_decode(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    use escrow::WaitingForEscrowConfirmation;
+
+    let state_file = args.next()
+        .context("missing state file")?;
+    let state_bytes = std::fs::read(&state_file).context("can't read state file")?;
+    let state = Ted::<WaitingForEscrowConfirmation<participant::TedO>, WaitingForEscrowConfirmation<participant::TedP>>::deserialize(&mut &*state_bytes).debug_context("invalid state")?;
+    let transactions = match &state {
+        Ted::O(state) => state.unsigned_transactions(),
+        Ted::P(state) => state.unsigned_transactions(),
+    };
+    println!("{}", transactions.explain().trim_end());
+    Ok(())
+}
+
+fn escrow(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let command = next_string(&mut args, "missing subcommand (init-from-prefund, presign, sign-from-prefund, verify-sigs, decode)", "command")?;
 
     match &*command {
         "init-from-prefund" => escrow_init_from_prefund(args),
         "sign-from-prefund" => escrow_sign_from_prefund(args),
+        "verify-sigs" => escrow_verify_sigs(args),
         "presign" => escrow_presign(args),
         "repayment" => escrow_repayment(args),
         "default" => escrow_default(args),
         "liquidation" => escrow_liquidation(args),
-        _ => panic!("unknown command \"{}\"", command),
+        "decode" => escrow_decode(args),
+        _ => Err(CliError::usage(format!("unknown command \"{}\"", command))),
     }
 }
 
-fn key_gen(mut args: std::env::ArgsOs) {
-    let role = args.next()
-        .expect("missing role (ted-o or ted-p)")
-        .into_string()
-        .expect("invalid role (must be ted-o or ted-p)");
+fn role_symbol(role: &str) -> Result<char, CliError> {
+    match role {
+        "ted-o" => Ok('o'),
+        "ted-p" => Ok('p'),
+        _ => Err(CliError::usage(format!("invalid role (must be ted-o or ted-p): {}", role))),
+    }
+}
+
+fn key_gen(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let role = next_string(&mut args, "missing role (ted-o or ted-p)", "role")?;
     let key_file = args.next()
-        .expect("missing key file");
+        .context("missing key file")?;
 
-    let symbol = match &*role {
-        "ted-o" => 'o',
-        "ted-p" => 'p',
-        _ => panic!("invalid role (must be ted-o or ted-p): {}", role),
-    };
+    let symbol = role_symbol(&role)?;
 
     let prefund_key_pair = Keypair::new(SECP256K1, &mut secp256k1::rand::thread_rng());
     let escrow_key_pair = Keypair::new(SECP256K1, &mut secp256k1::rand::thread_rng());
@@ -597,58 +687,46 @@ fn key_gen(mut args: std::env::ArgsOs) {
     secrets[..32].copy_from_slice(&prefund_key_pair.secret_bytes());
     secrets[32..].copy_from_slice(&escrow_key_pair.secret_bytes());
 
-    write_non_existing(&key_file, &secrets);
+    write_non_existing(&key_file, &secrets)?;
 
     println!("ffa{}k{}{}", symbol, prefund_key_pair.x_only_public_key().0, escrow_key_pair.x_only_public_key().0);
+    Ok(())
 }
 
-fn key_derive_public(mut args: std::env::ArgsOs) {
+fn key_derive_public(mut args: std::env::ArgsOs) -> Result<(), CliError> {
     use firefish_core::contract::pub_keys::PubKey;
     use firefish_core::contract::context;
 
-    let role = args.next()
-        .expect("missing role (ted-o or ted-p)")
-        .into_string()
-        .expect("invalid role (must be ted-o or ted-p)");
-    let xpub = args.next()
-        .expect("missing xpub")
-        .into_string()
-        .expect("xpub is not UTF-8")
+    let role = next_string(&mut args, "missing role (ted-o or ted-p)", "role")?;
+    let xpub = next_string(&mut args, "missing xpub", "xpub")?
         .parse::<bitcoin::bip32::Xpub>()
-        .expect("failed to parse xpup");
+        .context("failed to parse xpub")?;
 
-    let derive_path = args.next()
-        .expect("missing derivation path")
-        .into_string()
-        .expect("derivation path is not UTF-8")
+    let derive_path = next_string(&mut args, "missing derivation path", "derivation path")?
         .parse::<bitcoin::bip32::DerivationPath>()
-        .expect("invalid derivation path");
+        .context("invalid derivation path")?;
 
-    let symbol = match &*role {
-        "ted-o" => 'o',
-        "ted-p" => 'p',
-        _ => panic!("invalid role (must be ted-o or ted-p): {}", role),
-    };
+    let symbol = role_symbol(&role)?;
 
     let prefund_key = PubKey::<(), context::Prefund>::from_xpub(&xpub, &derive_path);
     let escrow_key = PubKey::<(), context::Escrow>::from_xpub(&xpub, &derive_path);
 
     println!("ffa{}k{}{}", symbol, prefund_key.as_x_only(), escrow_key.as_x_only());
+    Ok(())
 }
 
-fn key_gen_xpriv(mut args: std::env::ArgsOs) {
-    let network = args
-        .next()
-        .expect("missing network")
-        .into_string()
-        .expect("network is not UTF-8")
+fn key_gen_xpriv(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let network = next_string(&mut args, "missing network", "network")?
         .parse::<bitcoin::Network>()
-        .expect("invalid network");
+        .context("invalid network")?;
     let key_file = args.next()
-        .expect("missing key file");
+        .context("missing key file")?;
     let mnemonic = match args.next() {
         Some(seed) => {
-            seed.into_string().expect("seed is not UTF-8").parse().expect("invalid seed")
+            seed.into_string()
+                .map_err(|_| CliError::usage("seed is not UTF-8"))?
+                .parse()
+                .context("invalid seed")?
         },
         None => {
             let entropy = secp256k1::rand::random::<[u8; 16]>();
@@ -656,63 +734,77 @@ fn key_gen_xpriv(mut args: std::env::ArgsOs) {
         },
     };
     let seed = mnemonic.to_seed("");
-    let xpriv = bitcoin::bip32::Xpriv::new_master(network, &seed).expect("failed to create xpriv");
+    let xpriv = bitcoin::bip32::Xpriv::new_master(network, &seed).context("failed to create xpriv")?;
     let xpub = bitcoin::bip32::Xpub::from_priv(&SECP256K1, &xpriv);
     println!("seed: {}", mnemonic);
     println!("xpub: {}", xpub);
     write_non_existing(&key_file, xpriv.to_string().as_bytes())
 }
 
-fn key(mut args: std::env::ArgsOs) {
-    let command = args.next()
-        .expect("missing subcommand (gen)")
-        .into_string()
-        .expect("unrecognized command");
+fn key(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let command = next_string(&mut args, "missing subcommand (gen)", "command")?;
 
     match &*command {
         "gen" => key_gen(args),
         "gen-xpriv" => key_gen_xpriv(args),
         "derive-pub" => key_derive_public(args),
-        _ => panic!("unknown command \"{}\"", command),
+        _ => Err(CliError::usage(format!("unknown command \"{}\"", command))),
     }
 }
 
-fn print(mut args: std::env::ArgsOs) {
-    let subject = args.next()
-        .expect("missing subject")
-        .into_string()
-        .expect("unrecognized subject");
+fn print(mut args: std::env::ArgsOs) -> Result<(), CliError> {
+    let subject = next_string(&mut args, "missing subject", "subject")?;
 
     match &*subject {
         "api-version" => println!("1"),
-        _ => panic!("unknown subject \"{}\"", subject),
+        _ => return Err(CliError::usage(format!("unknown subject \"{}\"", subject))),
     }
+    Ok(())
 }
 
-fn base64_bytes_from_stdin() -> Vec<u8> {
-    let mut buf = Vec::new();
-    std::io::stdin().read_to_end(&mut buf).expect("failed to read offer from stdin");
-    if buf.last() == Some(&b'\n') {
-        buf.pop();
-    }
-    base64::decode(buf).expect("failed to decode the base64 offer bytes")
+fn base64_bytes_from_stdin() -> Result<Vec<u8>, CliError> {
+    let mut buf = String::new();
+    std::io::stdin().read_to_string(&mut buf).context("failed to read message from stdin")?;
+    encoding::decode_message(&buf).context("failed to decode the base64 message")
 }
 
-fn load_offer(args: &mut std::env::ArgsOs) -> contract::offer::Offer {
-    let bytes = match args.next() {
-        Some(path) => std::fs::read(&path).expect("failed to read offer"),
-        None => base64_bytes_from_stdin(),
+/// Loads the offer from the given file or stdin.
+///
+/// The offer is expected in the `ffoffer1` string form but the legacy formats - raw bytes in a
+/// file or base64 on stdin - are accepted as well.
+fn load_offer(args: &mut std::env::ArgsOs) -> Result<contract::offer::Offer, CliError> {
+    use contract::offer::{Offer, OfferParseError};
+
+    let (data, is_file) = match args.next() {
+        Some(path) => (std::fs::read(&path).context("failed to read offer")?, true),
+        None => {
+            let mut buf = Vec::new();
+            std::io::stdin().read_to_end(&mut buf).context("failed to read offer from stdin")?;
+            (buf, false)
+        },
     };
-    contract::offer::Offer::deserialize(&mut &*bytes).expect("failed to deserialize offer")
+    let parsed = core::str::from_utf8(&data)
+        .map_err(|_| OfferParseError::NotAnOffer)
+        .and_then(str::parse::<Offer>);
+    match parsed {
+        Ok(offer) => Ok(offer),
+        Err(OfferParseError::NotAnOffer) => {
+            let bytes = if is_file {
+                data
+            } else {
+                let data = core::str::from_utf8(&data).context("the offer is not UTF-8")?;
+                encoding::decode_message(data).context("failed to decode the base64 offer")?
+            };
+            Offer::deserialize(&mut &*bytes).debug_context("failed to deserialize offer")
+        },
+        Err(error) => Err(error).context("failed to parse offer"),
+    }
 }
 
-fn main() {
+fn run() -> Result<(), CliError> {
     let mut args = std::env::args_os();
-    let _program_name = args.next().expect("missing program name");
-    let command = args.next()
-        .expect("missing subcommand (offer, prefund)")
-        .into_string()
-        .expect("unrecognized command");
+    let _program_name = args.next();
+    let command = next_string(&mut args, "missing subcommand (offer, prefund)", "command")?;
 
     match &*command {
         "offer" => offer(args),
@@ -720,6 +812,13 @@ fn main() {
         "escrow" => escrow(args),
         "key" => key(args),
         "print" => print(args),
-        _ => panic!("unknown command \"{}\"", command),
+        _ => Err(CliError::usage(format!("unknown command \"{}\"", command))),
+    }
+}
+
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("error: {}", error);
+        std::process::exit(error.exit_code());
     }
 }
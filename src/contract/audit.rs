@@ -0,0 +1,92 @@
+//! Helpers for auditing broadcast transactions.
+//!
+//! The multisig witnesses order the signatures by the sorted keys rather than by the roles of the
+//! participants. This module undoes the ordering so that third parties can verify each signature
+//! against its signer.
+
+use bitcoin::Transaction;
+use bitcoin::key::XOnlyPublicKey;
+use secp256k1::schnorr::Signature;
+
+use super::primitives::Permutation;
+use super::pub_keys::PubKeys;
+
+/// Extracts the signatures from the multisig witness of the given input.
+///
+/// The returned signatures are paired with the keys of their signers and ordered by role:
+/// borrower, TED-O, TED-P. The signatures are not verified since the caller needs to compute the
+/// signed message.
+pub fn extract_witness_signatures<C>(tx: &Transaction, input: usize, keys: &PubKeys<C>) -> Result<[(XOnlyPublicKey, Signature); 3], AuditError> {
+    let txin = tx.input.get(input).ok_or(AuditError::InputOutOfRange { input, count: tx.input.len() })?;
+    let witness = &txin.witness;
+    if witness.len() != 5 {
+        return Err(AuditError::InvalidWitnessLength { input, len: witness.len() });
+    }
+    if witness[3] != *keys.generate_multisig_script().as_bytes() {
+        return Err(AuditError::ScriptMismatch { input });
+    }
+
+    // The signature of the first sorted key is on top of the stack which is the last signature
+    // in the witness.
+    let mut by_role = [None; 3];
+    for (i, role) in Permutation::from_keys(keys).permute([0usize, 1, 2]).iter().enumerate() {
+        let position = 2 - i;
+        let signature = Signature::from_slice(&witness[position])
+            .map_err(|error| AuditError::InvalidSignature { input, position, error })?;
+        by_role[*role] = Some(signature);
+    }
+    let signature = |role: usize| by_role[role].expect("permutation covers all roles");
+
+    Ok([
+        (*keys.borrower_eph.as_x_only(), signature(0)),
+        (*keys.ted_o.as_x_only(), signature(1)),
+        (*keys.ted_p.as_x_only(), signature(2)),
+    ])
+}
+
+/// Returned when the witness signatures can't be extracted.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AuditError {
+    /// The transaction doesn't have the requested input.
+    InputOutOfRange { input: usize, count: usize },
+    /// The witness doesn't have three signatures, the script and the control block.
+    InvalidWitnessLength { input: usize, len: usize },
+    /// The witness script is not the multisig script of the given keys.
+    ScriptMismatch { input: usize },
+    /// The witness element at `position` is not a valid signature.
+    InvalidSignature { input: usize, position: usize, error: secp256k1::Error },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::key::Keypair;
+    use super::super::context;
+    use super::super::pub_keys::PubKey;
+
+    #[test]
+    fn signatures_paired_with_signers() {
+        let key_pairs = [(); 3].map(|_| Keypair::new_global(&mut rand::thread_rng()));
+        let keys = PubKeys::<context::Escrow>::new(PubKey::from_key_pair(&key_pairs[0]), PubKey::from_key_pair(&key_pairs[1]), PubKey::from_key_pair(&key_pairs[2])).unwrap();
+        let message = secp256k1::Message::from_digest([42; 32]);
+        let signatures = key_pairs.map(|key_pair| secp256k1::SECP256K1.sign_schnorr(&message, &key_pair));
+        let script = keys.generate_multisig_script();
+
+        let mut tx = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: Vec::new(),
+        };
+        tx.input[0].witness = super::super::assemble_witness(&signatures[0], &signatures[1], &signatures[2], Permutation::from_keys(&keys), &script, &[0xc0]);
+
+        let extracted = extract_witness_signatures(&tx, 0, &keys).unwrap();
+        for (i, (key, signature)) in extracted.iter().enumerate() {
+            assert_eq!(*key, key_pairs[i].x_only_public_key().0);
+            assert_eq!(*signature, signatures[i]);
+            secp256k1::SECP256K1.verify_schnorr(signature, &message, key).unwrap();
+        }
+        assert!(matches!(extract_witness_signatures(&tx, 1, &keys), Err(AuditError::InputOutOfRange { input: 1, count: 1 })));
+    }
+}
@@ -36,6 +36,24 @@ u8_enum! {
     }
 }
 
+impl StateId {
+    /// Returns the position of the state in the protocol.
+    ///
+    /// States that are further along have higher ranks so that regressions can be detected.
+    pub fn progress_rank(self) -> u8 {
+        match self {
+            StateId::PrefundReceivingBorrowerData => 0,
+            StateId::Prefund => 1,
+            StateId::WaitingForFunding => 2,
+            StateId::EscrowReceivingBorrowerInfo => 3,
+            StateId::EscrowReceivingStateSignatures => 4,
+            StateId::EscrowReceivingEscrowSignatures => 5,
+            StateId::EscrowSignaturesVerified => 6,
+            StateId::WaitingForEscrowConfirmation => 7,
+        }
+    }
+}
+
 u8_enum! {
     pub enum MessageId {
         Offer = 0,
@@ -47,14 +65,21 @@ u8_enum! {
         StateSigsFromTedO = 6,
         StateSigsFromTedP = 7,
         EscrowSigsFromBorrower = 8,
+        ContractProof = 9,
+        EscrowHintsWithHeight = 10,
+        RecoverSigFromTedO = 11,
+        RecoverSigFromTedP = 12,
+        TerminalSigsFromTedO = 13,
     }
 }
 
-pub enum ParticipantId {
-    Verifier = 0,
-    Borrower = 1,
-    TedO = 2,
-    TedP = 3,
+u8_enum! {
+    pub enum ParticipantId {
+        Verifier = 0,
+        Borrower = 1,
+        TedO = 2,
+        TedP = 3,
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,135 @@
+//! Output descriptors for importing contract outputs into watch-only wallets.
+//!
+//! Only the subset of BIP380 needed by the contract is implemented. All contract outputs are
+//! taproot outputs with concrete keys so they are described using `rawtr` (BIP386).
+
+use bitcoin::key::TweakedPublicKey;
+use bitcoin::Script;
+
+const INPUT_CHARSET: &[u8] = b"0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Returns the `rawtr` descriptor, including the checksum, of the given taproot output key.
+pub fn rawtr(output_key: TweakedPublicKey) -> String {
+    with_checksum(&format!("rawtr({})", output_key))
+}
+
+/// Returns the `rawtr` descriptor of the script if it is a taproot script.
+pub fn rawtr_from_script(script: &Script) -> Option<String> {
+    if !script.is_p2tr() {
+        return None;
+    }
+    let key = bitcoin::XOnlyPublicKey::from_slice(&script.as_bytes()[2..]).ok()?;
+    Some(rawtr(TweakedPublicKey::dangerous_assume_tweaked(key)))
+}
+
+/// Appends the BIP380 checksum to the descriptor.
+///
+/// # Panics
+///
+/// If the descriptor contains characters not allowed in descriptors.
+fn with_checksum(descriptor: &str) -> String {
+    fn polymod(c: u64, val: u64) -> u64 {
+        const GENERATORS: [u64; 5] = [0xf5dee51989, 0xa9fdca3312, 0x1bab10e32d, 0x3706b1677a, 0x644d626ffd];
+
+        let c0 = c >> 35;
+        let mut c = ((c & 0x7ffffffff) << 5) ^ val;
+        for (i, generator) in GENERATORS.iter().enumerate() {
+            if (c0 >> i) & 1 != 0 {
+                c ^= generator;
+            }
+        }
+        c
+    }
+
+    let mut c = 1;
+    let mut class = 0;
+    let mut class_count = 0;
+    for ch in descriptor.bytes() {
+        let pos = INPUT_CHARSET.iter()
+            .position(|allowed| *allowed == ch)
+            .expect("descriptors we generate only contain valid characters") as u64;
+        c = polymod(c, pos & 31);
+        class = class * 3 + (pos >> 5);
+        class_count += 1;
+        if class_count == 3 {
+            c = polymod(c, class);
+            class = 0;
+            class_count = 0;
+        }
+    }
+    if class_count > 0 {
+        c = polymod(c, class);
+    }
+    for _ in 0..8 {
+        c = polymod(c, 0);
+    }
+    c ^= 1;
+
+    let mut result = String::with_capacity(descriptor.len() + 9);
+    result.push_str(descriptor);
+    result.push('#');
+    for j in 0..8 {
+        result.push(CHECKSUM_CHARSET[((c >> (5 * (7 - j))) & 31) as usize].into());
+    }
+    result
+}
+
+/// Parses a `rawtr` descriptor with checksum returning the output key.
+///
+/// Only used to check the generated descriptors.
+#[cfg(test)]
+pub(crate) fn parse_rawtr(descriptor: &str) -> Option<TweakedPublicKey> {
+    if !has_valid_checksum(descriptor) {
+        return None;
+    }
+    let (body, _) = descriptor.split_once('#')?;
+    let key = body.strip_prefix("rawtr(")?.strip_suffix(')')?;
+    let key = key.parse::<bitcoin::XOnlyPublicKey>().ok()?;
+    Some(TweakedPublicKey::dangerous_assume_tweaked(key))
+}
+
+#[cfg(test)]
+fn has_valid_checksum(descriptor: &str) -> bool {
+    match descriptor.split_once('#') {
+        Some((body, _)) => body.bytes().all(|ch| INPUT_CHARSET.contains(&ch)) && with_checksum(body) == descriptor,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn checksum_test_vector() {
+        // from BIP380
+        assert_eq!(super::with_checksum("raw(deadbeef)"), "raw(deadbeef)#89f8spxm");
+        // from Bitcoin Core descriptor tests
+        assert_eq!(
+            super::with_checksum("sh(multi(2,[00000000/111'/222]xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc,xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L/0))"),
+            "sh(multi(2,[00000000/111'/222]xprvA1RpRA33e1JQ7ifknakTFpgNXPmW2YvmhqLQYMmrj4xJXXWYpDPS3xz7iAxn8L39njGVyuoseXzU6rcxFLJ8HFsTjSyQbLYnMpCqE2VbFWc,xprv9uPDJpEQgRQfDcW7BkF7eTya6RPxXeJCqCJGHuCJ4GiRVLzkTXBAJMu2qaMWPrS7AANYqdq6vcBcBUdJCVVFceUvJFjaPdGZ2y9WACViL4L/0))#ggrsrxfy"
+        );
+        assert_eq!(
+            super::with_checksum("sh(multi(2,[00000000/111'/222]xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL,xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y/0))"),
+            "sh(multi(2,[00000000/111'/222]xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL,xpub68NZiKmJWnxxS6aaHmn81bvJeTESw724CRDs6HbuccFQN9Ku14VQrADWgqbhhTHBaohPX4CjNLf9fq9MYo6oDaPPLPxSb7gwQN3ih19Zm4Y/0))#tjg09x5t"
+        );
+    }
+
+    #[test]
+    fn invalid_checksum_test_vectors() {
+        // from BIP380
+        assert!(super::has_valid_checksum("raw(deadbeef)#89f8spxm"));
+        for invalid in ["raw(deadbeef)#", "raw(deadbeef)#89f8spxmx", "raw(deadbeef)#89f8spx", "raw(dedbeef)#89f8spxm", "raw(deadbeef)##9f8spxm", "raw(Ü)#00000000"] {
+            assert!(!super::has_valid_checksum(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn rawtr_roundtrip() {
+        // from BIP386
+        let script = bitcoin::ScriptBuf::from_hex("5120a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd").unwrap();
+        let descriptor = super::rawtr_from_script(&script).unwrap();
+        assert!(descriptor.starts_with("rawtr(a34b99f22c790c4e36b2b3c2c35a36db06226e41c692fc82b8b56ac1c540c5bd)#"));
+        let key = super::parse_rawtr(&descriptor).unwrap();
+        assert_eq!(bitcoin::ScriptBuf::new_p2tr_tweaked(key), script);
+    }
+}
@@ -103,11 +103,14 @@ version_enum! {
     pub enum StateVersion {
         V0 = 0x00,
         V1 = 0x01,
+        V2 = 0x02,
+        V3 = 0x03,
+        V4 = 0x04,
     }
 }
 
 impl StateVersion {
-    pub const CURRENT: Self = Self::V1;
+    pub const CURRENT: Self = Self::V4;
 
     /// Deserializes state version.
     ///
@@ -158,3 +161,41 @@ impl From<crate::contract::deserialize::UnexpectedEnd> for StateVersionDeserErro
         Self::UnexpectedEnd
     }
 }
+
+/// Checks that the serialized state belongs to the `expected` participant.
+///
+/// Only the header is inspected, the body is not decoded so this is cheap enough to be used when
+/// ingesting states into storage.
+pub fn expect_participant(mut bytes: &[u8], expected: super::constants::ParticipantId) -> Result<(), super::StateDeserError<core::convert::Infallible>> {
+    // Only checks that the version is supported
+    let _version = StateVersion::deserialize(&mut bytes)?;
+    match bytes.first() {
+        Some(participant) if *participant == expected as u8 => Ok(()),
+        Some(participant) => Err(super::StateDeserError::InvalidParticipant(*participant)),
+        None => Err(super::StateDeserError::UnexpectedEnd),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::StateDeserError;
+    use super::super::constants::ParticipantId;
+
+    #[test]
+    fn participant_checked_in_header() {
+        let mut state = Vec::new();
+        StateVersion::CURRENT.serialize(&mut state);
+        state.push(ParticipantId::TedP as u8);
+        state.push(0xff);
+
+        expect_participant(&state, ParticipantId::TedP).unwrap();
+        match expect_participant(&state, ParticipantId::TedO) {
+            Err(StateDeserError::InvalidParticipant(id)) => assert_eq!(id, ParticipantId::TedP as u8),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // legacy states have no version
+        expect_participant(&state[5..], ParticipantId::TedP).unwrap();
+        assert!(matches!(expect_participant(&state[..5], ParticipantId::TedP), Err(StateDeserError::UnexpectedEnd)));
+    }
+}
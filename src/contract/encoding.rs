@@ -0,0 +1,38 @@
+//! Encoding of messages exchanged between the participants.
+//!
+//! The messages are base64-encoded so that they can be easily copied around. All front-ends should
+//! use these functions so that they accept the same inputs.
+
+use core::fmt;
+
+/// Encodes the serialized message as base64.
+pub fn encode_message(bytes: &[u8]) -> String {
+    base64::encode(bytes)
+}
+
+/// Decodes the base64-encoded message.
+///
+/// Leading and trailing whitespace (e.g. a newline after copy-pasting) is ignored.
+pub fn decode_message(message: &str) -> Result<Vec<u8>, DecodeError> {
+    base64::decode(message.trim()).map_err(DecodeError)
+}
+
+/// Error returned when the message is not valid base64.
+#[derive(Debug)]
+pub struct DecodeError(base64::DecodeError);
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid message encoding: {}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    quickcheck::quickcheck! {
+        fn message_roundtrips_with_whitespace(bytes: Vec<u8>) -> bool {
+            let encoded = format!(" {}\n", super::encode_message(&bytes));
+            super::decode_message(&encoded).unwrap() == bytes
+        }
+    }
+}
@@ -4,15 +4,18 @@
 //! 
 
 use core::convert::TryInto;
-use bitcoin::{Transaction, TxIn, TxOut, ScriptBuf, OutPoint, Sequence, Witness, key::XOnlyPublicKey};
+use core::fmt;
+use bitcoin::{SignedAmount, Transaction, TxIn, TxOut, ScriptBuf, OutPoint, Sequence, Witness, key::XOnlyPublicKey};
 use bitcoin::secp256k1::schnorr::Signature;
 use bitcoin::locktime::absolute::{Height, LockTime};
-use bitcoin::taproot::{LeafVersion, TapLeafHash, TapNodeHash, TaprootSpendInfo};
+use bitcoin::taproot::{TapLeafHash, TapNodeHash, TaprootSpendInfo};
 use bitcoin::key::Keypair;
+use bitcoin::psbt::Psbt;
+use std::collections::BTreeMap;
 
 use super::deserialize;
 use super::{Serialize, Deserialize, context, participant, offer, constants};
-use super::pub_keys::{PubKey, PubKeys};
+use super::pub_keys::{self, PubKey, PubKeys};
 use super::participant::Participant;
 use super::primitives::{SpendableTxo, Permutation};
 
@@ -22,10 +25,36 @@ use super::primitives::{SpendableTxo, Permutation};
 ///
 /// More inputs than this definitely wouldn't fit the block, so this constant is a maximum sensible
 /// number. In practice, it is likely much lower but we don't care.
-const MAX_INPUT_COUNT: u32 = 4_000_000 / (32 + 4 + 4 + 1);
+pub const MAX_INPUT_COUNT: u32 = 4_000_000 / (32 + 4 + 4 + 1);
+
+/// Only accept this many outputs in transaction.
+///
+/// The value of the constant is block_size / min_txout_size and it's a maximum sensible number
+/// for the same reason as [`MAX_INPUT_COUNT`].
+pub const MAX_OUTPUT_COUNT: u32 = 4_000_000 / (8 + 1);
 
 pub(crate) type EscrowKeys = offer::TedSigPubKeys<context::Escrow>;
 
+/// Predicts the weight of an input spending escrow using the multisig script.
+///
+/// The size is exact since all elements of the witness have a known size.
+pub(crate) fn multisig_spend_input_prediction() -> bitcoin::transaction::InputWeightPrediction {
+    let witness_elem_sizes = &[
+        64, // len of signature1
+        64, // len of signature2
+        64, // len of signature3
+              33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1  // len of OP_CHECKSIGVERIFY
+            + 33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1  // len of OP_CHECKSIGVERIFY
+            + 33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1, // len of OP_CHECKSIG
+              33  // base len of control block
+                  // note: there's only one script so no other nodes
+    ];
+    bitcoin::transaction::InputWeightPrediction::new(0, witness_elem_sizes.iter().copied())
+}
+
 pub mod validation {
     pub enum Unvalidated {}
     pub enum Validated {}
@@ -90,6 +119,10 @@ crate::test_macros::impl_test_traits!(ReceivingBorrowerInfo<P: Participant> wher
 crate::test_macros::impl_arbitrary!(ReceivingBorrowerInfo<P: Participant> where { P::PreEscrowData }, params, keys, participant_data);
 
 impl<P: Participant> ReceivingBorrowerInfo<P> {
+    pub(crate) fn keys(&self) -> &EscrowKeys {
+        &self.keys
+    }
+
     /// Initializes the receiver.
     pub fn new(params: offer::EscrowParams, keys: EscrowKeys) -> Self where P::PreEscrowData: Default {
         Self::with_participant_data(params, keys, Default::default())
@@ -123,115 +156,25 @@ impl<P: Participant> ReceivingBorrowerInfo<P> {
         }
     }
 
+    pub fn has_borrower_spend_info(&self) -> bool where P::PreEscrowData: super::SetBorrowerSpendInfo {
+        use super::SetBorrowerSpendInfo;
+
+        self.participant_data.has_borrower_spend_info()
+    }
+
     /// Called when borrower information is received.
     ///
     /// This constructs `UnsignedTransactions` which can be used to verify the signatures.
     pub fn borrower_info(&self, borrower_info: BorrowerInfo<validation::Validated>) -> UnsignedTransactions {
-        let keys = self.keys.add_borrower_eph(borrower_info.escrow_eph_key);
-        let (escrow_out_script, multisig_leaf_hash, _) = output_script(&keys);
-
-        let escrow_txout = TxOut {
-            value: borrower_info.escrow_amount,
-            script_pubkey: escrow_out_script,
-        };
-        let escrow_output_index = borrower_info.escrow_contract_output_position as usize;
-        let mut escrow_txouts = borrower_info.escrow_extra_outputs;
-        escrow_txouts.insert(escrow_output_index, escrow_txout);
-        let (escrow_prevouts, escrow_txins) = borrower_info.inputs
-            .into_iter()
-            .map(SpendableTxo::unpack_with_empty_sig)
-            .unzip();
-        let escrow_tx = Transaction {
-            // Enable relative time locks
-            version: TX_VERSION,
-            input: escrow_txins,
-            output: escrow_txouts,
-            lock_time: LockTime::from(borrower_info.tx_height).into(),
-        };
-        let escrow_txid = escrow_tx.compute_txid();
-        let escrow_out_point = OutPoint {
-            txid: escrow_txid,
-            vout: borrower_info.escrow_contract_output_position,
-        };
-        let escrow_non_recover_txin = TxIn {
-            previous_output: escrow_out_point,
-            script_sig: ScriptBuf::new(),
-            // Since non-recover transactions don't use lock time in the contract and we can't
-            // predict when they will be broadcasted setting same height as the previous
-            // transaction would create an identifiable footprint. There are still wallets that
-            // don't implement anti-fee-sniping policy so it's better to hide among them rather
-            // than implement broken anti-fee-sniping. And if we don't use lock time anyway we
-            // should just disable it.
-            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
-            witness: Witness::new(),
-        };
-        let escrow_non_recover_txins = vec![escrow_non_recover_txin];
-        let liquidator_output_default = TxOut {
-            script_pubkey: self.params.liquidator_script_default.clone(),
-            value: borrower_info.collateral_amount_default,
-        };
-        let liquidator_output_liquidation = TxOut {
-            script_pubkey: self.params.liquidator_script_liquidation.clone(),
-            value: borrower_info.collateral_amount_liquidation,
-        };
-        fn vec_with_item_inserted<T: Clone>(base: &[T], inserted: T, index: usize) -> Vec<T> {
-            let mut result = Vec::with_capacity(base.len() + 1);
-            let mut iter = base.iter().cloned();
-            result.extend(iter.by_ref().take(index));
-            result.push(inserted);
-            result.extend(iter);
-            result
-        }
-        let termination_outputs_default = vec_with_item_inserted(&self.params.extra_termination_outputs, liquidator_output_default, self.params.liquidator_output_index);
-        let termination_outputs_liquidation = vec_with_item_inserted(&self.params.extra_termination_outputs, liquidator_output_liquidation, self.params.liquidator_output_index);
-
-        let repayment_tx = Transaction {
-            // Enable relative time locks
-            version: TX_VERSION,
-            input: escrow_non_recover_txins.clone(),
-            output: borrower_info.repayment_outputs,
-            lock_time: LockTime::ZERO,
-        };
-        let default_tx = Transaction {
-            // Enable relative time locks
-            version: TX_VERSION,
-            input: escrow_non_recover_txins.clone(),
-            output: termination_outputs_default,
-            lock_time: self.params.default_lock_time,
-        };
-        let liquidation_tx = Transaction {
-            // Enable relative time locks
-            version: TX_VERSION,
-            input: escrow_non_recover_txins,
-            output: termination_outputs_liquidation,
-            lock_time: LockTime::ZERO,
-        };
-        let escrow_recover_txin = TxIn {
-            previous_output: escrow_out_point,
-            script_sig: ScriptBuf::new(),
-            // Enable both RBF and lock time
-            sequence: Sequence::ZERO,
-            witness: Witness::new(),
-        };
-        let escrow_recover_txins = vec![escrow_recover_txin];
-        let recover_tx = Transaction {
-            version: TX_VERSION,
-            input: escrow_recover_txins,
-            output: borrower_info.recover_outputs,
-            lock_time: self.params.recover_lock_time.into(),
-        };
+        unsigned_transactions(&self.params, self.keys, borrower_info)
+    }
 
-        UnsignedTransactions {
-            borrower_eph: borrower_info.escrow_eph_key,
-            multisig_leaf_hash,
-            contract_index: borrower_info.escrow_contract_output_position,
-            escrow_prevouts,
-            escrow: escrow_tx,
-            repayment: repayment_tx,
-            default: default_tx,
-            liquidation: liquidation_tx,
-            recover: recover_tx,
-        }
+    /// Returns the taproot spend info of the escrow output for given borrower ephemeral key.
+    ///
+    /// This can be used to obtain the output key, the merkle root or the control block of the
+    /// multisig script e.g. when constructing PSBTs.
+    pub fn escrow_spend_info(&self, borrower_eph: PubKey<participant::Borrower, context::Escrow>) -> TaprootSpendInfo {
+        output_spend_info_with_scripts(&self.keys.add_borrower_eph(borrower_eph))
     }
 
     pub fn transactions_validated(self, unsigned_txes: UnsignedTransactions, recover: Signature, repayment: Signature) -> ReceivingEscrowSignature<P> {
@@ -262,8 +205,8 @@ impl<P: Participant> super::StateData for ReceivingBorrowerInfo<P> where P::PreE
 }
 impl<P: Participant> super::Serialize for ReceivingBorrowerInfo<P> where P::PreEscrowData: super::Serialize {
     fn serialize(&self, out: &mut Vec<u8>) {
-        out.reserve(self.params.reserve_suggestion() + 64);
-        self.keys.serialize(out);
+        out.reserve(self.params.serialized_size() + 64);
+        self.keys.serialize_state(out);
         self.params.serialize(out);
         self.participant_data.serialize(out);
     }
@@ -276,10 +219,10 @@ impl<P: Participant> super::Deserialize for ReceivingBorrowerInfo<P> where P::Pr
         if bytes.len() < 64 {
             return Err(ReceivingBorrowerInfoDeserErrorInner::Offer(super::offer::DeserializationError::UnexpectedEnd).into());
         }
-        let keys = super::offer::TedSigPubKeys::deserialize(bytes).map_err(ReceivingBorrowerInfoDeserErrorInner::Offer)?;
+        let keys = super::offer::TedSigPubKeys::deserialize_state(bytes, version).map_err(ReceivingBorrowerInfoDeserErrorInner::Offer)?;
         let escrow_params_version = match version {
             deserialize::StateVersion::V0 => super::offer::EscrowParamsVersion::V0,
-            deserialize::StateVersion::V1 => super::offer::EscrowParamsVersion::V1,
+            deserialize::StateVersion::V1 | deserialize::StateVersion::V2 | deserialize::StateVersion::V3 | deserialize::StateVersion::V4 => super::offer::EscrowParamsVersion::V1,
         };
         let params = super::offer::EscrowParams::deserialize(bytes, escrow_params_version).map_err(ReceivingBorrowerInfoDeserErrorInner::Offer)?;
         let participant_data = P::PreEscrowData::deserialize(bytes, version).map_err(ReceivingBorrowerInfoDeserErrorInner::Participant)?;
@@ -337,12 +280,35 @@ impl<P: super::Participant> WaitingForEscrowConfirmation<P> {
     pub fn escrow_txid(&self) -> bitcoin::Txid {
         self.unsigned_txes.escrow.compute_txid()
     }
+
+    /// Returns the contract transactions without the signatures of TEDs.
+    pub fn unsigned_transactions(&self) -> &UnsignedTransactions {
+        &self.unsigned_txes
+    }
+
+    /// Checks whether the confirmed transaction is the escrow transaction of this contract.
+    ///
+    /// This should be used before signing a terminal transaction to make sure the chain state
+    /// corresponds to the stored contract.
+    pub fn matches_onchain(&self, confirmed: &Transaction) -> bool {
+        confirmed.compute_txid() == self.escrow_txid() && confirmed.output == self.unsigned_txes.escrow.output
+    }
+
+    /// Verifies the liquidation signature produced by TED-O.
+    ///
+    /// TED-P should call this before finalizing the liquidation transaction since an invalid
+    /// signature would produce an invalid transaction.
+    pub fn verify_ted_o_liquidation(&self, signature: &Signature) -> Result<(), SignatureVerificationError> {
+        let message = self.unsigned_txes.liquidation_signing_data();
+        secp256k1::SECP256K1.verify_schnorr(signature, &message, self.keys.ted_o.as_x_only())?;
+        Ok(())
+    }
 }
 
 impl<P: Participant> Serialize for WaitingForEscrowConfirmation<P> where P::PreEscrowData: super::Serialize {
     fn serialize(&self, out: &mut Vec<u8>) {
         // TODO: state marker
-        self.keys.serialize(out);
+        self.keys.serialize_state(out);
         self.borrower.serialize(out);
         self.params.serialize(out);
         self.unsigned_txes.serialize(out);
@@ -356,9 +322,9 @@ impl<P: Participant> super::Deserialize for WaitingForEscrowConfirmation<P>  whe
     fn deserialize(bytes: &mut &[u8], version: deserialize::StateVersion) -> Result<Self, Self::Error> {
         let escrow_params_version = match version {
             deserialize::StateVersion::V0 => super::offer::EscrowParamsVersion::V0,
-            deserialize::StateVersion::V1 => super::offer::EscrowParamsVersion::V1,
+            deserialize::StateVersion::V1 | deserialize::StateVersion::V2 | deserialize::StateVersion::V3 | deserialize::StateVersion::V4 => super::offer::EscrowParamsVersion::V1,
         };
-        let keys = offer::TedSigPubKeys::deserialize(bytes)
+        let keys = offer::TedSigPubKeys::deserialize_state(bytes, version)
             .map_err(ReceivingEscrowSignatureDeserErrorInner::Keys)
             .map_err(ReceivingEscrowSignatureDeserError)?;
         let borrower = BorrowerSignatures::deserialize(bytes)
@@ -406,6 +372,11 @@ pub struct BorrowerInfoMessage {
 }
 
 impl BorrowerInfoMessage {
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        self.borrower_info.serialize(out);
+        self.signatures.serialize(out);
+    }
+
     pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, BorrowerInfoMessageDeserError> {
         let borrower_info = BorrowerInfo::deserialize(bytes)?;
         let signatures = BorrowerSignatures::deserialize(bytes)?;
@@ -452,6 +423,10 @@ crate::test_macros::impl_test_traits!(BorrowerInfo<Validation> where { }, escrow
 crate::test_macros::impl_arbitrary!(BorrowerInfo<Validation>, escrow_eph_key, inputs, tx_height, escrow_extra_outputs, escrow_contract_output_position, escrow_amount, collateral_amount_default, collateral_amount_liquidation, repayment_outputs, recover_outputs, _phantom);
 
 impl<V> BorrowerInfo<V> {
+    /// Serializes the message.
+    ///
+    /// The layout is part of the protocol and must never change, TEDs deployed in the wild depend
+    /// on it. It's checked by the `borrower_info_golden_vector` test.
     pub fn serialize(&self, out: &mut Vec<u8>) {
         use bitcoin::consensus::Encodable;
 
@@ -518,6 +493,9 @@ impl BorrowerInfo<validation::Unvalidated> {
             }
             let count  = u32::from_be_bytes(bytes[..4].try_into().expect("checked above"));
             *bytes = &bytes[4..];
+            if count > MAX_OUTPUT_COUNT {
+                return Err(BorrowerInfoDeserErrorInner::TooManyOutputs(count));
+            }
             let mut vec = Vec::with_capacity(count as usize);
             for _ in 0..count {
                 let tx_out = TxOut::consensus_decode(bytes)?;
@@ -569,6 +547,7 @@ enum BorrowerInfoDeserErrorInner {
     Height(bitcoin::locktime::absolute::ConversionError),
     Consensus(bitcoin::consensus::encode::Error),
     TooManyInputs(u32),
+    TooManyOutputs(u32),
 }
 
 impl From<bitcoin::consensus::encode::Error> for BorrowerInfoDeserErrorInner {
@@ -590,6 +569,17 @@ impl BorrowerInfo<validation::Unvalidated> {
         if self.collateral_amount_default < escrow_params.min_collateral || self.collateral_amount_liquidation < escrow_params.min_collateral {
             return Err(BorrowerInfoError::Undercollateralized);
         }
+        // The fee of the escrow transaction is implied by the difference so the inputs must
+        // cover all outputs. Overflows can't happen with valid amounts so we treat them as
+        // insufficient too.
+        let input_sum = self.inputs.iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, input| sum.checked_add(input.tx_out.value));
+        let output_sum = self.escrow_extra_outputs.iter()
+            .try_fold(self.escrow_amount, |sum, output| sum.checked_add(output.value));
+        match (input_sum, output_sum) {
+            (Some(input_sum), Some(output_sum)) if input_sum >= output_sum => (),
+            _ => return Err(BorrowerInfoError::InputsInsufficient),
+        }
         // Note: some checks here are "missing", e.g. collateral <= escrow_amount
         // However, that doesn't matter because borrower would just get invalid transaction(s).
         // Also because of how the transactions are constructed borrower can't cause default or
@@ -611,7 +601,7 @@ impl BorrowerInfo<validation::Unvalidated> {
 }
 
 /// Contains all data required to compute unwrap_or_else data.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct UnsignedTransactions {
     pub(crate) borrower_eph: PubKey<participant::Borrower, context::Escrow>,
     pub(crate) multisig_leaf_hash: bitcoin::taproot::TapLeafHash,
@@ -625,8 +615,70 @@ pub struct UnsignedTransactions {
     pub(crate) recover: Transaction,
 }
 
+/// Prints a summary instead of whole transactions to keep the logs readable.
+impl fmt::Debug for UnsignedTransactions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Summary<'a>(&'a Transaction);
+
+        impl fmt::Debug for Summary<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct("Transaction")
+                    .field("txid", &self.0.compute_txid())
+                    .field("input_count", &self.0.input.len())
+                    .field("output_count", &self.0.output.len())
+                    .field("output_total", &self.0.output.iter().map(|txout| txout.value.to_sat()).fold(0u64, u64::saturating_add))
+                    .finish()
+            }
+        }
+
+        f.debug_struct("UnsignedTransactions")
+            .field("borrower_eph", &self.borrower_eph)
+            .field("contract_index", &self.contract_index)
+            .field("escrow", &Summary(&self.escrow))
+            .field("repayment", &Summary(&self.repayment))
+            .field("default", &Summary(&self.default))
+            .field("liquidation", &Summary(&self.liquidation))
+            .field("recover", &Summary(&self.recover))
+            .finish()
+    }
+}
 
 impl UnsignedTransactions {
+    /// Recovers the borrower information the transactions were constructed from.
+    ///
+    /// Passing the result to [`unsigned_transactions`] reconstructs the same transactions which
+    /// allows the borrower to rebuild them with a different ephemeral key.
+    pub(crate) fn borrower_info(&self, params: &offer::EscrowParams) -> BorrowerInfo<validation::Validated> {
+        let contract_index = self.contract_index as usize;
+        let inputs = self.escrow.input.iter()
+            .zip(&self.escrow_prevouts)
+            .map(|(txin, prevout)| SpendableTxo {
+                out_point: txin.previous_output,
+                tx_out: prevout.clone(),
+                sequence: txin.sequence,
+            })
+            .collect();
+        let tx_height = match self.escrow.lock_time {
+            LockTime::Blocks(height) => height,
+            LockTime::Seconds(_) => unreachable!("the escrow lock time is always constructed from a height"),
+        };
+        let mut escrow_extra_outputs = self.escrow.output.clone();
+        let escrow_output = escrow_extra_outputs.remove(contract_index);
+        BorrowerInfo {
+            escrow_eph_key: self.borrower_eph,
+            inputs,
+            tx_height,
+            escrow_extra_outputs,
+            escrow_contract_output_position: self.contract_index,
+            escrow_amount: escrow_output.value,
+            collateral_amount_default: self.default.output[params.liquidator_output_index].value,
+            collateral_amount_liquidation: self.liquidation.output[params.liquidator_output_index].value,
+            repayment_outputs: self.repayment.output.clone(),
+            recover_outputs: self.recover.output.clone(),
+            _phantom: Default::default(),
+        }
+    }
+
     /// For debugging 
     pub fn explain(&self) -> String {
         use core::fmt::Write;
@@ -646,25 +698,111 @@ impl UnsignedTransactions {
             }
         }
         string.push_str("consumed by one of these:\n");
-        writeln!(string, " * recover with time lock {}:", self.recover.lock_time).unwrap();
-        for txo in &self.recover.output {
-            writeln!(string, "    - {} sats to {}", txo.value, txo.script_pubkey).unwrap();
-        }
-        writeln!(string, " * repayment:").unwrap();
-        for txo in &self.repayment.output {
-            writeln!(string, "    - {} sats to {}", txo.value, txo.script_pubkey).unwrap();
-        }
-        writeln!(string, " * default:").unwrap();
-        for txo in &self.default.output {
-            writeln!(string, "    - {} sats to {}", txo.value, txo.script_pubkey).unwrap();
-        }
-        writeln!(string, " * liquidation:").unwrap();
-        for txo in &self.liquidation.output {
-            writeln!(string, "    - {} sats to {}", txo.value, txo.script_pubkey).unwrap();
-        }
+        let escrow_amount = self.escrow.output.get(self.contract_index as usize).map(|txo| txo.value);
+        let mut explain_terminal = |name: &str, tx: &Transaction| {
+            let fee = escrow_amount.and_then(|amount| tx.output.iter().try_fold(amount, |remaining, txo| remaining.checked_sub(txo.value)));
+            match fee {
+                Some(fee) => writeln!(string, " * {} paying {} sats in fees:", name, fee.to_sat()).unwrap(),
+                None => writeln!(string, " * {} with outputs exceeding the escrow amount:", name).unwrap(),
+            }
+            for txo in &tx.output {
+                writeln!(string, "    - {} sats to {}", txo.value, txo.script_pubkey).unwrap();
+            }
+        };
+        explain_terminal(&format!("recover with time lock {}", self.recover.lock_time), &self.recover);
+        explain_terminal("repayment", &self.repayment);
+        explain_terminal("default", &self.default);
+        explain_terminal("liquidation", &self.liquidation);
         string
     }
 
+    /// Renders the escrow and terminal transactions as JSON for review.
+    ///
+    /// Unlike [`explain`](Self::explain) this is intended to be processed by other programs, e.g.
+    /// a companion app displaying what the borrower is about to sign. Amounts are in satoshis and
+    /// scripts are rendered as addresses on `network` (`null` if the script has no address).
+    #[cfg(feature = "serde")]
+    pub fn to_review_json(&self, network: bitcoin::Network) -> serde_json::Value {
+        use serde_json::json;
+
+        let outputs = |tx: &Transaction| tx.output.iter().map(|txout| json!({
+            "value": txout.value.to_sat(),
+            "address": bitcoin::Address::from_script(&txout.script_pubkey, network).ok().map(|address| address.to_string()),
+            "script_pubkey": txout.script_pubkey.to_hex_string(),
+        })).collect::<Vec<_>>();
+        let input = |txin: &TxIn, value: Option<bitcoin::Amount>| json!({
+            "txid": txin.previous_output.txid.to_string(),
+            "vout": txin.previous_output.vout,
+            "value": value.map(bitcoin::Amount::to_sat),
+            "sequence": txin.sequence.to_consensus_u32(),
+        });
+        let escrow_amount = self.escrow.output.get(self.contract_index as usize).map(|txout| txout.value);
+        let terminal = |tx: &Transaction| json!({
+            "txid": tx.compute_txid().to_string(),
+            "lock_time": tx.lock_time.to_consensus_u32(),
+            "inputs": tx.input.iter().map(|txin| input(txin, escrow_amount)).collect::<Vec<_>>(),
+            "outputs": outputs(tx),
+        });
+
+        json!({
+            "escrow": {
+                "txid": self.escrow.compute_txid().to_string(),
+                "lock_time": self.escrow.lock_time.to_consensus_u32(),
+                "inputs": self.escrow.input.iter().zip(&self.escrow_prevouts).map(|(txin, txo)| input(txin, Some(txo.value))).collect::<Vec<_>>(),
+                "outputs": outputs(&self.escrow),
+                "contract_index": self.contract_index,
+            },
+            "recover": terminal(&self.recover),
+            "repayment": terminal(&self.repayment),
+            "default": terminal(&self.default),
+            "liquidation": terminal(&self.liquidation),
+        })
+    }
+
+    /// Returns the highest finalization fee rate at which no terminal transaction has a dust output.
+    ///
+    /// The fees are assumed to be deducted from the largest output of each transaction, which is
+    /// the return output or the collateral. Returns zero if some transaction can't avoid dust at
+    /// all or its outputs overflow.
+    pub fn max_finalization_fee_rate(&self) -> bitcoin::FeeRate {
+        use bitcoin::amount::CheckedSum;
+
+        let escrow_amount = match self.escrow.output.get(self.contract_index as usize) {
+            Some(txout) => txout.value,
+            None => return bitcoin::FeeRate::ZERO,
+        };
+        [&self.repayment, &self.default, &self.liquidation, &self.recover]
+            .iter()
+            .map(|tx| {
+                let largest = match tx.output.iter().max_by_key(|txout| txout.value) {
+                    Some(largest) => largest,
+                    None => return u64::MAX,
+                };
+                let other_outputs = tx.output.iter()
+                    .map(|txout| txout.value)
+                    .checked_sum()
+                    .and_then(|total| total.checked_sub(largest.value));
+                let other_outputs = match other_outputs {
+                    Some(other_outputs) => other_outputs,
+                    None => return 0,
+                };
+                let weight = bitcoin::transaction::predict_weight(
+                    core::iter::once(multisig_spend_input_prediction()),
+                    tx.output.iter().map(|txout| txout.script_pubkey.len()),
+                );
+                let available = escrow_amount
+                    .checked_sub(other_outputs)
+                    .and_then(|available| available.checked_sub(largest.script_pubkey.minimal_non_dust()));
+                match available {
+                    Some(available) => available.to_sat().saturating_mul(1000) / weight.to_wu(),
+                    None => 0,
+                }
+            })
+            .min()
+            .map(bitcoin::FeeRate::from_sat_per_kwu)
+            .unwrap_or(bitcoin::FeeRate::ZERO)
+    }
+
     pub(crate) fn serialize(&self, out: &mut Vec<u8>) {
         use bitcoin::consensus::Encodable;
 
@@ -700,7 +838,7 @@ impl UnsignedTransactions {
         let recover = Transaction::consensus_decode(bytes)?;
         let keys = keys.add_borrower_eph(borrower_eph);
         let multisig_script = keys.generate_multisig_script();
-        let multisig_leaf_hash = multisig_script.tapscript_leaf_hash();
+        let multisig_leaf_hash = pub_keys::leaf_hash(&multisig_script);
         let transactions = UnsignedTransactions {
             borrower_eph,
             contract_index,
@@ -715,6 +853,19 @@ impl UnsignedTransactions {
         Ok(transactions)
     }
 
+    /// Returns the labeled messages the borrower needs to sign.
+    ///
+    /// This is useful for signing with an external device. The labels match the fields of
+    /// [`BorrowerSignatures`] which should be filled in with the produced signatures.
+    pub fn borrower_signing_requests(&self) -> [(&'static str, secp256k1::Message); 4] {
+        [
+            ("repayment", self.repayment_signing_data()),
+            ("default", self.default_signing_data()),
+            ("liquidation", self.liquidation_signing_data()),
+            ("recover", self.recover_signing_data()),
+        ]
+    }
+
     pub fn sign_borrower(&self, key_pair: Keypair) -> BorrowerSignatures {
         let repayment_signature = secp256k1::SECP256K1.sign_schnorr(&self.repayment_signing_data(), &key_pair);
         let default_signature = secp256k1::SECP256K1.sign_schnorr(&self.default_signing_data(), &key_pair);
@@ -762,18 +913,27 @@ impl UnsignedTransactions {
     fn sign_escrow<P: Participant>(&self, prefund: &super::prefund::Prefund<P>) -> Vec<Signature> where P::PrefundData: super::HotKey {
         use super::HotKey;
 
-        self.sign_escrow_external_key(prefund.participant_data.participant_key_pair(), prefund)
+        self.sign_escrow_with_key(prefund.participant_data.participant_key_pair(), prefund)
     }
 
-    fn sign_escrow_external_key<P: Participant>(&self, key_pair: &Keypair, prefund: &super::prefund::Prefund<P>) -> Vec<Signature> {
+    /// Signs the escrow inputs spending prefund with a key that isn't stored in the state.
+    ///
+    /// This allows TEDs to keep their prefund keys in cold storage. The messages to sign can be
+    /// obtained from [`escrow_signing_data`](Self::escrow_signing_data) and the resulting
+    /// signatures checked using [`verify_escrow_external`](Self::verify_escrow_external).
+    pub fn sign_escrow_with_key<P: Participant>(&self, key_pair: &Keypair, prefund: &super::prefund::Prefund<P>) -> Vec<Signature> {
         self.escrow_signing_data(prefund)
             .map(|(_, message)| secp256k1::SECP256K1.sign_schnorr(&message, &key_pair))
             .collect()
     }
 
-
-    pub fn verify_borrower(&self, signatures: &BorrowerSignatures) -> Result<(), secp256k1::Error> {
+    /// Verifies the borrower signatures against the ephemeral key declared by the borrower.
+    pub fn verify_borrower(&self, signatures: &BorrowerSignatures) -> Result<(), BorrowerSignatureError> {
         self.verify_borrower_external(self.borrower_eph.as_x_only(), signatures)
+            .map_err(|error| match error {
+                secp256k1::Error::IncorrectSignature => BorrowerSignatureError::BorrowerKeyMismatch,
+                error => BorrowerSignatureError::Secp256k1(error),
+            })
     }
 
     pub fn verify_borrower_external(&self, key: &XOnlyPublicKey, signatures: &BorrowerSignatures) -> Result<(), secp256k1::Error> {
@@ -804,12 +964,55 @@ impl UnsignedTransactions {
         Ok(())
     }
 
+    /// Verifies escrow signatures produced externally, e.g. on an air-gapped machine.
+    ///
+    /// The signatures must be in the same order as the messages returned by
+    /// [`escrow_signing_data`](Self::escrow_signing_data).
+    pub fn verify_escrow_external(&self, key: &XOnlyPublicKey, prefund: &super::prefund::Prefund<impl Participant>, signatures: &[Signature]) -> Result<(), secp256k1::Error> {
+        let mut signing_data = self.escrow_signing_data(prefund);
+        let mut signatures = signatures.iter();
+        loop {
+            match (signing_data.next(), signatures.next()) {
+                (Some((_, message)), Some(signature)) => secp256k1::SECP256K1.verify_schnorr(signature, &message, key)?,
+                (None, None) => return Ok(()),
+                _ => return Err(secp256k1::Error::InvalidSignature),
+            }
+        }
+    }
+
+    /// Returns the number of inputs of the escrow transaction.
+    ///
+    /// Each TED has to provide this many escrow signatures.
+    pub fn escrow_input_count(&self) -> usize {
+        self.escrow.input.len()
+    }
+
+    fn check_escrow_signature_count(&self, got: usize) -> Result<(), SignatureVerificationError> {
+        let expected = self.escrow_input_count();
+        if got != expected {
+            return Err(SignatureVerificationError::SignatureCountMismatch { expected, got });
+        }
+        Ok(())
+    }
+
+    /// Verifies the borrower's escrow signatures received in a broadcast request.
+    ///
+    /// `borrower_key` is the borrower's prefund key. There has to be exactly one signature for
+    /// each prefund input of the escrow transaction.
+    pub fn verify_broadcast_request(&self, request: &BroadcastRequest, borrower_key: &XOnlyPublicKey, prefund: &super::prefund::Prefund<impl Participant>) -> Result<(), SignatureVerificationError> {
+        self.check_escrow_signature_count(request.signatures.len())?;
+        self.verify_escrow_external(borrower_key, prefund, &request.signatures)?;
+        Ok(())
+    }
+
     pub fn escrow_signing_data(&self, prefund: &super::prefund::Prefund<impl Participant>) -> impl '_ + Iterator<Item=(usize, secp256k1::Message)> {
+        self.escrow_signing_data_for(prefund.funding_script(), prefund.multisig_leaf_hash())
+    }
+
+    /// Same as [`escrow_signing_data`](Self::escrow_signing_data) but doesn't require prefund state.
+    pub(crate) fn escrow_signing_data_for(&self, funding_script: ScriptBuf, leaf_hash: TapLeafHash) -> impl '_ + Iterator<Item=(usize, secp256k1::Message)> {
         use bitcoin::sighash::{SighashCache, Prevouts, TapSighashType};
 
-        let funding_script = prefund.funding_script();
-        let leaf_script = prefund.keys.generate_multisig_script();
-        let leaf_hash = leaf_script.tapscript_leaf_hash();
         let mut cache = SighashCache::new(&self.escrow);
         let prevouts = &self.escrow_prevouts;
         let prevouts = Prevouts::All(prevouts);
@@ -822,6 +1025,84 @@ impl UnsignedTransactions {
             })
     }
 
+    /// Creates a PSBT of the escrow transaction with all taproot fields of the prefund inputs filled.
+    ///
+    /// This allows signing the escrow transaction with any wallet supporting taproot PSBTs.
+    /// Key origins are only filled for the keys present in `key_sources` since they are not
+    /// known otherwise.
+    pub fn escrow_psbt(&self, prefund: &super::prefund::Prefund<impl Participant>, key_sources: &BTreeMap<XOnlyPublicKey, bitcoin::bip32::KeySource>) -> Psbt {
+        let funding_script = prefund.funding_script();
+        let multisig_script = prefund.multisig_script();
+        let leaf_hash = prefund.multisig_leaf_hash();
+        let control_block = prefund.multisig_control_block();
+        let merkle_root = TapNodeHash::from_node_hashes(prefund.borrower_return_hash, leaf_hash.into());
+        let key_origins = prefund.keys.sorted()
+            .iter()
+            .filter_map(|key| key_sources.get(*key).map(|source| (**key, (vec![leaf_hash], source.clone()))))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut psbt = Psbt::from_unsigned_tx(self.escrow.clone())
+            .expect("the escrow transaction is unsigned");
+        for (input, prevout) in psbt.inputs.iter_mut().zip(&self.escrow_prevouts) {
+            input.witness_utxo = Some(prevout.clone());
+            if prevout.script_pubkey == funding_script {
+                input.tap_scripts.insert(control_block.clone(), (multisig_script.clone(), pub_keys::LEAF_VERSION));
+                input.tap_key_origins = key_origins.clone();
+                input.tap_internal_key = Some(control_block.internal_key);
+                input.tap_merkle_root = Some(merkle_root);
+            }
+        }
+        psbt
+    }
+
+    /// Checks that the fee paid by the escrow transaction is consistent with the fee rate.
+    ///
+    /// The fee is computed from the values of the spent outputs and compared to the fee expected
+    /// for the predicted weight of the transaction. Deviations up to
+    /// `ESCROW_FEE_TOLERANCE_PERCENT` are accepted.
+    pub fn verify_escrow_balance(&self, fee_rate: bitcoin::FeeRate) -> Result<(), BalanceError> {
+        let input_sum = self.escrow_prevouts.iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, txout| sum.checked_add(txout.value))
+            .ok_or(BalanceError::Overflow)?;
+        let output_sum = self.escrow.output.iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, txout| sum.checked_add(txout.value))
+            .ok_or(BalanceError::Overflow)?;
+        let actual = input_sum.checked_sub(output_sum).ok_or(BalanceError::OutputsExceedInputs { input_sum, output_sum })?;
+
+        let input_predictions = core::iter::repeat(super::prefund::multisig_spend_input_prediction()).take(self.escrow.input.len());
+        let output_script_lens = self.escrow.output.iter().map(|txout| txout.script_pubkey.len());
+        let weight = bitcoin::transaction::predict_weight(input_predictions, output_script_lens);
+        let expected = fee_rate.checked_mul_by_weight(weight).ok_or(BalanceError::Overflow)?;
+
+        let min = expected.to_sat().saturating_mul(100 - ESCROW_FEE_TOLERANCE_PERCENT) / 100;
+        let max = expected.to_sat().saturating_mul(100 + ESCROW_FEE_TOLERANCE_PERCENT) / 100;
+        if actual.to_sat() < min {
+            Err(BalanceError::FeeTooLow { expected, actual })
+        } else if actual.to_sat() > max {
+            Err(BalanceError::FeeTooHigh { expected, actual })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Predicts the combined weight of the escrow transaction and the heaviest terminal transaction.
+    ///
+    /// Only one terminal transaction can be confirmed so the heaviest one is used as the upper
+    /// bound. The weight of the transaction funding prefund is not included since it's not known.
+    pub fn total_weight(&self) -> bitcoin::Weight {
+        let escrow_inputs = core::iter::repeat(super::prefund::multisig_spend_input_prediction()).take(self.escrow.input.len());
+        let escrow_weight = bitcoin::transaction::predict_weight(escrow_inputs, self.escrow.output.iter().map(|txout| txout.script_pubkey.len()));
+        let terminal_weight = [&self.repayment, &self.default, &self.liquidation, &self.recover]
+            .iter()
+            .map(|tx| {
+                let inputs = core::iter::repeat(multisig_spend_input_prediction()).take(tx.input.len());
+                bitcoin::transaction::predict_weight(inputs, tx.output.iter().map(|txout| txout.script_pubkey.len()))
+            })
+            .max()
+            .expect("non-empty array");
+        escrow_weight + terminal_weight
+    }
+
     pub fn repayment_signing_data(&self) -> secp256k1::Message {
         self.signing_data_for(&self.repayment)
     }
@@ -878,7 +1159,7 @@ impl UnsignedTransactions {
         let helper = UnsignedTransactionsHelper::arbitrary(gen);
         let keys = keys.add_borrower_eph(helper.borrower_eph);
         let multisig_script = keys.generate_multisig_script();
-        let multisig_leaf_hash = multisig_script.tapscript_leaf_hash();
+        let multisig_leaf_hash = pub_keys::leaf_hash(&multisig_script);
 
         UnsignedTransactions {
             borrower_eph: helper.borrower_eph,
@@ -894,6 +1175,33 @@ impl UnsignedTransactions {
     }
 }
 
+/// Returned when the borrower signatures fail verification.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BorrowerSignatureError {
+    /// A signature does not verify under the ephemeral key the borrower declared for these
+    /// transactions.
+    ///
+    /// This doesn't necessarily mean the borrower used a different key. A signature made by the
+    /// declared key over different (e.g. tampered) transactions is reported the same way.
+    BorrowerKeyMismatch,
+    /// The verification failed for another reason, such as a malformed key.
+    Secp256k1(secp256k1::Error),
+}
+
+/// How much the fee of the escrow transaction may deviate from the expected fee, in percent.
+const ESCROW_FEE_TOLERANCE_PERCENT: u64 = 10;
+
+/// Returned when the escrow transaction pays an implausible fee.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BalanceError {
+    Overflow,
+    OutputsExceedInputs { input_sum: bitcoin::Amount, output_sum: bitcoin::Amount },
+    FeeTooLow { expected: bitcoin::Amount, actual: bitcoin::Amount },
+    FeeTooHigh { expected: bitcoin::Amount, actual: bitcoin::Amount },
+}
+
 #[derive(Debug)]
 pub(crate) enum UnsignedTransactionsDeserError {
     UnexpectedEnd,
@@ -956,6 +1264,14 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
     pub fn verify_signatures(mut self, ted_o_signatures: TedOSignatures, ted_p_signatures: TedPSignatures) -> Result<SignaturesVerified<P>, (Self, SignatureVerificationError)> {
         // try { } hack
         let result = (|| {
+            // Escrow signatures may legitimately be missing but if they are present their number
+            // must match.
+            if ted_o_signatures.signed_escrow() {
+                self.unsigned_txes.check_escrow_signature_count(ted_o_signatures.escrow.len())?;
+            }
+            if ted_p_signatures.signed_escrow() {
+                self.unsigned_txes.check_escrow_signature_count(ted_p_signatures.escrow.len())?;
+            }
             self.unsigned_txes.verify_ted_o_external(self.keys.ted_o.as_x_only(), &ted_o_signatures)?;
             self.unsigned_txes.verify_ted_p_external(self.keys.ted_p.as_x_only(), &ted_p_signatures)?;
             Ok(())
@@ -976,14 +1292,50 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
         Ok(verified)
     }
 
+    /// Checks the compact recover signature received from TED-O or TED-P.
+    pub fn check_recover_signature(&self, message: &RecoverSignatureMessage) -> Result<(), secp256k1::Error> {
+        let key = match message {
+            RecoverSignatureMessage::TedO(_) => self.keys.ted_o.as_x_only(),
+            RecoverSignatureMessage::TedP(_) => self.keys.ted_p.as_x_only(),
+        };
+        let message_hash = self.unsigned_txes.recover_signing_data();
+        secp256k1::SECP256K1.verify_schnorr(message.signature(), &message_hash, key)
+    }
+
+    /// Checks the signatures received from TED-O or TED-P without changing the state.
+    ///
+    /// This can be used to triage the received messages before calling `verify_signatures`.
+    pub fn check_ted_signatures(&self, signatures: &TedSignatures) -> Result<(), secp256k1::Error> {
+        match signatures {
+            TedSignatures::TedO(signatures) => self.unsigned_txes.verify_ted_o_external(self.keys.ted_o.as_x_only(), signatures),
+            TedSignatures::TedP(signatures) => self.unsigned_txes.verify_ted_p_external(self.keys.ted_p.as_x_only(), signatures),
+        }
+    }
+
     pub fn liquidator_amount(&self) -> bitcoin::Amount {
         // We need to be pessimistic here, so we return the smaler one
         self.unsigned_txes.liquidation.output[self.params.liquidator_output_index].value.min(self.unsigned_txes.default.output[self.params.liquidator_output_index].value)
     }
 
+    /// Returns the larger of the amounts the liquidator receives in default and liquidation.
+    ///
+    /// Unlike [`liquidator_amount`](Self::liquidator_amount) this is the worst-case payout.
+    pub fn max_liquidator_amount(&self) -> bitcoin::Amount {
+        self.unsigned_txes.liquidation.output[self.params.liquidator_output_index].value.max(self.unsigned_txes.default.output[self.params.liquidator_output_index].value)
+    }
+
+    /// Returns how much more the liquidator receives in liquidation than in default.
+    ///
+    /// The value is negative if the default pays more.
+    pub fn collateral_spread(&self) -> SignedAmount {
+        let liquidation = self.unsigned_txes.liquidation.output[self.params.liquidator_output_index].value.to_sat();
+        let default = self.unsigned_txes.default.output[self.params.liquidator_output_index].value.to_sat();
+        // Both amounts are bounded by the escrow amount so the difference fits
+        SignedAmount::from_sat(liquidation.wrapping_sub(default) as i64)
+    }
+
     pub(crate) fn assemble_escrow<F: FnMut(secp256k1::Message) -> Result<Signature, SignatureVerificationError>>(&self, ted_o_signatures: &TedOSignatures, ted_p_signatures: &TedPSignatures, mut get_signature: F) -> Result<Transaction, SignatureVerificationError> where P::PreEscrowData: participant::PrefundData {
         use secp256k1::SECP256K1;
-        use bitcoin::taproot::ControlBlock;
         use participant::PrefundData;
 
         let prefund = self.participant_data.prefund();
@@ -994,16 +1346,14 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
         let ted_p_key = prefund.keys.ted_p.as_x_only();
 
         // pre-compute script and control block for faster serialization
-        let script = prefund.keys.generate_multisig_script();
-        let internal_key = prefund.keys.generate_internal_key();
-        let merkle_branch = [prefund.borrower_return_hash].into();
-        let control_block = ControlBlock {
-            leaf_version: LeafVersion::TapScript,
-            internal_key,
-            output_key_parity: prefund.parity,
-            merkle_branch,
-        };
-        let control_block = control_block.serialize();
+        let script = prefund.multisig_script();
+        let control_block = prefund.multisig_control_block().serialize();
+
+        if !ted_o_signatures.signed_escrow() || !ted_p_signatures.signed_escrow() {
+            return Err(SignatureVerificationError::EscrowNotCoSigned);
+        }
+        self.unsigned_txes.check_escrow_signature_count(ted_o_signatures.escrow.len())?;
+        self.unsigned_txes.check_escrow_signature_count(ted_p_signatures.escrow.len())?;
 
         let mut ted_o_escrow_sigs = ted_o_signatures.escrow.iter();
         let mut ted_p_escrow_sigs = ted_p_signatures.escrow.iter();
@@ -1016,11 +1366,10 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
                     let borrower = get_signature(message)?;
                     result.input[i].witness = super::assemble_witness(&borrower, ted_o, ted_p, permutation, &script, &control_block);
                 },
+                // only reachable if some escrow inputs don't spend prefund
                 _ => return Err(SignatureVerificationError::MissingSignature),
             }
         }
-        // Yes, there may be outstanding signatures. But what are we gonna do about them anyway? We
-        // have what we wanted.
         Ok(result)
     }
 
@@ -1032,6 +1381,7 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
                     tx_escrow: escrow,
                     recover: self.unsigned_txes.recover,
                     participant_data: self.participant_data,
+                    escrow_prevouts: self.unsigned_txes.escrow_prevouts,
                 };
                 Ok(state)
             },
@@ -1045,6 +1395,13 @@ impl<P: Participant> ReceivingEscrowSignature<P> {
 pub enum SignatureVerificationError {
     InvalidSignature(secp256k1::Error),
     MissingSignature,
+    /// At least one TED didn't sign the escrow transaction at all.
+    ///
+    /// Unlike `MissingSignature` this is not a sign of corruption, the TED needs to be asked to
+    /// co-sign the escrow transaction.
+    EscrowNotCoSigned,
+    /// The number of escrow signatures doesn't match the number of escrow inputs.
+    SignatureCountMismatch { expected: usize, got: usize },
 }
 
 impl From<secp256k1::Error> for SignatureVerificationError {
@@ -1063,7 +1420,7 @@ impl<P: Participant> Serialize for ReceivingEscrowSignature<P> where P::PreEscro
         // TODO: state marker
         out.extend_from_slice(self.recover_signature.as_ref());
         out.extend_from_slice(self.repayment_signature.as_ref());
-        self.keys.serialize(out);
+        self.keys.serialize_state(out);
         self.params.serialize(out);
         self.unsigned_txes.serialize(out);
         self.participant_data.serialize(out);
@@ -1076,7 +1433,7 @@ impl<P: Participant> super::Deserialize for ReceivingEscrowSignature<P>  where P
     fn deserialize(bytes: &mut &[u8], version: deserialize::StateVersion) -> Result<Self, Self::Error> {
         let escrow_params_version = match version {
             deserialize::StateVersion::V0 => super::offer::EscrowParamsVersion::V0,
-            deserialize::StateVersion::V1 => super::offer::EscrowParamsVersion::V1,
+            deserialize::StateVersion::V1 | deserialize::StateVersion::V2 | deserialize::StateVersion::V3 | deserialize::StateVersion::V4 => super::offer::EscrowParamsVersion::V1,
         };
         let recover_signature = deserialize::signature(bytes)
             .map_err(ReceivingEscrowSignatureDeserErrorInner::Secp256k1)
@@ -1084,7 +1441,7 @@ impl<P: Participant> super::Deserialize for ReceivingEscrowSignature<P>  where P
         let repayment_signature = deserialize::signature(bytes)
             .map_err(ReceivingEscrowSignatureDeserErrorInner::Secp256k1)
             .map_err(ReceivingEscrowSignatureDeserError)?;
-        let keys = offer::TedSigPubKeys::deserialize(bytes)
+        let keys = offer::TedSigPubKeys::deserialize_state(bytes, version)
             .map_err(ReceivingEscrowSignatureDeserErrorInner::Keys)
             .map_err(ReceivingEscrowSignatureDeserError)?;
         let params = offer::EscrowParams::deserialize(bytes, escrow_params_version)
@@ -1146,10 +1503,79 @@ impl<P: Participant> SignaturesVerified<P> {
         self.state.liquidator_amount()
     }
 
+    /// Returns how much more the liquidator receives in liquidation than in default.
+    pub fn collateral_spread(&self) -> SignedAmount {
+        self.state.collateral_spread()
+    }
+
+    /// Returns the maximum amount the liquidator can receive.
+    pub fn max_liquidator_amount(&self) -> bitcoin::Amount {
+        self.state.max_liquidator_amount()
+    }
+
+    /// Returns the taproot spend info of the escrow output.
+    ///
+    /// This can be used to obtain the output key, the merkle root or the control block of the
+    /// multisig script e.g. when constructing PSBTs.
+    pub fn escrow_spend_info(&self) -> TaprootSpendInfo {
+        let keys = self.state.keys.add_borrower_eph(self.state.unsigned_txes.borrower_eph);
+        output_spend_info_with_scripts(&keys)
+    }
+
+    /// Returns the absolute lock time after which the recover transaction can be broadcast.
+    pub fn recover_lock_time(&self) -> LockTime {
+        self.recover_tx().lock_time
+    }
+
+    /// Checks whether the recover transaction can be broadcast right now.
+    ///
+    /// See [`EscrowSigned::can_broadcast_recover`] for the meaning of the arguments.
+    pub fn can_broadcast_recover(&self, tip_height: Height, median_time_past: bitcoin::absolute::Time) -> bool {
+        can_broadcast(self.recover_lock_time(), tip_height, median_time_past)
+    }
+
+    /// Returns how long it takes until the default transaction can be broadcast.
+    ///
+    /// `current_height` is the height of the current best block.
+    pub fn default_available(&self, current_height: Height) -> LockStatus {
+        lock_status(self.state.params.default_lock_time, current_height)
+    }
+
+    /// Returns how long it takes until the default transaction can be broadcast.
+    ///
+    /// This is the variant for time-based lock times. `median_time_past` is the median time of the
+    /// last 11 blocks.
+    pub fn default_available_at_time(&self, median_time_past: bitcoin::absolute::Time) -> LockStatus {
+        lock_status_at_time(self.state.params.default_lock_time, median_time_past)
+    }
+
     pub fn escrow_output(&self) -> &TxOut {
         self.state.unsigned_txes.escrow_output()
     }
 
+    /// Checks that the escrow output pays to the multisig of the contract keys.
+    ///
+    /// This is a final safety check before committing the funds by signing the escrow.
+    pub fn verify_escrow_output_key(&self) -> bool {
+        ScriptBuf::new_p2tr_tweaked(self.tweaked_key()) == self.escrow_output().script_pubkey
+    }
+
+    /// Returns all outpoints spent by the transactions of the contract.
+    ///
+    /// These are the funding outpoints spent by the escrow followed by the escrow contract outpoint
+    /// spent by the terminal transactions. Monitoring them detects any movement of contract funds.
+    pub fn all_spent_outpoints(&self) -> Vec<OutPoint> {
+        let unsigned_txes = &self.state.unsigned_txes;
+        let mut outpoints = unsigned_txes.escrow.input.iter()
+            .map(|txin| txin.previous_output)
+            .collect::<Vec<_>>();
+        outpoints.push(OutPoint {
+            txid: unsigned_txes.escrow.compute_txid(),
+            vout: unsigned_txes.contract_index,
+        });
+        outpoints
+    }
+
     pub fn assemble_escrow_custom(mut self, get_signature: impl FnMut(secp256k1::Message) -> Result<Signature, SignatureVerificationError>) -> Result<EscrowSigned<P>, (Self, SignatureVerificationError)> where P::PreEscrowData: participant::PrefundData {
         let result = self.state.assemble_escrow_and_transition(&self.ted_o_signatures, &self.ted_p_signatures, get_signature);
         match result {
@@ -1221,16 +1647,105 @@ pub struct EscrowSigned<P: Participant> {
 
     /// Data relevant only to the specific participant.
     pub participant_data: P::PreEscrowData,
+
+    /// The outputs spent by the escrow transaction.
+    ///
+    /// Empty if the state was created by an older version which didn't store them.
+    pub(crate) escrow_prevouts: Vec<TxOut>,
 }
 
-crate::test_macros::impl_test_traits!(EscrowSigned<P: Participant> where { P::PreEscrowData }, tx_escrow, recover, participant_data);
-crate::test_macros::impl_arbitrary!(EscrowSigned<P: Participant> where { P::PreEscrowData }, tx_escrow, recover, participant_data);
+crate::test_macros::impl_test_traits!(EscrowSigned<P: Participant> where { P::PreEscrowData }, tx_escrow, recover, participant_data, escrow_prevouts);
+crate::test_macros::impl_arbitrary!(EscrowSigned<P: Participant> where { P::PreEscrowData }, tx_escrow, recover, participant_data, escrow_prevouts);
 
 impl<P: Participant> EscrowSigned<P> {
     /// Returns the transaction moving satoshis from prefund to escrow.
     pub fn tx_escrow(&self) -> &Transaction {
         &self.tx_escrow
     }
+
+    /// Returns the weight of the signed escrow transaction.
+    pub fn escrow_weight(&self) -> bitcoin::Weight {
+        self.tx_escrow.weight()
+    }
+
+    /// Returns the virtual size of the signed escrow transaction.
+    ///
+    /// Dividing the fee by this gives the effective fee rate the transaction pays.
+    pub fn escrow_vsize(&self) -> usize {
+        self.tx_escrow.vsize()
+    }
+
+    /// Returns the fee rate actually paid by the signed escrow transaction.
+    ///
+    /// This can be compared with the requested fee rate to detect a significant miss of the size
+    /// prediction. Returns `None` if the spent outputs are unknown because the state was created by
+    /// an older version or if the outputs exceed the inputs.
+    pub fn escrow_fee_rate(&self) -> Option<bitcoin::FeeRate> {
+        if self.escrow_prevouts.len() != self.tx_escrow.input.len() {
+            return None;
+        }
+        let input_sum = self.escrow_prevouts.iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, txout| sum.checked_add(txout.value))?;
+        let output_sum = self.tx_escrow.output.iter()
+            .try_fold(bitcoin::Amount::ZERO, |sum, txout| sum.checked_add(txout.value))?;
+        let fee = input_sum.checked_sub(output_sum)?;
+        Some(fee / self.tx_escrow.weight())
+    }
+
+    /// Returns the absolute lock time after which the recover transaction can be broadcast.
+    ///
+    /// Depending on the offer this is either a block height or a unix timestamp.
+    pub fn recover_lock_time(&self) -> LockTime {
+        self.recover.lock_time
+    }
+
+    /// Checks whether the recover transaction can be broadcast right now.
+    ///
+    /// `tip_height` is the height of the current best block and `median_time_past` is the median
+    /// time of the last 11 blocks. These are the values the nodes use to decide whether the
+    /// transaction can be included in the next block.
+    pub fn can_broadcast_recover(&self, tip_height: Height, median_time_past: bitcoin::absolute::Time) -> bool {
+        can_broadcast(self.recover_lock_time(), tip_height, median_time_past)
+    }
+}
+
+/// Tells whether a time-locked transaction can be broadcast.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockStatus {
+    /// The transaction can be included in the next block.
+    Available,
+    /// The transaction can be broadcast after this many blocks are mined.
+    MaturesInBlocks(u32),
+    /// Median time past has to increase by this many seconds before the transaction can be broadcast.
+    MaturesInSeconds(u32),
+    /// The lock time is time-based but height was supplied or vice versa.
+    DifferentUnit,
+}
+
+// Uses the same rules as `can_broadcast`.
+fn lock_status(lock_time: LockTime, tip_height: Height) -> LockStatus {
+    match lock_time {
+        LockTime::Blocks(height) if height <= tip_height => LockStatus::Available,
+        LockTime::Blocks(height) => LockStatus::MaturesInBlocks(height.to_consensus_u32() - tip_height.to_consensus_u32()),
+        LockTime::Seconds(_) => LockStatus::DifferentUnit,
+    }
+}
+
+fn lock_status_at_time(lock_time: LockTime, median_time_past: bitcoin::absolute::Time) -> LockStatus {
+    match lock_time {
+        LockTime::Seconds(time) if time < median_time_past => LockStatus::Available,
+        LockTime::Seconds(time) => LockStatus::MaturesInSeconds(time.to_consensus_u32() - median_time_past.to_consensus_u32() + 1),
+        LockTime::Blocks(_) => LockStatus::DifferentUnit,
+    }
+}
+
+fn can_broadcast(lock_time: LockTime, tip_height: Height, median_time_past: bitcoin::absolute::Time) -> bool {
+    // The next block has height `tip_height + 1` and the lock time must be strictly lower than
+    // that. Time locks are compared against MTP which must be strictly greater.
+    match lock_time {
+        LockTime::Blocks(height) => height <= tip_height,
+        LockTime::Seconds(time) => time < median_time_past,
+    }
 }
 
 impl<P: Participant> super::StateData for EscrowSigned<P> where P::PreEscrowData: super::Serialize {
@@ -1244,6 +1759,7 @@ impl<P: Participant> super::Serialize for EscrowSigned<P> where P::PreEscrowData
         self.tx_escrow.consensus_encode(out).expect("vec doesn't error");
         self.recover.consensus_encode(out).expect("vec doesn't error");
         self.participant_data.serialize(out);
+        self.escrow_prevouts.consensus_encode(out).expect("vec doesn't error");
     }
 }
 
@@ -1256,10 +1772,15 @@ impl<P: Participant> super::Deserialize for EscrowSigned<P> where P::PreEscrowDa
         let tx_escrow = Transaction::consensus_decode(bytes).map_err(EscrowSignedDeserErrorInner::Escrow)?;
         let recover = Transaction::consensus_decode(bytes).map_err(EscrowSignedDeserErrorInner::Recover)?;
         let participant_data = P::PreEscrowData::deserialize(bytes, version).map_err(EscrowSignedDeserErrorInner::Participant)?;
+        let escrow_prevouts = match version {
+            deserialize::StateVersion::V0 | deserialize::StateVersion::V1 => Vec::new(),
+            deserialize::StateVersion::V2 | deserialize::StateVersion::V3 | deserialize::StateVersion::V4 => Vec::<TxOut>::consensus_decode(bytes).map_err(EscrowSignedDeserErrorInner::Prevouts)?,
+        };
         Ok(EscrowSigned {
             tx_escrow,
             recover,
             participant_data,
+            escrow_prevouts,
         })
     }
 }
@@ -1278,6 +1799,7 @@ pub enum EscrowSignedDeserErrorInner<E> {
     Escrow(bitcoin::consensus::encode::Error),
     Recover(bitcoin::consensus::encode::Error),
     Participant(E),
+    Prevouts(bitcoin::consensus::encode::Error),
 }
 
 /*
@@ -1297,7 +1819,7 @@ pub(crate) fn finalize(tx: &mut Transaction, keys: &PubKeys<context::Escrow>, bo
         .try_into()
         .expect("0 < 128");
     let control_block = ControlBlock {
-        leaf_version: LeafVersion::TapScript,
+        leaf_version: pub_keys::LEAF_VERSION,
         internal_key,
         output_key_parity: parity,
         merkle_branch,
@@ -1376,6 +1898,13 @@ pub struct TedOSignatures {
 crate::test_macros::impl_arbitrary!(TedOSignatures, recover, repayment, default, escrow);
 
 impl TedOSignatures {
+    /// Returns `true` if the signatures contain signatures of the escrow transaction.
+    ///
+    /// TEDs don't sign the escrow transaction if they aren't co-signing the prefund spend.
+    pub fn signed_escrow(&self) -> bool {
+        !self.escrow.is_empty()
+    }
+
     pub fn serialize(&self, out: &mut Vec<u8>) {
         out.reserve((self.escrow.len() + 3) * 64);
         out.push(constants::MessageId::StateSigsFromTedO as u8);
@@ -1465,6 +1994,13 @@ pub struct TedPSignatures {
 crate::test_macros::impl_arbitrary!(TedPSignatures, recover, escrow);
 
 impl TedPSignatures {
+    /// Returns `true` if the signatures contain signatures of the escrow transaction.
+    ///
+    /// TEDs don't sign the escrow transaction if they aren't co-signing the prefund spend.
+    pub fn signed_escrow(&self) -> bool {
+        !self.escrow.is_empty()
+    }
+
     pub fn serialize(&self, out: &mut Vec<u8>) {
         out.reserve((self.escrow.len() + 3) * 64);
         out.push(constants::MessageId::StateSigsFromTedP as u8);
@@ -1505,6 +2041,131 @@ impl TedPSignatures {
     }
 }
 
+/// The recover signature of a single TED.
+///
+/// This is the minimal message the borrower needs to back up the recover transaction. It's much
+/// smaller than the full signatures so it can be carried over low-bandwidth channels such as SMS
+/// or QR codes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecoverSignatureMessage {
+    TedO(Signature),
+    TedP(Signature),
+}
+
+impl RecoverSignatureMessage {
+    pub fn signature(&self) -> &Signature {
+        match self {
+            RecoverSignatureMessage::TedO(signature) | RecoverSignatureMessage::TedP(signature) => signature,
+        }
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        let message_id = match self {
+            RecoverSignatureMessage::TedO(_) => constants::MessageId::RecoverSigFromTedO,
+            RecoverSignatureMessage::TedP(_) => constants::MessageId::RecoverSigFromTedP,
+        };
+        out.reserve(1 + 64);
+        out.push(message_id as u8);
+        out.extend_from_slice(self.signature().as_ref());
+    }
+
+    pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, CompactSignaturesDeserError> {
+        let message_id = *bytes.first().ok_or(TedXSignaturesDeserErrorInner::UnexpectedEnd)?;
+        let variant = if message_id == constants::MessageId::RecoverSigFromTedO as u8 {
+            RecoverSignatureMessage::TedO
+        } else if message_id == constants::MessageId::RecoverSigFromTedP as u8 {
+            RecoverSignatureMessage::TedP
+        } else {
+            return Err(TedXSignaturesDeserErrorInner::InvalidMessage(message_id).into());
+        };
+        if bytes.len() < 1 + 64 {
+            return Err(TedXSignaturesDeserErrorInner::UnexpectedEnd.into());
+        }
+        *bytes = &bytes[1..];
+        let signature = deserialize::signature(bytes)
+            .map_err(TedXSignaturesDeserErrorInner::Secp256k1)?;
+        Ok(variant(signature))
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for RecoverSignatureMessage {
+    fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
+        if bool::arbitrary(gen) {
+            RecoverSignatureMessage::TedO(crate::test_macros::arbitrary(gen))
+        } else {
+            RecoverSignatureMessage::TedP(crate::test_macros::arbitrary(gen))
+        }
+    }
+}
+
+/// The signatures TED-P needs from TED-O to finalize the repayment or the default transaction.
+///
+/// This is the compact alternative to sending the whole [`TedOSignatures`] to TED-P.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TedOTerminalSignatures {
+    pub repayment: Signature,
+    pub default: Signature,
+}
+
+crate::test_macros::impl_arbitrary!(TedOTerminalSignatures, repayment, default);
+
+impl TedOTerminalSignatures {
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.reserve(1 + 2 * 64);
+        out.push(constants::MessageId::TerminalSigsFromTedO as u8);
+        out.extend_from_slice(self.repayment.as_ref());
+        out.extend_from_slice(self.default.as_ref());
+    }
+
+    pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, CompactSignaturesDeserError> {
+        if bytes.len() < 1 + 2 * 64 {
+            return Err(TedXSignaturesDeserErrorInner::UnexpectedEnd.into());
+        }
+        if bytes[0] != constants::MessageId::TerminalSigsFromTedO as u8 {
+            return Err(TedXSignaturesDeserErrorInner::InvalidMessage(bytes[0]).into());
+        }
+        *bytes = &bytes[1..];
+        let repayment = deserialize::signature(bytes)
+            .map_err(TedXSignaturesDeserErrorInner::Secp256k1)?;
+        let default = deserialize::signature(bytes)
+            .map_err(TedXSignaturesDeserErrorInner::Secp256k1)?;
+        Ok(TedOTerminalSignatures { repayment, default })
+    }
+}
+
+impl TedOSignatures {
+    /// Returns the compact message containing only the recover signature.
+    pub fn recover_message(&self) -> RecoverSignatureMessage {
+        RecoverSignatureMessage::TedO(self.recover)
+    }
+
+    /// Returns the compact message containing only the signatures needed by TED-P.
+    pub fn terminal_message(&self) -> TedOTerminalSignatures {
+        TedOTerminalSignatures {
+            repayment: self.repayment,
+            default: self.default,
+        }
+    }
+}
+
+impl TedPSignatures {
+    /// Returns the compact message containing only the recover signature.
+    pub fn recover_message(&self) -> RecoverSignatureMessage {
+        RecoverSignatureMessage::TedP(self.recover)
+    }
+}
+
+/// Returned when deserializing [`RecoverSignatureMessage`] or [`TedOTerminalSignatures`] fails.
+#[derive(Debug)]
+pub struct CompactSignaturesDeserError(TedXSignaturesDeserErrorInner);
+
+impl From<TedXSignaturesDeserErrorInner> for CompactSignaturesDeserError {
+    fn from(error: TedXSignaturesDeserErrorInner) -> Self {
+        CompactSignaturesDeserError(error)
+    }
+}
+
 #[derive(Debug)]
 pub struct BorrowerSignaturesDeserError(BorrowerSignaturesDeserErrorInner);
 
@@ -1537,11 +2198,122 @@ impl From<secp256k1::Error> for BorrowerSignaturesDeserErrorInner {
 pub enum BorrowerInfoError {
     ContractPositionOob,
     Undercollateralized,
+    /// The declared input values don't cover the outputs of the escrow transaction.
+    InputsInsufficient,
+}
+
+/// Constructs the transactions of the contract from the offer and the borrower's information.
+pub(crate) fn unsigned_transactions(params: &offer::EscrowParams, keys: EscrowKeys, borrower_info: BorrowerInfo<validation::Validated>) -> UnsignedTransactions {
+    let keys = keys.add_borrower_eph(borrower_info.escrow_eph_key);
+    let (escrow_out_script, multisig_leaf_hash, _) = output_script(&keys);
+
+    let escrow_txout = TxOut {
+        value: borrower_info.escrow_amount,
+        script_pubkey: escrow_out_script,
+    };
+    let escrow_output_index = borrower_info.escrow_contract_output_position as usize;
+    let mut escrow_txouts = borrower_info.escrow_extra_outputs;
+    escrow_txouts.insert(escrow_output_index, escrow_txout);
+    let (escrow_prevouts, escrow_txins) = borrower_info.inputs
+        .into_iter()
+        .map(SpendableTxo::unpack_with_empty_sig)
+        .unzip();
+    let escrow_tx = Transaction {
+        // Enable relative time locks
+        version: TX_VERSION,
+        input: escrow_txins,
+        output: escrow_txouts,
+        lock_time: LockTime::from(borrower_info.tx_height).into(),
+    };
+    let escrow_txid = escrow_tx.compute_txid();
+    let escrow_out_point = OutPoint {
+        txid: escrow_txid,
+        vout: borrower_info.escrow_contract_output_position,
+    };
+    let escrow_non_recover_txin = TxIn {
+        previous_output: escrow_out_point,
+        script_sig: ScriptBuf::new(),
+        // Since non-recover transactions don't use lock time in the contract and we can't
+        // predict when they will be broadcasted setting same height as the previous
+        // transaction would create an identifiable footprint. There are still wallets that
+        // don't implement anti-fee-sniping policy so it's better to hide among them rather
+        // than implement broken anti-fee-sniping. And if we don't use lock time anyway we
+        // should just disable it.
+        sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+        witness: Witness::new(),
+    };
+    let escrow_non_recover_txins = vec![escrow_non_recover_txin];
+    let liquidator_output_default = TxOut {
+        script_pubkey: params.liquidator_script_default.clone(),
+        value: borrower_info.collateral_amount_default,
+    };
+    let liquidator_output_liquidation = TxOut {
+        script_pubkey: params.liquidator_script_liquidation.clone(),
+        value: borrower_info.collateral_amount_liquidation,
+    };
+    fn vec_with_item_inserted<T: Clone>(base: &[T], inserted: T, index: usize) -> Vec<T> {
+        let mut result = Vec::with_capacity(base.len() + 1);
+        let mut iter = base.iter().cloned();
+        result.extend(iter.by_ref().take(index));
+        result.push(inserted);
+        result.extend(iter);
+        result
+    }
+    let termination_outputs_default = vec_with_item_inserted(&params.extra_termination_outputs, liquidator_output_default, params.liquidator_output_index);
+    let termination_outputs_liquidation = vec_with_item_inserted(&params.extra_termination_outputs, liquidator_output_liquidation, params.liquidator_output_index);
+
+    let repayment_tx = Transaction {
+        // Enable relative time locks
+        version: TX_VERSION,
+        input: escrow_non_recover_txins.clone(),
+        output: borrower_info.repayment_outputs,
+        lock_time: LockTime::ZERO,
+    };
+    let default_tx = Transaction {
+        // Enable relative time locks
+        version: TX_VERSION,
+        input: escrow_non_recover_txins.clone(),
+        output: termination_outputs_default,
+        lock_time: params.default_lock_time,
+    };
+    let liquidation_tx = Transaction {
+        // Enable relative time locks
+        version: TX_VERSION,
+        input: escrow_non_recover_txins,
+        output: termination_outputs_liquidation,
+        lock_time: LockTime::ZERO,
+    };
+    let escrow_recover_txin = TxIn {
+        previous_output: escrow_out_point,
+        script_sig: ScriptBuf::new(),
+        // Enable both RBF and lock time
+        sequence: Sequence::ZERO,
+        witness: Witness::new(),
+    };
+    let escrow_recover_txins = vec![escrow_recover_txin];
+    let recover_tx = Transaction {
+        version: TX_VERSION,
+        input: escrow_recover_txins,
+        output: borrower_info.recover_outputs,
+        lock_time: params.recover_lock_time.into(),
+    };
+
+    UnsignedTransactions {
+        borrower_eph: borrower_info.escrow_eph_key,
+        multisig_leaf_hash,
+        contract_index: borrower_info.escrow_contract_output_position,
+        escrow_prevouts,
+        escrow: escrow_tx,
+        repayment: repayment_tx,
+        default: default_tx,
+        liquidation: liquidation_tx,
+        recover: recover_tx,
+    }
 }
 
 pub(crate) fn output_spend_info(keys: &PubKeys<context::Escrow>) -> (TaprootSpendInfo, TapLeafHash) {
     let multisig_script = keys.generate_multisig_script();
-    let multisig_leaf_hash = multisig_script.tapscript_leaf_hash();
+    let multisig_leaf_hash = pub_keys::leaf_hash(&multisig_script);
     // If there's a single leaf it's also the root
     // see https://github.com/rust-bitcoin/rust-bitcoin/issues/1393
     let root = TapNodeHash::from(multisig_leaf_hash);
@@ -1551,6 +2323,17 @@ pub(crate) fn output_spend_info(keys: &PubKeys<context::Escrow>) -> (TaprootSpen
     (spend_info, multisig_leaf_hash)
 }
 
+/// Computes the spend info including the script map, so that control blocks can be obtained.
+pub(crate) fn output_spend_info_with_scripts(keys: &PubKeys<context::Escrow>) -> TaprootSpendInfo {
+    let multisig_script = keys.generate_multisig_script();
+    let internal_key = keys.generate_internal_key();
+    bitcoin::taproot::TaprootBuilder::new()
+        .add_leaf_with_ver(0, multisig_script, pub_keys::LEAF_VERSION)
+        .expect("depth 0 is valid for a single leaf")
+        .finalize(secp256k1::SECP256K1, internal_key)
+        .expect("the tree with a single leaf is complete")
+}
+
 pub(crate) fn output_script(keys: &PubKeys<context::Escrow>) -> (ScriptBuf, TapLeafHash, secp256k1::Parity) {
     let (spend_info, multisig_leaf_hash) = output_spend_info(keys);
 
@@ -1670,8 +2453,533 @@ mod tests {
     crate::test_macros::check_roundtrip_with_version!(roundtrip_receiving_borrower_info, ReceivingBorrowerInfo<participant::Borrower>);
     crate::test_macros::check_roundtrip_with_version!(roundtrip_waiting_for_escrow_confirmation, WaitingForEscrowConfirmation<participant::Borrower>);
     crate::test_macros::check_roundtrip_with_version!(roundtrip_receiving_escrow_signature, ReceivingEscrowSignature<participant::Borrower>);
+    crate::test_macros::check_roundtrip_with_version!(roundtrip_escrow_signed, EscrowSigned<participant::Borrower>);
     crate::test_macros::check_roundtrip!(roundtrip_borrower_info, BorrowerInfo<validation::Unvalidated>);
     crate::test_macros::check_roundtrip!(roundtrip_borrower_signatures, BorrowerSignatures);
     crate::test_macros::check_roundtrip!(roundtrip_ted_o_signatures, TedOSignatures);
     crate::test_macros::check_roundtrip!(roundtrip_ted_p_signatures, TedPSignatures);
+    crate::test_macros::check_roundtrip!(roundtrip_recover_signature_message, RecoverSignatureMessage);
+    crate::test_macros::check_roundtrip!(roundtrip_ted_o_terminal_signatures, TedOTerminalSignatures);
+
+    #[derive(Debug, Clone)]
+    struct UnsignedTransactionsWithKeys {
+        keys: EscrowKeys,
+        transactions: UnsignedTransactions,
+    }
+
+    impl quickcheck::Arbitrary for UnsignedTransactionsWithKeys {
+        fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
+            let keys = EscrowKeys::arbitrary(gen);
+            let transactions = UnsignedTransactions::arbitrary(gen, keys);
+            UnsignedTransactionsWithKeys { keys, transactions }
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_psbt_annotates_prefund_inputs(val: UnsignedTransactionsWithKeys, prefund: super::super::prefund::Prefund<participant::TedO>) -> bool {
+            let mut txes = val.transactions;
+            for input in &mut txes.escrow.input {
+                input.script_sig = ScriptBuf::new();
+                input.witness.clear();
+            }
+            for prevout in &mut txes.escrow_prevouts {
+                prevout.script_pubkey = prefund.funding_script();
+            }
+            let psbt = txes.escrow_psbt(&prefund, &BTreeMap::new());
+            let output_key = XOnlyPublicKey::from(prefund.output_key);
+            psbt.inputs.iter().zip(&txes.escrow_prevouts).all(|(input, prevout)| {
+                input.witness_utxo.as_ref() == Some(prevout) &&
+                    input.tap_scripts.len() == 1 &&
+                    input.tap_scripts.iter().all(|(control_block, (script, _))| control_block.verify_taproot_commitment(secp256k1::SECP256K1, output_key, script))
+            })
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_fee_rate_from_prevouts(state: EscrowSigned<participant::Borrower>, fee: u32) -> bool {
+            let mut state = state;
+            for txout in &mut state.tx_escrow.output {
+                txout.value = bitcoin::Amount::from_sat(1_000);
+            }
+            if state.tx_escrow.input.is_empty() {
+                state.tx_escrow.input.push(TxIn::default());
+            }
+            let output_sum = bitcoin::Amount::from_sat(1_000) * state.tx_escrow.output.len() as u64;
+            let fee = bitcoin::Amount::from_sat(fee.into());
+            state.escrow_prevouts = state.tx_escrow.input.iter()
+                .map(|_| TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() })
+                .collect();
+            state.escrow_prevouts[0].value = output_sum + fee;
+            let correct = state.escrow_fee_rate() == Some(fee / state.tx_escrow.weight());
+            state.escrow_prevouts.pop();
+            correct && state.escrow_fee_rate().is_none()
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    quickcheck::quickcheck! {
+        fn review_json_lists_transactions(val: UnsignedTransactionsWithKeys) -> bool {
+            let mut txes = val.transactions;
+            // the arbitrary transactions don't uphold the invariant
+            let input_count = txes.escrow.input.len().min(txes.escrow_prevouts.len());
+            txes.escrow.input.truncate(input_count);
+            txes.escrow_prevouts.truncate(input_count);
+            let json = txes.to_review_json(bitcoin::Network::Regtest);
+            let escrow = &json["escrow"];
+            let input_values = escrow["inputs"].as_array().unwrap().iter()
+                .map(|input| input["value"].as_u64().unwrap())
+                .collect::<Vec<_>>();
+            let prevout_values = txes.escrow_prevouts.iter().map(|txo| txo.value.to_sat()).collect::<Vec<_>>();
+            input_values == prevout_values &&
+                escrow["outputs"].as_array().unwrap().len() == txes.escrow.output.len() &&
+                escrow["contract_index"] == txes.contract_index &&
+                json["recover"]["lock_time"] == txes.recover.lock_time.to_consensus_u32() &&
+                json["liquidation"]["outputs"].as_array().unwrap().len() == txes.liquidation.output.len()
+        }
+    }
+
+    #[test]
+    fn borrower_info_golden_vector() {
+        use bitcoin::hashes::Hash;
+        use bitcoin::hex::FromHex;
+
+        let key = XOnlyPublicKey::from_slice(&Vec::from_hex("79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap()).unwrap();
+        let p2wpkh = |byte| ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::from_byte_array([byte; 20]));
+        let info = BorrowerInfo::<validation::Unvalidated> {
+            escrow_eph_key: PubKey::new(key),
+            inputs: vec![SpendableTxo {
+                out_point: OutPoint { txid: bitcoin::Txid::from_byte_array([0x11; 32]), vout: 2 },
+                tx_out: TxOut {
+                    value: bitcoin::Amount::from_sat(1_100_000),
+                    script_pubkey: ScriptBuf::new_p2tr_tweaked(bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(key)),
+                },
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            }],
+            tx_height: Height::from_consensus(800_000).unwrap(),
+            escrow_extra_outputs: vec![TxOut { value: bitcoin::Amount::from_sat(330), script_pubkey: p2wpkh(0x22) }],
+            escrow_contract_output_position: 1,
+            escrow_amount: bitcoin::Amount::from_sat(1_000_000),
+            collateral_amount_default: bitcoin::Amount::from_sat(900_000),
+            collateral_amount_liquidation: bitcoin::Amount::from_sat(950_000),
+            repayment_outputs: vec![TxOut { value: bitcoin::Amount::from_sat(990_000), script_pubkey: p2wpkh(0x33) }],
+            recover_outputs: Vec::new(),
+            _phantom: Default::default(),
+        };
+        let expected = concat!(
+            "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179800350c000000000140420f00000000",
+            "00a0bb0d0000000000f07e0e000000000000000001111111111111111111111111111111111111111111111111111111",
+            "111111111102000000e0c810000000000022512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b",
+            "16f81798fdffffff000000014a0100000000000016001422222222222222222222222222222222222222220000000130",
+            "1b0f0000000000160014333333333333333333333333333333333333333300000000",
+        );
+        let mut bytes = Vec::new();
+        info.serialize(&mut bytes);
+        assert_eq!(bytes, Vec::from_hex(expected).unwrap());
+    }
+
+    #[test]
+    fn max_counts_match_min_sizes() {
+        let min_txin_size = bitcoin::consensus::serialize(&TxIn::default()).len() as u32;
+        let min_txout_size = bitcoin::consensus::serialize(&TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() }).len() as u32;
+        assert_eq!(MAX_INPUT_COUNT, 4_000_000 / min_txin_size);
+        assert_eq!(MAX_OUTPUT_COUNT, 4_000_000 / min_txout_size);
+    }
+
+    quickcheck::quickcheck! {
+        fn external_borrower_signatures_verify(val: UnsignedTransactionsWithKeys) -> bool {
+            let mut txes = val.transactions;
+            txes.contract_index = 0;
+            if txes.escrow.output.is_empty() {
+                txes.escrow.output.push(TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() });
+            }
+            for tx in [&mut txes.repayment, &mut txes.default, &mut txes.liquidation, &mut txes.recover] {
+                tx.input.truncate(1);
+                if tx.input.is_empty() {
+                    tx.input.push(TxIn::default());
+                }
+            }
+            let key_pair = Keypair::new_global(&mut rand::thread_rng());
+            let requests = txes.borrower_signing_requests();
+            let sign = |label: &str| {
+                let (_, message) = requests.iter().find(|(request_label, _)| *request_label == label).unwrap();
+                secp256k1::SECP256K1.sign_schnorr(message, &key_pair)
+            };
+            let signatures = BorrowerSignatures {
+                recover: sign("recover"),
+                repayment: sign("repayment"),
+                default: sign("default"),
+                liquidation: sign("liquidation"),
+            };
+            txes.verify_borrower_external(&key_pair.x_only_public_key().0, &signatures).is_ok()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_balance_checked(val: UnsignedTransactionsWithKeys, fee_rate: u16) -> bool {
+            let mut txes = val.transactions;
+            if txes.escrow.input.is_empty() {
+                return true;
+            }
+            let fee_rate = bitcoin::FeeRate::from_sat_per_kwu(u64::from(fee_rate) + 250);
+            for input in &mut txes.escrow.input {
+                input.script_sig = ScriptBuf::new();
+            }
+            txes.escrow.output.push(TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() });
+            for txout in &mut txes.escrow.output {
+                txout.value = bitcoin::Amount::from_sat(10_000);
+            }
+            let output_sum = txes.escrow.output.iter().map(|txout| txout.value).sum::<bitcoin::Amount>();
+            // Three signatures, the multisig script and the control block with one hash.
+            let mut signed = txes.escrow.clone();
+            for input in &mut signed.input {
+                input.witness = Witness::from_slice(&[vec![0u8; 64], vec![0; 64], vec![0; 64], vec![0; 102], vec![0; 65]]);
+            }
+            let fee = signed.weight() * fee_rate;
+            txes.escrow_prevouts = vec![TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() }; txes.escrow.input.len()];
+
+            let mut with_input_sum = |sum: bitcoin::Amount| {
+                txes.escrow_prevouts[0].value = sum;
+                txes.verify_escrow_balance(fee_rate)
+            };
+            with_input_sum(output_sum + fee).is_ok() &&
+                matches!(with_input_sum(output_sum - bitcoin::Amount::from_sat(1)), Err(BalanceError::OutputsExceedInputs { .. })) &&
+                matches!(with_input_sum(output_sum + fee / 2), Err(BalanceError::FeeTooLow { .. })) &&
+                matches!(with_input_sum(output_sum + fee * 2), Err(BalanceError::FeeTooHigh { .. }))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn broadcast_request_verification(val: UnsignedTransactionsWithKeys, prefund: super::super::prefund::Prefund<participant::TedO>) -> bool {
+            let mut txes = val.transactions;
+            let funding_txout = TxOut { value: bitcoin::Amount::ZERO, script_pubkey: prefund.funding_script() };
+            txes.escrow_prevouts = vec![funding_txout; txes.escrow.input.len()];
+            let key_pair = Keypair::new_global(&mut rand::thread_rng());
+            let key = key_pair.x_only_public_key().0;
+            let signatures = txes.escrow_signing_data(&prefund)
+                .map(|(_, message)| secp256k1::SECP256K1.sign_schnorr(&message, &key_pair))
+                .collect::<Vec<_>>();
+            let input_count = signatures.len();
+            let mut request = BroadcastRequest { signatures };
+            let valid = txes.verify_broadcast_request(&request, &key, &prefund).is_ok();
+            let extra = secp256k1::SECP256K1.sign_schnorr(&secp256k1::Message::from_digest([42; 32]), &key_pair);
+            request.signatures.push(extra);
+            let too_many = txes.verify_broadcast_request(&request, &key, &prefund);
+            request.signatures.truncate(input_count.saturating_sub(1));
+            let missing = txes.verify_broadcast_request(&request, &key, &prefund);
+            valid &&
+                matches!(too_many, Err(SignatureVerificationError::SignatureCountMismatch { expected, got }) if expected == input_count && got == input_count + 1) &&
+                (input_count == 0 || matches!(missing, Err(SignatureVerificationError::SignatureCountMismatch { expected, got }) if expected == input_count && got == input_count - 1))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_signature_count_checked(val: UnsignedTransactionsWithKeys) -> bool {
+            let txes = val.transactions;
+            let expected = txes.escrow_input_count();
+            txes.check_escrow_signature_count(expected).is_ok() &&
+                matches!(txes.check_escrow_signature_count(expected + 1), Err(SignatureVerificationError::SignatureCountMismatch { expected: e, got }) if e == expected && got == expected + 1)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn max_finalization_fee_rate_keeps_outputs_above_dust(val: UnsignedTransactionsWithKeys) -> bool {
+            let mut txes = val.transactions;
+            txes.contract_index = 0;
+            let escrow_amount = bitcoin::Amount::from_sat(1_000_000);
+            txes.escrow.output = vec![TxOut { value: escrow_amount, script_pubkey: ScriptBuf::new() }];
+            for tx in [&mut txes.repayment, &mut txes.default, &mut txes.liquidation, &mut txes.recover] {
+                tx.input = vec![TxIn::default()];
+                for txout in &mut tx.output {
+                    txout.value = txout.script_pubkey.minimal_non_dust() + bitcoin::Amount::from_sat(txout.value.to_sat() % 1000);
+                }
+            }
+            let keys = val.keys.add_borrower_eph(txes.borrower_eph);
+            let signature = Signature::from_slice(&[42; 64]).unwrap();
+
+            // Builds the transaction paying the fee from the largest output and checks for dust.
+            let above_dust = |tx: &Transaction, fee_rate: bitcoin::FeeRate| {
+                let mut tx = tx.clone();
+                let largest = match (0..tx.output.len()).max_by_key(|i| tx.output[*i].value) {
+                    Some(largest) => largest,
+                    None => return true,
+                };
+                finalize(&mut tx, &keys, &signature, &signature, &signature);
+                let others = tx.output.iter().map(|txout| txout.value).sum::<bitcoin::Amount>() - tx.output[largest].value;
+                let fee = tx.weight() * fee_rate;
+                match escrow_amount.checked_sub(others).and_then(|amount| amount.checked_sub(fee)) {
+                    Some(value) => tx.output[largest].value = value,
+                    None => return false,
+                }
+                tx.output.iter().all(|txout| txout.value >= txout.script_pubkey.minimal_non_dust())
+            };
+
+            let fee_rate = txes.max_finalization_fee_rate();
+            let higher = bitcoin::FeeRate::from_sat_per_kwu(fee_rate.to_sat_per_kwu().saturating_add(1));
+            let terminal = [&txes.repayment, &txes.default, &txes.liquidation, &txes.recover];
+            let all_outputs_empty = terminal.iter().all(|tx| tx.output.is_empty());
+            let valid = fee_rate == bitcoin::FeeRate::ZERO || terminal.iter().all(|tx| above_dust(tx, fee_rate));
+            let tight = all_outputs_empty || terminal.iter().any(|tx| !above_dust(tx, higher));
+
+            let mut overflowing = txes.clone();
+            overflowing.repayment.output = vec![TxOut { value: bitcoin::Amount::from_sat(u64::MAX), script_pubkey: ScriptBuf::new() }; 2];
+            valid && tight && overflowing.max_finalization_fee_rate() == bitcoin::FeeRate::ZERO
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn ted_o_liquidation_signature_verification(state: WaitingForEscrowConfirmation<participant::TedP>) -> bool {
+            let mut state = state;
+            let txes = &mut state.unsigned_txes;
+            txes.contract_index = 0;
+            txes.escrow.output = vec![TxOut { value: bitcoin::Amount::ZERO, script_pubkey: ScriptBuf::new() }];
+            txes.liquidation.input = vec![TxIn::default()];
+            let key_pair = Keypair::new_global(&mut rand::thread_rng());
+            state.keys.ted_o = PubKey::new(key_pair.x_only_public_key().0);
+            let message = state.unsigned_txes.liquidation_signing_data();
+            let valid = secp256k1::SECP256K1.sign_schnorr(&message, &key_pair);
+            let other_key = Keypair::new_global(&mut rand::thread_rng());
+            let invalid = secp256k1::SECP256K1.sign_schnorr(&message, &other_key);
+            state.verify_ted_o_liquidation(&valid).is_ok() && state.verify_ted_o_liquidation(&invalid).is_err()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn debug_summarizes_transactions(val: UnsignedTransactionsWithKeys) -> bool {
+            let txes = val.transactions;
+            let debug = format!("{:?}", txes);
+            [&txes.escrow, &txes.repayment, &txes.default, &txes.liquidation, &txes.recover]
+                .iter()
+                .all(|tx| debug.contains(&tx.compute_txid().to_string()))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn roundtrip_unsigned_transactions(val: UnsignedTransactionsWithKeys) -> bool {
+            let mut bytes = Vec::new();
+            val.transactions.serialize(&mut bytes);
+            let mut bytes = &*bytes;
+            let deserialized = UnsignedTransactions::deserialize(&mut bytes, val.keys).unwrap();
+            // PartialEq is derived so this includes `multisig_leaf_hash` recomputed from the keys
+            bytes.is_empty() && deserialized == val.transactions
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn total_weight_covers_unsigned_weights(val: UnsignedTransactionsWithKeys) -> bool {
+            let mut txes = val.transactions;
+            for tx in [&mut txes.escrow, &mut txes.repayment, &mut txes.default, &mut txes.liquidation, &mut txes.recover] {
+                // real contract transactions always have inputs and zero-input transactions are
+                // serialized in the segwit format making the comparison meaningless
+                if tx.input.is_empty() {
+                    tx.input.push(TxIn::default());
+                }
+                for input in &mut tx.input {
+                    input.script_sig = ScriptBuf::new();
+                    input.witness.clear();
+                }
+            }
+            let max_terminal = [&txes.repayment, &txes.default, &txes.liquidation, &txes.recover]
+                .iter()
+                .map(|tx| tx.weight())
+                .max()
+                .unwrap();
+            txes.total_weight() >= txes.escrow.weight() + max_terminal
+        }
+    }
+
+    /// Fixes up the output position and marks arbitrary info as validated.
+    fn assume_valid(info: BorrowerInfo<validation::Unvalidated>) -> BorrowerInfo<validation::Validated> {
+        let position = info.escrow_contract_output_position as usize % (info.escrow_extra_outputs.len() + 1);
+        BorrowerInfo {
+            escrow_eph_key: info.escrow_eph_key,
+            inputs: info.inputs,
+            tx_height: info.tx_height,
+            escrow_extra_outputs: info.escrow_extra_outputs,
+            escrow_contract_output_position: position as u32,
+            escrow_amount: info.escrow_amount,
+            collateral_amount_default: info.collateral_amount_default,
+            collateral_amount_liquidation: info.collateral_amount_liquidation,
+            repayment_outputs: info.repayment_outputs,
+            recover_outputs: info.recover_outputs,
+            _phantom: Default::default(),
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn verify_borrower_reports_key_mismatch(state: ReceivingBorrowerInfo<participant::Borrower>, info: BorrowerInfo<validation::Unvalidated>) -> bool {
+            let declared = Keypair::new_global(&mut rand::thread_rng());
+            let other = Keypair::new_global(&mut rand::thread_rng());
+            let mut info = assume_valid(info);
+            info.escrow_eph_key = PubKey::from_key_pair(&declared);
+            let txes = state.borrower_info(info);
+            let sign = |key_pair: &Keypair| BorrowerSignatures {
+                recover: secp256k1::SECP256K1.sign_schnorr(&txes.recover_signing_data(), key_pair),
+                repayment: secp256k1::SECP256K1.sign_schnorr(&txes.repayment_signing_data(), key_pair),
+                default: secp256k1::SECP256K1.sign_schnorr(&txes.default_signing_data(), key_pair),
+                liquidation: secp256k1::SECP256K1.sign_schnorr(&txes.liquidation_signing_data(), key_pair),
+            };
+            txes.verify_borrower(&sign(&declared)).is_ok() &&
+                matches!(txes.verify_borrower(&sign(&other)), Err(BorrowerSignatureError::BorrowerKeyMismatch))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn validate_rejects_insufficient_inputs(params: offer::EscrowParams, info: BorrowerInfo<validation::Unvalidated>) -> bool {
+            let mut info = BorrowerInfo {
+                escrow_contract_output_position: 0,
+                collateral_amount_default: params.min_collateral,
+                collateral_amount_liquidation: params.min_collateral,
+                ..info
+            };
+            for input in &mut info.inputs {
+                input.tx_out.value = bitcoin::Amount::from_sat(input.tx_out.value.to_sat() % bitcoin::Amount::MAX_MONEY.to_sat());
+            }
+            let input_sum = info.inputs.iter().map(|input| input.tx_out.value.to_sat()).sum::<u64>();
+            info.escrow_extra_outputs.clear();
+            info.escrow_amount = bitcoin::Amount::from_sat(input_sum + 1);
+            matches!(info.clone().validate(&params), Err(BorrowerInfoError::InputsInsufficient)) && {
+                info.escrow_amount = bitcoin::Amount::from_sat(input_sum);
+                info.validate(&params).is_ok()
+            }
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn liquidator_amounts_match_collateral(state: ReceivingEscrowSignature<participant::Borrower>, info: BorrowerInfo<validation::Unvalidated>) -> bool {
+            let mut state = state;
+            let mut info = assume_valid(info);
+            info.collateral_amount_default = bitcoin::Amount::from_sat(info.collateral_amount_default.to_sat() % bitcoin::Amount::MAX_MONEY.to_sat());
+            info.collateral_amount_liquidation = bitcoin::Amount::from_sat(info.collateral_amount_liquidation.to_sat() % bitcoin::Amount::MAX_MONEY.to_sat());
+            let expected = info.collateral_amount_liquidation.to_signed().unwrap() - info.collateral_amount_default.to_signed().unwrap();
+            let max = info.collateral_amount_default.max(info.collateral_amount_liquidation);
+            state.unsigned_txes = unsigned_transactions(&state.params, state.keys, info);
+            state.collateral_spread() == expected && state.max_liquidator_amount() == max
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_output_key_verified(state: ReceivingEscrowSignature<participant::Borrower>, info: BorrowerInfo<validation::Unvalidated>, other_key: PubKey<participant::Borrower, context::Escrow>, ted_o_signatures: TedOSignatures, ted_p_signatures: TedPSignatures) -> bool {
+            let mut state = state;
+            let info = assume_valid(info);
+            if other_key == info.escrow_eph_key {
+                return true;
+            }
+            let other_info = BorrowerInfo {
+                escrow_eph_key: other_key,
+                ..info.clone()
+            };
+            let other_script = unsigned_transactions(&state.params, state.keys, other_info).escrow_output().script_pubkey.clone();
+            state.unsigned_txes = unsigned_transactions(&state.params, state.keys, info);
+            let mut verified = SignaturesVerified { ted_o_signatures, ted_p_signatures, state };
+            let valid = verified.verify_escrow_output_key();
+            let contract_index = verified.state.unsigned_txes.contract_index as usize;
+            verified.state.unsigned_txes.escrow.output[contract_index].script_pubkey = other_script;
+            valid && !verified.verify_escrow_output_key()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn all_spent_outpoints_cover_funding_and_escrow(state: ReceivingEscrowSignature<participant::Borrower>, info: BorrowerInfo<validation::Unvalidated>, ted_o_signatures: TedOSignatures, ted_p_signatures: TedPSignatures) -> bool {
+            let mut state = state;
+            let mut info = assume_valid(info);
+            let mut seen = std::collections::HashSet::new();
+            info.inputs.retain(|input| seen.insert(input.out_point));
+            let funding_outpoints = info.inputs.iter().map(|input| input.out_point).collect::<Vec<_>>();
+            let escrow_position = info.escrow_contract_output_position;
+            state.unsigned_txes = unsigned_transactions(&state.params, state.keys, info);
+            let escrow_outpoint = OutPoint { txid: state.unsigned_txes.escrow.compute_txid(), vout: escrow_position };
+            let verified = SignaturesVerified { ted_o_signatures, ted_p_signatures, state };
+
+            let outpoints = verified.all_spent_outpoints();
+            let unique = outpoints.iter().collect::<std::collections::HashSet<_>>();
+            let mut expected = funding_outpoints;
+            expected.push(escrow_outpoint);
+            outpoints == expected && unique.len() == outpoints.len()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn matches_onchain_escrow(state: WaitingForEscrowConfirmation<participant::TedO>) -> bool {
+            let mut confirmed = state.unsigned_txes.escrow.clone();
+            // witnesses don't affect txid
+            for input in &mut confirmed.input {
+                input.witness.push([42]);
+            }
+            let mut other = confirmed.clone();
+            other.lock_time = LockTime::from_consensus(other.lock_time.to_consensus_u32() ^ 1);
+            state.matches_onchain(&confirmed) && !state.matches_onchain(&other)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn borrower_info_rebuilds_transactions(state: ReceivingBorrowerInfo<participant::Borrower>, info: BorrowerInfo<validation::Unvalidated>) -> bool {
+            let transactions = state.borrower_info(assume_valid(info));
+            state.borrower_info(transactions.borrower_info(&state.params)) == transactions
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_spend_info_matches_output(keys: PubKeys<context::Escrow>) -> bool {
+            let (spend_info, leaf_hash) = output_spend_info(&keys);
+            let full_spend_info = output_spend_info_with_scripts(&keys);
+            let script = (keys.generate_multisig_script(), pub_keys::LEAF_VERSION);
+            full_spend_info.output_key() == spend_info.output_key()
+                && full_spend_info.merkle_root() == Some(TapNodeHash::from(leaf_hash))
+                && full_spend_info.control_block(&script).is_some()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn finalized_witness_commits_to_output(keys: PubKeys<context::Escrow>) -> bool {
+            use bitcoin::taproot::ControlBlock;
+
+            let mut tx = Transaction {
+                version: TX_VERSION,
+                input: vec![TxIn::default()],
+                output: Vec::new(),
+                lock_time: LockTime::ZERO,
+            };
+            let signature = Signature::from_slice(&[42; 64]).unwrap();
+            finalize(&mut tx, &keys, &signature, &signature, &signature);
+            let witness = &tx.input[0].witness;
+            let control_block = ControlBlock::decode(witness.last().unwrap()).unwrap();
+            let script = keys.generate_multisig_script();
+            let (spend_info, _) = output_spend_info(&keys);
+            let output_key = XOnlyPublicKey::from(spend_info.output_key());
+            control_block.leaf_version == pub_keys::LEAF_VERSION
+                && control_block.verify_taproot_commitment(secp256k1::SECP256K1, output_key, &script)
+        }
+    }
+
+    #[test]
+    fn recover_broadcast_boundaries() {
+        use bitcoin::absolute::Time;
+
+        let height = |h| Height::from_consensus(h).unwrap();
+        let time = |t| Time::from_consensus(t).unwrap();
+        let lock_height = LockTime::from_consensus(800_000);
+        let lock_time = LockTime::from_consensus(1_700_000_000);
+
+        assert!(!can_broadcast(lock_height, height(799_999), time(1_700_000_000)));
+        assert!(can_broadcast(lock_height, height(800_000), time(500_000_000)));
+        assert!(!can_broadcast(lock_time, height(900_000), time(1_700_000_000)));
+        assert!(can_broadcast(lock_time, height(0), time(1_700_000_001)));
+    }
+
+    #[test]
+    fn lock_status_countdown() {
+        use bitcoin::absolute::Time;
+
+        let height = |h| Height::from_consensus(h).unwrap();
+        let time = |t| Time::from_consensus(t).unwrap();
+        let lock_height = LockTime::from_consensus(800_000);
+        let lock_time = LockTime::from_consensus(1_700_000_000);
+
+        assert_eq!(lock_status(lock_height, height(799_990)), LockStatus::MaturesInBlocks(10));
+        assert_eq!(lock_status(lock_height, height(800_000)), LockStatus::Available);
+        assert_eq!(lock_status(lock_time, height(800_000)), LockStatus::DifferentUnit);
+        assert_eq!(lock_status_at_time(lock_time, time(1_699_999_990)), LockStatus::MaturesInSeconds(11));
+        assert_eq!(lock_status_at_time(lock_time, time(1_700_000_001)), LockStatus::Available);
+        assert_eq!(lock_status_at_time(lock_height, time(1_700_000_001)), LockStatus::DifferentUnit);
+    }
 }
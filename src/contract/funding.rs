@@ -0,0 +1,56 @@
+//! Helpers for tracking the confirmation of funding transactions.
+//!
+//! The contract doesn't access the chain so the confirmation heights have to be supplied by the
+//! caller.
+
+use bitcoin::OutPoint;
+
+/// The number of confirmations of funding transactions.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ConfirmationStatus {
+    /// The funding is not in a block (or the block is not in the active chain).
+    Unconfirmed,
+    /// The funding is in a block but it doesn't have enough confirmations yet.
+    Confirming { have: u32, need: u32 },
+    /// The funding has at least the required number of confirmations.
+    Confirmed,
+}
+
+/// Computes the confirmation status of funding outpoints.
+///
+/// `confirmed_at_height` is the height of the block confirming the last of `funding_outpoints`,
+/// `None` if some of them are unconfirmed. The block at the `tip` height counts as one
+/// confirmation. If there are no funding outpoints the funding is considered unconfirmed.
+pub fn confirmation_status(funding_outpoints: &[OutPoint], confirmed_at_height: Option<u32>, tip: u32, required: u32) -> ConfirmationStatus {
+    let height = match confirmed_at_height {
+        Some(height) if !funding_outpoints.is_empty() && height <= tip => height,
+        _ => return ConfirmationStatus::Unconfirmed,
+    };
+    let have = tip - height + 1;
+    if have >= required {
+        ConfirmationStatus::Confirmed
+    } else {
+        ConfirmationStatus::Confirming { have, need: required }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    quickcheck::quickcheck! {
+        fn confirmations_count_up(height: u32, required: u32) -> bool {
+            let outpoints = [OutPoint::null()];
+            let height = height % 1_000_000;
+            let required = required % 10 + 1;
+            (0..required).all(|i| {
+                let status = confirmation_status(&outpoints, Some(height), height + i, required);
+                status == ConfirmationStatus::Confirming { have: i + 1, need: required } || i + 1 == required
+            })
+            && confirmation_status(&outpoints, Some(height), height + required - 1, required) == ConfirmationStatus::Confirmed
+            && confirmation_status(&outpoints, Some(height + 1), height, required) == ConfirmationStatus::Unconfirmed
+            && confirmation_status(&outpoints, None, height, required) == ConfirmationStatus::Unconfirmed
+            && confirmation_status(&[], Some(height), height + required, required) == ConfirmationStatus::Unconfirmed
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Classification of serialized messages.
+//!
+//! All messages start with [`MessageId`] which determines who sent them so routers can dispatch
+//! the messages without attempting to deserialize them.
+
+use core::convert::TryFrom;
+use super::constants::MessageId;
+
+/// The participant sending a message.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageSender {
+    /// The coordinator distributing offers and hints.
+    Firefish,
+    Borrower,
+    TedO,
+    TedP,
+}
+
+impl MessageId {
+    /// Returns the participant sending messages with this identifier.
+    ///
+    /// Returns `None` for contract proofs since anyone having the public data can create them.
+    pub fn sender(self) -> Option<MessageSender> {
+        match self {
+            MessageId::Offer => Some(MessageSender::Firefish),
+            MessageId::PrefundHints => Some(MessageSender::Firefish),
+            MessageId::PrefundBorrowerInfo => Some(MessageSender::Borrower),
+            MessageId::EscrowHints => Some(MessageSender::Firefish),
+            MessageId::EscrowBorrowerInfo => Some(MessageSender::Borrower),
+            MessageId::StateSigsFromBorrower => Some(MessageSender::Borrower),
+            MessageId::StateSigsFromTedO => Some(MessageSender::TedO),
+            MessageId::StateSigsFromTedP => Some(MessageSender::TedP),
+            MessageId::EscrowSigsFromBorrower => Some(MessageSender::Borrower),
+            MessageId::ContractProof => None,
+            MessageId::EscrowHintsWithHeight => Some(MessageSender::Firefish),
+            MessageId::RecoverSigFromTedO => Some(MessageSender::TedO),
+            MessageId::RecoverSigFromTedP => Some(MessageSender::TedP),
+            MessageId::TerminalSigsFromTedO => Some(MessageSender::TedO),
+        }
+    }
+}
+
+/// Returns the participant that sent the serialized message.
+///
+/// Only the first byte is inspected, the rest of the message is not validated. Returns `None` if
+/// the message is empty, its identifier is unknown or it has no specific sender.
+pub fn sender(bytes: &[u8]) -> Option<MessageSender> {
+    let message_id = MessageId::try_from(*bytes.first()?).ok()?;
+    message_id.sender()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn senders() {
+        let mut message = Vec::new();
+        let signature = secp256k1::schnorr::Signature::from_slice(&[42; 64]).unwrap();
+        super::super::escrow::TedPSignatures { recover: signature, escrow: Vec::new() }.serialize(&mut message);
+        assert_eq!(sender(&message), Some(MessageSender::TedP));
+        message.clear();
+        super::super::escrow::RecoverSignatureMessage::TedO(signature).serialize(&mut message);
+        assert_eq!(sender(&message), Some(MessageSender::TedO));
+        assert_eq!(sender(&[MessageId::EscrowSigsFromBorrower as u8]), Some(MessageSender::Borrower));
+        assert_eq!(sender(&[MessageId::EscrowHints as u8]), Some(MessageSender::Firefish));
+        assert_eq!(sender(&[MessageId::ContractProof as u8]), None);
+        assert_eq!(sender(&[]), None);
+        assert_eq!(sender(&[255]), None);
+    }
+}
@@ -12,6 +12,15 @@ pub mod pub_keys;
 pub mod offer;
 pub mod constants;
 pub mod deserialize;
+pub mod proof;
+pub mod encoding;
+pub mod descriptor;
+pub mod psbt;
+pub mod funding;
+pub mod message;
+pub mod audit;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod test_harness;
 
 use secp256k1::Keypair;
 use secp256k1::schnorr::Signature;
@@ -119,19 +128,27 @@ pub trait HotKey {
 
 pub trait SetBorrowerSpendInfo: Sized {
     fn set_borrower_spend_info(self, info: prefund::BorrowerSpendInfo) -> Result<Self, (Self, BorrowerInfoError)>;
+    fn has_borrower_spend_info(&self) -> bool;
 }
 
 impl Ted<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorrowerInfo<participant::TedP>> {
     /// Initializes the contract.
     ///
-    /// Matches the supplied keys with those in the offer. Returns `None` if the don't match.
-    pub fn init(prefund_key: Keypair, escrow_key: Keypair, offer: offer::Offer) -> Option<Self> {
-        if prefund_key.x_only_public_key().0 == *offer.prefund_keys.ted_o.as_x_only() && escrow_key.x_only_public_key().0 == *offer.escrow_keys.ted_o.as_x_only() {
-            Some(Ted::O(participant::ted_o::init(prefund_key, escrow_key, offer)))
-        } else if prefund_key.x_only_public_key().0 == *offer.prefund_keys.ted_p.as_x_only() && escrow_key.x_only_public_key().0 == *offer.escrow_keys.ted_p.as_x_only() {
-            Some(Ted::P(participant::ted_p::init(prefund_key, escrow_key, offer)))
-        } else {
-            None
+    /// Matches the supplied keys with those in the offer. Returns an error if they don't match.
+    pub fn init(prefund_key: Keypair, escrow_key: Keypair, offer: offer::Offer) -> Result<Self, InitError> {
+        let prefund_key_matches_o = prefund_key.x_only_public_key().0 == *offer.prefund_keys.ted_o.as_x_only();
+        let escrow_key_matches_o = escrow_key.x_only_public_key().0 == *offer.escrow_keys.ted_o.as_x_only();
+        let prefund_key_matches_p = prefund_key.x_only_public_key().0 == *offer.prefund_keys.ted_p.as_x_only();
+        let escrow_key_matches_p = escrow_key.x_only_public_key().0 == *offer.escrow_keys.ted_p.as_x_only();
+
+        match (prefund_key_matches_o, escrow_key_matches_o, prefund_key_matches_p, escrow_key_matches_p) {
+            (true, true, _, _) => Ok(Ted::O(participant::ted_o::init(prefund_key, escrow_key, offer))),
+            (_, _, true, true) => Ok(Ted::P(participant::ted_p::init(prefund_key, escrow_key, offer))),
+            (true, false, _, _) => Err(InitError::EscrowKeyMismatch(constants::ParticipantId::TedO)),
+            (_, _, true, false) => Err(InitError::EscrowKeyMismatch(constants::ParticipantId::TedP)),
+            (false, true, _, _) => Err(InitError::PrefundKeyMismatch(constants::ParticipantId::TedO)),
+            (_, _, false, true) => Err(InitError::PrefundKeyMismatch(constants::ParticipantId::TedP)),
+            (false, false, false, false) => Err(InitError::UnknownKeys),
         }
     }
 
@@ -142,6 +159,14 @@ impl Ted<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorr
         }
     }
 
+    /// Returns `true` if the borrower's prefund spend info was already received.
+    pub fn has_borrower_spend_info(&self) -> bool {
+        match self {
+            Ted::O(state) => state.has_borrower_spend_info(),
+            Ted::P(state) => state.has_borrower_spend_info(),
+        }
+    }
+
     pub fn borrower_info(&self, borrower_info: escrow::BorrowerInfo<escrow::validation::Validated>) -> escrow::UnsignedTransactions {
         match self {
             Ted::O(state) => state.borrower_info(borrower_info),
@@ -165,6 +190,18 @@ impl Ted<escrow::ReceivingBorrowerInfo<participant::TedO>, escrow::ReceivingBorr
     }
 }
 
+impl Ted<escrow::WaitingForEscrowConfirmation<participant::TedO>, escrow::WaitingForEscrowConfirmation<participant::TedP>> {
+    /// Produces all signatures of the TED according to its role.
+    ///
+    /// The escrow is signed as well if the prefund was ready when the transactions were set.
+    pub fn sign_all(&self) -> escrow::TedSignatures {
+        match self {
+            Ted::O(state) => escrow::TedSignatures::TedO(state.re_sign()),
+            Ted::P(state) => escrow::TedSignatures::TedP(state.re_sign()),
+        }
+    }
+}
+
 impl<O: Serialize + StateData, P: Serialize + StateData> Ted<O, P> {
     pub fn serialize(&self, out: &mut Vec<u8>) {
         match self {
@@ -221,6 +258,20 @@ pub enum BorrowerInfoError {
     AlreadyReceived,
 }
 
+/// Returned when the keys supplied to [`Ted::init`] don't match a role in the offer.
+///
+/// A partial match usually means that one of the keys was derived using a wrong path.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum InitError {
+    /// Neither key matches any role.
+    UnknownKeys,
+    /// The prefund key matches the role but the escrow key doesn't.
+    EscrowKeyMismatch(constants::ParticipantId),
+    /// The escrow key matches the role but the prefund key doesn't.
+    PrefundKeyMismatch(constants::ParticipantId),
+}
+
 fn assemble_witness(borrower: &Signature, ted_o: &Signature, ted_p: &Signature, permutation: primitives::Permutation, script: &bitcoin::Script, control_block: &[u8]) -> bitcoin::Witness {
     let mut witness = bitcoin::Witness::new();
     let sigs = permutation.permute([borrower, ted_o, ted_p]);
@@ -288,4 +339,23 @@ mod tests {
             }
         }
     }
+
+    quickcheck::quickcheck! {
+        fn ted_has_borrower_spend_info_once_received(offer: super::offer::Offer, key: super::pub_keys::PubKey<participant::Borrower, super::context::Prefund>) -> bool {
+            use bitcoin::hashes::Hash;
+            use bitcoin::key::Keypair;
+            use super::pub_keys::PubKey;
+
+            let prefund_key = Keypair::new_global(&mut rand::thread_rng());
+            let escrow_key = Keypair::new_global(&mut rand::thread_rng());
+            let mut offer = offer;
+            offer.prefund_keys.ted_o = PubKey::from_key_pair(&prefund_key);
+            offer.escrow_keys.ted_o = PubKey::from_key_pair(&escrow_key);
+            let ted = super::Ted::init(prefund_key, escrow_key, offer).unwrap();
+            let received_before = ted.has_borrower_spend_info();
+            let info = super::prefund::BorrowerSpendInfo { key, return_hash: bitcoin::taproot::TapNodeHash::all_zeros() };
+            let ted = ted.prefund_borrower_info(info).map_err(|(_, error)| error).unwrap();
+            !received_before && ted.has_borrower_spend_info()
+        }
+    }
 }
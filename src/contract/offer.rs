@@ -8,8 +8,8 @@ use bitcoin::p2p::Magic;
 use core::convert::TryInto;
 use core::fmt;
 
-use super::{context, participant, deserialize};
-use super::pub_keys::{PubKey, PubKeys};
+use super::{context, participant, deserialize, prefund};
+use super::pub_keys::{InternalKey, PubKey, PubKeys};
 use bitcoin::blockdata::FeeRate;
 
 pub struct MandatoryOfferFields {
@@ -31,13 +31,23 @@ pub struct MandatoryOfferFields {
 }
 
 impl MandatoryOfferFields {
-    pub fn into_offer(self) -> Offer {
+    /// Replaces the keys of both TEDs.
+    pub fn with_ted_keys(mut self, ted_o_keys: AllParticipantKeys<participant::TedO>, ted_p_keys: AllParticipantKeys<participant::TedP>) -> Self {
+        self.ted_o_keys = ted_o_keys;
+        self.ted_p_keys = ted_p_keys;
+        self
+    }
+
+    pub fn into_offer(self) -> Result<Offer, OfferError> {
         self.into_offer_with_optional(Default::default())
     }
 
-    pub fn into_offer_with_optional(self, optional: OptionalOfferFields) -> Offer {
+    /// Creates the offer checking that the liquidator scripts are standard on the network.
+    pub fn into_offer_with_optional(self, optional: OptionalOfferFields) -> Result<Offer, OfferError> {
         use bitcoin::secp256k1::rand::Rng;
 
+        check_liquidator_scripts(self.network, &self.liquidator_script_default, &self.liquidator_script_liquidation)?;
+
         let liquidator_output_index = bitcoin::secp256k1::rand::thread_rng()
             .gen_range::<usize, _>(0..=optional.extra_termination_outputs.len());
         let escrow = EscrowParams {
@@ -53,23 +63,93 @@ impl MandatoryOfferFields {
         let prefund_keys = TedSigPubKeys {
             ted_o: self.ted_o_keys.prefund,
             ted_p: self.ted_p_keys.prefund,
+            internal_key: optional.internal_key,
         };
         let escrow_keys = TedSigPubKeys {
             ted_o: self.ted_o_keys.escrow,
             ted_p: self.ted_p_keys.escrow,
+            internal_key: optional.internal_key,
         };
-        Offer {
+        Ok(Offer {
             escrow,
             escrow_keys,
             prefund_keys,
+        })
+    }
+}
+
+/// Offer that can be serialized in the original (V0) format.
+///
+/// Created by [`Offer::as_v0_compatible`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct V0CompatibleOffer(Offer);
+
+impl V0CompatibleOffer {
+    const VERSION: u8 = 0;
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        out.push(V0CompatibleOffer::VERSION);
+        self.0.prefund_keys.serialize(out);
+        self.0.escrow_keys.serialize(out);
+        self.0.escrow.serialize_with_version(out, EscrowParamsVersion::V0);
+    }
+
+    /// Deserializes the offer in the V0 format.
+    ///
+    /// This is the format understood by the clients that predate the current version.
+    pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, DeserializationError> {
+        if bytes.is_empty() {
+            return Err(DeserializationError::UnexpectedEnd);
         }
+        if bytes[0] != V0CompatibleOffer::VERSION {
+            return Err(DeserializationError::UnknownVersion(bytes[0]));
+        }
+
+        *bytes = &bytes[1..];
+        let prefund_keys = TedSigPubKeys::deserialize(bytes)?;
+        let escrow_keys = TedSigPubKeys::deserialize(bytes)?;
+        let escrow = EscrowParams::deserialize(bytes, EscrowParamsVersion::V0)?;
+        Ok(V0CompatibleOffer(Offer {
+            escrow_keys,
+            prefund_keys,
+            escrow,
+        }))
+    }
+
+    pub fn as_offer(&self) -> &Offer {
+        &self.0
+    }
+
+    pub fn into_offer(self) -> Offer {
+        self.0
+    }
+}
+
+fn check_liquidator_scripts(network: bitcoin::Network, default: &bitcoin::Script, liquidation: &bitcoin::Script) -> Result<(), OfferError> {
+    if bitcoin::Address::from_script(default, network).is_err() {
+        return Err(OfferError::NonStandardLiquidatorScriptDefault);
+    }
+    if bitcoin::Address::from_script(liquidation, network).is_err() {
+        return Err(OfferError::NonStandardLiquidatorScriptLiquidation);
     }
+    Ok(())
+}
+
+/// Error returned when constructing an invalid offer.
+#[derive(Debug)]
+pub enum OfferError {
+    /// The liquidator script used in default doesn't correspond to any address type.
+    NonStandardLiquidatorScriptDefault,
+    /// The liquidator script used in liquidation doesn't correspond to any address type.
+    NonStandardLiquidatorScriptLiquidation,
 }
 
 #[derive(Default)]
 #[non_exhaustive]
 pub struct OptionalOfferFields {
     pub extra_termination_outputs: Vec<TxOut>,
+    /// The internal key of both prefund and escrow outputs.
+    pub internal_key: InternalKey,
 }
 
 /// The initialization information about the contract.
@@ -86,6 +166,10 @@ pub struct Offer {
 
 impl Offer {
     const VERSION: u8 = 1;
+    /// The version of offers using a custom internal key, the keys are followed by it.
+    ///
+    /// The key read from the offer must be checked using [`Offer::validate_internal_key`].
+    const VERSION_WITH_INTERNAL_KEY: u8 = 2;
     const ESCROW_PARAMS_VERSION: EscrowParamsVersion = match EscrowParamsVersion::from_num(Offer::VERSION as u32) { Some(version) => version, None => unreachable!(), };
 
     pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, DeserializationError> {
@@ -93,13 +177,17 @@ impl Offer {
             return Err(DeserializationError::UnexpectedEnd);
         }
 
-        if bytes[0] != Offer::VERSION {
-            return Err(DeserializationError::UnknownVersion(bytes[0]));
-        }
-
-        *bytes = &bytes[1..];
-        let prefund_keys = TedSigPubKeys::deserialize(bytes)?;
-        let escrow_keys = TedSigPubKeys::deserialize(bytes)?;
+        let (prefund_keys, escrow_keys) = match bytes[0] {
+            Offer::VERSION => {
+                *bytes = &bytes[1..];
+                (TedSigPubKeys::deserialize(bytes)?, TedSigPubKeys::deserialize(bytes)?)
+            },
+            Offer::VERSION_WITH_INTERNAL_KEY => {
+                *bytes = &bytes[1..];
+                (TedSigPubKeys::deserialize_with_internal_key(bytes)?, TedSigPubKeys::deserialize_with_internal_key(bytes)?)
+            },
+            version => return Err(DeserializationError::UnknownVersion(version)),
+        };
         let escrow = EscrowParams::deserialize(bytes, Self::ESCROW_PARAMS_VERSION)?;
         let offer = Offer {
             escrow_keys,
@@ -110,15 +198,274 @@ impl Offer {
     }
 
     pub fn serialize(&self, out: &mut Vec<u8>) {
-        out.reserve(self.escrow.reserve_suggestion() + 1 + 4 * 32);
-        out.push(Offer::VERSION);
-        self.prefund_keys.serialize(out);
-        self.escrow_keys.serialize(out);
+        out.reserve(self.serialized_size());
+        if self.has_custom_internal_key() {
+            out.push(Offer::VERSION_WITH_INTERNAL_KEY);
+            self.prefund_keys.serialize_with_internal_key(out);
+            self.escrow_keys.serialize_with_internal_key(out);
+        } else {
+            out.push(Offer::VERSION);
+            self.prefund_keys.serialize(out);
+            self.escrow_keys.serialize(out);
+        }
         self.escrow.serialize(out);
     }
+
+    /// Returns the exact number of bytes produced by [`serialize`](Self::serialize).
+    pub fn serialized_size(&self) -> usize {
+        let internal_keys_size = if self.has_custom_internal_key() { 2 * 32 } else { 0 };
+        1 + 4 * 32 + internal_keys_size + self.escrow.serialized_size()
+    }
+
+    /// Returns `true` if any of the contract outputs uses an internal key other than the default.
+    ///
+    /// Such offers are serialized in a newer format that older clients don't understand.
+    pub fn has_custom_internal_key(&self) -> bool {
+        !self.prefund_keys.internal_key.is_default() || !self.escrow_keys.internal_key.is_default()
+    }
+
+    /// Checks that both contract outputs use the internal key expected by the caller.
+    ///
+    /// The offer is not trusted and whoever knows the discrete logarithm of the internal key can
+    /// spend the contract outputs. So the key must be compared with the one from local
+    /// configuration which is [`InternalKey::default`] unless the deployment uses its own.
+    pub fn validate_internal_key(&self, expected: InternalKey) -> Result<(), InternalKeyMismatch> {
+        for found in [self.prefund_keys.internal_key, self.escrow_keys.internal_key] {
+            if found != expected {
+                return Err(InternalKeyMismatch { found });
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the offer in a form serializable in the original (V0) format.
+    ///
+    /// The V0 format uses the same liquidator script for default and liquidation and the default
+    /// internal keys so this returns `None` if the scripts differ or an internal key is custom.
+    /// Clients that don't understand the current format can only process the V0 offers.
+    pub fn as_v0_compatible(&self) -> Option<V0CompatibleOffer> {
+        if self.escrow.liquidator_script_default == self.escrow.liquidator_script_liquidation && !self.has_custom_internal_key() {
+            Some(V0CompatibleOffer(self.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the serialized offer fits into `max_bytes`.
+    ///
+    /// Note that the limit applies to raw bytes. If the transport uses base64 (see
+    /// [`encoding`](super::encoding)) the encoded message is a third larger.
+    pub fn fits_in(&self, max_bytes: usize) -> bool {
+        self.serialized_size() <= max_bytes
+    }
+
+    /// Checks that the liquidator scripts are standard on the network of the offer.
+    ///
+    /// This is always true for offers created using [`MandatoryOfferFields`] but received offers
+    /// need to be checked.
+    pub fn validate_liquidator_scripts(&self) -> Result<(), OfferError> {
+        check_liquidator_scripts(self.escrow.network, &self.escrow.liquidator_script_default, &self.escrow.liquidator_script_liquidation)
+    }
+
+    /// Checks that the recover transaction becomes valid only after the default transaction.
+    ///
+    /// Otherwise the borrower could take the collateral back instead of the lender getting it.
+    pub fn validate_lock_time_order(&self) -> Result<(), LockTimeOrderError> {
+        self.escrow.validate_lock_time_order()
+    }
+
+    /// Checks that no TED key is used more than once.
+    ///
+    /// The keys also must not be the output keys of taproot liquidator scripts. Reusing keys could
+    /// allow replaying signatures across contexts.
+    pub fn validate_key_distinctness(&self) -> Result<(), KeyReuseError> {
+        let keys = [
+            self.prefund_keys.ted_o.as_x_only(),
+            self.prefund_keys.ted_p.as_x_only(),
+            self.escrow_keys.ted_o.as_x_only(),
+            self.escrow_keys.ted_p.as_x_only(),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            if keys[(i + 1)..].contains(key) {
+                return Err(KeyReuseError::TedKey(**key));
+            }
+        }
+        for script in [&self.escrow.liquidator_script_default, &self.escrow.liquidator_script_liquidation] {
+            if script.is_p2tr() {
+                let liquidator_key = bitcoin::key::XOnlyPublicKey::from_slice(&script.as_bytes()[2..])
+                    .map_err(|_| KeyReuseError::InvalidLiquidatorKey)?;
+                if keys.contains(&&liquidator_key) {
+                    return Err(KeyReuseError::LiquidatorKey(liquidator_key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the fields which differ between the offers.
+    ///
+    /// The values of `self` are first, the values of `other` second.
+    pub fn diff(&self, other: &Offer) -> Vec<OfferFieldDiff> {
+        use bitcoin::key::XOnlyPublicKey;
+
+        fn push_if_different<T: PartialEq + Clone>(diffs: &mut Vec<OfferFieldDiff>, a: &T, b: &T, variant: fn(T, T) -> OfferFieldDiff) {
+            if a != b {
+                diffs.push(variant(a.clone(), b.clone()));
+            }
+        }
+
+        let mut diffs = Vec::new();
+        let (a, b) = (&self.escrow, &other.escrow);
+        push_if_different(&mut diffs, &a.network, &b.network, OfferFieldDiff::Network);
+        push_if_different::<XOnlyPublicKey>(&mut diffs, self.prefund_keys.ted_o.as_x_only(), other.prefund_keys.ted_o.as_x_only(), OfferFieldDiff::PrefundKeyTedO);
+        push_if_different::<XOnlyPublicKey>(&mut diffs, self.prefund_keys.ted_p.as_x_only(), other.prefund_keys.ted_p.as_x_only(), OfferFieldDiff::PrefundKeyTedP);
+        push_if_different::<XOnlyPublicKey>(&mut diffs, self.escrow_keys.ted_o.as_x_only(), other.escrow_keys.ted_o.as_x_only(), OfferFieldDiff::EscrowKeyTedO);
+        push_if_different::<XOnlyPublicKey>(&mut diffs, self.escrow_keys.ted_p.as_x_only(), other.escrow_keys.ted_p.as_x_only(), OfferFieldDiff::EscrowKeyTedP);
+        push_if_different(&mut diffs, &self.prefund_keys.internal_key, &other.prefund_keys.internal_key, OfferFieldDiff::PrefundInternalKey);
+        push_if_different(&mut diffs, &self.escrow_keys.internal_key, &other.escrow_keys.internal_key, OfferFieldDiff::EscrowInternalKey);
+        push_if_different(&mut diffs, &a.liquidator_script_default, &b.liquidator_script_default, OfferFieldDiff::LiquidatorScriptDefault);
+        push_if_different(&mut diffs, &a.liquidator_script_liquidation, &b.liquidator_script_liquidation, OfferFieldDiff::LiquidatorScriptLiquidation);
+        push_if_different(&mut diffs, &a.min_collateral, &b.min_collateral, OfferFieldDiff::MinCollateral);
+        push_if_different(&mut diffs, &a.extra_termination_outputs, &b.extra_termination_outputs, OfferFieldDiff::ExtraTerminationOutputs);
+        push_if_different(&mut diffs, &a.liquidator_output_index, &b.liquidator_output_index, OfferFieldDiff::LiquidatorOutputIndex);
+        push_if_different(&mut diffs, &a.recover_lock_time, &b.recover_lock_time, OfferFieldDiff::RecoverLockTime);
+        push_if_different(&mut diffs, &a.default_lock_time, &b.default_lock_time, OfferFieldDiff::DefaultLockTime);
+        diffs
+    }
+}
+
+/// Error returned by [`Offer::validate_lock_time_order`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LockTimeOrderError {
+    /// One lock time is a block height and the other one is a timestamp.
+    UnitMismatch,
+    /// The recover lock time is not strictly greater than the default lock time.
+    RecoverNotAfterDefault,
+}
+
+/// Error returned by [`EscrowParams::new`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EscrowParamsError {
+    /// The liquidator output index is greater than the number of extra outputs.
+    LiquidatorOutputIndexOutOfRange { index: usize, count: usize },
+    /// The lock times are not ordered correctly.
+    LockTimeOrder(LockTimeOrderError),
+}
+
+/// Error returned when the offer uses an internal key other than the expected one.
+///
+/// See [`Offer::validate_internal_key`].
+#[derive(Debug)]
+pub struct InternalKeyMismatch {
+    /// The key found in the offer.
+    pub found: InternalKey,
+}
+
+/// Error returned by [`Offer::validate_key_distinctness`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KeyReuseError {
+    /// The key is used by TEDs more than once.
+    TedKey(bitcoin::key::XOnlyPublicKey),
+    /// The key is used by TEDs and also by a liquidator script.
+    LiquidatorKey(bitcoin::key::XOnlyPublicKey),
+    /// A taproot liquidator script contains an invalid key.
+    InvalidLiquidatorKey,
+}
+
+/// A field that differs between two offers returned by [`Offer::diff`].
+///
+/// Each variant holds the value from the first offer and the value from the second offer.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum OfferFieldDiff {
+    Network(bitcoin::Network, bitcoin::Network),
+    PrefundKeyTedO(bitcoin::key::XOnlyPublicKey, bitcoin::key::XOnlyPublicKey),
+    PrefundKeyTedP(bitcoin::key::XOnlyPublicKey, bitcoin::key::XOnlyPublicKey),
+    EscrowKeyTedO(bitcoin::key::XOnlyPublicKey, bitcoin::key::XOnlyPublicKey),
+    EscrowKeyTedP(bitcoin::key::XOnlyPublicKey, bitcoin::key::XOnlyPublicKey),
+    PrefundInternalKey(InternalKey, InternalKey),
+    EscrowInternalKey(InternalKey, InternalKey),
+    LiquidatorScriptDefault(bitcoin::ScriptBuf, bitcoin::ScriptBuf),
+    LiquidatorScriptLiquidation(bitcoin::ScriptBuf, bitcoin::ScriptBuf),
+    MinCollateral(bitcoin::Amount, bitcoin::Amount),
+    ExtraTerminationOutputs(Vec<TxOut>, Vec<TxOut>),
+    LiquidatorOutputIndex(usize, usize),
+    RecoverLockTime(bitcoin::absolute::LockTime, bitcoin::absolute::LockTime),
+    DefaultLockTime(bitcoin::absolute::LockTime, bitcoin::absolute::LockTime),
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for Offer {
+    fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
+        let prefund_keys = TedSigPubKeys::<context::Prefund>::arbitrary(gen);
+        let mut escrow_keys = TedSigPubKeys::<context::Escrow>::arbitrary(gen);
+        // Offers use the same internal key for both outputs, see `OptionalOfferFields`.
+        escrow_keys.internal_key = prefund_keys.internal_key;
+        Offer {
+            escrow: quickcheck::Arbitrary::arbitrary(gen),
+            escrow_keys,
+            prefund_keys,
+        }
+    }
+}
+
+/// The prefix of the string representation of the offer.
+const OFFER_PREFIX: &str = "ffoffer1";
+
+/// Displays the offer as a base64-encoded string prefixed with `ffoffer1`.
+///
+/// The prefix makes it obvious which kind of data it is, similarly to [`AllParticipantKeys`].
+impl fmt::Display for Offer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut bytes = Vec::new();
+        self.serialize(&mut bytes);
+        write!(f, "{}{}", OFFER_PREFIX, super::encoding::encode_message(&bytes))
+    }
+}
+
+impl core::str::FromStr for Offer {
+    type Err = OfferParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.starts_with(OFFER_PREFIX) {
+            return Err(OfferParseError::NotAnOffer);
+        }
+        let bytes = super::encoding::decode_message(&s[OFFER_PREFIX.len()..]).map_err(OfferParseError::Encoding)?;
+        let mut bytes = &*bytes;
+        let offer = Offer::deserialize(&mut bytes).map_err(OfferParseError::Deserialization)?;
+        if !bytes.is_empty() {
+            return Err(OfferParseError::TrailingBytes(bytes.len()));
+        }
+        Ok(offer)
+    }
 }
 
-crate::test_macros::impl_arbitrary!(Offer, escrow, escrow_keys, prefund_keys);
+/// Error returned when parsing the string representation of the offer fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OfferParseError {
+    /// The string doesn't have the offer prefix so it's most likely some other data.
+    NotAnOffer,
+    Encoding(super::encoding::DecodeError),
+    Deserialization(DeserializationError),
+    /// The offer is followed by the given number of unexpected bytes.
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for OfferParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OfferParseError::NotAnOffer => write!(f, "this is not an offer, offers start with {}", OFFER_PREFIX),
+            OfferParseError::Encoding(error) => fmt::Display::fmt(error, f),
+            OfferParseError::Deserialization(error) => write!(f, "invalid offer: {:?}", error),
+            OfferParseError::TrailingBytes(count) => write!(f, "the offer is followed by {} unexpected bytes", count),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum DeserializationError {
@@ -182,6 +529,49 @@ pub struct EscrowParams {
 }
 
 impl EscrowParams {
+    /// Creates the parameters checking that they are consistent.
+    ///
+    /// The liquidator output index must not exceed the number of extra outputs and the recover
+    /// lock time must be after the default lock time.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        network: bitcoin::Network,
+        liquidator_script_default: bitcoin::ScriptBuf,
+        liquidator_script_liquidation: bitcoin::ScriptBuf,
+        min_collateral: bitcoin::Amount,
+        extra_termination_outputs: Vec<TxOut>,
+        liquidator_output_index: usize,
+        recover_lock_time: bitcoin::absolute::LockTime,
+        default_lock_time: bitcoin::absolute::LockTime,
+    ) -> Result<Self, EscrowParamsError> {
+        if liquidator_output_index > extra_termination_outputs.len() {
+            return Err(EscrowParamsError::LiquidatorOutputIndexOutOfRange { index: liquidator_output_index, count: extra_termination_outputs.len() });
+        }
+        let params = EscrowParams {
+            network,
+            liquidator_script_default,
+            liquidator_script_liquidation,
+            min_collateral,
+            extra_termination_outputs,
+            liquidator_output_index,
+            recover_lock_time,
+            default_lock_time,
+        };
+        params.validate_lock_time_order().map_err(EscrowParamsError::LockTimeOrder)?;
+        Ok(params)
+    }
+
+    fn validate_lock_time_order(&self) -> Result<(), LockTimeOrderError> {
+        use bitcoin::absolute::LockTime;
+
+        match (self.default_lock_time, self.recover_lock_time) {
+            (LockTime::Blocks(default), LockTime::Blocks(recover)) if recover > default => Ok(()),
+            (LockTime::Seconds(default), LockTime::Seconds(recover)) if recover > default => Ok(()),
+            (LockTime::Blocks(_), LockTime::Blocks(_)) | (LockTime::Seconds(_), LockTime::Seconds(_)) => Err(LockTimeOrderError::RecoverNotAfterDefault),
+            _ => Err(LockTimeOrderError::UnitMismatch),
+        }
+    }
+
     pub(crate) fn deserialize(bytes: &mut &[u8], version: EscrowParamsVersion) -> Result<Self, DeserializationError> {
         if bytes.len() < 8 {
             return Err(DeserializationError::UnexpectedEnd);
@@ -242,32 +632,53 @@ impl EscrowParams {
     }
 
     pub(crate) fn serialize(&self, out: &mut Vec<u8>) {
+        self.serialize_with_version(out, EscrowParamsVersion::V1)
+    }
+
+    /// Serializes the params in the given format.
+    ///
+    /// The V0 format can only be used if both liquidator scripts are equal.
+    fn serialize_with_version(&self, out: &mut Vec<u8>, version: EscrowParamsVersion) {
         use bitcoin::consensus::Encodable;
 
         out.extend_from_slice(&self.network.magic().to_bytes());
         out.extend_from_slice(&(self.liquidator_output_index as u32).to_be_bytes());
         self.recover_lock_time.consensus_encode(out).expect("vec doesn't error");
         self.default_lock_time.consensus_encode(out).expect("vec doesn't error");
-        self.liquidator_script_default.consensus_encode(out).expect("vec doesn't error");
-        self.liquidator_script_liquidation.consensus_encode(out).expect("vec doesn't error");
-        self.min_collateral.consensus_encode(out).expect("vec doesn't error");
+        match version {
+            EscrowParamsVersion::V0 => {
+                debug_assert_eq!(self.liquidator_script_default, self.liquidator_script_liquidation);
+                let liquidator_output = TxOut { value: self.min_collateral, script_pubkey: self.liquidator_script_default.clone() };
+                liquidator_output.consensus_encode(out).expect("vec doesn't error");
+            },
+            EscrowParamsVersion::V1 => {
+                self.liquidator_script_default.consensus_encode(out).expect("vec doesn't error");
+                self.liquidator_script_liquidation.consensus_encode(out).expect("vec doesn't error");
+                self.min_collateral.consensus_encode(out).expect("vec doesn't error");
+            },
+        }
         out.extend_from_slice(&(self.extra_termination_outputs.len() as u32).to_be_bytes());
         for output in &self.extra_termination_outputs {
             output.consensus_encode(out).expect("vec doesn't error");
         }
     }
 
-    pub(crate) fn reserve_suggestion(&self) -> usize {
+    /// Returns the exact number of bytes produced by `serialize`.
+    pub(crate) fn serialized_size(&self) -> usize {
         use bitcoin::consensus::encode::VarInt;
 
-        let excluding_liquidator_script = self.extra_termination_outputs.iter()
-            .map(|txout| txout.script_pubkey.len() + VarInt(txout.script_pubkey.len() as u64).size())
-            .sum::<usize>()
-            + 4 + 1 + 2*8 + 4;
+        fn script_size(script: &bitcoin::Script) -> usize {
+            script.len() + VarInt(script.len() as u64).size()
+        }
+
+        let extra_outputs = self.extra_termination_outputs.iter()
+            .map(|txout| 8 + script_size(&txout.script_pubkey))
+            .sum::<usize>();
+
+        // magic, liquidator index, two lock times, min collateral, extra output count
+        let fixed = 4 + 4 + 2 * 4 + 8 + 4;
 
-        let default = self.liquidator_script_default.len() + VarInt(self.liquidator_script_default.len() as u64).size();
-        let liquidation = self.liquidator_script_liquidation.len() + VarInt(self.liquidator_script_liquidation.len() as u64).size();
-        excluding_liquidator_script + default + liquidation
+        fixed + extra_outputs + script_size(&self.liquidator_script_default) + script_size(&self.liquidator_script_liquidation)
     }
 }
 
@@ -320,22 +731,50 @@ pub struct TedSigPubKeys<C> {
 
     /// The public key of TED-P
     pub ted_p: PubKey<participant::TedP, C>,
+
+    /// The internal key of the taproot output.
+    pub internal_key: InternalKey,
 }
 
-crate::test_macros::impl_test_traits!(TedSigPubKeys<C>, ted_o, ted_p);
+crate::test_macros::impl_test_traits!(TedSigPubKeys<C>, ted_o, ted_p, internal_key);
 
 impl<C> TedSigPubKeys<C> {
+    /// Deserializes the TED keys using the default internal key.
     pub(crate) fn deserialize(bytes: &mut &[u8]) -> Result<Self, DeserializationError> {
         let ted_o = PubKey::deserialize_raw(bytes)?;
         let ted_p = PubKey::deserialize_raw(bytes)?;
 
-        Ok(TedSigPubKeys { ted_o, ted_p, })
+        Ok(TedSigPubKeys { ted_o, ted_p, internal_key: InternalKey::default(), })
     }
 
+    /// Serializes the TED keys without the internal key.
     pub(crate) fn serialize(&self, out: &mut Vec<u8>) {
         self.ted_o.serialize_raw(out);
         self.ted_p.serialize_raw(out);
     }
+
+    pub(crate) fn deserialize_with_internal_key(bytes: &mut &[u8]) -> Result<Self, DeserializationError> {
+        let mut keys = Self::deserialize(bytes)?;
+        keys.internal_key = InternalKey::deserialize(bytes)?;
+        Ok(keys)
+    }
+
+    pub(crate) fn serialize_with_internal_key(&self, out: &mut Vec<u8>) {
+        self.serialize(out);
+        self.internal_key.serialize(out);
+    }
+
+    /// Serializes the keys including the internal key as stored in the current state version.
+    pub(crate) fn serialize_state(&self, out: &mut Vec<u8>) {
+        self.serialize_with_internal_key(out);
+    }
+
+    /// Deserializes the keys stored in a state of the given version.
+    pub(crate) fn deserialize_state(bytes: &mut &[u8], version: deserialize::StateVersion) -> Result<Self, DeserializationError> {
+        let mut keys = Self::deserialize(bytes)?;
+        keys.internal_key = InternalKey::deserialize_state(bytes, version)?;
+        Ok(keys)
+    }
 }
 
 impl<C> Copy for TedSigPubKeys<C> {}
@@ -347,11 +786,12 @@ impl<C> TedSigPubKeys<C> {
             borrower_eph,
             ted_o: self.ted_o,
             ted_p: self.ted_p,
+            internal_key: self.internal_key,
         }
     }
 }
 
-crate::test_macros::impl_arbitrary!(TedSigPubKeys<C>, ted_o, ted_p);
+crate::test_macros::impl_arbitrary!(TedSigPubKeys<C>, ted_o, ted_p, internal_key);
 
 /// Helper for parsing and displaying all TedSig keys.
 pub struct AllParticipantKeys<P: participant::Participant> {
@@ -419,6 +859,106 @@ impl core::convert::TryFrom<String> for AnyTedSigKeys {
     }
 }
 
+/// Parses the keys of both TEDs from two `ffa...` strings in any order.
+pub fn try_from_key_strings(first: &str, second: &str) -> Result<(AllParticipantKeys<participant::TedO>, AllParticipantKeys<participant::TedP>), TedKeysError> {
+    let first = first.parse::<AnyTedSigKeys>().map_err(TedKeysError::Parse)?;
+    let second = second.parse::<AnyTedSigKeys>().map_err(TedKeysError::Parse)?;
+    match (first, second) {
+        (AnyTedSigKeys::TedO(ted_o), AnyTedSigKeys::TedP(ted_p)) | (AnyTedSigKeys::TedP(ted_p), AnyTedSigKeys::TedO(ted_o)) => Ok((ted_o, ted_p)),
+        (AnyTedSigKeys::TedO(_), AnyTedSigKeys::TedO(_)) => Err(TedKeysError::DuplicateTedO),
+        (AnyTedSigKeys::TedP(_), AnyTedSigKeys::TedP(_)) => Err(TedKeysError::DuplicateTedP),
+    }
+}
+
+/// Computes the address the borrower is expected to fund.
+///
+/// This only needs public information so it can be used by a verifier that doesn't hold the
+/// borrower state to check the borrower is funding the correct address.
+pub fn expected_funding_address(offer: &Offer, borrower_info: &prefund::BorrowerSpendInfo) -> bitcoin::Address {
+    let keys = offer.prefund_keys.add_borrower_eph(borrower_info.key);
+    let (output_key, _) = prefund::compute_output_key(secp256k1::SECP256K1, keys, borrower_info.return_hash);
+    bitcoin::Address::p2tr_tweaked(output_key, offer.escrow.network)
+}
+
+/// Tag of the hash signed by Firefish when attesting TED keys.
+const TED_KEY_ATTESTATION_TAG: &[u8] = b"Firefish/TedKeyAttestation";
+
+/// Signature of Firefish confirming the TED keys of an offer.
+///
+/// The signature commits to the network, all four TED keys and the custom internal keys, if any.
+/// The `attester` key only identifies the signer, it is not trusted by itself - see
+/// [`verify_ted_key_attestation`].
+#[derive(Debug, Clone)]
+pub struct TedKeyAttestation {
+    pub attester: bitcoin::key::XOnlyPublicKey,
+    pub signature: secp256k1::schnorr::Signature,
+}
+
+impl TedKeyAttestation {
+    /// Attests the TED keys of the offer using the Firefish key.
+    pub fn sign(offer: &Offer, key_pair: &bitcoin::key::Keypair) -> Self {
+        TedKeyAttestation {
+            attester: key_pair.x_only_public_key().0,
+            signature: secp256k1::SECP256K1.sign_schnorr(&ted_key_attestation_message(offer), key_pair),
+        }
+    }
+}
+
+fn ted_key_attestation_message(offer: &Offer) -> secp256k1::Message {
+    use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+    let tag = sha256::Hash::hash(TED_KEY_ATTESTATION_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    let mut data = offer.escrow.network.magic().to_bytes().to_vec();
+    // The custom internal keys are attested too because whoever knows their discrete logarithm
+    // can spend the outputs. The default keys are omitted to keep the existing attestations valid.
+    if offer.has_custom_internal_key() {
+        offer.prefund_keys.serialize_with_internal_key(&mut data);
+        offer.escrow_keys.serialize_with_internal_key(&mut data);
+    } else {
+        offer.prefund_keys.serialize(&mut data);
+        offer.escrow_keys.serialize(&mut data);
+    }
+    engine.input(&data);
+    secp256k1::Message::from_digest(sha256::Hash::from_engine(engine).to_byte_array())
+}
+
+/// Checks that the TED keys of the offer were attested by the Firefish key.
+///
+/// The `firefish_key` must be obtained from a trusted source (e.g. hard-coded in the application),
+/// not from the same channel as the offer or the attestation. This should be done before sending
+/// funds to make sure the TEDs are the trusted parties.
+pub fn verify_ted_key_attestation(offer: &Offer, attestation: &TedKeyAttestation, firefish_key: &bitcoin::key::XOnlyPublicKey) -> Result<(), AttestationError> {
+    if attestation.attester != *firefish_key {
+        return Err(AttestationError::UntrustedAttester(attestation.attester));
+    }
+    secp256k1::SECP256K1.verify_schnorr(&attestation.signature, &ted_key_attestation_message(offer), firefish_key)
+        .map_err(AttestationError::InvalidSignature)
+}
+
+/// Error returned when the TED key attestation is invalid.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AttestationError {
+    /// The signature doesn't match the TED keys of the offer or the attester.
+    InvalidSignature(secp256k1::Error),
+    /// The attestation was signed by a key other than the trusted Firefish key.
+    UntrustedAttester(bitcoin::key::XOnlyPublicKey),
+}
+
+/// Error returned by [`try_from_key_strings`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TedKeysError {
+    Parse(TedSigKeysParseError),
+    /// Both strings contain TED-O keys.
+    DuplicateTedO,
+    /// Both strings contain TED-P keys.
+    DuplicateTedP,
+}
+
 #[derive(Debug)]
 pub enum TedSigKeysParseError {
     InvalidPrefix(String),
@@ -459,9 +999,14 @@ pub struct EscrowHints {
     /// Transactions in the mempool or chain that have the script in at least one of the outputs
     /// equal to the script generated by prefund.
     pub transactions: Vec<bitcoin::Transaction>,
+
+    /// The height of the best block at the time the hints were created, if known.
+    ///
+    /// Hints with the height are serialized as a different message for backwards compatibility.
+    pub height: Option<bitcoin::absolute::Height>,
 }
 
-crate::test_macros::impl_arbitrary!(EscrowHints, fee_rate, finalization_fee_bump_txout, escrow_fee_bump_txout, transactions);
+crate::test_macros::impl_arbitrary!(EscrowHints, fee_rate, finalization_fee_bump_txout, escrow_fee_bump_txout, transactions, height);
 
 impl EscrowHints {
     pub fn new(fee_rate: FeeRate, escrow_fee_bump_txout: bitcoin::TxOut, finalization_fee_bump_txout: bitcoin::TxOut, transactions: Vec<bitcoin::Transaction>) -> Self {
@@ -470,13 +1015,49 @@ impl EscrowHints {
             finalization_fee_bump_txout,
             escrow_fee_bump_txout,
             transactions,
+            height: None,
+        }
+    }
+
+    /// Returns `true` if the hints were created more than `max_age_blocks` blocks ago.
+    ///
+    /// The age of hints without height is unknown so they are never considered stale.
+    pub fn is_stale(&self, current_height: bitcoin::absolute::Height, max_age_blocks: u32) -> bool {
+        match self.height {
+            Some(height) => current_height.to_consensus_u32().saturating_sub(height.to_consensus_u32()) > max_age_blocks,
+            None => false,
+        }
+    }
+
+    /// Checks that the fee bump outputs are standard and not dust.
+    ///
+    /// Otherwise the contract transactions containing them wouldn't be relayed.
+    pub fn validate(&self) -> Result<(), EscrowHintsError> {
+        fn check(txout: &TxOut, output: FeeBumpOutput) -> Result<(), EscrowHintsError> {
+            let script = &txout.script_pubkey;
+            if !(script.is_p2pkh() || script.is_p2sh() || script.is_witness_program()) {
+                return Err(EscrowHintsError::NonStandardScript(output));
+            }
+            if txout.value < script.minimal_non_dust() {
+                return Err(EscrowHintsError::Dust(output));
+            }
+            Ok(())
         }
+
+        check(&self.escrow_fee_bump_txout, FeeBumpOutput::Escrow)?;
+        check(&self.finalization_fee_bump_txout, FeeBumpOutput::Finalization)
     }
 
     pub fn serialize(&self, buf: &mut Vec<u8>) {
         use bitcoin::consensus::Encodable;
 
-        buf.push(super::constants::MessageId::EscrowHints as u8);
+        match self.height {
+            Some(height) => {
+                buf.push(super::constants::MessageId::EscrowHintsWithHeight as u8);
+                buf.extend_from_slice(&height.to_consensus_u32().to_be_bytes());
+            },
+            None => buf.push(super::constants::MessageId::EscrowHints as u8),
+        }
         buf.extend_from_slice(&self.fee_rate.to_sat_per_kwu().to_be_bytes());
         self.escrow_fee_bump_txout.consensus_encode(buf).expect("vec doesn't error");
         self.finalization_fee_bump_txout.consensus_encode(buf).expect("vec doesn't error");
@@ -489,11 +1070,16 @@ impl EscrowHints {
     pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, EscrowHintsDeserError> {
         use bitcoin::consensus::Decodable;
 
-        let message_id = bytes.get(0).ok_or(super::deserialize::UnexpectedEnd)?;
-        if *message_id != super::constants::MessageId::EscrowHints as u8 {
-            return Err(EscrowHintsDeserErrorInner::InvalidMessageId(*message_id).into());
-        }
+        let message_id = *bytes.get(0).ok_or(super::deserialize::UnexpectedEnd)?;
         *bytes = &bytes[1..];
+        let height = if message_id == super::constants::MessageId::EscrowHints as u8 {
+            None
+        } else if message_id == super::constants::MessageId::EscrowHintsWithHeight as u8 {
+            let height = deserialize::be::<u32>(bytes)?;
+            Some(bitcoin::absolute::Height::from_consensus(height).map_err(EscrowHintsDeserErrorInner::InvalidHeight)?)
+        } else {
+            return Err(EscrowHintsDeserErrorInner::InvalidMessageId(message_id).into());
+        };
         let fee_rate = FeeRate::from_sat_per_kwu(deserialize::be(bytes)?);
         let escrow_fee_bump_txout = TxOut::consensus_decode(bytes)
             .map_err(EscrowHintsDeserErrorInner::InvalidTxOut)?;
@@ -510,10 +1096,26 @@ impl EscrowHints {
             escrow_fee_bump_txout,
             finalization_fee_bump_txout,
             transactions,
+            height,
         })
     }
 }
 
+/// Identifies the fee bump output in [`EscrowHints`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FeeBumpOutput {
+    Escrow,
+    Finalization,
+}
+
+/// Error returned by [`EscrowHints::validate`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum EscrowHintsError {
+    NonStandardScript(FeeBumpOutput),
+    Dust(FeeBumpOutput),
+}
+
 #[derive(Debug)]
 pub struct EscrowHintsDeserError(EscrowHintsDeserErrorInner);
 
@@ -529,6 +1131,7 @@ enum EscrowHintsDeserErrorInner {
     InvalidMessageId(u8),
     InvalidTxOut(bitcoin::consensus::encode::Error),
     InvalidTransaction(bitcoin::consensus::encode::Error),
+    InvalidHeight(bitcoin::absolute::ConversionError),
 }
 
 impl From<EscrowHintsDeserErrorInner> for EscrowHintsDeserError {
@@ -539,6 +1142,8 @@ impl From<EscrowHintsDeserErrorInner> for EscrowHintsDeserError {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     quickcheck::quickcheck! {
         fn tedsig_pub_keys_roundtrips(keys: super::TedSigPubKeys<super::context::Escrow>) -> bool {
             let mut bytes = Vec::new();
@@ -555,14 +1160,219 @@ mod tests {
             escrow_params2 == escrow_params && bytes.len() == 0
         }
 
+        fn escrow_params_new_validates(escrow_params: super::EscrowParams) -> bool {
+            use bitcoin::absolute::LockTime;
+
+            let p = escrow_params;
+            let count = p.extra_termination_outputs.len();
+            let new = |index, recover| super::EscrowParams::new(p.network, p.liquidator_script_default.clone(), p.liquidator_script_liquidation.clone(), p.min_collateral, p.extra_termination_outputs.clone(), index, LockTime::from_consensus(recover), LockTime::from_consensus(800_000));
+            new(count, 800_001).is_ok() &&
+                matches!(new(count + 1, 800_001), Err(super::EscrowParamsError::LiquidatorOutputIndexOutOfRange { .. })) &&
+                matches!(new(count, 800_000), Err(super::EscrowParamsError::LockTimeOrder(LockTimeOrderError::RecoverNotAfterDefault)))
+        }
+
+        fn offer_serialized_size_is_exact(offer: Offer) -> bool {
+            let mut bytes = Vec::new();
+            offer.serialize(&mut bytes);
+            offer.serialized_size() == bytes.len() && offer.fits_in(bytes.len()) && !offer.fits_in(bytes.len() - 1)
+        }
+
+        fn lock_time_order_validation(offer: Offer) -> bool {
+            use bitcoin::absolute::LockTime;
+
+            let with_lock_times = |default: u32, recover: u32| {
+                let mut offer = offer.clone();
+                offer.escrow.default_lock_time = LockTime::from_consensus(default);
+                offer.escrow.recover_lock_time = LockTime::from_consensus(recover);
+                offer.validate_lock_time_order()
+            };
+            with_lock_times(800_000, 800_001).is_ok() &&
+                matches!(with_lock_times(800_000, 800_000), Err(LockTimeOrderError::RecoverNotAfterDefault)) &&
+                matches!(with_lock_times(800_000, 1_700_000_000), Err(LockTimeOrderError::UnitMismatch))
+        }
+
+        fn ted_keys_from_strings(ted_o: PubKey<participant::TedO, context::Prefund>, ted_p: PubKey<participant::TedP, context::Prefund>) -> bool {
+            let ted_o_string = format!("ffaok{}{}", ted_o.as_x_only(), ted_p.as_x_only());
+            let ted_p_string = format!("ffapk{}{}", ted_p.as_x_only(), ted_o.as_x_only());
+            let in_order = try_from_key_strings(&ted_o_string, &ted_p_string).unwrap();
+            let reversed = try_from_key_strings(&ted_p_string, &ted_o_string).unwrap();
+            in_order.0.to_string() == ted_o_string && in_order.1.to_string() == ted_p_string &&
+                reversed.0.to_string() == ted_o_string && reversed.1.to_string() == ted_p_string &&
+                matches!(try_from_key_strings(&ted_o_string, &ted_o_string), Err(TedKeysError::DuplicateTedO)) &&
+                matches!(try_from_key_strings(&ted_p_string, &ted_p_string), Err(TedKeysError::DuplicateTedP))
+        }
+
+        fn into_offer_rejects_nonstandard_scripts(ted_o_prefund: PubKey<participant::TedO, context::Prefund>, ted_o_escrow: PubKey<participant::TedO, context::Escrow>, ted_p_prefund: PubKey<participant::TedP, context::Prefund>, ted_p_escrow: PubKey<participant::TedP, context::Escrow>) -> bool {
+            let key = bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(*ted_o_escrow.as_x_only());
+            let standard = bitcoin::ScriptBuf::new_p2tr_tweaked(key);
+            let non_standard = bitcoin::ScriptBuf::new_op_return(&[42]);
+            let fields = |default: &bitcoin::ScriptBuf, liquidation: &bitcoin::ScriptBuf| MandatoryOfferFields {
+                network: bitcoin::Network::Regtest,
+                liquidator_script_default: default.clone(),
+                liquidator_script_liquidation: liquidation.clone(),
+                min_collateral: bitcoin::Amount::from_sat(100_000),
+                recover_lock_time: bitcoin::absolute::LockTime::ZERO,
+                default_lock_time: bitcoin::absolute::LockTime::ZERO,
+                ted_o_keys: AllParticipantKeys { prefund: ted_o_prefund, escrow: ted_o_escrow },
+                ted_p_keys: AllParticipantKeys { prefund: ted_p_prefund, escrow: ted_p_escrow },
+            };
+            fields(&standard, &standard).into_offer().is_ok() &&
+                matches!(fields(&non_standard, &standard).into_offer(), Err(OfferError::NonStandardLiquidatorScriptDefault)) &&
+                matches!(fields(&standard, &non_standard).into_offer(), Err(OfferError::NonStandardLiquidatorScriptLiquidation))
+        }
+
+        fn offer_diff_lists_changed_fields(offer: Offer, min_collateral: u64) -> bool {
+            let min_collateral = bitcoin::Amount::from_sat(min_collateral);
+            let mut other = offer.clone();
+            other.escrow.min_collateral = min_collateral;
+            let expected = if min_collateral == offer.escrow.min_collateral {
+                Vec::new()
+            } else {
+                vec![OfferFieldDiff::MinCollateral(offer.escrow.min_collateral, min_collateral)]
+            };
+            offer.diff(&offer).is_empty() && offer.diff(&other) == expected
+        }
+
+        fn offer_key_reuse_detected(offer: Offer) -> bool {
+            let mut offer = offer;
+            // arbitrary keys are distinct with overwhelming probability
+            let liquidator_key = bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(*offer.escrow_keys.ted_p.as_x_only());
+            let distinct = offer.validate_key_distinctness().is_ok();
+            offer.escrow.liquidator_script_liquidation = bitcoin::ScriptBuf::new_p2tr_tweaked(liquidator_key);
+            let liquidator_reuse = matches!(offer.validate_key_distinctness(), Err(KeyReuseError::LiquidatorKey(_)));
+            offer.escrow_keys.ted_o = PubKey::new(*offer.prefund_keys.ted_p.as_x_only());
+            distinct && liquidator_reuse && matches!(offer.validate_key_distinctness(), Err(KeyReuseError::TedKey(_)))
+        }
+
+        fn offer_v0_compatibility(offer: Offer) -> bool {
+            let mut offer = offer;
+            let incompatible = (offer.escrow.liquidator_script_default == offer.escrow.liquidator_script_liquidation && !offer.has_custom_internal_key()) || offer.as_v0_compatible().is_none();
+            offer.escrow.liquidator_script_liquidation = offer.escrow.liquidator_script_default.clone();
+            offer.prefund_keys.internal_key = Default::default();
+            offer.escrow_keys.internal_key = Default::default();
+            let mut bytes = Vec::new();
+            offer.as_v0_compatible().unwrap().serialize(&mut bytes);
+            let mut v1_bytes = Vec::new();
+            offer.serialize(&mut v1_bytes);
+            let mut escrow_bytes = &bytes[129..];
+            let escrow = EscrowParams::deserialize(&mut escrow_bytes, EscrowParamsVersion::V0).unwrap();
+            incompatible && bytes[0] == 0 && bytes[1..129] == v1_bytes[1..129] && escrow == offer.escrow && escrow_bytes.is_empty()
+        }
+
+        fn v0_compatible_offer_roundtrip(offer: Offer) -> bool {
+            let mut offer = offer;
+            offer.escrow.liquidator_script_liquidation = offer.escrow.liquidator_script_default.clone();
+            offer.prefund_keys.internal_key = Default::default();
+            offer.escrow_keys.internal_key = Default::default();
+            let mut bytes = Vec::new();
+            offer.as_v0_compatible().unwrap().serialize(&mut bytes);
+            let mut cursor = &*bytes;
+            let deserialized = V0CompatibleOffer::deserialize(&mut cursor).unwrap();
+            let current_rejected = matches!(Offer::deserialize(&mut &*bytes), Err(DeserializationError::UnknownVersion(0)));
+            let mut v1_bytes = Vec::new();
+            offer.serialize(&mut v1_bytes);
+            let v1_rejected = matches!(V0CompatibleOffer::deserialize(&mut &*v1_bytes), Err(DeserializationError::UnknownVersion(1)));
+            deserialized.into_offer() == offer && cursor.is_empty() && current_rejected && v1_rejected
+        }
+
+        fn expected_funding_address_matches_borrower(offer: Offer) -> bool {
+            use super::super::participant::borrower::{MandatoryPrefundParams, PrefundDelay, WaitingForFunding};
+
+            let key_pair = bitcoin::key::Keypair::new_global(&mut rand::thread_rng());
+            let mut params = MandatoryPrefundParams {
+                key_pair,
+                lock_time: PrefundDelay::from_height(144),
+                return_script: bitcoin::ScriptBuf::new_p2tr(secp256k1::SECP256K1, key_pair.x_only_public_key().0, None),
+            }.into_params();
+            params.internal_key = offer.prefund_keys.internal_key;
+            let borrower = WaitingForFunding::new(offer.clone(), params).unwrap();
+            expected_funding_address(&offer, &borrower.borrower_info()) == borrower.funding_address()
+        }
+
+        fn offer_string_roundtrips(offer: Offer) -> bool {
+            let string = offer.to_string();
+            let unprefixed = &string[OFFER_PREFIX.len()..];
+            string.parse::<Offer>().unwrap() == offer &&
+                format!(" {}\n", string).parse::<Offer>().unwrap() == offer &&
+                matches!(unprefixed.parse::<Offer>(), Err(OfferParseError::NotAnOffer))
+        }
+
+        fn ted_key_attestation(offer: Offer, other: PubKey<participant::TedP, context::Escrow>) -> bool {
+            let firefish = bitcoin::key::Keypair::new_global(&mut rand::thread_rng());
+            let attestation = TedKeyAttestation::sign(&offer, &firefish);
+            let mut other_keys = offer.clone();
+            other_keys.escrow_keys.ted_p = other;
+            let mut other_internal_key = offer.clone();
+            other_internal_key.prefund_keys.internal_key = InternalKey::new(*other.as_x_only());
+            let firefish_key = firefish.x_only_public_key().0;
+            let mut other_attester = attestation.clone();
+            other_attester.attester = *offer.prefund_keys.ted_o.as_x_only();
+            // Properly signed but by a key the borrower doesn't trust.
+            let untrusted = bitcoin::key::Keypair::new_global(&mut rand::thread_rng());
+            let self_signed = TedKeyAttestation::sign(&offer, &untrusted);
+            verify_ted_key_attestation(&offer, &attestation, &firefish_key).is_ok() &&
+                matches!(verify_ted_key_attestation(&other_keys, &attestation, &firefish_key), Err(AttestationError::InvalidSignature(_))) &&
+                matches!(verify_ted_key_attestation(&other_internal_key, &attestation, &firefish_key), Err(AttestationError::InvalidSignature(_))) &&
+                matches!(verify_ted_key_attestation(&offer, &other_attester, &firefish_key), Err(AttestationError::UntrustedAttester(_))) &&
+                matches!(verify_ted_key_attestation(&offer, &self_signed, &firefish_key), Err(AttestationError::UntrustedAttester(key)) if key == untrusted.x_only_public_key().0) &&
+                verify_ted_key_attestation(&offer, &self_signed, &untrusted.x_only_public_key().0).is_ok()
+        }
+
         fn offer_roundtrips(offer: super::Offer) -> bool {
             let mut bytes = Vec::new();
             offer.serialize(&mut bytes);
+            let version = bytes[0];
             let mut bytes = &*bytes;
             let offer2 = super::Offer::deserialize(&mut bytes).unwrap();
-            offer2 == offer && bytes.len() == 0
+            offer2 == offer && offer2.prefund_keys.internal_key == offer.prefund_keys.internal_key && offer2.escrow_keys.internal_key == offer.escrow_keys.internal_key && bytes.len() == 0 && (version == 2) == offer.has_custom_internal_key()
         }
     }
 
     crate::test_macros::check_roundtrip!(roundtrip_escrow_hints, super::super::EscrowHints);
+
+    #[test]
+    fn escrow_params_v0_golden() {
+        use bitcoin::absolute::LockTime;
+
+        let v0 = hex_lit::hex!("fabfb5da00000000a0bb0d0000350c00a086010000000000160014111111111111111111111111111111111111111100000000");
+        let mut bytes = &v0[..];
+        let escrow_params = EscrowParams::deserialize(&mut bytes, EscrowParamsVersion::V0).unwrap();
+        let script = bitcoin::ScriptBuf::from(hex_lit::hex!("00141111111111111111111111111111111111111111").to_vec());
+        assert!(bytes.is_empty());
+        assert_eq!(escrow_params.network, bitcoin::Network::Regtest);
+        assert_eq!(escrow_params.liquidator_script_default, script);
+        assert_eq!(escrow_params.liquidator_script_liquidation, script);
+        assert_eq!(escrow_params.min_collateral, bitcoin::Amount::from_sat(100_000));
+        assert_eq!(escrow_params.recover_lock_time, LockTime::from_consensus(900_000));
+        assert_eq!(escrow_params.default_lock_time, LockTime::from_consensus(800_000));
+        assert_eq!(escrow_params.liquidator_output_index, 0);
+        assert!(escrow_params.extra_termination_outputs.is_empty());
+    }
+
+    #[test]
+    fn escrow_hints_staleness() {
+        let height = |h| bitcoin::absolute::Height::from_consensus(h).unwrap();
+        let txout = TxOut { value: bitcoin::Amount::ZERO, script_pubkey: bitcoin::ScriptBuf::new() };
+        let mut hints = EscrowHints::new(FeeRate::BROADCAST_MIN, txout.clone(), txout, Vec::new());
+        assert!(!hints.is_stale(height(800_100), 6));
+        hints.height = Some(height(800_000));
+        assert!(!hints.is_stale(height(800_006), 6));
+        assert!(hints.is_stale(height(800_007), 6));
+        assert!(!hints.is_stale(height(799_000), 6));
+    }
+
+    #[test]
+    fn escrow_hints_validation() {
+        use bitcoin::hashes::Hash;
+
+        let script = bitcoin::ScriptBuf::new_p2wsh(&bitcoin::WScriptHash::all_zeros());
+        let valid = TxOut::minimal_non_dust(script.clone());
+        let dust = TxOut { value: valid.value - bitcoin::Amount::from_sat(1), script_pubkey: script };
+        let non_standard = TxOut { value: valid.value, script_pubkey: bitcoin::ScriptBuf::new_op_return(&[42]) };
+        let hints = |escrow: &TxOut, finalization: &TxOut| EscrowHints::new(FeeRate::BROADCAST_MIN, escrow.clone(), finalization.clone(), Vec::new());
+
+        assert!(hints(&valid, &valid).validate().is_ok());
+        assert!(matches!(hints(&dust, &valid).validate(), Err(EscrowHintsError::Dust(FeeBumpOutput::Escrow))));
+        assert!(matches!(hints(&valid, &non_standard).validate(), Err(EscrowHintsError::NonStandardScript(FeeBumpOutput::Finalization))));
+    }
 }
@@ -1,17 +1,21 @@
-use bitcoin::{Transaction, Sequence, OutPoint, Script, ScriptBuf, Address, TxOut, Amount};
+use bitcoin::{Transaction, Sequence, OutPoint, Script, ScriptBuf, Address, TxOut, Txid, Amount};
 use bitcoin::locktime::absolute::{LockTime, Height};
 use bitcoin::key::Keypair;
 use bitcoin::blockdata::{Weight, FeeRate};
 use bitcoin::blockdata::transaction::InputWeightPrediction;
 use core::convert::{TryFrom, TryInto};
+use core::time::Duration;
 use super::super::{prefund, escrow, context, deserialize};
 use super::super::offer::{self, Offer};
-use super::super::pub_keys::PubKey;
+use super::super::pub_keys::{self, PubKey};
 use super::super::constants;
 use secp256k1::SECP256K1;
 
 use crate::contract::primitives::SpendableTxo;
 
+/// Tag of the hash used to derive the escrow ephemeral key from the prefund key.
+const ESCROW_KEY_TAG: &[u8] = b"Firefish/EscrowEphemeralKey";
+
 #[derive(Debug, PartialEq, Clone)]
 #[non_exhaustive]
 pub struct PrefundData {
@@ -27,6 +31,11 @@ impl PrefundData {
         let tapscript = pub_key.borrower_prefund_script(self.prefund_lock_time);
         (pub_key, tapscript)
     }
+
+    /// Returns the relative lock time after which the borrower can recover the prefund.
+    pub fn recovery_delay(&self) -> Sequence {
+        self.prefund_lock_time
+    }
 }
 
 impl super::super::HotKey for PrefundData {
@@ -53,6 +62,9 @@ impl super::super::Deserialize for PrefundData {
         match version {
             deserialize::StateVersion::V0 => (),
             deserialize::StateVersion::V1 => (),
+            deserialize::StateVersion::V2 => (),
+            deserialize::StateVersion::V3 => (),
+            deserialize::StateVersion::V4 => (),
         }
         if bytes.len() < 36 {
             return Err(PrefundDataDeserError(PrefundDataDeserErrorInner::UnexpectedEnd));
@@ -79,6 +91,10 @@ enum PrefundDataDeserErrorInner {
 pub struct EscrowData {
     prefund: prefund::Prefund<super::Borrower>,
     return_script: ScriptBuf,
+    /// The ephemeral escrow key, known once the escrow transactions were built.
+    ///
+    /// States older than `V4` don't store it.
+    escrow_key_pair: Option<Keypair>,
 }
 
 impl super::PrefundData for EscrowData {
@@ -89,7 +105,31 @@ impl super::PrefundData for EscrowData {
     }
 }
 
-crate::test_macros::impl_arbitrary!(EscrowData, prefund, return_script);
+crate::test_macros::impl_arbitrary!(EscrowData, prefund, return_script, escrow_key_pair);
+
+impl EscrowData {
+    /// Checks that the return script is a standard script spendable on the network.
+    ///
+    /// This should be called before assembling the escrow so that the recovered funds can't end
+    /// up in an unspendable output.
+    pub fn validate_return_script(&self, network: bitcoin::Network) -> Result<(), ReturnScriptError> {
+        let address = Address::from_script(&self.return_script, network)
+            .map_err(|_| ReturnScriptError::NonStandard)?;
+        // Unknown witness versions are not spendable by any wallet today
+        if address.address_type().is_none() {
+            return Err(ReturnScriptError::UnknownAddressType);
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`EscrowData::validate_return_script`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReturnScriptError {
+    NonStandard,
+    UnknownAddressType,
+}
 
 impl super::super::Serialize for EscrowData {
     fn serialize(&self, out: &mut Vec<u8>) {
@@ -98,6 +138,13 @@ impl super::super::Serialize for EscrowData {
         out.push(constants::state_id::BORROWER_ESCROW_DATA);
         self.return_script.consensus_encode(out).expect("vec doesn't error");
         self.prefund.serialize(out);
+        match &self.escrow_key_pair {
+            Some(key_pair) => {
+                out.push(1);
+                out.extend_from_slice(&key_pair.secret_bytes());
+            },
+            None => out.push(0),
+        }
     }
 }
 
@@ -116,10 +163,23 @@ impl super::super::Deserialize for EscrowData {
         *bytes = &bytes[1..];
         let return_script = ScriptBuf::consensus_decode(bytes).map_err(EscrowDataDeserErrorInner::Consensus)?;
         let prefund = prefund::Prefund::deserialize(bytes, version).map_err(EscrowDataDeserErrorInner::Prefund)?;
+        let escrow_key_pair = match version {
+            deserialize::StateVersion::V0 | deserialize::StateVersion::V1 | deserialize::StateVersion::V2 | deserialize::StateVersion::V3 => None,
+            deserialize::StateVersion::V4 => {
+                let (flag, rest) = bytes.split_first().ok_or(EscrowDataDeserErrorInner::UnexpectedEnd)?;
+                *bytes = rest;
+                match flag {
+                    0 => None,
+                    1 => Some(deserialize::key_pair(bytes).map_err(EscrowDataDeserErrorInner::Secp256k1)?),
+                    flag => return Err(EscrowDataDeserErrorInner::InvalidKeyFlag(*flag).into()),
+                }
+            },
+        };
 
         Ok(EscrowData {
             prefund,
             return_script,
+            escrow_key_pair,
         })
     }
 }
@@ -139,10 +199,12 @@ enum EscrowDataDeserErrorInner {
     InvalidState(u8),
     Consensus(bitcoin::consensus::encode::Error),
     Prefund(<prefund::Prefund<super::Borrower> as super::super::Deserialize>::Error),
+    InvalidKeyFlag(u8),
+    Secp256k1(secp256k1::Error),
 }
 
 /// A convenient alias for [`WaitingForFunding::new`]
-pub fn init_prefund(offer: Offer, params: PrefundParams) -> WaitingForFunding {
+pub fn init_prefund(offer: Offer, params: PrefundParams) -> Result<WaitingForFunding, offer::InternalKeyMismatch> {
     WaitingForFunding::new(offer, params)
 }
 
@@ -154,17 +216,19 @@ pub struct WaitingForFunding {
 crate::test_macros::impl_arbitrary!(WaitingForFunding, escrow);
 
 impl WaitingForFunding {
-    pub fn new(offer: Offer, params: PrefundParams) -> Self {
-        use bitcoin::taproot::LeafVersion;
-
+    /// Creates the borrower state from the offer.
+    ///
+    /// Returns an error if the offer uses an internal key other than
+    /// [`PrefundParams::internal_key`].
+    pub fn new(offer: Offer, params: PrefundParams) -> Result<Self, offer::InternalKeyMismatch> {
+        offer.validate_internal_key(params.internal_key)?;
         let prefund = PrefundData {
             key_pair: params.mandatory.key_pair,
-            prefund_lock_time: params.mandatory.lock_time,
+            prefund_lock_time: params.mandatory.lock_time.to_sequence(),
         };
         let (pub_key, tapscript) = prefund.borrower_key_and_leaf_script();
         let receiver = prefund::ReceivingBorrowerInfo::with_participant_data(offer.prefund_keys, offer.escrow.network, prefund);
-        let leaf_hash = bitcoin::sighash::ScriptPath::new(&tapscript, LeafVersion::TapScript)
-            .leaf_hash();
+        let leaf_hash = pub_keys::leaf_hash(&tapscript);
         let borrower_info = prefund::BorrowerSpendInfo {
             key: pub_key,
             return_hash: leaf_hash.into(),
@@ -174,14 +238,17 @@ impl WaitingForFunding {
         let escrow_data = EscrowData {
             prefund,
             return_script: params.mandatory.return_script,
+            escrow_key_pair: None,
         };
         let escrow = escrow::ReceivingBorrowerInfo::with_participant_data(offer.escrow, offer.escrow_keys, escrow_data);
-        WaitingForFunding {
+        Ok(WaitingForFunding {
             escrow,
-        }
+        })
     }
 
-    fn from_escrow_data_and_offer(escrow_data: EscrowData, offer: Offer) -> Self {
+    fn from_escrow_data_and_offer(escrow_data: EscrowData, mut offer: Offer) -> Self {
+        // The internal key was checked when the contract was created, the offer must not change it.
+        offer.escrow_keys.internal_key = escrow_data.prefund.keys().internal_key;
         let escrow = escrow::ReceivingBorrowerInfo::with_participant_data(offer.escrow, offer.escrow_keys, escrow_data);
         WaitingForFunding {
             escrow,
@@ -201,62 +268,120 @@ impl WaitingForFunding {
         data.prefund.funding_address()
     }
 
+    /// Returns the Electrum-style hash of the funding script.
+    pub fn funding_script_hash(&self) -> bitcoin::hashes::sha256::Hash {
+        self.escrow.participant_data.prefund.funding_script_hash()
+    }
+
     pub fn liquidator_amount(&self) -> Amount {
         self.escrow.params.min_collateral
     }
 
+    /// Returns the delay after which the borrower can recover the prefund if the contract fails.
+    pub fn recovery_delay(&self) -> PrefundDelay {
+        PrefundDelay(self.escrow.participant_data.prefund.participant_data.recovery_delay())
+    }
+
+    /// Derives the escrow ephemeral key from the prefund key.
+    ///
+    /// Passing this key in [`Funding::escrow_key_pair`] allows reconstructing the whole contract
+    /// from the prefund key and the offer.
+    pub fn deterministic_escrow_key_pair(&self) -> Keypair {
+        use bitcoin::hashes::{sha256, Hash, HashEngine};
+
+        let prefund_key = &self.escrow.participant_data.prefund.participant_data.key_pair;
+        let tag = sha256::Hash::hash(ESCROW_KEY_TAG);
+        let mut engine = sha256::Hash::engine();
+        engine.input(tag.as_ref());
+        engine.input(tag.as_ref());
+        engine.input(&prefund_key.secret_bytes());
+        let hash = sha256::Hash::from_engine(engine);
+        Keypair::from_seckey_slice(SECP256K1, hash.as_ref())
+            .expect("the probability of hash being an invalid key is negligible")
+    }
+
     pub fn funding_received(self, funding: Funding, message: &mut Vec<u8>) -> Result<escrow::ReceivingEscrowSignature<super::Borrower>, (Self, FundingError)> {
+        if funding.escrow_contract_output_position as usize > funding.escrow_extra_outputs.len() {
+            let error = FundingError {
+                reason: FundingErrorReason::ContractOutputPositionOutOfRange {
+                    position: funding.escrow_contract_output_position,
+                    extra_output_count: funding.escrow_extra_outputs.len(),
+                },
+            };
+            return Err((self, error));
+        }
+
         let escrow_data = &self.escrow.participant_data;
         let prefund = &escrow_data.prefund;
 
         let funding_script = prefund.funding_script();
-        let eph_key_pair = Keypair::new_global(&mut rand::thread_rng());
+        let eph_key_pair = funding.escrow_key_pair.unwrap_or_else(|| Keypair::new_global(&mut rand::thread_rng()));
         let eph_pubkey = PubKey::new(eph_key_pair.x_only_public_key().0);
         //let escrow_output = escrow.escrow_output(eph_pubkey);
 
+        // The escrow address can only be known in advance if the key was supplied, otherwise it's
+        // random.
+        let sent_to_escrow = funding.escrow_key_pair.is_some() && {
+            let escrow_script = ScriptBuf::new_p2tr_tweaked(self.escrow.escrow_spend_info(eph_pubkey).output_key());
+            funding.mandatory.transactions.iter()
+                .flat_map(|tx| &tx.output)
+                .any(|txout| txout.script_pubkey == escrow_script)
+        };
+
         let mut max_lock_height = Height::from_consensus(0).expect("zero blocks is valid height");
-        let txos = extract_spendable_outputs(funding.mandatory.transactions, &mut max_lock_height, |script| *script == funding_script);
+        let mut skipped_dust = false;
+        // Maps the funding outputs to their position in the shuffled order.
+        let input_ranks = funding.escrow_input_order.as_ref().map(|order| {
+            let out_points = funding.mandatory.transactions.iter()
+                .flat_map(|tx| {
+                    let txid = tx.compute_txid();
+                    (0..tx.output.len()).map(move |vout| OutPoint { txid, vout: vout as u32 })
+                })
+                .collect::<Vec<_>>();
+            let mut ranks = std::collections::HashMap::new();
+            for (rank, index) in order.iter().enumerate() {
+                if let Some(out_point) = out_points.get(*index) {
+                    ranks.entry(*out_point).or_insert(rank);
+                }
+            }
+            ranks
+        });
+        let mut txos = extract_spendable_outputs(funding.mandatory.transactions, &mut max_lock_height, &mut skipped_dust, |script| *script == funding_script);
+        if let Some(ranks) = input_ranks {
+            txos.sort_by_key(|txo| ranks.get(&txo.out_point).copied().unwrap_or(usize::MAX));
+        }
 
         if txos.is_empty() {
-            let error = FundingError {
-                reason: FundingErrorReason::NoMatchingOutputs,
+            let reason = if sent_to_escrow {
+                FundingErrorReason::SentToEscrowAddress
+            } else {
+                FundingErrorReason::no_spendable_outputs(skipped_dust)
             };
-            return Err((self, error));
+            return Err((self, FundingError { reason }));
         }
 
+        if let Some(current_height) = funding.current_height {
+            let max_plausible = current_height.to_consensus_u32().saturating_add(MAX_LOCK_HEIGHT_WINDOW);
+            if max_lock_height.to_consensus_u32() > max_plausible {
+                let error = FundingError {
+                    reason: FundingErrorReason::ImplausibleLockHeight { lock_height: max_lock_height, current_height },
+                };
+                return Err((self, error));
+            }
+        }
+
+        let escrow_lock_height = match (funding.randomize_lock_time, funding.current_height) {
+            (true, Some(current_height)) => anti_fee_sniping_height(current_height, &mut rand::thread_rng()),
+            _ => max_lock_height,
+        };
+
         // We can't simply instantiate `UnsignedTransactions` and call `size()` on each because
         // they don't have the witnesses filled so the calulation would be wrong.
         // Thus we have to predict fees based on expected sizes.
         // In case of prefund there's an exact, known size.
-        let prefund_witness_elem_sizes = &[
-            64, // len of signature1
-            64, // len of signature2
-            64, // len of signature3
-                  33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1  // len of OP_CHECKSIGVERIFY
-                + 33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1  // len of OP_CHECKSIGVERIFY
-                + 33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1, // len of OP_CHECKSIG
-                  33  // base len of control block
-                + 32  // len of the hash hiding the borrower conditions
-        ];
-        let prefund_spend_input_prediction = InputWeightPrediction::new(0, prefund_witness_elem_sizes.iter().copied());
-
-        let escrow_witness_elem_sizes = &[
-            64, // len of signature1
-            64, // len of signature2
-            64, // len of signature3
-                  33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1  // len of OP_CHECKSIGVERIFY
-                + 33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1  // len of OP_CHECKSIGVERIFY
-                + 33  // len of push_x_only_key (1 instr + 32 B data)
-                +  1, // len of OP_CHECKSIG
-                  33  // base len of control block
-                      // note: there's only one script so no other nodes
-        ];
-        let escrow_spend_input_prediction = InputWeightPrediction::new(0, escrow_witness_elem_sizes.iter().copied());
+        let prefund_spend_input_prediction = prefund::multisig_spend_input_prediction();
+
+        let escrow_spend_input_prediction = escrow::multisig_spend_input_prediction();
 
         // witness version (1B) + OP_PUSHBYTES_32 + x-only key (32 B)
         let escrow_out_script_lengths = core::iter::once(1 + 1 + 32)
@@ -265,7 +390,9 @@ impl WaitingForFunding {
         let repayment_out_script_lengths = core::iter::once(escrow_data.return_script.len())
             .chain(funding.repayment_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
         let repayment_weight = predict_tx_weight(1, escrow_spend_input_prediction, repayment_out_script_lengths);
+        let recover_secondary_script_len = funding.recover_split.as_ref().map(|split| split.secondary_script.len());
         let recover_out_script_lengths = core::iter::once(escrow_data.return_script.len())
+            .chain(recover_secondary_script_len)
             .chain(funding.recover_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
         let recover_weight = predict_tx_weight(1, escrow_spend_input_prediction, recover_out_script_lengths);
         let default_out_script_lengths = self.escrow.params.extra_termination_outputs.iter()
@@ -297,13 +424,60 @@ impl WaitingForFunding {
         if escrow_funding_amount < required_funding_amount {
             return Err((self, FundingError { reason: FundingErrorReason::Underfunded { required: required_funding_amount, available: escrow_funding_amount }}));
         }
-        let escrow_amount = escrow_funding_amount - escrow_cost;
-        let recover_txout = TxOut {
-            value: escrow_amount - recover_fee - recover_extra_amount,
-            script_pubkey: escrow_data.return_script.clone(),
-        };
+        let mut escrow_extra_outputs = funding.escrow_extra_outputs;
+        let mut escrow_contract_output_position = funding.escrow_contract_output_position;
+        let mut escrow_amount = escrow_funding_amount - escrow_cost;
+        if let Some(change_script) = &funding.overfunding_change {
+            let escrow_out_script_lengths = core::iter::once(1 + 1 + 32)
+                .chain(escrow_extra_outputs.iter().map(|txout| txout.script_pubkey.len()))
+                .chain(core::iter::once(change_script.len()));
+            let escrow_weight_with_change = predict_tx_weight(txos.len(), prefund_spend_input_prediction, escrow_out_script_lengths);
+            let change_fee = escrow_weight_with_change * funding.mandatory.escrow_fee_rate - escrow_fee;
+            let excess = escrow_funding_amount - required_funding_amount;
+            // If the change would be dust it's simply left in the escrow
+            if let Some(change) = excess.checked_sub(change_fee).filter(|change| *change >= change_script.minimal_non_dust()) {
+                let change_txout = TxOut { value: change, script_pubkey: change_script.clone() };
+                // The position is among all escrow outputs, including the contract output.
+                let position = funding.escrow_change_position
+                    .map_or(usize::MAX, |position| position as usize)
+                    .min(escrow_extra_outputs.len() + 1);
+                if position <= escrow_contract_output_position as usize {
+                    escrow_extra_outputs.insert(position, change_txout);
+                    escrow_contract_output_position += 1;
+                } else {
+                    escrow_extra_outputs.insert(position - 1, change_txout);
+                }
+                escrow_amount = required_escrow_amount;
+            }
+        }
+        let recover_amount = escrow_amount - recover_fee - recover_extra_amount;
         let mut recover_outputs = funding.recover_extra_outputs;
-        recover_outputs.push(recover_txout);
+        match funding.recover_split {
+            Some(split) => {
+                let secondary_amount = u128::from(recover_amount.to_sat()) * u128::from(split.secondary_percent) / 100;
+                let secondary_amount = Amount::from_sat(secondary_amount as u64);
+                let primary_txout = TxOut {
+                    value: recover_amount - secondary_amount,
+                    script_pubkey: escrow_data.return_script.clone(),
+                };
+                let secondary_txout = TxOut {
+                    value: secondary_amount,
+                    script_pubkey: split.secondary_script,
+                };
+                if primary_txout.value < primary_txout.script_pubkey.minimal_non_dust() || secondary_txout.value < secondary_txout.script_pubkey.minimal_non_dust() {
+                    return Err((self, FundingError { reason: FundingErrorReason::RecoverSplitDust }));
+                }
+                recover_outputs.push(primary_txout);
+                recover_outputs.push(secondary_txout);
+            },
+            None => {
+                let recover_txout = TxOut {
+                    value: recover_amount,
+                    script_pubkey: escrow_data.return_script.clone(),
+                };
+                recover_outputs.push(recover_txout);
+            },
+        }
         let repayment_txout = TxOut {
             value: escrow_amount - repayment_fee - repayment_extra_amount,
             script_pubkey: escrow_data.return_script.clone(),
@@ -319,10 +493,10 @@ impl WaitingForFunding {
         // Borrower info created by the borrower is always valid
         let info = escrow::BorrowerInfo::<escrow::validation::Validated> {
             inputs: txos,
-            tx_height: max_lock_height,
+            tx_height: escrow_lock_height,
             escrow_eph_key: eph_pubkey,
-            escrow_extra_outputs: funding.escrow_extra_outputs,
-            escrow_contract_output_position: funding.escrow_contract_output_position,
+            escrow_extra_outputs,
+            escrow_contract_output_position,
             escrow_amount,
             collateral_amount_default,
             collateral_amount_liquidation,
@@ -336,13 +510,45 @@ impl WaitingForFunding {
 
         sigs.serialize(message);
 
-        Ok(self.escrow.transactions_validated(transactions, sigs.recover, sigs.repayment))
+        let mut escrow = self.escrow;
+        escrow.participant_data.escrow_key_pair = Some(eph_key_pair);
+        Ok(escrow.transactions_validated(transactions, sigs.recover, sigs.repayment))
     }
 
     pub fn funding_cancel(&self, transactions: Vec<Transaction>, fee_rate: FeeRate, current_height: Height, delay_rtl: RelativeDelay) -> Result<Transaction, FundingError> {
         self.escrow.participant_data.funding_cancel(transactions, fee_rate, current_height, delay_rtl)
     }
 
+    /// Compares the fee of cancelling the prefund with the fees of completing the loan.
+    ///
+    /// The cancel fee is predicted at `fee_rate` the same way as in `funding_cancel` while the
+    /// completion fees (escrow and repayment) use the fee rates in `funding`. Both spend the same
+    /// prefund outputs so the UI can show the user how much more cancelling costs.
+    pub fn cancel_fee_comparison(&self, funding: &Funding, fee_rate: FeeRate) -> Result<CancelFeeComparison, FundingError> {
+        let escrow_data = &self.escrow.participant_data;
+        let funding_script = escrow_data.prefund.funding_script();
+
+        let mut max_lock_height = Height::from_consensus(0).expect("zero blocks is valid height");
+        let mut skipped_dust = false;
+        let input_count = extract_spendable_outputs(funding.mandatory.transactions.iter().cloned(), &mut max_lock_height, &mut skipped_dust, |script| *script == funding_script).len();
+        if input_count == 0 {
+            return Err(FundingError { reason: FundingErrorReason::no_spendable_outputs(skipped_dust) });
+        }
+
+        let cancel_fee = escrow_data.prefund.funding_cancel_weight(input_count, escrow_data.return_script.len()) * fee_rate;
+
+        // witness version (1B) + OP_PUSHBYTES_32 + x-only key (32 B)
+        let escrow_out_script_lengths = core::iter::once(1 + 1 + 32)
+            .chain(funding.escrow_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
+        let escrow_weight = predict_tx_weight(input_count, prefund::multisig_spend_input_prediction(), escrow_out_script_lengths);
+        let repayment_out_script_lengths = core::iter::once(escrow_data.return_script.len())
+            .chain(funding.repayment_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
+        let repayment_weight = predict_tx_weight(1, escrow::multisig_spend_input_prediction(), repayment_out_script_lengths);
+        let completion_fee = escrow_weight * funding.mandatory.escrow_fee_rate + repayment_weight * funding.mandatory.finalization_fee_rate;
+
+        Ok(CancelFeeComparison { cancel_fee, completion_fee })
+    }
+
     pub fn serialize(&self, out: &mut Vec<u8>) {
         use super::super::Serialize;
 
@@ -386,11 +592,12 @@ impl prefund::Prefund<super::Borrower> {
         let funding_script = self.funding_script();
 
         let mut max_lock_height = Height::from_consensus(0).expect("zero blocks is valid height");
-        let mut txos = extract_spendable_outputs(transactions, &mut max_lock_height, |script| *script == funding_script);
+        let mut skipped_dust = false;
+        let mut txos = extract_spendable_outputs(transactions, &mut max_lock_height, &mut skipped_dust, |script| *script == funding_script);
 
         if txos.is_empty() {
             let error = FundingError {
-                reason: FundingErrorReason::NoMatchingOutputs,
+                reason: FundingErrorReason::no_spendable_outputs(skipped_dust),
             };
             return Err(error);
         }
@@ -400,18 +607,7 @@ impl prefund::Prefund<super::Borrower> {
             txo.sequence = sequence;
         }
 
-        let (_, leaf_script) = self.participant_data.borrower_key_and_leaf_script();
-
-        let witness_elem_sizes = [
-            64, // len of schnorr signature
-            leaf_script.len(),
-
-              33 // base len of control block
-            + 32 // len of merkle proof
-        ];
-        let input_weight_prediction = InputWeightPrediction::new(0, witness_elem_sizes.iter().copied());
-        let return_script_len = return_script.len();
-        let weight = predict_tx_weight(txos.len(), input_weight_prediction, core::iter::once(return_script_len));
+        let weight = self.funding_cancel_weight(txos.len(), return_script.len());
         let total_input_amount = txos.iter()
             .map(|txo| txo.tx_out.value)
             .sum::<Amount>();
@@ -431,6 +627,44 @@ impl prefund::Prefund<super::Borrower> {
 
         Ok(self.spend_borrower(txos, vec![tx_out], current_height))
     }
+
+    /// Predicts the weight of the cancel transaction spending `input_count` prefund outputs.
+    fn funding_cancel_weight(&self, input_count: usize, return_script_len: usize) -> Weight {
+        predict_tx_weight(input_count, self.recovery_spend_input_prediction(), core::iter::once(return_script_len))
+    }
+
+    /// Predicts the weight of an input spending prefund using the borrower recovery script.
+    fn recovery_spend_input_prediction(&self) -> InputWeightPrediction {
+        let (_, leaf_script) = self.participant_data.borrower_key_and_leaf_script();
+
+        let witness_elem_sizes = [
+            64, // len of schnorr signature
+            leaf_script.len(),
+
+              33 // base len of control block
+            + 32 // len of merkle proof
+        ];
+        InputWeightPrediction::new(0, witness_elem_sizes.iter().copied())
+    }
+}
+
+/// Fees of cancelling the prefund and of completing the loan as returned by
+/// `WaitingForFunding::cancel_fee_comparison`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CancelFeeComparison {
+    /// The fee of the cancel transaction.
+    pub cancel_fee: Amount,
+    /// The sum of the escrow and repayment fees.
+    pub completion_fee: Amount,
+}
+
+impl CancelFeeComparison {
+    /// Returns how much more cancelling costs than completing the loan.
+    ///
+    /// The result is negative if cancelling is cheaper, e.g. when the current fee rate is low.
+    pub fn cancel_extra_cost(&self) -> bitcoin::SignedAmount {
+        bitcoin::SignedAmount::from_sat(self.cancel_fee.to_sat() as i64 - self.completion_fee.to_sat() as i64)
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -441,6 +675,22 @@ pub enum RelativeDelay {
 }
 
 impl RelativeDelay {
+    /// Returns the approximate time it takes for the delay to pass.
+    ///
+    /// Blocks are assumed to be mined every 10 minutes on average while time units are exactly
+    /// 512 seconds. Returns `None` if the delay doesn't fit into a relative lock time.
+    pub fn approx_duration(&self) -> Option<Duration> {
+        const SECONDS_PER_BLOCK: u64 = 600;
+        const SECONDS_PER_TIME_UNIT: u64 = 512;
+
+        match *self {
+            RelativeDelay::Height(blocks) if blocks <= u16::MAX.into() => Some(Duration::from_secs(u64::from(blocks) * SECONDS_PER_BLOCK)),
+            RelativeDelay::TimeUnits(units) if units <= u16::MAX.into() => Some(Duration::from_secs(u64::from(units) * SECONDS_PER_TIME_UNIT)),
+            RelativeDelay::Height(_) | RelativeDelay::TimeUnits(_) => None,
+            RelativeDelay::Zero => Some(Duration::ZERO),
+        }
+    }
+
     fn offset_sequence(self, sequence: bitcoin::Sequence) -> Result<bitcoin::Sequence, FundingError> {
         match (self, sequence.is_height_locked(), sequence.is_time_locked()) {
             (RelativeDelay::Zero, _, _) => Ok(sequence),
@@ -495,6 +745,25 @@ impl From<deserialize::StateVersionDeserError> for WaitingForFundingErrorInner {
     }
 }
 
+/// How many blocks above the current height can the lock time of funding transactions be.
+///
+/// Transactions with lock time above the next block can not be mined but the height known to the
+/// borrower may be slightly outdated so we allow some slack.
+const MAX_LOCK_HEIGHT_WINDOW: u32 = 6;
+
+/// How far below the current height the randomized escrow lock time may be.
+const ANTI_FEE_SNIPING_WINDOW: u32 = 100;
+
+/// Returns a random height at most `ANTI_FEE_SNIPING_WINDOW` blocks below the current height.
+///
+/// Since the returned height is never above the current one the transaction stays immediately
+/// minable.
+fn anti_fee_sniping_height(current_height: Height, rng: &mut impl rand::Rng) -> Height {
+    let offset = rng.gen_range(0..ANTI_FEE_SNIPING_WINDOW);
+    Height::from_consensus(current_height.to_consensus_u32().saturating_sub(offset))
+        .expect("lower than a valid height")
+}
+
 #[non_exhaustive]
 pub struct Funding {
     pub mandatory: MandatoryFundingParams,
@@ -502,6 +771,98 @@ pub struct Funding {
     pub escrow_contract_output_position: u32,
     pub repayment_extra_outputs: Vec<TxOut>,
     pub recover_extra_outputs: Vec<TxOut>,
+    /// The height of the current best block, if known.
+    ///
+    /// If set, funding transactions with a lock time far above this height are rejected so that
+    /// they can't force an absurd lock time onto the escrow transaction.
+    pub current_height: Option<Height>,
+    /// The escrow ephemeral key to use instead of a randomly generated one.
+    ///
+    /// See [`WaitingForFunding::deterministic_escrow_key_pair`].
+    pub escrow_key_pair: Option<Keypair>,
+    /// Picks the lock time of the escrow transaction randomly slightly below `current_height`.
+    ///
+    /// This follows the anti-fee-sniping practice of other wallets improving privacy. It has no
+    /// effect if `current_height` is `None` in which case the highest lock time of the funding
+    /// transactions is used.
+    pub randomize_lock_time: bool,
+    /// Splits the recovered amount between the return script and a secondary backup script.
+    pub recover_split: Option<RecoverSplit>,
+    /// Sends the funds exceeding the required amount to this script right away.
+    ///
+    /// By default the excess is locked in the escrow and returned on repayment or recovery. If
+    /// the change would be dust it stays in the escrow anyway. The script should be a fresh
+    /// address since reusing the return script would link it to the escrow on-chain.
+    pub overfunding_change: Option<ScriptBuf>,
+    /// The position of the overfunding change among all escrow outputs, set by
+    /// [`Funding::shuffle_escrow_outputs`].
+    ///
+    /// If `None` the change is the last output.
+    pub escrow_change_position: Option<u32>,
+    /// The order of the escrow inputs, set by [`Funding::shuffle_escrow_inputs`].
+    ///
+    /// Contains the indices of the outputs of all funding transactions concatenated. If `None` the
+    /// inputs are ordered the same way as the funding outputs.
+    pub escrow_input_order: Option<Vec<usize>>,
+}
+
+/// The maximum size of `OP_RETURN` data relayed by default.
+const MAX_OP_RETURN_DATA: usize = 80;
+
+/// Error returned when an `OP_RETURN` output can't be added to the escrow transaction.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum OpReturnError {
+    /// The data exceeds the standard limit, contains the length of the rejected data.
+    TooLarge(usize),
+    /// The escrow transaction already has an `OP_RETURN` output and more than one is not
+    /// standard.
+    AlreadyPresent,
+}
+
+/// Sends a part of the recovered satoshis to a secondary script.
+///
+/// This way the recovery doesn't depend on a single key being available.
+#[derive(Debug, Clone)]
+pub struct RecoverSplit {
+    secondary_script: ScriptBuf,
+    secondary_percent: u8,
+}
+
+impl RecoverSplit {
+    /// Sends `secondary_percent` percent of the recovered amount to `secondary_script`.
+    ///
+    /// The remainder goes to the return script so the percentage must be between 1 and 99.
+    pub fn new(secondary_script: ScriptBuf, secondary_percent: u8) -> Result<Self, RecoverSplitError> {
+        match secondary_percent {
+            0 => Err(RecoverSplitError::NothingToSecondary),
+            100 => Err(RecoverSplitError::NothingToReturn),
+            101..=u8::MAX => Err(RecoverSplitError::AboveHundred(secondary_percent)),
+            _ => Ok(RecoverSplit { secondary_script, secondary_percent }),
+        }
+    }
+}
+
+/// Error returned when the percentage of [`RecoverSplit`] is invalid.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RecoverSplitError {
+    /// The percentage is zero so the secondary script would get nothing.
+    NothingToSecondary,
+    /// The percentage is 100 so the return script would get nothing.
+    NothingToReturn,
+    /// The percentage is above 100.
+    AboveHundred(u8),
+}
+
+impl core::fmt::Display for RecoverSplitError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            RecoverSplitError::NothingToSecondary => write!(f, "the recover split sends nothing to the secondary script, use no split instead"),
+            RecoverSplitError::NothingToReturn => write!(f, "the recover split sends nothing to the return script, use the secondary script as the return script instead"),
+            RecoverSplitError::AboveHundred(percent) => write!(f, "the recover split percentage {} is above 100", percent),
+        }
+    }
 }
 
 pub struct MandatoryFundingParams {
@@ -517,6 +878,94 @@ impl MandatoryFundingParams {
 }
 
 impl Funding {
+    /// Randomly orders the outputs of the escrow transaction including the contract output.
+    ///
+    /// This prevents fingerprinting the escrow transaction by the position of the contract output.
+    /// Call this after all extra outputs were added.
+    pub fn shuffle_escrow_outputs<R: rand::Rng + ?Sized>(&mut self, rng: &mut R) {
+        use rand::seq::SliceRandom;
+
+        self.escrow_extra_outputs.shuffle(rng);
+        let position = rng.gen_range(0..=self.escrow_extra_outputs.len());
+        self.escrow_contract_output_position = position.try_into().expect("the number of outputs is limited by tx size");
+        // The change may not be added but if it is it must not stand out either.
+        let position = rng.gen_range(0..=self.escrow_extra_outputs.len() + 1);
+        self.escrow_change_position = Some(position.try_into().expect("the number of outputs is limited by tx size"));
+    }
+
+    /// Randomly orders the inputs of the escrow transaction.
+    ///
+    /// The inputs are not known until the funding is processed so this shuffles all outputs of
+    /// the funding transactions. Call this after the transactions were set, outputs of
+    /// transactions added later are placed after the shuffled ones.
+    pub fn shuffle_escrow_inputs<R: rand::Rng + ?Sized>(&mut self, rng: &mut R) {
+        use rand::seq::SliceRandom;
+
+        let output_count = self.mandatory.transactions.iter().map(|tx| tx.output.len()).sum();
+        let mut order = (0..output_count).collect::<Vec<_>>();
+        order.shuffle(rng);
+        self.escrow_input_order = Some(order);
+    }
+
+    /// Returns the IDs of funding transactions explicitly signaling replaceability (BIP125).
+    ///
+    /// If such transaction is unconfirmed it can be replaced, invalidating the escrow built on top
+    /// of it. Wallets should advise waiting for confirmation in such case. Note that nodes with
+    /// full RBF enabled may replace any unconfirmed transaction, so an empty list doesn't guarantee
+    /// anything until the funding is confirmed.
+    pub fn rbf_warnings(&self) -> Vec<Txid> {
+        self.mandatory.transactions.iter()
+            .filter(|tx| tx.is_explicitly_rbf())
+            .map(Transaction::compute_txid)
+            .collect()
+    }
+
+    /// Returns the maximum number of prefund inputs the escrow can spend without its fee
+    /// exceeding `budget`.
+    ///
+    /// The fee is predicted the same way as when the funding is processed, including the extra
+    /// escrow outputs but excluding the overfunding change. Coin selection can use this to avoid
+    /// sweeping many small inputs that cost more to spend than they're worth.
+    pub fn max_inputs_for_fee(&self, budget: Amount, fee_rate: FeeRate) -> usize {
+        let fee = |input_count| {
+            // witness version (1B) + OP_PUSHBYTES_32 + x-only key (32 B)
+            let out_script_lengths = core::iter::once(1 + 1 + 32)
+                .chain(self.escrow_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
+            predict_tx_weight(input_count, prefund::multisig_spend_input_prediction(), out_script_lengths) * fee_rate
+        };
+
+        // The fee grows with the input count so we can binary search for the highest count.
+        // Invariant: `low` inputs fit the budget (trivially for zero), `high` inputs don't.
+        let mut low = 0;
+        let mut high = escrow::MAX_INPUT_COUNT as usize + 1;
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if fee(mid) <= budget {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    /// Adds an `OP_RETURN` output carrying `data` to the escrow transaction.
+    ///
+    /// This can be used to tie the escrow to e.g. a loan identifier. The output has zero value and
+    /// its size is included in the escrow fee. The data must not exceed the standard limit of
+    /// 80 bytes and only one `OP_RETURN` output can be added.
+    pub fn add_escrow_op_return(&mut self, data: &[u8]) -> Result<(), OpReturnError> {
+        if data.len() > MAX_OP_RETURN_DATA {
+            return Err(OpReturnError::TooLarge(data.len()));
+        }
+        if self.escrow_extra_outputs.iter().any(|txout| txout.script_pubkey.is_op_return()) {
+            return Err(OpReturnError::AlreadyPresent);
+        }
+        let data = <&bitcoin::script::PushBytes>::try_from(data).expect("checked above");
+        self.escrow_extra_outputs.push(TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::new_op_return(data) });
+        Ok(())
+    }
+
     pub fn new(mandatory: MandatoryFundingParams) -> Self {
         Funding {
             mandatory,
@@ -524,16 +973,30 @@ impl Funding {
             escrow_contract_output_position: 0,
             repayment_extra_outputs: Default::default(),
             recover_extra_outputs: Default::default(),
+            current_height: None,
+            escrow_key_pair: None,
+            randomize_lock_time: false,
+            recover_split: None,
+            overfunding_change: None,
+            escrow_change_position: None,
+            escrow_input_order: None,
         }
     }
 
     pub fn from_hints(hints: offer::EscrowHints) -> Self {
+        // Rely mostly on fee bumping while allowing the opportunity to not pay any when
+        // mempool is empty.
+        Self::from_hints_with_finalization_rate(hints, FeeRate::BROADCAST_MIN)
+    }
+
+    /// Same as `from_hints` but uses the given fee rate for the transactions spending the escrow.
+    ///
+    /// This is useful if the borrower doesn't want to rely on fee bumping.
+    pub fn from_hints_with_finalization_rate(hints: offer::EscrowHints, finalization_fee_rate: FeeRate) -> Self {
         let mandatory = MandatoryFundingParams {
             transactions: hints.transactions,
             escrow_fee_rate: hints.fee_rate,
-            // Rely mostly on fee bumping while allowing the opportunity to not pay any when
-            // mempool is empty.
-            finalization_fee_rate: FeeRate::BROADCAST_MIN,
+            finalization_fee_rate,
         };
         Funding {
             mandatory,
@@ -543,13 +1006,100 @@ impl Funding {
             // Insert fee bumping outputs only
             repayment_extra_outputs: vec![hints.finalization_fee_bump_txout.clone()],
             recover_extra_outputs: vec![hints.finalization_fee_bump_txout],
+            // The hints were created at this height so it's the best estimate we have.
+            current_height: hints.height,
+            escrow_key_pair: None,
+            randomize_lock_time: false,
+            recover_split: None,
+            overfunding_change: None,
+            escrow_change_position: None,
+            escrow_input_order: None,
+        }
+    }
+}
+
+/// Relative lock time of the borrower's return path in prefund.
+///
+/// Unlike a plain `Sequence` this can only hold a valid relative lock time (either in blocks or in
+/// 512-second units) so it can't be confused with an absolute lock time.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PrefundDelay(Sequence);
+
+impl PrefundDelay {
+    /// Creates the delay measured in blocks.
+    pub fn from_height(blocks: u16) -> Self {
+        PrefundDelay(Sequence::from_height(blocks))
+    }
+
+    /// Creates the delay measured in 512-second intervals.
+    pub fn from_512_second_intervals(intervals: u16) -> Self {
+        PrefundDelay(Sequence::from_512_second_intervals(intervals))
+    }
+
+    pub fn is_height_locked(self) -> bool {
+        self.0.is_height_locked()
+    }
+
+    pub fn is_time_locked(self) -> bool {
+        self.0.is_time_locked()
+    }
+
+    pub fn to_sequence(self) -> Sequence {
+        self.0
+    }
+
+    /// Returns the number of blocks if the delay is measured in blocks.
+    pub fn blocks(self) -> Option<u16> {
+        if self.is_height_locked() {
+            Some(self.0.0 as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of seconds if the delay is measured in time.
+    pub fn seconds(self) -> Option<u32> {
+        if self.is_time_locked() {
+            Some(u32::from(self.0.0 as u16) * 512)
+        } else {
+            None
+        }
+    }
+}
+
+impl TryFrom<Sequence> for PrefundDelay {
+    type Error = PrefundDelayError;
+
+    fn try_from(sequence: Sequence) -> Result<Self, Self::Error> {
+        if !sequence.is_relative_lock_time() {
+            return Err(PrefundDelayError(PrefundDelayErrorInner::NotRelativeLockTime(sequence)));
+        }
+        // only the type flag (bit 22) and the 16-bit value are allowed to be set
+        if sequence.0 & !(0x0040_0000 | 0xFFFF) != 0 {
+            return Err(PrefundDelayError(PrefundDelayErrorInner::NonCanonical(sequence)));
         }
+        Ok(PrefundDelay(sequence))
     }
 }
 
+impl From<PrefundDelay> for Sequence {
+    fn from(delay: PrefundDelay) -> Self {
+        delay.0
+    }
+}
+
+#[derive(Debug)]
+pub struct PrefundDelayError(PrefundDelayErrorInner);
+
+#[derive(Debug)]
+enum PrefundDelayErrorInner {
+    NotRelativeLockTime(Sequence),
+    NonCanonical(Sequence),
+}
+
 pub struct MandatoryPrefundParams {
     pub key_pair: Keypair,
-    pub lock_time: Sequence,
+    pub lock_time: PrefundDelay,
     pub return_script: ScriptBuf,
 }
 
@@ -562,12 +1112,17 @@ impl MandatoryPrefundParams {
 #[non_exhaustive]
 pub struct PrefundParams {
     pub mandatory: MandatoryPrefundParams,
+    /// The internal key the offer is expected to use.
+    ///
+    /// This is local configuration, the key in the offer is not trusted.
+    pub internal_key: pub_keys::InternalKey,
 }
 
 impl PrefundParams {
     pub fn new(mandatory: MandatoryPrefundParams) -> Self {
         PrefundParams {
             mandatory,
+            internal_key: Default::default(),
         }
     }
 }
@@ -584,20 +1139,47 @@ pub enum FundingErrorReason {
     Overflow,
     NotLocked,
     UnitMismatch,
+    ImplausibleLockHeight { lock_height: Height, current_height: Height, },
+    /// One of the recover outputs would be dust after splitting.
+    RecoverSplitDust,
+    /// Outputs paying to the funding script were found but all of them are zero or dust.
+    ///
+    /// This usually means the deposit was sent with a wrong amount.
+    NoSpendableValue,
+    /// The position of the contract output is greater than the number of extra escrow outputs.
+    ContractOutputPositionOutOfRange { position: u32, extra_output_count: usize, },
+    /// No output pays to the funding script but some pay to the escrow script.
+    ///
+    /// The borrower sent the funds to the escrow address instead of the funding address. This
+    /// can only be detected if [`Funding::escrow_key_pair`] is set.
+    SentToEscrowAddress,
+}
+
+impl FundingErrorReason {
+    fn no_spendable_outputs(skipped_dust: bool) -> Self {
+        if skipped_dust {
+            FundingErrorReason::NoSpendableValue
+        } else {
+            FundingErrorReason::NoMatchingOutputs
+        }
+    }
 }
 
 /// Extracts outputs with matching scripts from the previous transactions.
 ///
 /// This performs a bunch of heavy lifting:
 ///
-/// * Identifies all outputs
+/// * Identifies all outputs, skipping dust ones which would cost more to spend than they're worth
+///   (`skipped_dust` is set if any were skipped)
+/// * Skips outputs that were already seen in case the same transaction was passed multiple times
 /// * Identifies the largest block-based lock time, if any
 /// * Sets sequences to enable lock time if the height is not 0
 ///
 /// All this locktime stuff is to implement anti-fee-sniping. Apart from incentivizing the miners
 /// to not reorg the chain it also minimizes differences between the resulting transaction and
 /// other transactions in the chain making analysis harder.
-fn extract_spendable_outputs(transactions: impl IntoIterator<Item=Transaction>, max_lock_height: &mut Height, is_owned: impl Fn(&Script) -> bool) -> Vec<SpendableTxo> {
+fn extract_spendable_outputs(transactions: impl IntoIterator<Item=Transaction>, max_lock_height: &mut Height, skipped_dust: &mut bool, is_owned: impl Fn(&Script) -> bool) -> Vec<SpendableTxo> {
+    let mut seen_outpoints = std::collections::HashSet::new();
     let mut outputs = transactions.into_iter().flat_map(|transaction| {
         let txid = transaction.compute_txid();
         // Cheaper checks go first
@@ -639,7 +1221,15 @@ fn extract_spendable_outputs(transactions: impl IntoIterator<Item=Transaction>,
                     sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
                 }
             })
-    }).collect::<Vec<_>>();
+    })
+    .filter(|txo| {
+        let is_dust = txo.tx_out.value < txo.tx_out.script_pubkey.minimal_non_dust();
+        *skipped_dust |= is_dust;
+        !is_dust
+    })
+    // Spending the same output twice would make the transaction invalid
+    .filter(|txo| seen_outpoints.insert(txo.out_point))
+    .collect::<Vec<_>>();
 
     if max_lock_height.to_consensus_u32() != 0 {
         for output in &mut outputs {
@@ -659,6 +1249,43 @@ fn predict_tx_weight(input_count: usize, input_prediction: InputWeightPrediction
     bitcoin::transaction::predict_weight(core::iter::repeat(input_prediction).take(input_count), txouts)
 }
 
+impl escrow::ReceivingEscrowSignature<super::Borrower> {
+    /// Replaces the ephemeral escrow key with a freshly generated one.
+    ///
+    /// This allows the borrower to rotate a compromised key without going back to prefund. The
+    /// transactions are rebuilt and signed again and the new message for TEDs is written into
+    /// `message` the same way as in [`WaitingForFunding::funding_received`]. The signatures TEDs
+    /// made for the previous key become invalid since the escrow output changes.
+    pub fn rotate_escrow_key(self, message: &mut Vec<u8>) -> Self {
+        let key_pair = Keypair::new_global(&mut rand::thread_rng());
+        let mut info = self.unsigned_txes.borrower_info(&self.params);
+        info.escrow_eph_key = PubKey::new(key_pair.x_only_public_key().0);
+        info.serialize(message);
+        let unsigned_txes = escrow::unsigned_transactions(&self.params, self.keys, info);
+        let sigs = unsigned_txes.sign_borrower(key_pair);
+
+        sigs.serialize(message);
+
+        let mut participant_data = self.participant_data;
+        participant_data.escrow_key_pair = Some(key_pair);
+        escrow::ReceivingEscrowSignature {
+            params: self.params,
+            recover_signature: sigs.recover,
+            repayment_signature: sigs.repayment,
+            keys: self.keys,
+            unsigned_txes,
+            participant_data,
+        }
+    }
+
+    /// Returns the ephemeral escrow key pair used to sign the transactions.
+    ///
+    /// Returns `None` for states created by versions that didn't store the key.
+    pub fn escrow_key_pair(&self) -> Option<&Keypair> {
+        self.participant_data.escrow_key_pair.as_ref()
+    }
+}
+
 impl escrow::SignaturesVerified<super::Borrower> {
     pub fn assemble_escrow(self) -> Result<escrow::EscrowSigned<super::Borrower>, (Self, escrow::SignatureVerificationError)> {
         let sig_key = self.state.participant_data.prefund.participant_data.key_pair;
@@ -666,9 +1293,34 @@ impl escrow::SignaturesVerified<super::Borrower> {
             Ok(SECP256K1.sign_schnorr(&message, &sig_key))
         })
     }
+
+    /// Returns the amount the borrower receives back when the loan is repaid.
+    ///
+    /// This is the value of the repayment output paying to the borrower's return script, so it
+    /// already has the repayment fee and extra outputs subtracted.
+    pub fn repayment_return_amount(&self) -> Amount {
+        let return_script = &self.state.participant_data.return_script;
+        self.state.unsigned_txes.repayment.output
+            .iter()
+            .find(|txout| txout.script_pubkey == *return_script)
+            .expect("the borrower always creates the repayment output paying to return script")
+            .value
+    }
 }
 
 impl escrow::EscrowSigned<super::Borrower> {
+    /// Returns the hex-encoded transactions to be tested using `testmempoolaccept`.
+    ///
+    /// The package contains the signed escrow transaction followed by the recover transaction. Note
+    /// that the node will report the recover transaction as non-final until its lock time passes,
+    /// the important result is the one for escrow.
+    pub fn acceptance_test_package(&self) -> Vec<String> {
+        vec![
+            bitcoin::consensus::encode::serialize_hex(self.tx_escrow()),
+            bitcoin::consensus::encode::serialize_hex(&self.recover),
+        ]
+    }
+
     pub fn serialize_broadcast_request(&self, buf: &mut Vec<u8>) {
         buf.push(constants::MessageId::EscrowSigsFromBorrower as u8);
         buf.extend_from_slice(&(self.tx_escrow().input.len() as u32).to_be_bytes());
@@ -696,6 +1348,15 @@ impl escrow::EscrowSigned<super::Borrower> {
     }
 }
 
+/// Scripts and outputs relevant to the contract in its current state.
+#[derive(Debug, Clone)]
+pub struct WatchTargets {
+    /// Scripts to watch for incoming payments.
+    pub incoming_scripts: Vec<ScriptBuf>,
+    /// Outputs to watch for being spent.
+    pub spent_outpoints: Vec<OutPoint>,
+}
+
 /// Contains all possible borrower states.
 #[derive(Debug, Clone, PartialEq)]
 pub enum State {
@@ -734,6 +1395,9 @@ impl State {
         match version {
             deserialize::StateVersion::V0 => (),
             deserialize::StateVersion::V1 => (),
+            deserialize::StateVersion::V2 => (),
+            deserialize::StateVersion::V3 => (),
+            deserialize::StateVersion::V4 => (),
         }
         let first = bytes_tmp.get(1).ok_or(StateDeserErrorInner::UnexpectedEnd)?;
         let state_id = StateId::try_from(*first).map_err(StateDeserErrorInner::InvalidStateId)?;
@@ -751,6 +1415,54 @@ impl State {
         Ok(state)
     }
 
+    /// Serializes the state followed by SHA256 of the serialized bytes.
+    ///
+    /// This allows detecting silent corruption of stored states, see
+    /// [`deserialize_verifying_checksum`](Self::deserialize_verifying_checksum).
+    pub fn serialize_with_checksum(&self, out: &mut Vec<u8>) {
+        use bitcoin::hashes::{sha256, Hash};
+
+        let start = out.len();
+        self.serialize(out);
+        let checksum = sha256::Hash::hash(&out[start..]);
+        out.extend_from_slice(checksum.as_byte_array());
+    }
+
+    /// Deserializes the state produced by [`serialize_with_checksum`](Self::serialize_with_checksum).
+    ///
+    /// The whole input is consumed since the checksum is expected at its end.
+    pub fn deserialize_verifying_checksum(bytes: &mut &[u8]) -> Result<Self, StateDeserError> {
+        use bitcoin::hashes::{sha256, Hash};
+
+        if bytes.len() < 32 {
+            return Err(StateDeserErrorInner::UnexpectedEnd.into());
+        }
+        let (mut body, checksum) = bytes.split_at(bytes.len() - 32);
+        if sha256::Hash::hash(body).as_byte_array() != checksum {
+            return Err(StateDeserErrorInner::ChecksumMismatch.into());
+        }
+        let state = Self::deserialize(&mut body)?;
+        if !body.is_empty() {
+            return Err(StateDeserErrorInner::TrailingBytes.into());
+        }
+        *bytes = &[];
+        Ok(state)
+    }
+
+    /// Re-serializes a stored state of any supported version using the current version.
+    ///
+    /// This allows upgrading old stored states in bulk.
+    pub fn migrate_to_current(bytes: &[u8]) -> Result<Vec<u8>, MigrationError> {
+        let mut cursor = bytes;
+        let state = Self::deserialize(&mut cursor).map_err(MigrationError::Deserialization)?;
+        if !cursor.is_empty() {
+            return Err(MigrationError::TrailingBytes(cursor.len()));
+        }
+        let mut out = Vec::with_capacity(bytes.len() + 5);
+        state.serialize(&mut out);
+        Ok(out)
+    }
+
     pub fn network(&self) -> bitcoin::Network {
         match self {
             State::WaitingForFunding(state) => state.network(),
@@ -760,6 +1472,109 @@ impl State {
         }
     }
 
+    /// Returns `true` if cancelling the prefund still makes sense in this state.
+    ///
+    /// Once the escrow transaction is signed it can be broadcast at any time and the state
+    /// doesn't track whether it was, so cancelling is considered not applicable anymore.
+    pub fn is_cancellable(&self) -> bool {
+        match self {
+            State::WaitingForFunding(_) => true,
+            State::ReceivingEscrowSignature { .. } => true,
+            State::SignaturesVerified(_) => true,
+            State::EscrowSigned(_) => false,
+        }
+    }
+
+    /// Returns the address receiving recovered and repaid funds.
+    ///
+    /// Fails if the return script is not a standard script.
+    pub fn return_address(&self) -> Result<Address, bitcoin::address::FromScriptError> {
+        let escrow_data = match self {
+            State::WaitingForFunding(state) => &state.escrow.participant_data,
+            State::ReceivingEscrowSignature { state, .. } => &state.participant_data,
+            State::SignaturesVerified(state) => &state.state.participant_data,
+            State::EscrowSigned(state) => &state.participant_data,
+        };
+        Address::from_script(&escrow_data.return_script, escrow_data.prefund.network())
+    }
+
+    /// Returns the position of the state in the protocol.
+    ///
+    /// See [`StateId::progress_rank`](constants::StateId::progress_rank).
+    pub fn progress_rank(&self) -> u8 {
+        let state_id = match self {
+            State::WaitingForFunding(_) => constants::StateId::WaitingForFunding,
+            State::ReceivingEscrowSignature { .. } => constants::StateId::EscrowReceivingEscrowSignatures,
+            State::SignaturesVerified(_) => constants::StateId::EscrowSignaturesVerified,
+            State::EscrowSigned(_) => constants::StateId::WaitingForEscrowConfirmation,
+        };
+        state_id.progress_rank()
+    }
+
+    /// Returns `true` if the state processes incoming messages from TEDs.
+    ///
+    /// Once the signatures are verified no further messages are expected.
+    pub fn expects_message(&self) -> bool {
+        match self {
+            State::WaitingForFunding(_) => true,
+            State::ReceivingEscrowSignature { .. } => true,
+            State::SignaturesVerified(_) => false,
+            State::EscrowSigned(_) => false,
+        }
+    }
+
+    /// Returns what an on-chain monitor should watch in the current state.
+    ///
+    /// Before funding only deposits to the funding script are interesting. Once the escrow
+    /// transaction is known the prefund outputs it spends and the escrow output are watched
+    /// instead.
+    pub fn watch_targets(&self) -> WatchTargets {
+        fn spends(escrow: &Transaction, recover: &Transaction) -> WatchTargets {
+            let spent_outpoints = escrow.input.iter()
+                .map(|txin| txin.previous_output)
+                .chain(recover.input.iter().map(|txin| txin.previous_output))
+                .collect();
+            WatchTargets {
+                incoming_scripts: Vec::new(),
+                spent_outpoints,
+            }
+        }
+
+        match self {
+            State::WaitingForFunding(state) => WatchTargets {
+                incoming_scripts: vec![state.escrow.participant_data.prefund.funding_script()],
+                spent_outpoints: Vec::new(),
+            },
+            State::ReceivingEscrowSignature { state, .. } => spends(&state.unsigned_txes.escrow, &state.unsigned_txes.recover),
+            State::SignaturesVerified(state) => spends(&state.state.unsigned_txes.escrow, &state.state.unsigned_txes.recover),
+            State::EscrowSigned(state) => spends(&state.tx_escrow, &state.recover),
+        }
+    }
+
+    /// Returns watch-only descriptors of all contract outputs known in the current state.
+    ///
+    /// The descriptors include checksums so they can be passed to `importdescriptors` as-is.
+    /// They should be imported again after each state transition since new outputs may appear.
+    pub fn watch_descriptors(&self) -> Vec<String> {
+        let (escrow_data, escrow_output) = match self {
+            State::WaitingForFunding(state) => (&state.escrow.participant_data, None),
+            State::ReceivingEscrowSignature { state, .. } => (&state.participant_data, Some(&state.unsigned_txes.escrow_output().script_pubkey)),
+            State::SignaturesVerified(state) => (&state.state.participant_data, Some(&state.escrow_output().script_pubkey)),
+            State::EscrowSigned(state) => {
+                let escrow_output = state.recover.input.first()
+                    .and_then(|txin| state.tx_escrow.output.get(txin.previous_output.vout as usize))
+                    .map(|txout| &txout.script_pubkey);
+                (&state.participant_data, escrow_output)
+            },
+        };
+
+        let funding_script = escrow_data.prefund.funding_script();
+        core::iter::once(&funding_script)
+            .chain(escrow_output)
+            .filter_map(|script| crate::contract::descriptor::rawtr_from_script(script))
+            .collect()
+    }
+
     pub fn funding_cancel(&self, transactions: Vec<Transaction>, fee_rate: FeeRate, current_height: Height, delay_rtl: RelativeDelay) -> Result<Transaction, FundingError> {
         let escrow_data = match self {
             State::WaitingForFunding(state) => &state.escrow.participant_data,
@@ -775,6 +1590,30 @@ impl State {
         State::WaitingForFunding(WaitingForFunding::from_escrow_data_and_offer(escrow_data, offer))
     }
 
+    /// Reconstructs the offer this state was created from.
+    ///
+    /// Returns `None` once the escrow is signed because the offer parameters are not stored
+    /// anymore.
+    pub fn embedded_offer(&self) -> Option<Offer> {
+        let (escrow, escrow_keys, escrow_data) = match self {
+            State::WaitingForFunding(state) => (&state.escrow.params, *state.escrow.keys(), &state.escrow.participant_data),
+            State::ReceivingEscrowSignature { state, .. } => (&state.params, state.keys, &state.participant_data),
+            State::SignaturesVerified(state) => (&state.state.params, state.state.keys, &state.state.participant_data),
+            State::EscrowSigned(_) => return None,
+        };
+        let prefund_keys = escrow_data.prefund.keys();
+        let prefund_keys = offer::TedSigPubKeys {
+            ted_o: prefund_keys.ted_o,
+            ted_p: prefund_keys.ted_p,
+            internal_key: prefund_keys.internal_key,
+        };
+        Some(Offer {
+            escrow: escrow.clone(),
+            escrow_keys,
+            prefund_keys,
+        })
+    }
+
     /// Changes the state back to WaitingForFunding.
     pub fn reset(&mut self, offer: Offer) {
         match self {
@@ -817,6 +1656,8 @@ enum StateDeserErrorInner {
     TedSignatures(escrow::TedSignaturesDeserError),
     SignaturesVerified(super::super::StateDeserError<escrow::SignaturesVerifiedDeserError<EscrowDataDeserError>>),
     EscrowSigned(super::super::StateDeserError<escrow::EscrowSignedDeserError<EscrowDataDeserError>>),
+    ChecksumMismatch,
+    TrailingBytes,
 }
 
 impl From<StateDeserErrorInner> for StateDeserError {
@@ -825,6 +1666,16 @@ impl From<StateDeserErrorInner> for StateDeserError {
     }
 }
 
+/// Returned when migrating a stored state fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MigrationError {
+    /// The state couldn't be deserialized.
+    Deserialization(StateDeserError),
+    /// The state was followed by this many unexpected bytes which would be lost by migration.
+    TrailingBytes(usize),
+}
+
 impl From<deserialize::StateVersionDeserError> for StateDeserErrorInner {
     fn from(value: deserialize::StateVersionDeserError) -> Self {
         match value {
@@ -838,6 +1689,536 @@ impl From<deserialize::StateVersionDeserError> for StateDeserErrorInner {
 mod tests {
     use super::*;
 
+    /// Creates the borrower state trusting the internal key of the offer.
+    fn accept_offer(offer: Offer, params: MandatoryPrefundParams) -> WaitingForFunding {
+        let mut params = params.into_params();
+        params.internal_key = offer.prefund_keys.internal_key;
+        WaitingForFunding::new(offer, params).unwrap()
+    }
+
+    /// A freshly accepted offer together with a transaction funding it.
+    struct FundingFixture {
+        state: WaitingForFunding,
+        return_script: ScriptBuf,
+        funding_tx: Transaction,
+    }
+
+    impl FundingFixture {
+        /// Accepts the offer and pays each of `amounts` to the funding address.
+        ///
+        /// The offer is adjusted so that the amounts comfortably cover the collateral.
+        fn new(offer: Offer, amounts: &[u64]) -> Self {
+            let mut offer = offer;
+            offer.escrow.min_collateral = Amount::from_sat(10_000);
+            offer.escrow.extra_termination_outputs.clear();
+            offer.escrow.liquidator_output_index = 0;
+            let key_pair = Keypair::new_global(&mut rand::thread_rng());
+            let return_script = ScriptBuf::new_p2tr(SECP256K1, key_pair.x_only_public_key().0, None);
+            let params = MandatoryPrefundParams {
+                key_pair,
+                lock_time: PrefundDelay::from_height(144),
+                return_script: return_script.clone(),
+            };
+            let state = accept_offer(offer, params);
+            let funding_tx = Transaction {
+                version: bitcoin::transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![bitcoin::TxIn::default()],
+                output: amounts.iter().map(|amount| TxOut { value: Amount::from_sat(*amount), script_pubkey: state.funding_address().script_pubkey() }).collect(),
+            };
+            FundingFixture { state, return_script, funding_tx }
+        }
+
+        /// Funding parameters spending the funding transaction at the minimum fee rates.
+        fn funding(&self) -> Funding {
+            MandatoryFundingParams {
+                transactions: vec![self.funding_tx.clone()],
+                escrow_fee_rate: FeeRate::BROADCAST_MIN,
+                finalization_fee_rate: FeeRate::BROADCAST_MIN,
+            }.into_funding()
+        }
+    }
+
     crate::test_macros::check_roundtrip!(roundtrip_waiting_for_funding, WaitingForFunding);
     crate::test_macros::check_roundtrip!(roundtrip_state, State);
+
+    quickcheck::quickcheck! {
+        fn checksum_detects_corruption(state: State, position: usize, bit: u8) -> bool {
+            let mut bytes = Vec::new();
+            state.serialize_with_checksum(&mut bytes);
+            let restored = State::deserialize_verifying_checksum(&mut &*bytes).unwrap();
+            let position = position % bytes.len();
+            bytes[position] ^= 1 << (bit % 8);
+            restored == state && State::deserialize_verifying_checksum(&mut &*bytes).is_err()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn embedded_offer_matches_original(offer: Offer) -> bool {
+            let params = MandatoryPrefundParams {
+                key_pair: Keypair::new_global(&mut rand::thread_rng()),
+                lock_time: PrefundDelay::from_height(42),
+                return_script: ScriptBuf::new(),
+            };
+            let state = State::WaitingForFunding(accept_offer(offer.clone(), params));
+            state.embedded_offer() == Some(offer)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn migrate_current_is_identity(state: State) -> bool {
+            let mut bytes = Vec::new();
+            state.serialize(&mut bytes);
+            State::migrate_to_current(&bytes).unwrap() == bytes
+        }
+    }
+
+    /// Computes the weight of a transaction spending `input_count` inputs via the script path
+    /// with all-zero signatures.
+    ///
+    /// Dummy signatures have the same size as real ones so the result is the actual weight the
+    /// signed transaction would have.
+    fn dummy_signed_weight(input_count: usize, sig_count: usize, script: &Script, control_block: &[u8], output_scripts: &[ScriptBuf]) -> Weight {
+        let mut witness = bitcoin::Witness::new();
+        for _ in 0..sig_count {
+            witness.push([0u8; 64]);
+        }
+        witness.push(script);
+        witness.push(control_block);
+
+        let input = bitcoin::TxIn {
+            previous_output: Default::default(),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness,
+        };
+        let output = output_scripts.iter()
+            .map(|script| TxOut { value: Amount::ZERO, script_pubkey: script.clone() })
+            .collect();
+        let transaction = Transaction {
+            version: bitcoin::transaction::Version(2),
+            lock_time: LockTime::ZERO,
+            input: vec![input; input_count],
+            output,
+        };
+        transaction.weight()
+    }
+
+    fn matches_within_vbyte(predicted: Weight, actual: Weight) -> bool {
+        let (predicted, actual) = (predicted.to_wu(), actual.to_wu());
+        predicted.max(actual) - predicted.min(actual) < 4
+    }
+
+    quickcheck::quickcheck! {
+        fn prefund_weight_predictions_match(prefund: prefund::Prefund<super::super::Borrower>, return_script: Vec<u8>, input_count: u8) -> bool {
+            let return_script = ScriptBuf::from(return_script);
+            let input_count = usize::from(input_count % 8) + 1;
+            // any P2TR script has the same length as the escrow output script
+            let escrow_output_script = prefund.funding_script();
+            let multisig_predicted = predict_tx_weight(input_count, prefund::multisig_spend_input_prediction(), core::iter::once(escrow_output_script.len()));
+            let control_block = prefund.multisig_control_block().serialize();
+            let multisig_actual = dummy_signed_weight(input_count, 3, &prefund.multisig_script(), &control_block, &[escrow_output_script]);
+
+            let recovery_predicted = predict_tx_weight(input_count, prefund.recovery_spend_input_prediction(), core::iter::once(return_script.len()));
+            let (script, control_block) = prefund.recovery_spend_components();
+            let recovery_actual = dummy_signed_weight(input_count, 1, &script, &control_block, &[return_script]);
+
+            matches_within_vbyte(multisig_predicted, multisig_actual) && matches_within_vbyte(recovery_predicted, recovery_actual)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_weight_prediction_matches(keys: pub_keys::PubKeys<context::Escrow>, return_script: Vec<u8>) -> bool {
+            let return_script = ScriptBuf::from(return_script);
+            let (_, _, parity) = escrow::output_script(&keys);
+            let control_block = bitcoin::taproot::ControlBlock {
+                leaf_version: pub_keys::LEAF_VERSION,
+                internal_key: keys.generate_internal_key(),
+                output_key_parity: parity,
+                merkle_branch: (&[] as &[_]).try_into().expect("0 < 128"),
+            };
+            let predicted = predict_tx_weight(1, escrow::multisig_spend_input_prediction(), core::iter::once(return_script.len()));
+            let actual = dummy_signed_weight(1, 3, &keys.generate_multisig_script(), &control_block.serialize(), &[return_script]);
+            matches_within_vbyte(predicted, actual)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn watch_descriptors_match_funding_script(state: WaitingForFunding) -> bool {
+            let funding_address = state.funding_address();
+            let network = state.escrow.participant_data.prefund.network();
+            let descriptors = State::WaitingForFunding(state).watch_descriptors();
+            let key = crate::contract::descriptor::parse_rawtr(&descriptors[0]).unwrap();
+            descriptors.len() == 1 && Address::p2tr_tweaked(key, network) == funding_address
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn deterministic_escrow_key_is_stable(state: WaitingForFunding) -> bool {
+            let key_pair = state.deterministic_escrow_key_pair();
+            let prefund_key = state.escrow.participant_data.prefund.participant_data.key_pair;
+            key_pair == state.clone().deterministic_escrow_key_pair() && key_pair != prefund_key
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn anti_fee_sniping_height_within_window(height: u32) -> bool {
+            let height = height % bitcoin::absolute::LOCK_TIME_THRESHOLD;
+            let randomized = anti_fee_sniping_height(Height::from_consensus(height).unwrap(), &mut rand::thread_rng()).to_consensus_u32();
+            randomized <= height && randomized >= height.saturating_sub(ANTI_FEE_SNIPING_WINDOW)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn return_script_validation(data: EscrowData) -> bool {
+            let key = data.prefund.participant_data.key_pair.x_only_public_key().0;
+            let valid = ScriptBuf::new_p2tr(SECP256K1, key, None);
+            let unknown_version = ScriptBuf::new_witness_program(&bitcoin::WitnessProgram::new(bitcoin::WitnessVersion::V2, &[42; 32]).unwrap());
+            let with_script = |return_script: ScriptBuf| EscrowData { return_script, ..data.clone() };
+            with_script(valid).validate_return_script(bitcoin::Network::Regtest).is_ok() &&
+                matches!(with_script(ScriptBuf::new_op_return(&[42])).validate_return_script(bitcoin::Network::Regtest), Err(ReturnScriptError::NonStandard)) &&
+                matches!(with_script(unknown_version).validate_return_script(bitcoin::Network::Regtest), Err(ReturnScriptError::UnknownAddressType))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn overfunding_change_output(offer: Offer) -> bool {
+            let fixture = FundingFixture::new(offer, &[1_000_000]);
+            let return_script = fixture.return_script.clone();
+            let change_key = Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0;
+            let change_script = ScriptBuf::new_p2tr(SECP256K1, change_key, None);
+            let mut funding = fixture.funding();
+            funding.overfunding_change = Some(change_script.clone());
+            let state = fixture.state.funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+            let escrow = &state.unsigned_txes.escrow;
+            let change = escrow.output.iter().find(|txout| txout.script_pubkey == change_script).unwrap().value;
+            let escrow_amount = state.unsigned_txes.escrow_output().value;
+            escrow.output.len() == 2 && change > escrow_amount && escrow_amount < Amount::from_sat(20_000) &&
+                escrow.output.iter().all(|txout| txout.script_pubkey != return_script)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn overfunding_change_position_shuffled(offer: Offer, seed: u64) -> bool {
+            use rand::SeedableRng;
+
+            let fixture = FundingFixture::new(offer, &[1_000_000]);
+            let change_script = ScriptBuf::new_p2tr(SECP256K1, Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0, None);
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+            let mut change_positions = std::collections::BTreeSet::new();
+            for _ in 0..32 {
+                let mut funding = fixture.funding();
+                funding.escrow_extra_outputs = (1..=2).map(|i| TxOut { value: Amount::from_sat(i * 1000), script_pubkey: ScriptBuf::new_op_return([i as u8]) }).collect();
+                funding.overfunding_change = Some(change_script.clone());
+                funding.shuffle_escrow_outputs(&mut rng);
+                let state = fixture.state.clone().funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+                let txes = &state.unsigned_txes;
+                let contract_output = txes.escrow_output();
+                if txes.escrow.output.len() != 4 || !contract_output.script_pubkey.is_p2tr() || contract_output.script_pubkey == change_script || contract_output.value >= Amount::from_sat(20_000) {
+                    return false;
+                }
+                change_positions.insert(txes.escrow.output.iter().position(|txout| txout.script_pubkey == change_script).unwrap());
+            }
+            change_positions.len() > 1
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn max_inputs_within_budget(budget: u32, fee_rate: u16, extra_script_lengths: Vec<u8>) -> bool {
+            let budget = Amount::from_sat(budget.into());
+            let fee_rate = FeeRate::from_sat_per_kwu(u64::from(fee_rate) + 1);
+            let mut funding = MandatoryFundingParams {
+                transactions: Vec::new(),
+                escrow_fee_rate: fee_rate,
+                finalization_fee_rate: fee_rate,
+            }.into_funding();
+            funding.escrow_extra_outputs = extra_script_lengths.iter()
+                .map(|len| TxOut { value: Amount::ZERO, script_pubkey: ScriptBuf::from(vec![0; usize::from(*len)]) })
+                .collect();
+            let fee = |input_count| {
+                let out_script_lengths = core::iter::once(34)
+                    .chain(funding.escrow_extra_outputs.iter().map(|txout| txout.script_pubkey.len()));
+                predict_tx_weight(input_count, prefund::multisig_spend_input_prediction(), out_script_lengths) * fee_rate
+            };
+            let max = funding.max_inputs_for_fee(budget, fee_rate);
+            (max == 0 || fee(max) <= budget) && (max == escrow::MAX_INPUT_COUNT as usize || fee(max + 1) > budget)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn acceptance_test_package_contains_escrow_and_recover(state: escrow::EscrowSigned<super::super::Borrower>) -> bool {
+            let package = state.acceptance_test_package()
+                .iter()
+                .map(|hex| bitcoin::consensus::encode::deserialize_hex::<Transaction>(hex).unwrap())
+                .collect::<Vec<_>>();
+            package == [state.tx_escrow().clone(), state.recover.clone()]
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn cancel_fee_comparison_matches_transactions(offer: Offer, fee_rate: u16) -> bool {
+            let fixture = FundingFixture::new(offer, &[100_000, 200_000, 300_000]);
+            let fee_rate = FeeRate::from_sat_per_kwu(u64::from(fee_rate) + 250);
+            let mut funding = fixture.funding();
+            funding.mandatory.escrow_fee_rate = FeeRate::from_sat_per_kwu(2500);
+            let state = fixture.state;
+            let comparison = state.cancel_fee_comparison(&funding, fee_rate).unwrap();
+
+            let cancel = state.funding_cancel(vec![fixture.funding_tx], fee_rate, Height::ZERO, RelativeDelay::Zero).unwrap();
+            let cancel_fee = Amount::from_sat(600_000) - cancel.output[0].value;
+            let state = state.funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+            let txes = &state.unsigned_txes;
+            let escrow_fee = Amount::from_sat(600_000) - txes.escrow.output.iter().map(|txout| txout.value).sum::<Amount>();
+            let repayment_fee = txes.escrow_output().value - txes.repayment.output.iter().map(|txout| txout.value).sum::<Amount>();
+
+            comparison.cancel_fee == cancel_fee &&
+                comparison.completion_fee == escrow_fee + repayment_fee &&
+                comparison.cancel_extra_cost().to_sat() == cancel_fee.to_sat() as i64 - (escrow_fee + repayment_fee).to_sat() as i64
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_op_return_paid_for(offer: Offer, data: Vec<u8>) -> bool {
+            let fixture = FundingFixture::new(offer, &[1_000_000]);
+            let data = &data[..data.len().min(MAX_OP_RETURN_DATA)];
+            let escrow = |op_return: Option<&[u8]>| {
+                let mut funding = fixture.funding();
+                funding.mandatory.escrow_fee_rate = FeeRate::from_sat_per_kwu(2500);
+                if let Some(data) = op_return {
+                    funding.add_escrow_op_return(data).unwrap();
+                    if !matches!(funding.add_escrow_op_return(data), Err(OpReturnError::AlreadyPresent)) {
+                        return None;
+                    }
+                }
+                let state = fixture.state.clone().funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+                Some(state.unsigned_txes.escrow.clone())
+            };
+            let plain = escrow(None).unwrap();
+            let labeled = match escrow(Some(data)) {
+                Some(labeled) => labeled,
+                None => return false,
+            };
+            let op_return = &labeled.output[1];
+            let output_size = op_return.size() as u64;
+            let mut too_large = MandatoryFundingParams {
+                transactions: Vec::new(),
+                escrow_fee_rate: FeeRate::BROADCAST_MIN,
+                finalization_fee_rate: FeeRate::BROADCAST_MIN,
+            }.into_funding();
+            op_return.value == Amount::ZERO &&
+                op_return.script_pubkey.is_op_return() &&
+                labeled.output[0].value + Amount::from_sat(output_size * 10) == plain.output[0].value &&
+                matches!(too_large.add_escrow_op_return(&[0; 81]), Err(OpReturnError::TooLarge(81)))
+        }
+    }
+
+    #[test]
+    fn rbf_funding_flagged() {
+        let tx = |sequence| Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![bitcoin::TxIn { sequence, ..Default::default() }],
+            output: Vec::new(),
+        };
+        let final_tx = tx(Sequence::MAX);
+        let rbf_tx = tx(Sequence::ENABLE_RBF_NO_LOCKTIME);
+        let funding = MandatoryFundingParams {
+            transactions: vec![final_tx, rbf_tx.clone()],
+            escrow_fee_rate: FeeRate::BROADCAST_MIN,
+            finalization_fee_rate: FeeRate::BROADCAST_MIN,
+        }.into_funding();
+        assert_eq!(funding.rbf_warnings(), [rbf_tx.compute_txid()]);
+    }
+
+    #[test]
+    fn relative_delay_duration() {
+        assert_eq!(RelativeDelay::Zero.approx_duration(), Some(Duration::ZERO));
+        assert_eq!(RelativeDelay::Height(0).approx_duration(), Some(Duration::ZERO));
+        assert_eq!(RelativeDelay::Height(144 * 7).approx_duration(), Some(Duration::from_secs(7 * 24 * 3600)));
+        assert_eq!(RelativeDelay::Height(u16::MAX.into()).approx_duration(), Some(Duration::from_secs(65535 * 600)));
+        assert_eq!(RelativeDelay::Height(u32::from(u16::MAX) + 1).approx_duration(), None);
+        assert_eq!(RelativeDelay::TimeUnits(1).approx_duration(), Some(Duration::from_secs(512)));
+        assert_eq!(RelativeDelay::TimeUnits(u16::MAX.into()).approx_duration(), Some(Duration::from_secs(65535 * 512)));
+        assert_eq!(RelativeDelay::TimeUnits(u32::MAX).approx_duration(), None);
+    }
+
+    quickcheck::quickcheck! {
+        fn hints_height_used_as_current_height(hints: offer::EscrowHints) -> bool {
+            let height = hints.height;
+            Funding::from_hints(hints).current_height == height
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn implausible_lock_height_rejected(offer: Offer, offset: u16) -> bool {
+            let mut fixture = FundingFixture::new(offer, &[1_000_000]);
+            let current_height = Height::from_consensus(800_000).unwrap();
+            let lock_height = Height::from_consensus(800_000 + MAX_LOCK_HEIGHT_WINDOW + 1 + u32::from(offset)).unwrap();
+            fixture.funding_tx.lock_time = LockTime::Blocks(lock_height);
+            fixture.funding_tx.input[0].sequence = Sequence::ENABLE_LOCKTIME_NO_RBF;
+            let mut funding = fixture.funding();
+            funding.current_height = Some(current_height);
+            let result = fixture.state.funding_received(funding, &mut Vec::new());
+            matches!(result, Err((_, FundingError { reason: FundingErrorReason::ImplausibleLockHeight { lock_height: l, current_height: c } })) if l == lock_height && c == current_height)
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn payment_to_escrow_address_detected(offer: Offer) -> bool {
+            let mut fixture = FundingFixture::new(offer, &[1_000_000]);
+            let escrow_key = fixture.state.deterministic_escrow_key_pair();
+            let funding = |fixture: &FundingFixture| {
+                let mut funding = fixture.funding();
+                funding.escrow_key_pair = Some(escrow_key);
+                funding
+            };
+            // Get the actual escrow address by funding the contract correctly first.
+            let funded = fixture.state.clone().funding_received(funding(&fixture), &mut Vec::new())
+                .map_err(|(_, error)| error)
+                .unwrap();
+            fixture.funding_tx.output[0].script_pubkey = funded.unsigned_txes.escrow_output().script_pubkey.clone();
+            let result = fixture.state.clone().funding_received(funding(&fixture), &mut Vec::new());
+            matches!(result, Err((_, FundingError { reason: FundingErrorReason::SentToEscrowAddress })))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn contract_output_position_checked(offer: Offer, position: u32) -> bool {
+            let fixture = FundingFixture::new(offer, &[1_000_000]);
+            let mut funding = fixture.funding();
+            funding.escrow_extra_outputs.push(TxOut::minimal_non_dust(ScriptBuf::new()));
+            funding.escrow_contract_output_position = position.max(2);
+            let result = fixture.state.funding_received(funding, &mut Vec::new());
+            matches!(result, Err((_, FundingError { reason: FundingErrorReason::ContractOutputPositionOutOfRange { extra_output_count: 1, .. } })))
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn escrow_inputs_shuffled(offer: Offer, seed: u64) -> bool {
+            use rand::SeedableRng;
+
+            let amounts = (1..=8).map(|i| i * 100_000).collect::<Vec<_>>();
+            let fixture = FundingFixture::new(offer, &amounts);
+            let escrow_inputs = |shuffle: bool| {
+                let mut funding = fixture.funding();
+                if shuffle {
+                    funding.shuffle_escrow_inputs(&mut rand::rngs::StdRng::seed_from_u64(seed));
+                }
+                let state = fixture.state.clone().funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+                let psbt = state.unsigned_txes.escrow_psbt(&state.participant_data.prefund, &Default::default());
+                let paired = psbt.unsigned_tx.input.iter().zip(&psbt.inputs).all(|(txin, input)| {
+                    input.witness_utxo.as_ref() == Some(&fixture.funding_tx.output[txin.previous_output.vout as usize])
+                });
+                assert!(paired);
+                psbt.unsigned_tx.input.iter().map(|txin| txin.previous_output.vout).collect::<Vec<_>>()
+            };
+            let ordered = escrow_inputs(false);
+            let shuffled = escrow_inputs(true);
+            let mut sorted = shuffled.clone();
+            sorted.sort();
+            let mut funding = fixture.funding();
+            funding.shuffle_escrow_inputs(&mut rand::rngs::StdRng::seed_from_u64(seed));
+            let drawn = funding.escrow_input_order.unwrap().iter().map(|index| *index as u32).collect::<Vec<_>>();
+            ordered == (0..8).collect::<Vec<_>>() && sorted == ordered && shuffled == escrow_inputs(true) && shuffled == drawn
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn recover_split_outputs(offer: Offer) -> bool {
+            let fixture = FundingFixture::new(offer, &[1_000_000]);
+            let return_script = fixture.return_script.clone();
+            let secondary_script = ScriptBuf::new_p2tr(SECP256K1, Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0, None);
+            let mut funding = fixture.funding();
+            funding.recover_split = Some(RecoverSplit::new(secondary_script.clone(), 25).unwrap());
+            let state = fixture.state.funding_received(funding, &mut Vec::new()).map_err(|(_, error)| error).unwrap();
+            let recover = &state.unsigned_txes.recover;
+            let primary = recover.output.iter().find(|txout| txout.script_pubkey == return_script).unwrap().value;
+            let secondary = recover.output.iter().find(|txout| txout.script_pubkey == secondary_script).unwrap().value;
+            recover.output.len() == 2 && secondary == (primary + secondary) * 25 / 100
+        }
+    }
+
+    #[test]
+    fn recover_split_percentage_checked() {
+        let script = ScriptBuf::new();
+        assert!(matches!(RecoverSplit::new(script.clone(), 0), Err(RecoverSplitError::NothingToSecondary)));
+        assert!(matches!(RecoverSplit::new(script.clone(), 100), Err(RecoverSplitError::NothingToReturn)));
+        assert!(matches!(RecoverSplit::new(script.clone(), 101), Err(RecoverSplitError::AboveHundred(101))));
+        assert!(RecoverSplit::new(script.clone(), 1).is_ok());
+        assert!(RecoverSplit::new(script, 99).is_ok());
+    }
+
+    #[test]
+    fn shuffle_escrow_outputs_keeps_outputs() {
+        use rand::SeedableRng;
+
+        let outputs = (0..10)
+            .map(|i| TxOut { value: Amount::from_sat(1000 + i), script_pubkey: ScriptBuf::new() })
+            .collect::<Vec<_>>();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for _ in 0..100 {
+            let mut funding = MandatoryFundingParams {
+                transactions: Vec::new(),
+                escrow_fee_rate: FeeRate::BROADCAST_MIN,
+                finalization_fee_rate: FeeRate::BROADCAST_MIN,
+            }.into_funding();
+            funding.escrow_extra_outputs = outputs.clone();
+            funding.shuffle_escrow_outputs(&mut rng);
+            assert!(funding.escrow_contract_output_position as usize <= outputs.len());
+            let mut shuffled = funding.escrow_extra_outputs;
+            shuffled.sort_by_key(|txout| txout.value);
+            assert_eq!(shuffled, outputs);
+        }
+    }
+
+    #[test]
+    fn prefund_delay_validation() {
+        assert_eq!(PrefundDelay::try_from(Sequence::from_height(1008)).unwrap(), PrefundDelay::from_height(1008));
+        assert!(PrefundDelay::try_from(Sequence::from_512_second_intervals(42)).unwrap().is_time_locked());
+        assert!(PrefundDelay::try_from(Sequence::MAX).is_err());
+        assert!(PrefundDelay::try_from(Sequence::ENABLE_LOCKTIME_NO_RBF).is_err());
+        assert!(PrefundDelay::try_from(Sequence(1 << 20)).is_err());
+        assert_eq!(PrefundDelay::from_height(1008).blocks(), Some(1008));
+        assert_eq!(PrefundDelay::from_height(1008).seconds(), None);
+        assert_eq!(PrefundDelay::from_512_second_intervals(42).seconds(), Some(42 * 512));
+        assert_eq!(PrefundDelay::from_512_second_intervals(42).blocks(), None);
+    }
+
+    #[test]
+    fn extract_spendable_outputs_skips_dust() {
+        let key = Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0;
+        let script = ScriptBuf::new_p2tr_tweaked(bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(key));
+        let transaction = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![
+                TxOut { value: Amount::ZERO, script_pubkey: script.clone() },
+                TxOut { value: Amount::from_sat(100_000), script_pubkey: script.clone() },
+            ],
+        };
+        let mut max_lock_height = Height::ZERO;
+        let mut skipped_dust = false;
+        let txos = extract_spendable_outputs(vec![transaction], &mut max_lock_height, &mut skipped_dust, |candidate| *candidate == script);
+        assert!(skipped_dust);
+        assert_eq!(txos.len(), 1);
+        assert_eq!(txos[0].out_point.vout, 1);
+        assert_eq!(txos[0].tx_out.value, Amount::from_sat(100_000));
+    }
+
+    #[test]
+    fn extract_spendable_outputs_skips_duplicates() {
+        let key = Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0;
+        let script = ScriptBuf::new_p2tr_tweaked(bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(key));
+        let transaction = Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: Vec::new(),
+            output: vec![TxOut { value: Amount::from_sat(100_000), script_pubkey: script.clone() }],
+        };
+        let mut max_lock_height = Height::ZERO;
+        let mut skipped_dust = false;
+        let txos = extract_spendable_outputs(vec![transaction.clone(), transaction], &mut max_lock_height, &mut skipped_dust, |candidate| *candidate == script);
+        assert_eq!(txos.len(), 1);
+    }
 }
@@ -1,4 +1,5 @@
-use super::super::{offer, prefund, escrow, constants::MessageId};
+use super::super::{offer, prefund, escrow, deserialize, Deserialize, StateDeserError, constants::{MessageId, ParticipantId, StateId}};
+use super::{Ted, TedO, TedP};
 use core::convert::TryFrom;
 
 pub enum IncomingMessage {
@@ -49,3 +50,119 @@ impl From<escrow::BorrowerInfoMessageDeserError> for MessageDeserError {
         Self::InvalidEscrowInfo(value)
     }
 }
+
+/// Returns the participant and state of serialized state without consuming the bytes.
+pub fn peek_state(mut bytes: &[u8]) -> Result<(ParticipantId, StateId), PeekStateError> {
+    // Only checks that the version is supported
+    let _version = deserialize::StateVersion::deserialize(&mut bytes)?;
+    if bytes.len() < 2 {
+        return Err(PeekStateError::UnexpectedEnd);
+    }
+    let participant_id = ParticipantId::try_from(bytes[0]).map_err(|_| PeekStateError::InvalidParticipant(bytes[0]))?;
+    let state_id = StateId::try_from(bytes[1]).map_err(|_| PeekStateError::InvalidState(bytes[1]))?;
+    Ok((participant_id, state_id))
+}
+
+#[derive(Debug)]
+pub enum PeekStateError {
+    UnexpectedEnd,
+    UnsupportedVersion(u32),
+    InvalidParticipant(u8),
+    InvalidState(u8),
+}
+
+impl From<deserialize::StateVersionDeserError> for PeekStateError {
+    fn from(value: deserialize::StateVersionDeserError) -> Self {
+        match value {
+            deserialize::StateVersionDeserError::UnexpectedEnd => PeekStateError::UnexpectedEnd,
+            deserialize::StateVersionDeserError::UnsupportedVersion(version) => PeekStateError::UnsupportedVersion(version),
+        }
+    }
+}
+
+type TedReceivingBorrowerInfo = Ted<escrow::ReceivingBorrowerInfo<TedO>, escrow::ReceivingBorrowerInfo<TedP>>;
+type TedWaitingForEscrowConfirmation = Ted<escrow::WaitingForEscrowConfirmation<TedO>, escrow::WaitingForEscrowConfirmation<TedP>>;
+type TedStateDeserError<O, P> = StateDeserError<Ted<<O as Deserialize>::Error, <P as Deserialize>::Error>>;
+
+/// Any state a TED can be in.
+///
+/// This allows loading persisted contracts without knowing their state upfront.
+pub enum AnyTedState {
+    ReceivingBorrowerInfo(Box<TedReceivingBorrowerInfo>),
+    WaitingForEscrowConfirmation(Box<TedWaitingForEscrowConfirmation>),
+}
+
+impl AnyTedState {
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        match self {
+            AnyTedState::ReceivingBorrowerInfo(state) => state.serialize(out),
+            AnyTedState::WaitingForEscrowConfirmation(state) => state.serialize(out),
+        }
+    }
+
+    pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, AnyTedStateDeserError> {
+        let (participant_id, state_id) = peek_state(bytes).map_err(AnyTedStateDeserErrorInner::Peek)?;
+        match participant_id {
+            ParticipantId::TedO | ParticipantId::TedP => (),
+            _ => return Err(AnyTedStateDeserErrorInner::UnexpectedParticipant(participant_id).into()),
+        }
+        match state_id {
+            StateId::EscrowReceivingBorrowerInfo => {
+                TedReceivingBorrowerInfo::deserialize(bytes)
+                    .map(|state| AnyTedState::ReceivingBorrowerInfo(Box::new(state)))
+                    .map_err(|error| AnyTedStateDeserErrorInner::ReceivingBorrowerInfo(error).into())
+            },
+            StateId::WaitingForEscrowConfirmation => {
+                TedWaitingForEscrowConfirmation::deserialize(bytes)
+                    .map(|state| AnyTedState::WaitingForEscrowConfirmation(Box::new(state)))
+                    .map_err(|error| AnyTedStateDeserErrorInner::WaitingForEscrowConfirmation(error).into())
+            },
+            _ => Err(AnyTedStateDeserErrorInner::UnexpectedState(state_id).into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AnyTedStateDeserError(AnyTedStateDeserErrorInner);
+
+impl From<AnyTedStateDeserErrorInner> for AnyTedStateDeserError {
+    fn from(error: AnyTedStateDeserErrorInner) -> Self {
+        AnyTedStateDeserError(error)
+    }
+}
+
+#[derive(Debug)]
+enum AnyTedStateDeserErrorInner {
+    Peek(PeekStateError),
+    UnexpectedParticipant(ParticipantId),
+    UnexpectedState(StateId),
+    ReceivingBorrowerInfo(TedStateDeserError<escrow::ReceivingBorrowerInfo<TedO>, escrow::ReceivingBorrowerInfo<TedP>>),
+    WaitingForEscrowConfirmation(TedStateDeserError<escrow::WaitingForEscrowConfirmation<TedO>, escrow::WaitingForEscrowConfirmation<TedP>>),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_any_state(bytes: Vec<u8>) -> bool {
+        let mut slice = &*bytes;
+        let state = AnyTedState::deserialize(&mut slice).unwrap();
+        let mut serialized = Vec::new();
+        state.serialize(&mut serialized);
+        slice.is_empty() && serialized == bytes
+    }
+
+    quickcheck::quickcheck! {
+        fn any_state_receiving_borrower_info(state: TedReceivingBorrowerInfo) -> bool {
+            let mut bytes = Vec::new();
+            state.serialize(&mut bytes);
+            check_any_state(bytes)
+        }
+
+        fn any_state_waiting_for_escrow_confirmation(state: TedWaitingForEscrowConfirmation) -> bool {
+            let mut bytes = Vec::new();
+            state.serialize(&mut bytes);
+            check_any_state(bytes)
+        }
+    }
+}
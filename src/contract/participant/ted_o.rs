@@ -24,6 +24,9 @@ impl Deserialize for PrefundData {
         match version {
             deserialize::StateVersion::V0 => (),
             deserialize::StateVersion::V1 => (),
+            deserialize::StateVersion::V2 => (),
+            deserialize::StateVersion::V3 => (),
+            deserialize::StateVersion::V4 => (),
         }
         let key_pair = deserialize::key_pair(bytes)
             .map_err(PrefundDataDeserErrorInner::Secp256k1)
@@ -103,6 +106,10 @@ impl super::super::SetBorrowerSpendInfo for EscrowData {
             }
         }
     }
+
+    fn has_borrower_spend_info(&self) -> bool {
+        self.prefund.is_ready()
+    }
 }
 
 pub fn init(prefund_key_pair: Keypair, escrow_key_pair: Keypair, offer: offer::Offer) -> escrow::ReceivingBorrowerInfo<super::TedO> {
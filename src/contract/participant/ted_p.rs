@@ -23,6 +23,9 @@ impl Deserialize for PrefundData {
         match version {
             deserialize::StateVersion::V0 => (),
             deserialize::StateVersion::V1 => (),
+            deserialize::StateVersion::V2 => (),
+            deserialize::StateVersion::V3 => (),
+            deserialize::StateVersion::V4 => (),
         }
         let key_pair = deserialize::key_pair(bytes)
             .map_err(PrefundDataDeserErrorInner::Secp256k1)
@@ -103,6 +106,10 @@ impl super::super::SetBorrowerSpendInfo for EscrowData {
             }
         }
     }
+
+    fn has_borrower_spend_info(&self) -> bool {
+        self.prefund.is_ready()
+    }
 }
 
 pub fn init(prefund_key_pair: Keypair, escrow_key_pair: Keypair, offer: offer::Offer) -> escrow::ReceivingBorrowerInfo<super::TedP> {
@@ -130,6 +137,15 @@ impl escrow::ReceivingBorrowerInfo<super::TedP> {
 }
 
 impl escrow::WaitingForEscrowConfirmation<super::TedP> {
+    /// Signs the transactions again producing TedPSignatures
+    pub fn re_sign(&self) -> escrow::TedPSignatures {
+        let prefund = match &self.participant_data.prefund {
+            prefund::State::Ready(prefund) => Some(prefund),
+            prefund::State::ReceivingBorrowerInfo(_) => None,
+        };
+        self.unsigned_txes.sign_ted_p(self.participant_data.key_pair, prefund)
+    }
+
     pub fn sign_repayment(&mut self, ted_o_signature: &Signature) -> &Transaction {
         let signature = secp256k1::SECP256K1.sign_schnorr(&self.unsigned_txes.repayment_signing_data(), &self.participant_data.key_pair);
         let keys = self.keys.add_borrower_eph(self.unsigned_txes.borrower_eph);
@@ -7,15 +7,16 @@ use core::fmt;
 use bitcoin::{Address, ScriptBuf, TxOut, Transaction, Witness};
 use bitcoin::locktime::absolute::{LockTime, Height};
 use bitcoin::p2p::Magic;
-use bitcoin::taproot::{LeafVersion, TaprootSpendInfo};
+use bitcoin::taproot::{ControlBlock, TaprootSpendInfo};
 use bitcoin::key::TweakedPublicKey;
+use bitcoin::hashes::{sha256, Hash};
 use super::context;
 use super::primitives::SpendableTxo;
 //use super::multisig::MultisigSigningState;
 use super::participant::{self, Participant};
-use super::pub_keys::{PubKeys, PubKey};
+use super::pub_keys::{self, PubKeys, PubKey};
 use bitcoin::secp256k1::{Secp256k1, Verification};
-use bitcoin::taproot::TapNodeHash;
+use bitcoin::taproot::{TapLeafHash, TapNodeHash};
 use super::offer::TedSigPubKeys;
 use super::{Serialize, Deserialize, StateData, constants, deserialize};
 
@@ -101,9 +102,9 @@ impl<P: Participant> StateData for Prefund<P> {
 
 impl<P: Participant> Serialize for Prefund<P> where P::PrefundData: super::Serialize {
     fn serialize(&self, out: &mut Vec<u8>) {
-        out.reserve(4 + 3 * 32 + 32);
+        out.reserve(4 + 4 * 32 + 32);
         out.extend_from_slice(&self.network.magic().to_bytes());
-        self.keys.serialize_raw(out);
+        self.keys.serialize_state(out);
         out.extend_from_slice(self.borrower_return_hash.as_ref());
         // no need to store output key since it's a cache
         self.participant_data.serialize(out);
@@ -117,7 +118,7 @@ impl<P: Participant> Deserialize for Prefund<P> where P::PrefundData: super::Des
         let magic = deserialize::magic(bytes)?;
         let network = bitcoin::Network::from_magic(magic)
             .ok_or(PrefundDeserializationErrorInner::UnknownNetwork(magic))?;
-        let keys = PubKeys::deserialize_raw(bytes).map_err(PrefundDeserializationErrorInner::from)?;
+        let keys = PubKeys::deserialize_state(bytes, version).map_err(PrefundDeserializationErrorInner::from)?;
         if bytes.len() < 32 {
             return Err(PrefundDeserializationErrorInner::UnexpectedEnd.into());
         }
@@ -184,6 +185,35 @@ impl<P: Participant> Prefund<P> {
         ScriptBuf::new_p2tr_tweaked(self.output_key)
     }
 
+    /// Returns the Electrum-style hash of the funding script.
+    ///
+    /// This can be used to subscribe to the funding address using Electrum or Esplora APIs.
+    pub fn funding_script_hash(&self) -> sha256::Hash {
+        electrum_script_hash(&self.funding_script())
+    }
+
+    /// Returns the script of the leaf spendable by all three parties.
+    pub fn multisig_script(&self) -> ScriptBuf {
+        self.keys.generate_multisig_script()
+    }
+
+    /// Returns the hash of the leaf spendable by all three parties.
+    ///
+    /// This is the leaf hash committed to by the escrow signatures.
+    pub fn multisig_leaf_hash(&self) -> TapLeafHash {
+        pub_keys::leaf_hash(&self.multisig_script())
+    }
+
+    /// Returns the control block for spending using the multisig leaf.
+    pub fn multisig_control_block(&self) -> ControlBlock {
+        ControlBlock {
+            leaf_version: pub_keys::LEAF_VERSION,
+            internal_key: self.keys.generate_internal_key(),
+            output_key_parity: self.parity,
+            merkle_branch: [self.borrower_return_hash].into(),
+        }
+    }
+
     pub fn borrower_info(&self) -> BorrowerSpendInfo {
         BorrowerSpendInfo {
             key: self.keys.borrower_eph,
@@ -197,10 +227,24 @@ impl<P: Participant> Prefund<P> {
 }
 
 impl Prefund<participant::Borrower> {
+    /// Returns the borrower's recovery leaf script and its serialized control block.
+    ///
+    /// This allows assembling the recovery witness with a signature produced by an external
+    /// signer. The witness is the signature followed by these two elements.
+    pub fn recovery_spend_components(&self) -> (ScriptBuf, Vec<u8>) {
+        let (_, tapscript) = self.participant_data.borrower_key_and_leaf_script();
+        let control_block = ControlBlock {
+            leaf_version: pub_keys::LEAF_VERSION,
+            internal_key: self.keys.generate_internal_key(),
+            output_key_parity: self.parity,
+            merkle_branch: [TapNodeHash::from(self.multisig_leaf_hash())].into(),
+        };
+        (tapscript, control_block.serialize())
+    }
+
     /// Used when the borrower decides to cancel the contract in the prefund stage.
     pub fn spend_borrower(&self, inputs: Vec<SpendableTxo>, outputs: Vec<TxOut>, current_height: Height) -> Transaction {
         use bitcoin::sighash::{SighashCache, Prevouts, TapSighashType};
-        use bitcoin::taproot::ControlBlock;
         use super::HotKey;
 
         let (prevouts, inputs): (Vec<_>, Vec<_>) = inputs
@@ -210,20 +254,8 @@ impl Prefund<participant::Borrower> {
 
         let lock_time = LockTime::Blocks(current_height);
         let output_script = self.funding_script();
-        let internal_key = self.keys.generate_internal_key();
-        let multisig_script = self.keys.generate_multisig_script();
-        let multisig_script_hash = multisig_script.tapscript_leaf_hash();
-        let multisig_script_hash = TapNodeHash::from(multisig_script_hash);
-        let (_, tapscript) = self.participant_data.borrower_key_and_leaf_script();
-        let merkle_branch = [multisig_script_hash].into();
-        let control_block = ControlBlock {
-            leaf_version: LeafVersion::TapScript,
-            internal_key,
-            output_key_parity: self.parity,
-            merkle_branch,
-        };
-        let control_block = control_block.serialize();
-        let leaf_hash = tapscript.tapscript_leaf_hash();
+        let (tapscript, control_block) = self.recovery_spend_components();
+        let leaf_hash = pub_keys::leaf_hash(&tapscript);
 
         let mut transaction = Transaction {
             version: bitcoin::transaction::Version(2),
@@ -259,6 +291,26 @@ impl Prefund<participant::Borrower> {
     }
 }
 
+/// Predicts the weight of an input spending prefund using the multisig script.
+///
+/// The size is exact since all elements of the witness have a known size.
+pub(crate) fn multisig_spend_input_prediction() -> bitcoin::transaction::InputWeightPrediction {
+    let witness_elem_sizes = &[
+        64, // len of signature1
+        64, // len of signature2
+        64, // len of signature3
+              33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1  // len of OP_CHECKSIGVERIFY
+            + 33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1  // len of OP_CHECKSIGVERIFY
+            + 33  // len of push_x_only_key (1 instr + 32 B data)
+            +  1, // len of OP_CHECKSIG
+              33  // base len of control block
+            + 32  // len of the hash hiding the borrower conditions
+    ];
+    bitcoin::transaction::InputWeightPrediction::new(0, witness_elem_sizes.iter().copied())
+}
+
 /// The state of the prefund contract when the borrower information is not yet known.
 pub struct ReceivingBorrowerInfo<P: Participant> {
     network: bitcoin::Network,
@@ -272,9 +324,9 @@ pub struct ReceivingBorrowerInfo<P: Participant> {
 crate::test_macros::impl_test_traits!(ReceivingBorrowerInfo<P: Participant> where { P::PrefundData }, network, keys, participant_data);
 crate::test_macros::impl_arbitrary!(ReceivingBorrowerInfo<P: Participant> where { P::PrefundData }, network, keys, participant_data);
 
-fn compute_output_key(ctx: &Secp256k1<impl Verification>, keys: PubKeys<context::Prefund>, borrower_hash: TapNodeHash) -> (TweakedPublicKey, secp256k1::Parity) {
+pub(crate) fn compute_output_key(ctx: &Secp256k1<impl Verification>, keys: PubKeys<context::Prefund>, borrower_hash: TapNodeHash) -> (TweakedPublicKey, secp256k1::Parity) {
     let multisig_script = keys.generate_multisig_script();
-    let multisig_hash = multisig_script.tapscript_leaf_hash();
+    let multisig_hash = pub_keys::leaf_hash(&multisig_script);
     let root = TapNodeHash::from_node_hashes(borrower_hash, multisig_hash.into());
     let internal_key = keys.generate_internal_key();
     let spend_info = TaprootSpendInfo::new_key_spend(&ctx, internal_key, Some(root));
@@ -321,7 +373,7 @@ impl<P: Participant> StateData for ReceivingBorrowerInfo<P> {
 impl<P: Participant> Serialize for ReceivingBorrowerInfo<P> where P::PrefundData: Serialize {
     fn serialize(&self, out: &mut Vec<u8>) {
         out.extend_from_slice(&self.network.magic().to_bytes());
-        self.keys.serialize(out);
+        self.keys.serialize_state(out);
         self.participant_data.serialize(out);
     }
 }
@@ -335,7 +387,7 @@ impl<P: Participant> Deserialize for ReceivingBorrowerInfo<P> where P::PrefundDa
         let magic = deserialize::magic(bytes)?;
         let network = bitcoin::Network::from_magic(magic)
             .ok_or(ReceivingBorrowerInfoDeserError(ReceivingBorrowerInfoDeserErrorInner::InvalidNetwork(magic)))?;
-        let keys = TedSigPubKeys::deserialize(bytes)
+        let keys = TedSigPubKeys::deserialize_state(bytes, version)
             .map_err(ReceivingBorrowerInfoDeserErrorInner::Keys)
             .map_err(ReceivingBorrowerInfoDeserError)?;
         let participant_data = P::PrefundData::deserialize(bytes, version)
@@ -381,6 +433,11 @@ impl<P: Participant> State<P> {
         State::ReceivingBorrowerInfo(ReceivingBorrowerInfo::with_participant_data(keys, network, participant_data))
     }
 
+    /// Returns `true` if the borrower's spend info was already received.
+    pub fn is_ready(&self) -> bool {
+        matches!(self, State::Ready(_))
+    }
+
     pub fn serialize(&self, out: &mut Vec<u8>) where P::PrefundData: super::Serialize {
         // The individual variants are self-tagged
         match self {
@@ -491,7 +548,7 @@ impl<E> From<deserialize::StateVersionDeserError> for StateDeserError<E> {
 }
 
 /// Information about the borrower's spending conditions.
-#[derive(Clone)]
+#[derive(Debug, Clone)]
 pub struct BorrowerSpendInfo {
     pub key: PubKey<participant::Borrower, context::Prefund>,
     // Hash of Taproot node representing spending conditions for return transaction
@@ -533,11 +590,45 @@ enum BorrowerSpendInfoDeserErrorInner {
     Secp256k1(secp256k1::Error),
 }
 
+/// Computes the script hash used by the Electrum protocol.
+///
+/// The bytes are reversed so that the hex representation of the hash matches the one used by
+/// Electrum.
+fn electrum_script_hash(script: &bitcoin::Script) -> sha256::Hash {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).to_byte_array();
+    hash.reverse();
+    sha256::Hash::from_byte_array(hash)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn electrum_script_hash_test_vector() {
+        // from the Electrum protocol documentation
+        let script = bitcoin::ScriptBuf::from(hex_lit::hex!("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").to_vec());
+        assert_eq!(electrum_script_hash(&script).to_string(), "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161");
+    }
+
     crate::test_macros::check_roundtrip_with_version!(roundtrip_prefund, Prefund<participant::Borrower>);
     crate::test_macros::check_roundtrip_with_version!(roundtrip_receiving_borrower_info, ReceivingBorrowerInfo<participant::Borrower>);
     crate::test_macros::check_roundtrip!(roundtrip_state, State<participant::Borrower>);
+
+    quickcheck::quickcheck! {
+        fn recovery_control_block_commits_to_output(prefund: Prefund<participant::Borrower>) -> bool {
+            let mut prefund = prefund;
+            // arbitrary prefund doesn't commit to the borrower's script
+            let (script, _) = prefund.recovery_spend_components();
+            prefund.borrower_return_hash = pub_keys::leaf_hash(&script).into();
+            let (output_key, parity) = compute_output_key(secp256k1::SECP256K1, prefund.keys, prefund.borrower_return_hash);
+            prefund.output_key = output_key;
+            prefund.parity = parity;
+
+            let (script, control_block) = prefund.recovery_spend_components();
+            let control_block = ControlBlock::decode(&control_block).unwrap();
+            let output_key = bitcoin::key::XOnlyPublicKey::from(prefund.output_key);
+            control_block.verify_taproot_commitment(secp256k1::SECP256K1, output_key, &script)
+        }
+    }
 }
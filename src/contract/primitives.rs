@@ -1,7 +1,7 @@
 //! Primitives shared by both subcontracts.
 
 use core::marker::PhantomData;
-use bitcoin::{OutPoint, ScriptBuf, Sequence, TxOut, TxIn, Witness};
+use bitcoin::{Amount, FeeRate, OutPoint, ScriptBuf, Sequence, TxOut, TxIn, Weight, Witness};
 
 /// Contains all information required to spend an output excluding signatures.
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -48,6 +48,17 @@ impl SpendableTxo {
 
 crate::test_macros::impl_arbitrary!(SpendableTxo, out_point, tx_out, sequence);
 
+/// Computes the fee a CPFP child has to pay to bring the package to `target_rate`.
+///
+/// The fee already paid by the parent is subtracted so the result is zero if the parent alone
+/// pays enough. Saturates at `Amount::MAX` if the required fee overflows.
+pub fn cpfp_required_fee(parent_weight: Weight, parent_fee: Amount, child_weight: Weight, target_rate: FeeRate) -> Amount {
+    parent_weight.checked_add(child_weight)
+        .and_then(|package_weight| target_rate.checked_mul_by_weight(package_weight))
+        .map(|package_fee| package_fee.checked_sub(parent_fee).unwrap_or(Amount::ZERO))
+        .unwrap_or(Amount::MAX)
+}
+
 /// Shared seed for randomization of transactions.
 ///
 /// To make it harder for chain analysts to identify the transactions belonging to this contract
@@ -91,6 +102,21 @@ enum KeyIndex {
 
 #[cfg(test)]
 mod tests {
+    quickcheck::quickcheck! {
+        fn cpfp_fee_reaches_target_rate(parent_weight: u32, parent_fee: u32, child_weight: u32, target_rate: u16) -> bool {
+            use bitcoin::{Amount, FeeRate, Weight};
+
+            let parent_weight = Weight::from_wu(parent_weight.into());
+            let child_weight = Weight::from_wu(child_weight.into());
+            let parent_fee = Amount::from_sat(parent_fee.into());
+            let target_rate = FeeRate::from_sat_per_kwu(target_rate.into());
+            let fee = super::cpfp_required_fee(parent_weight, parent_fee, child_weight, target_rate);
+            let package_weight = parent_weight + child_weight;
+            let reaches = |fee: Amount| FeeRate::from_sat_per_kwu((fee + parent_fee).to_sat() * 1000 / package_weight.to_wu().max(1)) >= target_rate;
+            reaches(fee) && (fee == Amount::ZERO || !reaches(fee - Amount::from_sat(1)))
+        }
+    }
+
     #[test]
     fn permutation() {
         use secp256k1::XOnlyPublicKey;
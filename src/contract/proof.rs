@@ -0,0 +1,288 @@
+//! Proof of contract.
+//!
+//! This module contains a bundle of all public data of the contract which allows a third party,
+//! such as an arbiter, to verify the contract without access to the private state of any
+//! participant.
+
+use bitcoin::{Transaction, ScriptBuf, key::XOnlyPublicKey};
+use bitcoin::taproot::ControlBlock;
+
+use super::{audit, constants, escrow, offer, prefund, pub_keys};
+
+/// All public data of the contract.
+///
+/// The escrow transaction and the recover transaction are fully signed.
+#[derive(Debug)]
+pub struct ContractProof {
+    pub offer: offer::Offer,
+    pub prefund_info: prefund::BorrowerSpendInfo,
+    pub borrower_info: escrow::BorrowerInfoMessage,
+    pub ted_o_signatures: escrow::TedOSignatures,
+    pub ted_p_signatures: escrow::TedPSignatures,
+    pub escrow: Transaction,
+    pub recover: Transaction,
+}
+
+impl ContractProof {
+    /// Checks that all data is consistent and all signatures are valid.
+    ///
+    /// The transactions are constructed again from the offer and the borrower information so this
+    /// doesn't trust any of the participants.
+    pub fn verify(&self) -> Result<(), ProofError> {
+        let info = self.borrower_info.borrower_info.clone()
+            .validate(&self.offer.escrow)
+            .map_err(ProofError::InvalidBorrowerInfo)?;
+        let escrow_keys = self.offer.escrow_keys.add_borrower_eph(info.escrow_eph_key);
+        let unsigned = escrow::unsigned_transactions(&self.offer.escrow, self.offer.escrow_keys, info);
+
+        unsigned.verify_borrower(&self.borrower_info.signatures)
+            .map_err(ProofError::BorrowerSignatures)?;
+        unsigned.verify_ted_o_external(escrow_keys.ted_o.as_x_only(), &self.ted_o_signatures)
+            .map_err(ProofError::TedOSignatures)?;
+        unsigned.verify_ted_p_external(escrow_keys.ted_p.as_x_only(), &self.ted_p_signatures)
+            .map_err(ProofError::TedPSignatures)?;
+
+        let mut recover = unsigned.recover.clone();
+        escrow::finalize(&mut recover, &escrow_keys, &self.borrower_info.signatures.recover, &self.ted_o_signatures.recover, &self.ted_p_signatures.recover);
+        if recover != self.recover {
+            return Err(ProofError::RecoverMismatch);
+        }
+
+        // The txid doesn't commit to witnesses so this checks everything except them.
+        if self.escrow.compute_txid() != unsigned.escrow.compute_txid() {
+            return Err(ProofError::EscrowMismatch);
+        }
+
+        let prefund_keys = self.offer.prefund_keys.add_borrower_eph(self.prefund_info.key);
+        let (output_key, _) = prefund::compute_output_key(secp256k1::SECP256K1, prefund_keys, self.prefund_info.return_hash);
+        let funding_script = ScriptBuf::new_p2tr_tweaked(output_key);
+        let output_key = XOnlyPublicKey::from(output_key);
+        let multisig_script = prefund_keys.generate_multisig_script();
+        let leaf_hash = pub_keys::leaf_hash(&multisig_script);
+
+        let mut ted_o_escrow_sigs = self.ted_o_signatures.escrow.iter();
+        let mut ted_p_escrow_sigs = self.ted_p_signatures.escrow.iter();
+        // we don't use `Iterator::zip` because that wouldn't detect fewer signatures
+        for (i, message) in unsigned.escrow_signing_data_for(funding_script, leaf_hash) {
+            match (ted_o_escrow_sigs.next(), ted_p_escrow_sigs.next()) {
+                (Some(ted_o), Some(ted_p)) => {
+                    secp256k1::SECP256K1.verify_schnorr(ted_o, &message, prefund_keys.ted_o.as_x_only())
+                        .map_err(ProofError::TedOSignatures)?;
+                    secp256k1::SECP256K1.verify_schnorr(ted_p, &message, prefund_keys.ted_p.as_x_only())
+                        .map_err(ProofError::TedPSignatures)?;
+                },
+                _ => return Err(ProofError::MissingEscrowSignature),
+            }
+
+            // this also checks the witness length and the script
+            let signatures = audit::extract_witness_signatures(&self.escrow, i, &prefund_keys)
+                .map_err(|_| ProofError::InvalidEscrowWitness { input: i })?;
+            let control_block = ControlBlock::decode(&self.escrow.input[i].witness[4])
+                .map_err(|_| ProofError::InvalidEscrowWitness { input: i })?;
+            if control_block.leaf_version != pub_keys::LEAF_VERSION || !control_block.verify_taproot_commitment(secp256k1::SECP256K1, output_key, &multisig_script) {
+                return Err(ProofError::InvalidEscrowWitness { input: i });
+            }
+            for (key, signature) in &signatures {
+                secp256k1::SECP256K1.verify_schnorr(signature, &message, key)
+                    .map_err(|_| ProofError::InvalidEscrowWitness { input: i })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn serialize(&self, out: &mut Vec<u8>) {
+        use bitcoin::consensus::Encodable;
+
+        out.push(constants::MessageId::ContractProof as u8);
+        self.offer.serialize(out);
+        self.prefund_info.serialize(out);
+        self.borrower_info.serialize(out);
+        self.ted_o_signatures.serialize(out);
+        self.ted_p_signatures.serialize(out);
+        self.escrow.consensus_encode(out).expect("vec doesn't error");
+        self.recover.consensus_encode(out).expect("vec doesn't error");
+    }
+
+    pub fn deserialize(bytes: &mut &[u8]) -> Result<Self, ContractProofDeserError> {
+        use bitcoin::consensus::Decodable;
+
+        match bytes.first() {
+            Some(&id) if id == constants::MessageId::ContractProof as u8 => *bytes = &bytes[1..],
+            Some(&id) => return Err(ContractProofDeserErrorInner::InvalidMessage(id).into()),
+            None => return Err(ContractProofDeserErrorInner::UnexpectedEnd.into()),
+        }
+        let offer = offer::Offer::deserialize(bytes).map_err(ContractProofDeserErrorInner::Offer)?;
+        let prefund_info = prefund::BorrowerSpendInfo::deserialize(bytes).map_err(ContractProofDeserErrorInner::PrefundInfo)?;
+        let borrower_info = escrow::BorrowerInfoMessage::deserialize(bytes).map_err(ContractProofDeserErrorInner::BorrowerInfo)?;
+        let ted_o_signatures = escrow::TedOSignatures::deserialize(bytes).map_err(ContractProofDeserErrorInner::TedOSignatures)?;
+        let ted_p_signatures = escrow::TedPSignatures::deserialize(bytes).map_err(ContractProofDeserErrorInner::TedPSignatures)?;
+        let escrow = Transaction::consensus_decode(bytes).map_err(ContractProofDeserErrorInner::Escrow)?;
+        let recover = Transaction::consensus_decode(bytes).map_err(ContractProofDeserErrorInner::Recover)?;
+
+        Ok(ContractProof {
+            offer,
+            prefund_info,
+            borrower_info,
+            ted_o_signatures,
+            ted_p_signatures,
+            escrow,
+            recover,
+        })
+    }
+}
+
+/// Returned when the proof of contract is invalid.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ProofError {
+    InvalidBorrowerInfo(escrow::BorrowerInfoError),
+    BorrowerSignatures(escrow::BorrowerSignatureError),
+    TedOSignatures(secp256k1::Error),
+    TedPSignatures(secp256k1::Error),
+    MissingEscrowSignature,
+    /// The escrow transaction doesn't match the one constructed from the contract.
+    EscrowMismatch,
+    /// The witness of the escrow input at the given index is not a valid multisig spend.
+    InvalidEscrowWitness { input: usize },
+    /// The recover transaction doesn't match the one constructed from the contract.
+    RecoverMismatch,
+}
+
+#[derive(Debug)]
+pub struct ContractProofDeserError(ContractProofDeserErrorInner);
+
+impl From<ContractProofDeserErrorInner> for ContractProofDeserError {
+    fn from(error: ContractProofDeserErrorInner) -> Self {
+        ContractProofDeserError(error)
+    }
+}
+
+#[derive(Debug)]
+enum ContractProofDeserErrorInner {
+    UnexpectedEnd,
+    InvalidMessage(u8),
+    Offer(offer::DeserializationError),
+    PrefundInfo(prefund::BorrowerSpendInfoDeserError),
+    BorrowerInfo(escrow::BorrowerInfoMessageDeserError),
+    TedOSignatures(escrow::TedOSignaturesDeserError),
+    TedPSignatures(escrow::TedPSignaturesDeserError),
+    Escrow(bitcoin::consensus::encode::Error),
+    Recover(bitcoin::consensus::encode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::key::Keypair;
+    use bitcoin::taproot::TapNodeHash;
+    use crate::contract::pub_keys::PubKey;
+    use crate::contract::primitives::Permutation;
+
+    fn new_key_pair() -> Keypair {
+        Keypair::new_global(&mut rand::thread_rng())
+    }
+
+    fn build_proof(mut offer: offer::Offer, mut info: escrow::BorrowerInfo<escrow::validation::Unvalidated>) -> ContractProof {
+        let borrower_prefund = new_key_pair();
+        let ted_o_prefund = new_key_pair();
+        let ted_p_prefund = new_key_pair();
+        let borrower_escrow = new_key_pair();
+        let ted_o_escrow = new_key_pair();
+        let ted_p_escrow = new_key_pair();
+
+        offer.prefund_keys = offer::TedSigPubKeys { ted_o: PubKey::from_key_pair(&ted_o_prefund), ted_p: PubKey::from_key_pair(&ted_p_prefund), internal_key: offer.prefund_keys.internal_key };
+        offer.escrow_keys = offer::TedSigPubKeys { ted_o: PubKey::from_key_pair(&ted_o_escrow), ted_p: PubKey::from_key_pair(&ted_p_escrow), internal_key: offer.escrow_keys.internal_key };
+        let prefund_info = prefund::BorrowerSpendInfo {
+            key: PubKey::from_key_pair(&borrower_prefund),
+            return_hash: TapNodeHash::assume_hidden([42; 32]),
+        };
+        let prefund_keys = offer.prefund_keys.add_borrower_eph(prefund_info.key);
+        let (output_key, parity) = prefund::compute_output_key(secp256k1::SECP256K1, prefund_keys, prefund_info.return_hash);
+        let funding_script = ScriptBuf::new_p2tr_tweaked(output_key);
+
+        info.escrow_eph_key = PubKey::from_key_pair(&borrower_escrow);
+        info.escrow_contract_output_position %= info.escrow_extra_outputs.len() as u32 + 1;
+        info.collateral_amount_default = offer.escrow.min_collateral;
+        info.collateral_amount_liquidation = offer.escrow.min_collateral;
+        // keep the test fast, signing is expensive
+        info.inputs.truncate(4);
+        for input in &mut info.inputs {
+            input.tx_out.script_pubkey = funding_script.clone();
+            input.tx_out.value = bitcoin::Amount::from_sat(100_000);
+        }
+        for output in &mut info.escrow_extra_outputs {
+            output.value = bitcoin::Amount::ZERO;
+        }
+        info.escrow_amount = bitcoin::Amount::from_sat(100_000) * info.inputs.len() as u64;
+
+        let validated = info.clone().validate(&offer.escrow).unwrap();
+        let unsigned = escrow::unsigned_transactions(&offer.escrow, offer.escrow_keys, validated);
+        let signatures = unsigned.sign_borrower(borrower_escrow);
+        let mut ted_o_signatures = unsigned.sign_ted_o(ted_o_escrow, None);
+        let mut ted_p_signatures = unsigned.sign_ted_p(ted_p_escrow, None);
+
+        let multisig_script = prefund_keys.generate_multisig_script();
+        let control_block = ControlBlock {
+            leaf_version: pub_keys::LEAF_VERSION,
+            internal_key: prefund_keys.generate_internal_key(),
+            output_key_parity: parity,
+            merkle_branch: [prefund_info.return_hash].into(),
+        }.serialize();
+        let mut escrow_tx = unsigned.escrow.clone();
+        for (i, message) in unsigned.escrow_signing_data_for(funding_script.clone(), pub_keys::leaf_hash(&multisig_script)) {
+            let borrower = secp256k1::SECP256K1.sign_schnorr(&message, &borrower_prefund);
+            let ted_o = secp256k1::SECP256K1.sign_schnorr(&message, &ted_o_prefund);
+            let ted_p = secp256k1::SECP256K1.sign_schnorr(&message, &ted_p_prefund);
+            escrow_tx.input[i].witness = super::super::assemble_witness(&borrower, &ted_o, &ted_p, Permutation::from_keys(&prefund_keys), &multisig_script, &control_block);
+            ted_o_signatures.escrow.push(ted_o);
+            ted_p_signatures.escrow.push(ted_p);
+        }
+
+        let escrow_keys = offer.escrow_keys.add_borrower_eph(info.escrow_eph_key);
+        let mut recover = unsigned.recover.clone();
+        escrow::finalize(&mut recover, &escrow_keys, &signatures.recover, &ted_o_signatures.recover, &ted_p_signatures.recover);
+
+        ContractProof {
+            offer,
+            prefund_info,
+            borrower_info: escrow::BorrowerInfoMessage { borrower_info: info, signatures },
+            ted_o_signatures,
+            ted_p_signatures,
+            escrow: escrow_tx,
+            recover,
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn valid_proof_verifies(offer: offer::Offer, info: escrow::BorrowerInfo<escrow::validation::Unvalidated>) -> bool {
+            let proof = build_proof(offer, info);
+            let mut bytes = Vec::new();
+            proof.serialize(&mut bytes);
+            let mut cursor = &*bytes;
+            let deserialized = ContractProof::deserialize(&mut cursor).unwrap();
+            proof.verify().is_ok() && deserialized.verify().is_ok() && cursor.is_empty()
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn tampered_proof_fails(offer: offer::Offer, info: escrow::BorrowerInfo<escrow::validation::Unvalidated>) -> bool {
+            let mut proof = build_proof(offer, info);
+            let recover = proof.recover.clone();
+            proof.recover.lock_time = bitcoin::absolute::LockTime::from_consensus(recover.lock_time.to_consensus_u32() ^ 1);
+            let recover_detected = matches!(proof.verify(), Err(ProofError::RecoverMismatch));
+            proof.recover = recover;
+
+            let escrow_detected = match proof.escrow.input.first_mut() {
+                Some(input) => {
+                    let mut elements = input.witness.to_vec();
+                    elements.swap(0, 1);
+                    input.witness = elements.into();
+                    matches!(proof.verify(), Err(ProofError::InvalidEscrowWitness { input: 0 }))
+                },
+                None => true,
+            };
+            recover_detected && escrow_detected
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! PSBT version 2 (BIP370) export of contract transactions.
+//!
+//! This allows any PSBT-v2-capable coordinator to drive the signing ceremony. The PSBTs are
+//! first constructed using the version 0 types from the `bitcoin` crate, which doesn't support
+//! version 2, and then converted by replacing the global unsigned transaction with the
+//! per-input and per-output fields.
+
+use std::collections::BTreeMap;
+use bitcoin::bip32::KeySource;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::locktime::absolute::LockTime;
+use bitcoin::psbt::Psbt;
+use bitcoin::sighash::TapSighashType;
+use bitcoin::Transaction;
+
+use super::{context, escrow, offer, prefund, pub_keys};
+use super::escrow::UnsignedTransactions;
+use super::participant::Participant;
+
+const MAGIC: &[u8] = b"psbt\xff";
+
+const GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const GLOBAL_TX_VERSION: u8 = 0x02;
+const GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const GLOBAL_INPUT_COUNT: u8 = 0x04;
+const GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const GLOBAL_VERSION: u8 = 0xfb;
+
+const IN_PREVIOUS_TXID: u8 = 0x0e;
+const IN_OUTPUT_INDEX: u8 = 0x0f;
+const IN_SEQUENCE: u8 = 0x10;
+const IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+
+const OUT_AMOUNT: u8 = 0x03;
+const OUT_SCRIPT: u8 = 0x04;
+
+type Map = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// The transactions spending the escrow output.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Terminal {
+    Repayment,
+    Default,
+    Liquidation,
+    Recover,
+}
+
+impl Terminal {
+    pub const ALL: [Terminal; 4] = [Terminal::Repayment, Terminal::Default, Terminal::Liquidation, Terminal::Recover];
+
+    fn transaction(self, txes: &UnsignedTransactions) -> &Transaction {
+        match self {
+            Terminal::Repayment => &txes.repayment,
+            Terminal::Default => &txes.default,
+            Terminal::Liquidation => &txes.liquidation,
+            Terminal::Recover => &txes.recover,
+        }
+    }
+}
+
+/// Returns the escrow transaction as PSBT v2.
+///
+/// See [`UnsignedTransactions::escrow_psbt`] for details.
+pub fn escrow_v2(txes: &UnsignedTransactions, prefund: &prefund::Prefund<impl Participant>, key_sources: &BTreeMap<XOnlyPublicKey, KeySource>) -> Vec<u8> {
+    to_v2(&txes.escrow_psbt(prefund, key_sources))
+}
+
+/// Returns the terminal transaction as PSBT v2.
+///
+/// See [`terminal`] for details.
+pub fn terminal_v2(txes: &UnsignedTransactions, terminal: Terminal, ted_keys: &offer::TedSigPubKeys<context::Escrow>, key_sources: &BTreeMap<XOnlyPublicKey, KeySource>) -> Vec<u8> {
+    to_v2(&self::terminal(txes, terminal, ted_keys, key_sources))
+}
+
+/// Returns the terminal transaction as PSBT with the escrow input annotated.
+///
+/// The input contains the escrow output being spent, the multisig leaf script with its control
+/// block, the sighash type and key origins of those keys found in `key_sources`.
+pub fn terminal(txes: &UnsignedTransactions, terminal: Terminal, ted_keys: &offer::TedSigPubKeys<context::Escrow>, key_sources: &BTreeMap<XOnlyPublicKey, KeySource>) -> Psbt {
+    let keys = ted_keys.add_borrower_eph(txes.borrower_eph);
+    let spend_info = escrow::output_spend_info_with_scripts(&keys);
+    let script = (keys.generate_multisig_script(), pub_keys::LEAF_VERSION);
+    let control_block = spend_info.control_block(&script)
+        .expect("the multisig script is in the tree");
+    let key_origins = keys.sorted()
+        .iter()
+        .filter_map(|key| key_sources.get(*key).map(|source| (**key, (vec![txes.multisig_leaf_hash], source.clone()))))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut psbt = Psbt::from_unsigned_tx(terminal.transaction(txes).clone())
+        .expect("the terminal transactions are unsigned");
+    // All terminal transactions have a single input spending the escrow output.
+    for input in &mut psbt.inputs {
+        input.witness_utxo = Some(txes.escrow_output().clone());
+        input.tap_scripts.insert(control_block.clone(), script.clone());
+        input.tap_key_origins = key_origins.clone();
+        input.tap_internal_key = Some(spend_info.internal_key());
+        input.tap_merkle_root = spend_info.merkle_root();
+        input.sighash_type = Some(TapSighashType::Default.into());
+    }
+    psbt
+}
+
+/// Converts the PSBT to version 2 and serializes it.
+///
+/// All fields of the PSBT are preserved except the global unsigned transaction which is encoded
+/// in the version 2 fields instead. If the transaction is time-locked the lock time is also
+/// required by all inputs so that coordinators can't change it.
+pub fn to_v2(psbt: &Psbt) -> Vec<u8> {
+    let tx = &psbt.unsigned_tx;
+    let serialized = psbt.serialize();
+    let mut bytes = serialized
+        .strip_prefix(MAGIC)
+        .expect("the bitcoin crate serializes valid PSBTs");
+
+    let mut global = read_map(&mut bytes);
+    global.remove(&[GLOBAL_UNSIGNED_TX][..]);
+    global.insert(vec![GLOBAL_TX_VERSION], tx.version.0.to_le_bytes().to_vec());
+    global.insert(vec![GLOBAL_FALLBACK_LOCKTIME], tx.lock_time.to_consensus_u32().to_le_bytes().to_vec());
+    global.insert(vec![GLOBAL_INPUT_COUNT], var_int(tx.input.len()));
+    global.insert(vec![GLOBAL_OUTPUT_COUNT], var_int(tx.output.len()));
+    global.insert(vec![GLOBAL_VERSION], 2u32.to_le_bytes().to_vec());
+
+    let required_lock_time = match tx.lock_time {
+        LockTime::Blocks(height) if height.to_consensus_u32() != 0 => Some((IN_REQUIRED_HEIGHT_LOCKTIME, height.to_consensus_u32())),
+        LockTime::Blocks(_) => None,
+        LockTime::Seconds(time) => Some((IN_REQUIRED_TIME_LOCKTIME, time.to_consensus_u32())),
+    };
+
+    let mut result = MAGIC.to_vec();
+    write_map(&mut result, &global);
+    for txin in &tx.input {
+        let mut input = read_map(&mut bytes);
+        input.insert(vec![IN_PREVIOUS_TXID], bitcoin::consensus::serialize(&txin.previous_output.txid));
+        input.insert(vec![IN_OUTPUT_INDEX], txin.previous_output.vout.to_le_bytes().to_vec());
+        input.insert(vec![IN_SEQUENCE], txin.sequence.to_consensus_u32().to_le_bytes().to_vec());
+        if let Some((key, lock_time)) = required_lock_time {
+            input.insert(vec![key], lock_time.to_le_bytes().to_vec());
+        }
+        write_map(&mut result, &input);
+    }
+    for txout in &tx.output {
+        let mut output = read_map(&mut bytes);
+        output.insert(vec![OUT_AMOUNT], txout.value.to_sat().to_le_bytes().to_vec());
+        output.insert(vec![OUT_SCRIPT], txout.script_pubkey.to_bytes());
+        write_map(&mut result, &output);
+    }
+    assert!(bytes.is_empty(), "the number of maps matches the transaction");
+    result
+}
+
+fn var_int(value: usize) -> Vec<u8> {
+    let mut result = Vec::new();
+    VarInt(value as u64).consensus_encode(&mut result).expect("vec doesn't error");
+    result
+}
+
+/// Reads a key-value map serialized by the `bitcoin` crate.
+///
+/// # Panics
+///
+/// If the map is malformed.
+fn read_map(bytes: &mut &[u8]) -> Map {
+    fn read_slice(bytes: &mut &[u8]) -> Vec<u8> {
+        let len = VarInt::consensus_decode(bytes).expect("valid length").0 as usize;
+        let (data, rest) = bytes.split_at(len);
+        *bytes = rest;
+        data.to_vec()
+    }
+
+    let mut map = Map::new();
+    loop {
+        let key = read_slice(bytes);
+        if key.is_empty() {
+            break map;
+        }
+        let value = read_slice(bytes);
+        map.insert(key, value);
+    }
+}
+
+fn write_map(out: &mut Vec<u8>, map: &Map) {
+    for (key, value) in map {
+        out.extend_from_slice(&var_int(key.len()));
+        out.extend_from_slice(key);
+        out.extend_from_slice(&var_int(value.len()));
+        out.extend_from_slice(value);
+    }
+    out.push(0x00);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::TryInto;
+    use bitcoin::{Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxOut, Txid, Witness};
+    use bitcoin::transaction::Version;
+    use crate::test_macros::qc_help::Arbitrary;
+
+    /// Reconstructs the unsigned transaction using the BIP370 algorithm.
+    fn unsigned_tx_from_v2(mut bytes: &[u8]) -> Transaction {
+        fn get_u32(map: &Map, key: u8) -> Option<u32> {
+            map.get(&[key][..]).map(|value| u32::from_le_bytes(value[..].try_into().unwrap()))
+        }
+
+        assert!(bytes.starts_with(MAGIC));
+        bytes = &bytes[MAGIC.len()..];
+        let global = read_map(&mut bytes);
+        assert_eq!(get_u32(&global, GLOBAL_VERSION), Some(2));
+        assert!(!global.contains_key(&[GLOBAL_UNSIGNED_TX][..]));
+        let input_count = VarInt::consensus_decode(&mut &global[&[GLOBAL_INPUT_COUNT][..]][..]).unwrap().0;
+        let output_count = VarInt::consensus_decode(&mut &global[&[GLOBAL_OUTPUT_COUNT][..]][..]).unwrap().0;
+
+        let mut heights = Vec::new();
+        let mut times = Vec::new();
+        let input = (0..input_count).map(|_| {
+            let map = read_map(&mut bytes);
+            heights.extend(get_u32(&map, IN_REQUIRED_HEIGHT_LOCKTIME));
+            times.extend(get_u32(&map, IN_REQUIRED_TIME_LOCKTIME));
+            TxIn {
+                previous_output: OutPoint {
+                    txid: bitcoin::consensus::deserialize(&map[&[IN_PREVIOUS_TXID][..]]).unwrap(),
+                    vout: get_u32(&map, IN_OUTPUT_INDEX).unwrap(),
+                },
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence(get_u32(&map, IN_SEQUENCE).unwrap_or(u32::MAX)),
+                witness: Witness::new(),
+            }
+        }).collect();
+        let output = (0..output_count).map(|_| {
+            let map = read_map(&mut bytes);
+            TxOut {
+                value: Amount::from_sat(u64::from_le_bytes(map[&[OUT_AMOUNT][..]][..].try_into().unwrap())),
+                script_pubkey: ScriptBuf::from(map[&[OUT_SCRIPT][..]].clone()),
+            }
+        }).collect();
+        assert!(bytes.is_empty());
+
+        let lock_time = heights.into_iter().max()
+            .or_else(|| times.into_iter().max())
+            .or_else(|| get_u32(&global, GLOBAL_FALLBACK_LOCKTIME))
+            .unwrap_or(0);
+        Transaction {
+            version: Version(i32::from_le_bytes(global[&[GLOBAL_TX_VERSION][..]][..].try_into().unwrap())),
+            lock_time: LockTime::from_consensus(lock_time),
+            input,
+            output,
+        }
+    }
+
+    quickcheck::quickcheck! {
+        fn v2_encodes_unsigned_transaction(prevouts: Vec<(u32, u32)>, outputs: Vec<(u64, Vec<u8>)>, lock_time: u32) -> bool {
+            let mut gen = quickcheck::Gen::new(32);
+            let tx = Transaction {
+                version: Version::TWO,
+                lock_time: LockTime::from_consensus(lock_time),
+                input: prevouts.into_iter().map(|(vout, sequence)| TxIn {
+                    previous_output: OutPoint { txid: Txid::arbitrary(&mut gen), vout },
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence(sequence),
+                    witness: Witness::new(),
+                }).collect(),
+                output: outputs.into_iter().map(|(value, script)| TxOut {
+                    value: Amount::from_sat(value),
+                    script_pubkey: ScriptBuf::from(script),
+                }).collect(),
+            };
+            let mut psbt = Psbt::from_unsigned_tx(tx.clone()).unwrap();
+            for input in &mut psbt.inputs {
+                input.sighash_type = Some(TapSighashType::Default.into());
+            }
+
+            let v2 = to_v2(&psbt);
+            let mut inputs = &v2[MAGIC.len()..];
+            read_map(&mut inputs);
+            let sighash_preserved = psbt.inputs.iter().all(|_| read_map(&mut inputs).contains_key(&[0x03][..]));
+            sighash_preserved && unsigned_tx_from_v2(&v2).compute_txid() == tx.compute_txid()
+        }
+    }
+}
@@ -4,25 +4,117 @@ use bitcoin::{key::XOnlyPublicKey, ScriptBuf, key::Keypair, Sequence};
 use bitcoin::key::UntweakedPublicKey;
 use bitcoin::blockdata::script;
 use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::taproot::{LeafVersion, TapLeafHash};
 use core::fmt;
 use core::marker::PhantomData;
 use super::context;
 
+/// The leaf version of all scripts used in the contracts.
+///
+/// All leaf hashes and control blocks have to be computed using this version.
+pub const LEAF_VERSION: LeafVersion = LeafVersion::TapScript;
+
+/// Computes the leaf hash of a contract script using [`LEAF_VERSION`].
+pub fn leaf_hash(script: &bitcoin::Script) -> TapLeafHash {
+    TapLeafHash::from_script(script, LEAF_VERSION)
+}
+
+/// The internal key of the taproot outputs of the contract.
+///
+/// The key should be a NUMS point so that the outputs can only be spent using the scripts. The
+/// default is the point used by Firefish but alternative deployments may use their own point and
+/// tests may use a key with known discrete logarithm. All participants of a contract must use the
+/// same key. It's carried by the [`Offer`](super::offer::Offer) but it's part of the deployment
+/// configuration so the borrower checks it against the locally configured key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InternalKey(UntweakedPublicKey);
+
+impl InternalKey {
+    /// Uses the given key as the internal key.
+    pub fn new(key: UntweakedPublicKey) -> Self {
+        InternalKey(key)
+    }
+
+    /// Parses the x-only serialization of the key checking that it's a valid point.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, bitcoin::secp256k1::Error> {
+        XOnlyPublicKey::from_slice(bytes).map(InternalKey)
+    }
+
+    pub fn to_x_only_public_key(self) -> UntweakedPublicKey {
+        self.0
+    }
+
+    /// Returns `true` if this is the NUMS point used by Firefish.
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub(crate) fn serialize(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.serialize());
+    }
+
+    pub(crate) fn deserialize(bytes: &mut &[u8]) -> Result<Self, bitcoin::secp256k1::Error> {
+        if bytes.len() < 32 {
+            return Err(bitcoin::secp256k1::Error::InvalidPublicKey);
+        }
+        let key = Self::from_slice(&bytes[..32])?;
+        *bytes = &bytes[32..];
+        Ok(key)
+    }
+
+    /// Deserializes the key stored in a state of the given version.
+    ///
+    /// The states prior to `V3` don't contain the key, they always use the default.
+    pub(crate) fn deserialize_state(bytes: &mut &[u8], version: super::deserialize::StateVersion) -> Result<Self, bitcoin::secp256k1::Error> {
+        use super::deserialize::StateVersion;
+
+        match version {
+            StateVersion::V0 | StateVersion::V1 | StateVersion::V2 => Ok(Self::default()),
+            StateVersion::V3 | StateVersion::V4 => Self::deserialize(bytes),
+        }
+    }
+}
+
+impl Default for InternalKey {
+    fn default() -> Self {
+        // Hash of "Firefish NUMS 79BE667E F9DCBBAC 55A06295 CE870B07 029BFCDB 2DCE28D9 59F2815B 16F81798\n"
+        InternalKey(XOnlyPublicKey::from_slice(&hex_lit::hex!("42bd12e5ccca5b830e755b1e9d7104bdf89819276746d7b5d42cb2a227bff08d")).expect("we statically know the input and it is correct"))
+    }
+}
+
+#[cfg(test)]
+impl quickcheck::Arbitrary for InternalKey {
+    fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
+        if bool::arbitrary(gen) {
+            InternalKey::default()
+        } else {
+            InternalKey(crate::test_macros::arbitrary(gen))
+        }
+    }
+}
+
 /// Contains public keys of all participants.
 pub struct PubKeys<Contract> {
     pub borrower_eph: PubKey<participant::Borrower, Contract>,
     pub ted_o: PubKey<participant::TedO, Contract>,
     pub ted_p: PubKey<participant::TedP, Contract>,
+    /// The internal key of the taproot output.
+    pub internal_key: InternalKey,
 }
 
 impl<C> Copy for PubKeys<C> { }
 
-crate::test_macros::impl_test_traits!(PubKeys<C>, borrower_eph, ted_o, ted_p);
+crate::test_macros::impl_test_traits!(PubKeys<C>, borrower_eph, ted_o, ted_p, internal_key);
 
 impl<C> Eq for PubKeys<C> { }
 
 impl<C> PubKeys<C> {
     pub fn new(borrower_eph: PubKey<participant::Borrower, C>, ted_o: PubKey<participant::TedO, C>, ted_p: PubKey<participant::TedP, C>) -> Result<Self, Error> {
+        Self::with_internal_key(borrower_eph, ted_o, ted_p, InternalKey::default())
+    }
+
+    /// Creates the keys using a custom internal key.
+    pub fn with_internal_key(borrower_eph: PubKey<participant::Borrower, C>, ted_o: PubKey<participant::TedO, C>, ted_p: PubKey<participant::TedP, C>, internal_key: InternalKey) -> Result<Self, Error> {
         if borrower_eph.0 == ted_o.0 || borrower_eph.0 == ted_p.0 || ted_o.0 == ted_p.0 {
             Err(Error::DuplicateKeys)
         } else {
@@ -30,6 +122,7 @@ impl<C> PubKeys<C> {
                 borrower_eph,
                 ted_o,
                 ted_p,
+                internal_key,
             })
         }
     }
@@ -41,8 +134,7 @@ impl<C> PubKeys<C> {
     }
 
     pub fn generate_internal_key(&self) -> UntweakedPublicKey {
-        // Hash of "Firefish NUMS 79BE667E F9DCBBAC 55A06295 CE870B07 029BFCDB 2DCE28D9 59F2815B 16F81798\n"
-        XOnlyPublicKey::from_slice(&hex_lit::hex!("42bd12e5ccca5b830e755b1e9d7104bdf89819276746d7b5d42cb2a227bff08d")).expect("we statically know the input and it is correct")
+        self.internal_key.to_x_only_public_key()
     }
 
     pub fn generate_multisig_script(&self) -> ScriptBuf {
@@ -57,21 +149,36 @@ impl<C> PubKeys<C> {
             .into_script()
     }
 
+    /// Serializes the participant keys without the internal key.
     pub(crate) fn serialize_raw(&self, out: &mut Vec<u8>) {
         self.borrower_eph.serialize_raw(out);
         self.ted_o.serialize_raw(out);
         self.ted_p.serialize_raw(out);
     }
 
+    /// Deserializes the participant keys using the default internal key.
     pub(crate) fn deserialize_raw(bytes: &mut &[u8]) -> Result<Self, RawDeserError> {
         let borrower_eph = PubKey::deserialize_raw(bytes)?;
         let ted_o = PubKey::deserialize_raw(bytes)?;
         let ted_p = PubKey::deserialize_raw(bytes)?;
         Self::new(borrower_eph, ted_o, ted_p).map_err(RawDeserError::DuplicateKeys)
     }
+
+    /// Serializes the keys including the internal key as stored in the current state version.
+    pub(crate) fn serialize_state(&self, out: &mut Vec<u8>) {
+        self.serialize_raw(out);
+        self.internal_key.serialize(out);
+    }
+
+    /// Deserializes the keys stored in a state of the given version.
+    pub(crate) fn deserialize_state(bytes: &mut &[u8], version: super::deserialize::StateVersion) -> Result<Self, RawDeserError> {
+        let mut keys = Self::deserialize_raw(bytes)?;
+        keys.internal_key = InternalKey::deserialize_state(bytes, version)?;
+        Ok(keys)
+    }
 }
 
-crate::test_macros::impl_arbitrary!(PubKeys<C>, borrower_eph, ted_o, ted_p);
+crate::test_macros::impl_arbitrary!(PubKeys<C>, borrower_eph, ted_o, ted_p, internal_key);
 
 #[derive(Debug)]
 pub(crate) enum RawDeserError {
@@ -211,13 +318,34 @@ mod tests {
         check_sorted(key_c, key_b, key_a);
     }
 
+    #[test]
+    fn custom_internal_key_spendable() {
+        use bitcoin::key::{Keypair, TapTweak};
+        use super::{InternalKey, PubKeys, PubKey};
+        use super::super::context;
+
+        let internal_key_pair = Keypair::from_seckey_slice(secp256k1::SECP256K1, &[42; 32]).unwrap();
+        let internal_key = InternalKey::from_slice(&internal_key_pair.x_only_public_key().0.serialize()).unwrap();
+        assert!(InternalKey::from_slice(&[0; 32]).is_err());
+
+        let key_pairs = [(); 3].map(|_| Keypair::new_global(&mut rand::thread_rng()));
+        let keys = PubKeys::<context::Escrow>::with_internal_key(PubKey::from_key_pair(&key_pairs[0]), PubKey::from_key_pair(&key_pairs[1]), PubKey::from_key_pair(&key_pairs[2]), internal_key).unwrap();
+        let default_keys = PubKeys::<context::Escrow>::new(keys.borrower_eph, keys.ted_o, keys.ted_p).unwrap();
+        assert_ne!(keys.generate_internal_key(), default_keys.generate_internal_key());
+        assert!(default_keys.internal_key.is_default() && !internal_key.is_default());
+
+        let (spend_info, _) = super::super::escrow::output_spend_info(&keys);
+        let tweaked = internal_key_pair.tap_tweak(secp256k1::SECP256K1, spend_info.merkle_root());
+        assert_eq!(tweaked.to_inner().x_only_public_key().0, spend_info.output_key().to_inner());
+    }
+
     quickcheck::quickcheck! {
         fn pub_keys_roundtrips(keys: super::PubKeys<super::super::context::Escrow>) -> bool {
             let mut bytes = Vec::new();
-            keys.serialize_raw(&mut bytes);
-            let keys2 = super::PubKeys::deserialize_raw(&mut &*bytes).unwrap();
+            keys.serialize_state(&mut bytes);
+            let keys2 = super::PubKeys::deserialize_state(&mut &*bytes, super::super::deserialize::StateVersion::CURRENT).unwrap();
 
-            keys == keys2
+            keys == keys2 && keys.internal_key == keys2.internal_key
         }
     }
 }
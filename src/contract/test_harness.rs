@@ -0,0 +1,389 @@
+//! In-memory simulation of the whole contract.
+//!
+//! This drives all participants in a single program, passing the serialized messages between
+//! them the same way they would be passed over the network. It's intended for integration tests
+//! of applications using the contract, it must not be used with real funds since all keys are
+//! held by a single party.
+
+use bitcoin::{Address, Transaction};
+use bitcoin::key::Keypair;
+
+use super::{escrow, participant, prefund, BorrowerInfoError, InitError};
+use super::participant::Ted;
+use super::pub_keys::PubKey;
+use super::offer::{self, AllParticipantKeys, Offer};
+use super::participant::borrower::{Funding, FundingError, PrefundParams, WaitingForFunding};
+
+/// The keys of a single TED.
+#[derive(Debug, Copy, Clone)]
+pub struct TedKeyPairs {
+    pub prefund: Keypair,
+    pub escrow: Keypair,
+}
+
+impl TedKeyPairs {
+    fn random() -> Self {
+        TedKeyPairs {
+            prefund: Keypair::new_global(&mut rand::thread_rng()),
+            escrow: Keypair::new_global(&mut rand::thread_rng()),
+        }
+    }
+
+    fn public_keys<P: participant::Participant>(&self) -> AllParticipantKeys<P> {
+        AllParticipantKeys {
+            prefund: PubKey::from_key_pair(&self.prefund),
+            escrow: PubKey::from_key_pair(&self.escrow),
+        }
+    }
+}
+
+/// Both TEDs of a simulated contract.
+#[derive(Debug, Copy, Clone)]
+pub struct FullContract {
+    pub ted_o: TedKeyPairs,
+    pub ted_p: TedKeyPairs,
+}
+
+/// All final transactions of a simulated contract.
+///
+/// All transactions are fully signed.
+#[derive(Debug, Clone)]
+pub struct SimResult {
+    pub escrow: Transaction,
+    pub recover: Transaction,
+    pub repayment: Transaction,
+    pub default: Transaction,
+    pub liquidation: Transaction,
+}
+
+/// Error returned when a step of the simulation fails.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SimError {
+    /// The TED keys don't match the offer.
+    Init(InitError),
+    /// The TED keys are in the wrong slots of the offer.
+    ///
+    /// The keys of TED-O were found in the TED-P slot or vice versa.
+    RoleMismatch,
+    /// The borrower rejected the internal key of the offer.
+    InternalKey(offer::InternalKeyMismatch),
+    /// TEDs rejected the prefund spend information of the borrower.
+    SpendInfo(BorrowerInfoError),
+    /// The borrower rejected the funding.
+    Funding(FundingError),
+    /// TEDs rejected the borrower information.
+    BorrowerInfo(escrow::BorrowerInfoError),
+    /// TEDs rejected the borrower signatures.
+    BorrowerSignatures(escrow::BorrowerSignatureError),
+    /// The borrower rejected the TED signatures.
+    TedSignatures(escrow::SignatureVerificationError),
+    /// The escrow transaction couldn't be assembled.
+    Escrow(escrow::SignatureVerificationError),
+}
+
+impl FullContract {
+    /// Creates the contract with randomly generated TED keys.
+    pub fn random() -> Self {
+        FullContract {
+            ted_o: TedKeyPairs::random(),
+            ted_p: TedKeyPairs::random(),
+        }
+    }
+
+    /// Returns the public keys of TEDs to be used in the offer.
+    ///
+    /// See [`MandatoryOfferFields::with_ted_keys`](super::offer::MandatoryOfferFields::with_ted_keys).
+    pub fn ted_keys(&self) -> (AllParticipantKeys<participant::TedO>, AllParticipantKeys<participant::TedP>) {
+        (self.ted_o.public_keys(), self.ted_p.public_keys())
+    }
+
+    /// Runs the whole message exchange returning the final transactions.
+    ///
+    /// The offer must contain the keys returned by [`ted_keys`](Self::ted_keys). The `funding`
+    /// closure receives the funding address and returns the funding of the prefund.
+    pub fn simulate(&self, offer: Offer, borrower_params: PrefundParams, funding: impl FnOnce(Address) -> Funding) -> Result<SimResult, SimError> {
+        let Presigned { borrower, ted_o, mut ted_p, ted_o_signatures, ted_p_signatures } = self.presign(offer, borrower_params, funding, false)?;
+        let borrower = borrower.verify_signatures(ted_o_signatures.clone(), ted_p_signatures)
+            .map_err(|(_, error)| SimError::TedSignatures(error))?;
+        let borrower = borrower.assemble_escrow().map_err(|(_, error)| SimError::Escrow(error))?;
+
+        let liquidation_signature = ted_o.ted_o_sign_liquidation();
+        Ok(SimResult {
+            escrow: borrower.tx_escrow().clone(),
+            recover: borrower.recover.clone(),
+            repayment: ted_p.sign_repayment(&ted_o_signatures.repayment).clone(),
+            default: ted_p.sign_default(&ted_o_signatures.default).clone(),
+            liquidation: ted_p.sign_liquidation(&liquidation_signature).clone(),
+        })
+    }
+
+    /// Runs the message exchange up to the point the borrower receives the TED signatures.
+    ///
+    /// If `rotate_escrow_key` is set the borrower replaces the escrow key before TEDs sign.
+    fn presign(&self, offer: Offer, borrower_params: PrefundParams, funding: impl FnOnce(Address) -> Funding, rotate_escrow_key: bool) -> Result<Presigned, SimError> {
+        let ted_o = Ted::init(self.ted_o.prefund, self.ted_o.escrow, offer.clone()).map_err(SimError::Init)?;
+        let ted_p = Ted::init(self.ted_p.prefund, self.ted_p.escrow, offer.clone()).map_err(SimError::Init)?;
+        if !matches!((&ted_o, &ted_p), (Ted::O(_), Ted::P(_))) {
+            return Err(SimError::RoleMismatch);
+        }
+        let borrower = WaitingForFunding::new(offer, borrower_params).map_err(SimError::InternalKey)?;
+
+        let mut message = Vec::new();
+        borrower.borrower_info().serialize(&mut message);
+        let receive_spend_info = |ted: Ted<_, _>| {
+            let info = prefund::BorrowerSpendInfo::deserialize(&mut &*message).expect("we've just serialized it");
+            ted.prefund_borrower_info(info).map_err(|(_, error)| SimError::SpendInfo(error))
+        };
+        let ted_o = receive_spend_info(ted_o)?;
+        let ted_p = receive_spend_info(ted_p)?;
+
+        let funding = funding(borrower.funding_address());
+        let mut message = Vec::new();
+        let mut borrower = borrower.funding_received(funding, &mut message).map_err(|(_, error)| SimError::Funding(error))?;
+        if rotate_escrow_key {
+            message.clear();
+            borrower = borrower.rotate_escrow_key(&mut message);
+        }
+
+        let presign = |ted: Ted<escrow::ReceivingBorrowerInfo<_>, escrow::ReceivingBorrowerInfo<_>>| {
+            let info_message = escrow::BorrowerInfoMessage::deserialize(&mut &*message).expect("we've just serialized it");
+            let params = match &ted {
+                Ted::O(state) => &state.params,
+                Ted::P(state) => &state.params,
+            };
+            let info = info_message.borrower_info.validate(params).map_err(SimError::BorrowerInfo)?;
+            let transactions = ted.borrower_info(info);
+            transactions.verify_borrower(&info_message.signatures).map_err(SimError::BorrowerSignatures)?;
+            let mut signatures = Vec::new();
+            let ted = ted.set_and_sign_transactions(transactions, info_message.signatures, &mut signatures);
+            Ok((ted, signatures))
+        };
+        let (ted_o, ted_o_message) = presign(ted_o)?;
+        let (ted_p, ted_p_message) = presign(ted_p)?;
+        let (ted_o, ted_p) = match (ted_o, ted_p) {
+            (Ted::O(ted_o), Ted::P(ted_p)) => (ted_o, ted_p),
+            _ => return Err(SimError::RoleMismatch),
+        };
+
+        let ted_o_signatures = escrow::TedOSignatures::deserialize(&mut &*ted_o_message).expect("we've just serialized it");
+        let ted_p_signatures = escrow::TedPSignatures::deserialize(&mut &*ted_p_message).expect("we've just serialized it");
+        Ok(Presigned { borrower, ted_o, ted_p, ted_o_signatures, ted_p_signatures })
+    }
+}
+
+/// The states of all participants after TEDs signed the transactions.
+struct Presigned {
+    borrower: escrow::ReceivingEscrowSignature<participant::Borrower>,
+    ted_o: escrow::WaitingForEscrowConfirmation<participant::TedO>,
+    ted_p: escrow::WaitingForEscrowConfirmation<participant::TedP>,
+    ted_o_signatures: escrow::TedOSignatures,
+    ted_p_signatures: escrow::TedPSignatures,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Amount, FeeRate, ScriptBuf, TxOut};
+    use bitcoin::absolute::LockTime;
+    use super::super::participant::borrower::{MandatoryFundingParams, MandatoryPrefundParams, PrefundDelay};
+
+    fn test_offer(contract: &FullContract) -> Offer {
+        test_offer_with_optional(contract, Default::default())
+    }
+
+    fn test_offer_with_optional(contract: &FullContract, optional: offer::OptionalOfferFields) -> Offer {
+        let (ted_o_keys, ted_p_keys) = contract.ted_keys();
+        let liquidator_key = Keypair::new_global(&mut rand::thread_rng()).x_only_public_key().0;
+        let liquidator_script = ScriptBuf::new_p2tr(secp256k1::SECP256K1, liquidator_key, None);
+        offer::MandatoryOfferFields {
+            network: bitcoin::Network::Regtest,
+            liquidator_script_default: liquidator_script.clone(),
+            liquidator_script_liquidation: liquidator_script,
+            min_collateral: Amount::from_sat(100_000),
+            recover_lock_time: LockTime::from_consensus(900_000),
+            default_lock_time: LockTime::from_consensus(800_000),
+            ted_o_keys,
+            ted_p_keys,
+        }.into_offer_with_optional(optional).unwrap()
+    }
+
+    fn test_borrower_params() -> PrefundParams {
+        let key_pair = Keypair::new_global(&mut rand::thread_rng());
+        MandatoryPrefundParams {
+            key_pair,
+            lock_time: PrefundDelay::from_height(144),
+            return_script: ScriptBuf::new_p2tr(secp256k1::SECP256K1, key_pair.x_only_public_key().0, None),
+        }.into_params()
+    }
+
+    fn test_funding_tx(address: &Address) -> Transaction {
+        Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![TxOut { value: Amount::from_sat(1_000_000), script_pubkey: address.script_pubkey() }],
+        }
+    }
+
+    fn test_funding(tx: Transaction) -> Funding {
+        MandatoryFundingParams {
+            transactions: vec![tx],
+            escrow_fee_rate: FeeRate::BROADCAST_MIN,
+            finalization_fee_rate: FeeRate::BROADCAST_MIN,
+        }.into_funding()
+    }
+
+    #[test]
+    fn simulation_produces_connected_transactions() {
+        let contract = FullContract::random();
+        let offer = test_offer(&contract);
+        let mut funding_tx = None;
+        let result = contract.simulate(offer, test_borrower_params(), |address| {
+            let tx = test_funding_tx(&address);
+            funding_tx = Some(tx.clone());
+            test_funding(tx)
+        }).unwrap();
+
+        let funding_txid = funding_tx.unwrap().compute_txid();
+        assert!(result.escrow.input.iter().all(|txin| txin.previous_output.txid == funding_txid && !txin.witness.is_empty()));
+        let escrow_txid = result.escrow.compute_txid();
+        for tx in &[&result.recover, &result.repayment, &result.default, &result.liquidation] {
+            assert_eq!(tx.input.len(), 1);
+            assert_eq!(tx.input[0].previous_output.txid, escrow_txid);
+            assert!(!tx.input[0].witness.is_empty());
+        }
+    }
+
+    #[test]
+    fn re_signed_signatures_accepted_by_borrower() {
+        let contract = FullContract::random();
+        let offer = test_offer(&contract);
+        let presigned = contract.presign(offer, test_borrower_params(), |address| test_funding(test_funding_tx(&address)), false).unwrap();
+        let ted_o = Ted::O(presigned.ted_o.clone());
+        let ted_p = Ted::P(presigned.ted_p.clone());
+
+        let (ted_o_signatures, ted_p_signatures) = match (ted_o.sign_all(), ted_p.sign_all(), presigned.ted_o.re_sign(), presigned.ted_p.re_sign()) {
+            (escrow::TedSignatures::TedO(all_o), escrow::TedSignatures::TedP(all_p), re_signed_o, re_signed_p) => {
+                // BIP340 signing uses random auxiliary data so the signatures can only be compared
+                // by what they sign.
+                let original_o = &presigned.ted_o_signatures;
+                let original_p = &presigned.ted_p_signatures;
+                for signatures in &[&all_o, &re_signed_o] {
+                    assert_eq!(signatures.escrow.len(), original_o.escrow.len());
+                    assert_eq!(signatures.signed_escrow(), original_o.signed_escrow());
+                }
+                for signatures in &[&all_p, &re_signed_p] {
+                    assert_eq!(signatures.escrow.len(), original_p.escrow.len());
+                    assert_eq!(signatures.signed_escrow(), original_p.signed_escrow());
+                }
+                assert!(all_o.signed_escrow() && all_p.signed_escrow());
+                (all_o, re_signed_p)
+            },
+            _ => panic!("sign_all changed the role"),
+        };
+        let borrower = presigned.borrower.verify_signatures(ted_o_signatures, ted_p_signatures)
+            .map_err(|(_, error)| error)
+            .unwrap();
+        borrower.assemble_escrow().map_err(|(_, error)| error).unwrap();
+    }
+
+    #[test]
+    fn rotated_escrow_key_accepted_by_teds() {
+        use super::super::{Serialize, Deserialize};
+        use super::super::deserialize::StateVersion;
+
+        let contract = FullContract::random();
+        let offer = test_offer(&contract);
+        let presigned = contract.presign(offer, test_borrower_params(), |address| test_funding(test_funding_tx(&address)), true).unwrap();
+
+        // TEDs signed the transactions built from the new key.
+        let key_pair = *presigned.borrower.escrow_key_pair().unwrap();
+        let new_key = PubKey::from_key_pair(&key_pair);
+        assert!(presigned.borrower.unsigned_txes.borrower_eph == new_key);
+        assert!(presigned.ted_o.unsigned_txes.borrower_eph == new_key);
+        assert!(presigned.ted_p.unsigned_txes.borrower_eph == new_key);
+        assert!(presigned.ted_o.unsigned_txes == presigned.borrower.unsigned_txes);
+
+        // The key survives saving the state.
+        let mut bytes = Vec::new();
+        presigned.borrower.serialize(&mut bytes);
+        let restored = escrow::ReceivingEscrowSignature::<participant::Borrower>::deserialize(&mut &*bytes, StateVersion::CURRENT).unwrap();
+        assert_eq!(restored.escrow_key_pair(), Some(&key_pair));
+
+        let borrower = presigned.borrower.verify_signatures(presigned.ted_o_signatures, presigned.ted_p_signatures)
+            .map_err(|(_, error)| error)
+            .unwrap();
+        let borrower = borrower.assemble_escrow().map_err(|(_, error)| error).unwrap();
+        let escrow_spend_info = super::super::escrow::output_spend_info(&presigned.ted_o.keys.add_borrower_eph(new_key)).0;
+        assert!(borrower.tx_escrow().output.iter().any(|txout| txout.script_pubkey == bitcoin::ScriptBuf::new_p2tr_tweaked(escrow_spend_info.output_key())));
+    }
+
+    #[test]
+    fn swapped_ted_keys_rejected() {
+        let contract = FullContract::random();
+        let offer = test_offer(&contract);
+        let swapped = FullContract {
+            ted_o: contract.ted_p,
+            ted_p: contract.ted_o,
+        };
+        let result = swapped.simulate(offer, test_borrower_params(), |address| test_funding(test_funding_tx(&address)));
+        assert!(matches!(result, Err(SimError::RoleMismatch)));
+    }
+
+    #[test]
+    fn custom_internal_key_contract() {
+        use bitcoin::key::TapTweak;
+        use bitcoin::taproot::{ControlBlock, TapNodeHash};
+        use super::super::pub_keys::{self, InternalKey};
+
+        let internal_key_pair = Keypair::from_seckey_slice(secp256k1::SECP256K1, &[42; 32]).unwrap();
+        let internal_key = InternalKey::new(internal_key_pair.x_only_public_key().0);
+        let contract = FullContract::random();
+        let optional = offer::OptionalOfferFields { internal_key, ..Default::default() };
+        let offer = test_offer_with_optional(&contract, optional);
+        let offer = offer.to_string().parse::<Offer>().unwrap();
+        assert_eq!(offer.prefund_keys.internal_key, internal_key);
+        assert_eq!(offer.escrow_keys.internal_key, internal_key);
+
+        // The borrower doesn't trust the key from the offer.
+        let result = contract.simulate(offer.clone(), test_borrower_params(), |address| test_funding(test_funding_tx(&address)));
+        assert!(matches!(result, Err(SimError::InternalKey(error)) if error.found == internal_key));
+
+        let mut borrower_params = test_borrower_params();
+        borrower_params.internal_key = internal_key;
+        let mut funding_tx = None;
+        let result = contract.simulate(offer, borrower_params, |address| {
+            let tx = test_funding_tx(&address);
+            funding_tx = Some(tx.clone());
+            test_funding(tx)
+        }).unwrap();
+        let funding_tx = funding_tx.unwrap();
+
+        // Every script spend commits to the custom key and to the output it spends.
+        let check_spend = |txin: &bitcoin::TxIn, prevout: &TxOut| {
+            let witness = &txin.witness;
+            let control_block = ControlBlock::decode(witness.last().unwrap()).unwrap();
+            let script = bitcoin::Script::from_bytes(witness.nth(witness.len() - 2).unwrap());
+            let output_key = bitcoin::key::XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..]).unwrap();
+            assert_eq!(control_block.internal_key, internal_key.to_x_only_public_key());
+            assert!(control_block.verify_taproot_commitment(secp256k1::SECP256K1, output_key, script));
+            (script.to_owned(), output_key)
+        };
+        let funding_txid = funding_tx.compute_txid();
+        for txin in &result.escrow.input {
+            assert_eq!(txin.previous_output.txid, funding_txid);
+            check_spend(txin, &funding_tx.output[txin.previous_output.vout as usize]);
+        }
+        let escrow_txid = result.escrow.compute_txid();
+        for tx in &[&result.recover, &result.repayment, &result.default, &result.liquidation] {
+            assert_eq!(tx.input[0].previous_output.txid, escrow_txid);
+            let (script, output_key) = check_spend(&tx.input[0], &result.escrow.output[tx.input[0].previous_output.vout as usize]);
+            // The escrow output has a single leaf so the key with the known secret can spend it
+            // directly.
+            let merkle_root = TapNodeHash::from(pub_keys::leaf_hash(&script));
+            let tweaked = internal_key_pair.tap_tweak(secp256k1::SECP256K1, Some(merkle_root));
+            assert_eq!(tweaked.to_inner().x_only_public_key().0, output_key);
+        }
+    }
+}
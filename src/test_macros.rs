@@ -259,6 +259,18 @@ pub(crate) mod qc_help {
         }
     }
 
+    impl<T: Arbitrary> Arbitrary for Option<T> {
+        fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
+            use quickcheck::Arbitrary;
+
+            if bool::arbitrary(gen) {
+                Some(T::arbitrary(gen))
+            } else {
+                None
+            }
+        }
+    }
+
     impl<T: Arbitrary> Arbitrary for Vec<T> {
         fn arbitrary(gen: &mut quickcheck::Gen) -> Self {
             use quickcheck::Arbitrary;
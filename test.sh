@@ -158,7 +158,7 @@ function prepare_and_prefund() {
 function escrow() {
 	if [ "$test_kind" = "--bitcoin-cli" ];
 	then
-		presigned_states="$(echo "$prefund_raw_tx" | $cli escrow init-from-prefund "$borrower_state_file" 1000 1000 "$borrower_fee_bump_address" | tail -n 1)"
+		presigned_states="$(echo "$prefund_raw_tx" | $cli escrow init-from-prefund "$borrower_state_file" 1000 1000 "$borrower_fee_bump_address" "`$bitcoin_cli getblockcount`" | tail -n 1)"
 	else
 		presigned_states="$($cli escrow init-from-prefund "$borrower_state_file" 1000 1000 "$borrower_fee_bump_address" | tail -n 1)"
 	fi